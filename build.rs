@@ -32,10 +32,61 @@ fn run() -> anyhow::Result<()> {
     };
 
     build_scss(paths).context("Failed to build CSS stylesheets")?;
+    capture_build_info(&manifest_dir);
 
     Ok(())
 }
 
+/// Exposes the commit rgit was built from and the version of rustc building it to the crate,
+/// falling back to `unknown` rather than failing if either can't be determined, such as when
+/// building from a tarball.
+fn capture_build_info(manifest_dir: &Path) {
+    println!("cargo:rerun-if-env-changed=RGIT_GIT_COMMIT");
+
+    let git_dir = manifest_dir.join(".git");
+    if git_dir.exists() {
+        println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+        println!("cargo:rerun-if-changed={}", git_dir.join("refs").display());
+    }
+
+    // packagers building without git metadata can pass the commit in themselves
+    let commit = std::env::var("RGIT_GIT_COMMIT")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .or_else(|| {
+            command_output(
+                std::process::Command::new("git")
+                    .args(["rev-parse", "HEAD"])
+                    .current_dir(manifest_dir),
+            )
+        });
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(std::process::Command::new(rustc).arg("--version"));
+
+    println!(
+        "cargo:rustc-env=RGIT_GIT_COMMIT={}",
+        commit.as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "cargo:rustc-env=RGIT_RUSTC_VERSION={}",
+        rustc_version.as_deref().unwrap_or("unknown")
+    );
+}
+
+fn command_output(command: &mut std::process::Command) -> Option<String> {
+    let output = command.output().ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let output = String::from_utf8(output.stdout).ok()?;
+    let output = output.trim();
+
+    (!output.is_empty()).then(|| output.to_string())
+}
+
 fn build_scss(paths: Paths) -> anyhow::Result<()> {
     let in_dir = paths.statics_in_dir.join("sass");
     let out_dir = paths.statics_out_dir.join("css");
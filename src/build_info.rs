@@ -0,0 +1,55 @@
+//! Information about the build of rgit that's running, for working out exactly what's deployed.
+
+use std::{
+    fmt::{Display, Formatter},
+    sync::OnceLock,
+};
+
+use serde::Serialize;
+
+use crate::CRATE_VERSION;
+
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    version: CRATE_VERSION,
+    commit: env!("RGIT_GIT_COMMIT"),
+    rustc: env!("RGIT_RUSTC_VERSION"),
+};
+
+/// Whether build information is shown to visitors, set once at startup.
+static PUBLIC: OnceLock<bool> = OnceLock::new();
+
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// The commit of rgit this was built from, or `unknown` if it was built without git
+    /// metadata.
+    pub commit: &'static str,
+    pub rustc: &'static str,
+}
+
+impl BuildInfo {
+    pub fn short_commit(&self) -> &'static str {
+        self.commit.get(..7).unwrap_or(self.commit)
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rgit v{} ({}, {})",
+            self.version, self.commit, self.rustc
+        )
+    }
+}
+
+pub fn set_public(public: bool) {
+    PUBLIC
+        .set(public)
+        .expect("build info visibility already set");
+}
+
+/// Whether build information should be shown on pages and served from `/-/version`.
+pub fn is_public() -> bool {
+    PUBLIC.get().copied().unwrap_or(true)
+}
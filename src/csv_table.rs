@@ -0,0 +1,81 @@
+//! Renders CSV and TSV files as HTML tables.
+
+use anyhow::{ensure, Context};
+
+use crate::git::RenderedBlob;
+
+/// Largest file that'll be rendered as a table, anything bigger is shown as text.
+const MAX_FILE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Number of rows rendered before the table is cut short.
+const MAX_ROWS: usize = 1000;
+
+/// Files with rows wider than this aren't readable as a table, so they're shown as text.
+const MAX_COLUMNS: usize = 100;
+
+/// Renders `content` as a table of fields separated by `delimiter`, failing if it isn't
+/// well-formed or is too large to be worth rendering.
+pub fn render(content: &str, delimiter: u8) -> anyhow::Result<RenderedBlob> {
+    ensure!(content.len() <= MAX_FILE_SIZE, "file too large to render");
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(content.as_bytes());
+
+    let mut rows = Vec::new();
+    let mut truncated = false;
+
+    for record in reader.records() {
+        let record = record.context("malformed record")?;
+        ensure!(record.len() <= MAX_COLUMNS, "too many columns to render");
+
+        if rows.len() == MAX_ROWS {
+            truncated = true;
+            break;
+        }
+
+        rows.push(record);
+    }
+
+    let mut rows = rows.into_iter().peekable();
+    let mut html = String::from("<div class=\"table-responsive\"><table class=\"csv\">");
+
+    if let Some(header) = rows.next_if(is_header) {
+        html.push_str("<thead><tr>");
+        for field in &header {
+            push_cell(&mut html, "th", field);
+        }
+        html.push_str("</tr></thead>");
+    }
+
+    html.push_str("<tbody>");
+    for row in rows {
+        html.push_str("<tr>");
+        for field in &row {
+            push_cell(&mut html, "td", field);
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</tbody></table></div>");
+
+    Ok(RenderedBlob { html, truncated })
+}
+
+/// Guesses whether the row is a header, which is the case if it has a name for every column
+/// and none of them are numbers.
+fn is_header(row: &csv::StringRecord) -> bool {
+    row.iter()
+        .all(|field| !field.trim().is_empty() && field.trim().parse::<f64>().is_err())
+}
+
+fn push_cell(html: &mut String, tag: &str, field: &str) {
+    html.push('<');
+    html.push_str(tag);
+    html.push('>');
+    v_htmlescape::b_escape(field.as_bytes(), html);
+    html.push_str("</");
+    html.push_str(tag);
+    html.push('>');
+}
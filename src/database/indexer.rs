@@ -2,61 +2,343 @@ use std::{
     collections::HashSet,
     ffi::OsStr,
     fmt::Debug,
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use gix::{bstr::ByteSlice, refs::Category, Reference};
+use gix::{bstr::ByteSlice, object::Kind, refs::Category, ObjectId, Reference};
 use itertools::Itertools;
 use rocksdb::WriteBatch;
-use time::{OffsetDateTime, UtcOffset};
-use tracing::{error, info, info_span, instrument, warn};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tracing::{debug, error, info, info_span, instrument, warn};
 
 use crate::database::schema::{
-    commit::Commit,
-    repository::{ArchivedRepository, Repository, RepositoryId},
-    tag::{Tag, TagTree},
+    commit::{Backfill, Commit, CommitTree},
+    repository::{
+        ArchivedRepository, Link, ObjectStats, Remote, Repository, RepositoryId, FEED_ITEMS_RANGE,
+        LOG_PAGE_SIZE_RANGE,
+    },
+    tag::{Tag, TagTarget, TagTree},
+    tombstone::Tombstone,
 };
+use crate::git::{
+    configured_default_branch, find_default_branch, is_empty_repository, is_export_ok,
+    is_missing_object, peel_nested_tag, retry_transient, signature_time, unsupported_object_format,
+};
+use crate::methods::repo::ScanRoot;
+
+/// What happened during a single run of the indexer.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RunSummary {
+    /// Repositories found within the scan path.
+    pub repositories_scanned: usize,
+    /// Repositories that had new commits indexed.
+    pub repositories_updated: usize,
+    /// Repositories that had nothing new to index.
+    pub repositories_unchanged: usize,
+    /// Repositories that failed to index in some way, these will be retried on the next run.
+    pub repositories_failed: usize,
+    /// Total number of commits written to the index.
+    pub commits_indexed: u64,
+    /// Repositories with older history still to be indexed on later runs.
+    pub backfilling: Vec<Backfilling>,
+    #[serde(skip)]
+    pub duration: Duration,
+}
+
+/// A repository whose older history is still being indexed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Backfilling {
+    pub repository: String,
+    /// Commits left to index, across all of the repository's refs.
+    pub remaining: u64,
+}
+
+/// The state of the indexer, as shown on the status page.
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexerStatus {
+    #[serde(with = "time::serde::rfc3339")]
+    pub started_at: OffsetDateTime,
+    /// What caused the current or most recent run.
+    pub trigger: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_run_start: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_run_end: Option<OffsetDateTime>,
+    /// When a run last completed, persisted across restarts.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_success: Option<OffsetDateTime>,
+    pub last_summary: Option<RunSummary>,
+    pub last_error: Option<String>,
+    /// Size of the database on disk in bytes, as of startup or the end of the last run.
+    pub database_size: Option<u64>,
+}
+
+impl IndexerStatus {
+    pub fn new(db: &rocksdb::DB, database_size: Option<u64>) -> Self {
+        let last_success = match fetch_last_success(db) {
+            Ok(v) => v,
+            Err(error) => {
+                warn!(%error, "Failed to read time of last successful index");
+                None
+            }
+        };
+
+        Self {
+            started_at: OffsetDateTime::now_utc(),
+            trigger: None,
+            last_run_start: None,
+            last_run_end: None,
+            last_success,
+            last_summary: None,
+            last_error: None,
+            database_size,
+        }
+    }
+
+    /// Whether a run is currently in progress.
+    pub fn is_running(&self) -> bool {
+        match (self.last_run_start, self.last_run_end) {
+            (Some(start), Some(end)) => start > end,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+const LAST_SUCCESS_KEY: &str = "indexer_last_success";
+
+fn fetch_last_success(db: &rocksdb::DB) -> anyhow::Result<Option<OffsetDateTime>> {
+    let Some(value) = db.get(LAST_SUCCESS_KEY)? else {
+        return Ok(None);
+    };
+
+    let value: [u8; std::mem::size_of::<i64>()] = value
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid last success timestamp"))?;
 
-pub fn run(scan_path: &Path, db: &Arc<rocksdb::DB>) {
+    Ok(Some(OffsetDateTime::from_unix_timestamp(
+        i64::from_be_bytes(value),
+    )?))
+}
+
+/// Persists the time of the last successful run so it survives restarts.
+pub fn store_last_success(db: &rocksdb::DB, time: OffsetDateTime) -> anyhow::Result<()> {
+    db.put(LAST_SUCCESS_KEY, time.unix_timestamp().to_be_bytes())?;
+    Ok(())
+}
+
+/// Runs shorter than this don't log any progress, keeping quick reindexes to a single start
+/// and finish line.
+const PROGRESS_QUIET_PERIOD: Duration = Duration::from_secs(5);
+/// Progress is logged at most this often...
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+/// ...or after this many repositories have been processed, whichever comes first.
+const PROGRESS_REPOSITORIES: usize = 100;
+
+/// Default for the most commits indexed for each ref in a single run, enough for most
+/// repositories to be indexed in one go.
+pub const DEFAULT_COMMIT_BUDGET: u64 = 50_000;
+
+/// Default for how long repositories found deleted are remembered for, see
+/// [`record_tombstone`].
+pub const DEFAULT_TOMBSTONE_RETENTION: Duration = Duration::from_hours(30 * 24);
+
+/// Tracks the outcome of each repository as it passes through the indexer's stages.
+struct RunState {
+    start: Instant,
+    scanned: usize,
+    updated: HashSet<String>,
+    failed: HashSet<String>,
+    commits_indexed: u64,
+    /// Most commits indexed for each ref in a single run, see [`branch_index_update`].
+    commit_budget: u64,
+    backfilling: Vec<Backfilling>,
+    /// How long repositories found deleted are remembered for, see [`record_tombstone`].
+    tombstone_retention: Duration,
+}
+
+/// Rate limited logging of how far through a stage of the indexer we are, so long runs don't
+/// look like they've hung.
+struct Progress {
+    stage: &'static str,
+    run_start: Instant,
+    total: usize,
+    processed: usize,
+    last_logged: Instant,
+    processed_since_log: usize,
+}
+
+impl Progress {
+    fn new(stage: &'static str, run_start: Instant, total: usize) -> Self {
+        Self {
+            stage,
+            run_start,
+            total,
+            processed: 0,
+            last_logged: Instant::now(),
+            processed_since_log: 0,
+        }
+    }
+
+    fn tick(&mut self, current: &str) {
+        self.processed += 1;
+        self.processed_since_log += 1;
+
+        let elapsed = self.run_start.elapsed();
+        if elapsed < PROGRESS_QUIET_PERIOD {
+            return;
+        }
+
+        if self.processed_since_log >= PROGRESS_REPOSITORIES
+            || self.last_logged.elapsed() >= PROGRESS_INTERVAL
+        {
+            info!(
+                stage = self.stage,
+                processed = self.processed,
+                total = self.total,
+                current,
+                ?elapsed,
+                "Indexing in progress"
+            );
+
+            self.last_logged = Instant::now();
+            self.processed_since_log = 0;
+        }
+    }
+}
+
+pub fn run(
+    scan_path: &Path,
+    db: &Arc<rocksdb::DB>,
+    commit_budget: u64,
+    export_all: bool,
+    scan_root: &ScanRoot,
+    tombstone_retention: Duration,
+) -> RunSummary {
     let span = info_span!("index_update");
     let _entered = span.enter();
 
+    let mut state = RunState {
+        start: Instant::now(),
+        scanned: 0,
+        updated: HashSet::new(),
+        failed: HashSet::new(),
+        commits_indexed: 0,
+        commit_budget,
+        backfilling: Vec::new(),
+        tombstone_retention,
+    };
+
     info!("Starting index update");
 
-    update_repository_metadata(scan_path, db);
-    update_repository_reflog(scan_path, db.clone());
-    update_repository_tags(scan_path, db.clone());
+    update_repository_metadata(scan_path, db, export_all, scan_root, &mut state);
+    remove_orphaned_ids(db, &state);
+    update_tombstones(scan_path, db);
+    update_repository_reflog(scan_path, db.clone(), &mut state);
+    update_repository_tags(scan_path, db.clone(), &mut state);
 
-    info!("Flushing to disk");
+    debug!("Flushing to disk");
 
     if let Err(error) = db.flush() {
         error!(%error, "Failed to flush database to disk");
     }
 
-    info!("Finished index update");
+    let repositories_failed = state.failed.len();
+    let repositories_updated = state.updated.difference(&state.failed).count();
+
+    RunSummary {
+        repositories_scanned: state.scanned,
+        repositories_updated,
+        repositories_unchanged: state
+            .scanned
+            .saturating_sub(repositories_updated + repositories_failed),
+        repositories_failed,
+        commits_indexed: state.commits_indexed,
+        backfilling: state.backfilling,
+        duration: state.start.elapsed(),
+    }
 }
 
-#[instrument(skip(db))]
-fn update_repository_metadata(scan_path: &Path, db: &rocksdb::DB) {
+#[instrument(skip(db, scan_root, state))]
+fn update_repository_metadata(
+    scan_path: &Path,
+    db: &rocksdb::DB,
+    export_all: bool,
+    scan_root: &ScanRoot,
+    state: &mut RunState,
+) {
     let mut discovered = Vec::new();
     discover_repositories(scan_path, &mut discovered);
 
+    state.scanned = discovered.len();
+    let mut progress = Progress::new("metadata", state.start, discovered.len());
+
     for (repository_path, git_repository) in discovered {
         let Some(relative) = get_relative_path(scan_path, &repository_path) else {
             continue;
         };
 
+        progress.tick(&relative.to_string_lossy());
+
+        // the web interface refuses these anyway, but they'd still be listed and have their
+        // history read into the index otherwise
+        if !scan_root.contains_blocking(&repository_path) {
+            warn!(
+                "{} resolves to outside of the scan path, skipping",
+                relative.display()
+            );
+
+            if let Ok(Some(v)) = Repository::open(db, relative) {
+                if let Err(error) = v.get().delete(db, relative) {
+                    warn!(%error, "Failed to delete repository outside of the scan path");
+                }
+            }
+
+            continue;
+        }
+
+        let exported = is_export_ok(&repository_path);
+
         let id = match Repository::open(db, relative) {
-            Ok(v) => v.map_or_else(RepositoryId::new, |v| {
-                RepositoryId(v.get().id.0.to_native())
-            }),
+            // repositories that are no longer exported are removed as though they'd been
+            // deleted, so they can't be found through any of the indexed data
+            Ok(Some(v)) if !export_all && !exported => {
+                info!(
+                    "{} is no longer exported, removing from db",
+                    relative.display()
+                );
+
+                if let Err(error) = v.get().delete(db, relative) {
+                    warn!(%error, "Failed to delete unexported repository");
+                }
+
+                continue;
+            }
+            Ok(None) if !export_all && !exported => continue,
+            Ok(Some(v)) => RepositoryId(v.get().id.0.to_native()),
+            // the repository's row may have been cleared by a migration, in which case the
+            // id it was indexed under is picked back up so its history doesn't have to be
+            // indexed all over again
+            Ok(None) => match Repository::find_id(db, relative) {
+                Ok(Some(id)) => id,
+                Ok(None) => RepositoryId::new(),
+                Err(error) => {
+                    warn!(%error, "Failed to read id of {}", relative.display());
+                    RepositoryId::new()
+                }
+            },
             Err(error) => {
                 // maybe we could nuke it ourselves, but we need to instantly trigger
                 // a reindex and we could enter into an infinite loop if there's a bug
                 // or something
                 error!(%error, "Failed to open repository index {}, please consider nuking database", relative.display());
+                state.failed.insert(relative.to_string_lossy().into_owned());
                 continue;
             }
         };
@@ -69,57 +351,319 @@ fn update_repository_metadata(scan_path: &Path, db: &rocksdb::DB) {
             .ok()
             .filter(|v| !v.is_empty());
 
+        let git_repository = match git_repository {
+            DiscoveredRepository::Supported(v) => *v,
+            DiscoveredRepository::UnsupportedObjectFormat(format) => {
+                let last_modified = directory_modified(&repository_path);
+                let allowed = |key| configured_permission(&repository_path, key);
+
+                let res = Repository {
+                    id,
+                    name: name.to_string(),
+                    description,
+                    owner: None,
+                    last_modified: (
+                        last_modified.unix_timestamp(),
+                        last_modified.offset().whole_seconds(),
+                    ),
+                    default_branch: None,
+                    exported,
+                    noindex: false,
+                    unsupported_object_format: Some(format),
+                    objects: None,
+                    remotes: Vec::new(),
+                    feed_items: None,
+                    log_page_size: None,
+                    empty: false,
+                    allow_snapshots: allowed("rgit.allow-snapshots"),
+                    allow_clone: allowed("rgit.allow-clone"),
+                    links: Vec::new(),
+                }
+                .insert(db, relative);
+
+                if let Err(error) = res {
+                    warn!(%error, "Failed to insert repository");
+                    state.failed.insert(relative.to_string_lossy().into_owned());
+                }
+
+                continue;
+            }
+        };
+
         let owner = git_repository
             .config_snapshot()
             .string("gitweb.owner")
             .map(|v| v.to_string());
 
+        let default_branch = find_default_branch(&git_repository).ok();
+        if let Some(configured) = configured_default_branch(&git_repository) {
+            if default_branch.as_deref() != Some(&format!("refs/heads/{configured}")) {
+                warn!(
+                    "Configured default branch {configured} of {} doesn't exist, falling back to HEAD",
+                    relative.display()
+                );
+            }
+        }
+
+        let empty = is_empty_repository(&git_repository).unwrap_or(false);
+
         let res = Repository {
             id,
             name: name.to_string(),
             description,
             owner,
             last_modified: {
-                let r =
-                    find_last_committed_time(&git_repository).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+                // without any commits to go by, empty repositories are as old as their directory
+                let r = if empty {
+                    directory_modified(&repository_path)
+                } else {
+                    find_last_committed_time(&git_repository).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+                };
                 (r.unix_timestamp(), r.offset().whole_seconds())
             },
-            default_branch: find_default_branch(&git_repository).ok().flatten(),
-            exported: repository_path.join("git-daemon-export-ok").exists(),
+            default_branch,
+            exported,
+            noindex: git_repository
+                .config_snapshot()
+                .boolean("rgit.noindex")
+                .unwrap_or(false),
+            unsupported_object_format: None,
+            objects: object_stats(&git_repository),
+            remotes: remotes(&git_repository),
+            feed_items: configured_limit(
+                &git_repository,
+                relative,
+                "rgit.feed-items",
+                FEED_ITEMS_RANGE,
+            ),
+            log_page_size: configured_limit(
+                &git_repository,
+                relative,
+                "rgit.log-page-size",
+                LOG_PAGE_SIZE_RANGE,
+            ),
+            empty,
+            allow_snapshots: git_repository
+                .config_snapshot()
+                .boolean("rgit.allow-snapshots")
+                .unwrap_or(true),
+            allow_clone: git_repository
+                .config_snapshot()
+                .boolean("rgit.allow-clone")
+                .unwrap_or(true),
+            links: links(&git_repository, relative),
         }
         .insert(db, relative);
 
         if let Err(error) = res {
             warn!(%error, "Failed to insert repository");
+            state.failed.insert(relative.to_string_lossy().into_owned());
+        }
+    }
+}
+
+/// Reads a limit on the number of entries shown from the repository configuration, clamped
+/// to `range`. Values that aren't numbers are ignored so the global limit is used instead.
+fn configured_limit(
+    repository: &gix::Repository,
+    relative: &Path,
+    key: &str,
+    range: RangeInclusive<u64>,
+) -> Option<u64> {
+    let value = match repository.config_snapshot().try_integer(key)? {
+        Ok(v) => v,
+        Err(error) => {
+            warn!(%error, "Ignoring invalid {key} of {}", relative.display());
+            return None;
+        }
+    };
+
+    let clamped = u64::try_from(value)
+        .unwrap_or(0)
+        .clamp(*range.start(), *range.end());
+    if i64::try_from(clamped).ok() != Some(value) {
+        warn!(
+            "{key} of {} is outside of {}..={}, using {clamped} instead",
+            relative.display(),
+            range.start(),
+            range.end(),
+        );
+    }
+
+    Some(clamped)
+}
+
+/// Reads the remotes configured in the repository along with the URL each is fetched from.
+/// Credentials are removed from the URLs, since they're going to be shown to anyone browsing
+/// the repository.
+fn remotes(repository: &gix::Repository) -> Vec<Remote> {
+    let mut remotes = Vec::new();
+
+    for name in repository.remote_names() {
+        let remote = match repository.find_remote(name.as_ref()) {
+            Ok(v) => v,
+            Err(error) => {
+                warn!(%error, "Failed to read remote {name}");
+                continue;
+            }
+        };
+
+        let Some(url) = remote.url(gix::remote::Direction::Fetch) else {
+            continue;
+        };
+
+        let mut url = url.clone();
+        url.set_password(None);
+
+        // the user of an ssh url only picks the account to log in as, anywhere else it's
+        // likely to be a token
+        if url.scheme != gix::url::Scheme::Ssh {
+            url.set_user(None);
         }
+
+        remotes.push(Remote {
+            name: name.to_string(),
+            url: url.to_bstring().to_string(),
+        });
     }
+
+    remotes
+}
+
+/// Reads the links configured by `rgit.link` entries of the form `Label|https://url`. Entries
+/// that are malformed or link anywhere but an `http` or `https` URL are skipped.
+fn links(repository: &gix::Repository, relative: &Path) -> Vec<Link> {
+    let config = repository.config_snapshot();
+    let Some(entries) = config.plumbing().strings("rgit.link") else {
+        return Vec::new();
+    };
+
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let link = parse_link(&entry.to_str_lossy());
+            if link.is_none() {
+                warn!(
+                    "Ignoring invalid rgit.link {entry:?} of {}",
+                    relative.display()
+                );
+            }
+            link
+        })
+        .collect()
 }
 
-fn find_default_branch(repo: &gix::Repository) -> Result<Option<String>, anyhow::Error> {
-    Ok(Some(repo.head()?.name().as_bstr().to_string()))
+/// Parses a single `rgit.link` entry, see [`links`].
+fn parse_link(entry: &str) -> Option<Link> {
+    let (label, url) = entry.split_once('|')?;
+    let label = label.trim();
+    if label.is_empty() {
+        return None;
+    }
+
+    let url = url::Url::parse(url.trim()).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return None;
+    }
+
+    Some(Link {
+        label: label.to_string(),
+        url: url.into(),
+    })
+}
+
+/// Counts the loose and packed objects in the repository along with the size of its object
+/// store on disk, the same figures `git count-objects` would give. Objects borrowed from
+/// alternates aren't counted.
+fn object_stats(repository: &gix::Repository) -> Option<ObjectStats> {
+    let mut count = match repository.objects.packed_object_count() {
+        Ok(v) => v,
+        Err(error) => {
+            warn!(%error, "Failed to count packed objects");
+            return None;
+        }
+    };
+    let mut size = 0;
+
+    let objects_dir = repository.objects.store_ref().path();
+    let entries = match std::fs::read_dir(objects_dir) {
+        Ok(v) => v,
+        Err(error) => {
+            warn!(%error, "Failed to read object store");
+            return None;
+        }
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name();
+        let is_pack = name == "pack";
+        // loose objects are fanned out into directories named after their first byte
+        let is_fanout = name.len() == 2
+            && name
+                .to_str()
+                .is_some_and(|v| v.bytes().all(|c| c.is_ascii_hexdigit()));
+
+        if !is_pack && !is_fanout {
+            continue;
+        }
+
+        let Ok(files) = std::fs::read_dir(entry.path()) else {
+            continue;
+        };
+
+        for file in files.filter_map(Result::ok) {
+            let Ok(metadata) = file.metadata() else {
+                continue;
+            };
+
+            if metadata.is_file() {
+                size += metadata.len();
+                count += u64::from(is_fanout);
+            }
+        }
+    }
+
+    Some(ObjectStats { count, size })
+}
+
+pub(super) fn peels_to_commit(reference: &Reference<'_>) -> bool {
+    reference
+        .clone()
+        .into_fully_peeled_id()
+        .ok()
+        .and_then(|id| id.header().ok())
+        .is_some_and(|header| header.kind() == Kind::Commit)
+}
+
+/// The last time the directory at `path` was modified, or the epoch if it can't be read.
+fn directory_modified(path: &Path) -> OffsetDateTime {
+    std::fs::metadata(path)
+        .and_then(|v| v.modified())
+        .map_or(OffsetDateTime::UNIX_EPOCH, OffsetDateTime::from)
 }
 
 fn find_last_committed_time(repo: &gix::Repository) -> Result<OffsetDateTime, anyhow::Error> {
     let mut timestamp = OffsetDateTime::UNIX_EPOCH;
 
     for reference in repo.references()?.all()? {
-        let Ok(commit) = reference.unwrap().peel_to_commit() else {
+        let Ok(mut reference) = reference else {
+            continue;
+        };
+
+        let Ok(commit) = reference.peel_to_commit() else {
             continue;
         };
 
         let committer = commit.committer()?;
-        let offset = UtcOffset::from_whole_seconds(committer.time.offset).unwrap_or(UtcOffset::UTC);
-        let committed_time = OffsetDateTime::from_unix_timestamp(committer.time.seconds)
-            .unwrap_or(OffsetDateTime::UNIX_EPOCH)
-            .to_offset(offset);
+        let committed_time = signature_time(committer.time.seconds, committer.time.offset);
         timestamp = timestamp.max(committed_time);
     }
 
     Ok(timestamp)
 }
 
-#[instrument(skip(db))]
-fn update_repository_reflog(scan_path: &Path, db: Arc<rocksdb::DB>) {
+#[instrument(skip(db, state))]
+fn update_repository_reflog(scan_path: &Path, db: Arc<rocksdb::DB>, state: &mut RunState) {
     let repos = match Repository::fetch_all(&db) {
         Ok(v) => v,
         Err(error) => {
@@ -128,9 +672,23 @@ fn update_repository_reflog(scan_path: &Path, db: Arc<rocksdb::DB>) {
         }
     };
 
+    let mut progress = Progress::new("reflog", state.start, repos.len());
+
     for (relative_path, db_repository) in repos {
-        let Some(git_repository) = open_repo(scan_path, &relative_path, db_repository.get(), &db)
-        else {
+        progress.tick(&relative_path);
+
+        if is_unsupported(scan_path, &relative_path, db_repository.get()) {
+            continue;
+        }
+
+        let Some(git_repository) = open_repo(
+            scan_path,
+            &relative_path,
+            db_repository.get(),
+            &db,
+            state.tombstone_retention,
+        ) else {
+            state.failed.insert(relative_path);
             continue;
         };
 
@@ -138,6 +696,7 @@ fn update_repository_reflog(scan_path: &Path, db: Arc<rocksdb::DB>) {
             Ok(v) => v,
             Err(error) => {
                 error!(%error, "Failed to read references for {relative_path}");
+                state.failed.insert(relative_path);
                 continue;
             }
         };
@@ -146,17 +705,21 @@ fn update_repository_reflog(scan_path: &Path, db: Arc<rocksdb::DB>) {
             Ok(v) => v,
             Err(error) => {
                 error!(%error, "Failed to read references for {relative_path}");
+                state.failed.insert(relative_path);
                 continue;
             }
         };
 
         let mut valid_references = Vec::new();
+        let mut unreadable_references = false;
 
         for reference in references {
             let mut reference = match reference {
                 Ok(v) => v,
                 Err(error) => {
                     error!(%error, "Failed to read reference for {relative_path}");
+                    state.failed.insert(relative_path.clone());
+                    unreadable_references = true;
                     continue;
                 }
             };
@@ -169,22 +732,95 @@ fn update_repository_reflog(scan_path: &Path, db: Arc<rocksdb::DB>) {
                 continue;
             }
 
+            // tags of trees and blobs have no history to index
+            if reference_name.category() == Some(Category::Tag) && !peels_to_commit(&reference) {
+                continue;
+            }
+
             valid_references.push(reference_name.as_bstr().to_string());
 
-            if let Err(error) = branch_index_update(
+            match branch_index_update(
                 &mut reference,
                 &relative_path,
                 db_repository.get(),
                 db.clone(),
                 &git_repository,
+                state.commit_budget,
                 false,
             ) {
-                error!(%error, "Failed to update reflog for {relative_path}@{:?}", valid_references.last());
+                Ok(0) => {}
+                Ok(indexed) => {
+                    state.commits_indexed += indexed;
+                    state.updated.insert(relative_path.clone());
+                }
+                Err(error) => {
+                    error!(%error, "Failed to update reflog for {relative_path}@{:?}", valid_references.last());
+                    state.failed.insert(relative_path.clone());
+                }
             }
         }
 
+        // a detached HEAD isn't on any branch, so its history is indexed under HEAD itself
+        // for the summary and log to fall back to
+        if git_repository.head().is_ok_and(|head| head.is_detached()) {
+            let result = git_repository
+                .find_reference("HEAD")
+                .map_err(anyhow::Error::from)
+                .and_then(|mut reference| {
+                    branch_index_update(
+                        &mut reference,
+                        &relative_path,
+                        db_repository.get(),
+                        db.clone(),
+                        &git_repository,
+                        state.commit_budget,
+                        false,
+                    )
+                });
+
+            match result {
+                Ok(0) => {}
+                Ok(indexed) => {
+                    state.commits_indexed += indexed;
+                    state.updated.insert(relative_path.clone());
+                }
+                Err(error) => {
+                    error!(%error, "Failed to update reflog for detached HEAD of {relative_path}");
+                    state.failed.insert(relative_path.clone());
+                }
+            }
+
+            valid_references.push("HEAD".to_string());
+        }
+
+        let backfill_remaining = valid_references
+            .iter()
+            .filter_map(|reference| {
+                db_repository
+                    .get()
+                    .commit_tree(db.clone(), reference)
+                    .backfill()
+                    .ok()
+                    .flatten()
+            })
+            .map(|backfill| backfill.remaining)
+            .sum::<u64>();
+        if backfill_remaining > 0 {
+            state.backfilling.push(Backfilling {
+                repository: relative_path.clone(),
+                remaining: backfill_remaining,
+            });
+        }
+
+        // refs can briefly fail to read whilst they're being packed, the heads we already
+        // have are better than a list missing some of them
+        if unreadable_references {
+            continue;
+        }
+
         if let Err(error) = db_repository.get().replace_heads(&db, &valid_references) {
             error!(%error, "Failed to update heads");
+            state.failed.insert(relative_path);
         }
     }
 }
@@ -196,9 +832,10 @@ fn branch_index_update(
     db_repository: &ArchivedRepository,
     db: Arc<rocksdb::DB>,
     git_repository: &gix::Repository,
+    commit_budget: u64,
     force_reindex: bool,
-) -> Result<(), anyhow::Error> {
-    info!("Refreshing indexes");
+) -> Result<u64, anyhow::Error> {
+    debug!("Refreshing indexes");
 
     let commit_tree = db_repository.commit_tree(db.clone(), reference.name().as_bstr().to_str()?);
 
@@ -210,8 +847,8 @@ fn branch_index_update(
 
     let latest_indexed = if let Some(latest_indexed) = commit_tree.fetch_latest_one()? {
         if commit.id().as_bytes() == latest_indexed.get().hash.as_slice() {
-            info!("No commits since last index");
-            return Ok(());
+            debug!("No commits since last index");
+            return backfill_index_update(&commit_tree, &db, git_repository, commit_budget);
         }
 
         Some(latest_indexed)
@@ -219,23 +856,29 @@ fn branch_index_update(
         None
     };
 
-    // TODO: stop collecting into a vec
-    let revwalk = git_repository
-        .rev_walk([commit.id().detach()])
-        .all()?
-        .collect::<Vec<_>>()
-        .into_iter()
-        .rev();
+    let revwalk = walk_history(git_repository, commit.id().detach())?;
 
+    // the first index of a reference only takes the newest commits within the budget, their
+    // ids leave room for the older ones to be backfilled on later runs
     let tree_len = commit_tree.len()?;
+    let skipped = if latest_indexed.is_none() && tree_len == 0 {
+        (revwalk.len() as u64).saturating_sub(commit_budget)
+    } else {
+        0
+    };
+
+    let revwalk = revwalk
+        .into_iter()
+        .rev()
+        .skip(usize::try_from(skipped).unwrap_or(usize::MAX));
+
+    let first_id = tree_len + skipped;
     let mut seen = false;
     let mut i = 0;
     for revs in &revwalk.chunks(250) {
         let mut batch = WriteBatch::default();
 
         for rev in revs {
-            let rev = rev?;
-
             if let (false, Some(latest_indexed)) = (seen, &latest_indexed) {
                 if rev.id.as_bytes() == latest_indexed.get().hash.as_slice() {
                     seen = true;
@@ -258,13 +901,13 @@ fn branch_index_update(
 
             Commit::new(oid, &commit, author, committer)?.insert(
                 &commit_tree,
-                tree_len + i,
+                first_id + i,
                 &mut batch,
             )?;
             i += 1;
         }
 
-        commit_tree.update_counter(tree_len + i, &mut batch)?;
+        commit_tree.update_counter(first_id + i, &mut batch)?;
         db.write_without_wal(batch)?;
     }
 
@@ -277,15 +920,130 @@ fn branch_index_update(
             db_repository,
             db,
             git_repository,
+            commit_budget,
             true,
         );
     }
 
-    Ok(())
+    if skipped > 0 {
+        info!("Indexed the newest {i} commits, leaving {skipped} older commits to backfill");
+
+        let mut batch = WriteBatch::default();
+        commit_tree.update_backfill(
+            &Backfill {
+                tip: match commit.id().detach() {
+                    ObjectId::Sha1(d) => d,
+                },
+                remaining: skipped,
+            },
+            &mut batch,
+        )?;
+        db.write_without_wal(batch)?;
+
+        return Ok(i);
+    }
+
+    // older commits left out of the first index carry on being backfilled with whatever's
+    // left of the budget, otherwise they'd only get anywhere on runs where the tip hasn't moved
+    let budget = commit_budget.saturating_sub(i);
+    if budget == 0 {
+        return Ok(i);
+    }
+
+    Ok(i + backfill_index_update(&commit_tree, &db, git_repository, budget)?)
 }
 
-#[instrument(skip(db))]
-fn update_repository_tags(scan_path: &Path, db: Arc<rocksdb::DB>) {
+/// Indexes up to `commit_budget` of the older commits left out of the first index of the
+/// reference, if there are any.
+fn backfill_index_update(
+    commit_tree: &CommitTree,
+    db: &rocksdb::DB,
+    git_repository: &gix::Repository,
+    commit_budget: u64,
+) -> Result<u64, anyhow::Error> {
+    let Some(backfill) = commit_tree.backfill()? else {
+        return Ok(0);
+    };
+
+    // commits were keyed by their position in the history walked from the tip at the time,
+    // history is immutable so walking from that same tip gives back the same positions
+    let revwalk = walk_history(git_repository, ObjectId::Sha1(backfill.tip))?;
+    if (revwalk.len() as u64) < backfill.remaining {
+        warn!("History no longer matches backfill cursor, forcing reindex");
+        commit_tree.drop_commits()?;
+        return Ok(0);
+    }
+
+    let start = backfill.remaining.saturating_sub(commit_budget);
+    let revwalk = revwalk
+        .into_iter()
+        .rev()
+        .zip(0..backfill.remaining)
+        .skip(usize::try_from(start).unwrap_or(usize::MAX));
+
+    for revs in &revwalk.chunks(250) {
+        let mut batch = WriteBatch::default();
+
+        for (rev, id) in revs {
+            let commit = rev.object()?;
+            let oid = commit.id;
+            let commit = commit.decode()?;
+
+            Commit::new(oid, &commit, commit.author(), commit.committer())?.insert(
+                commit_tree,
+                id,
+                &mut batch,
+            )?;
+        }
+
+        db.write_without_wal(batch)?;
+    }
+
+    debug!(
+        remaining = start,
+        "Backfilled {} commits",
+        backfill.remaining - start
+    );
+
+    let mut batch = WriteBatch::default();
+    commit_tree.update_backfill(
+        &Backfill {
+            remaining: start,
+            ..backfill
+        },
+        &mut batch,
+    )?;
+    db.write_without_wal(batch)?;
+
+    Ok(backfill.remaining - start)
+}
+
+/// Walks the history of `tip`, newest first.
+fn walk_history(
+    git_repository: &gix::Repository,
+    tip: ObjectId,
+) -> Result<Vec<gix::revision::walk::Info<'_>>, anyhow::Error> {
+    // TODO: stop collecting into a vec
+    let mut revwalk = Vec::new();
+
+    for rev in git_repository.rev_walk([tip]).all()? {
+        match rev.map_err(anyhow::Error::from) {
+            Ok(rev) => revwalk.push(rev),
+            // shallow boundaries are already respected by the walk, this catches mirrors
+            // missing commits they don't know are missing
+            Err(error) if is_missing_object(&error) => {
+                warn!(%error, "History is truncated, indexing up to the missing commit");
+                break;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(revwalk)
+}
+
+#[instrument(skip(db, state))]
+fn update_repository_tags(scan_path: &Path, db: Arc<rocksdb::DB>, state: &mut RunState) {
     let repos = match Repository::fetch_all(&db) {
         Ok(v) => v,
         Err(error) => {
@@ -294,9 +1052,23 @@ fn update_repository_tags(scan_path: &Path, db: Arc<rocksdb::DB>) {
         }
     };
 
+    let mut progress = Progress::new("tags", state.start, repos.len());
+
     for (relative_path, db_repository) in repos {
-        let Some(git_repository) = open_repo(scan_path, &relative_path, db_repository.get(), &db)
-        else {
+        progress.tick(&relative_path);
+
+        if is_unsupported(scan_path, &relative_path, db_repository.get()) {
+            continue;
+        }
+
+        let Some(git_repository) = open_repo(
+            scan_path,
+            &relative_path,
+            db_repository.get(),
+            &db,
+            state.tombstone_retention,
+        ) else {
+            state.failed.insert(relative_path);
             continue;
         };
 
@@ -307,6 +1079,7 @@ fn update_repository_tags(scan_path: &Path, db: Arc<rocksdb::DB>) {
             &git_repository,
         ) {
             error!(%error, "Failed to update tags for {relative_path}");
+            state.failed.insert(relative_path);
         }
     }
 }
@@ -320,14 +1093,16 @@ fn tag_index_scan(
 ) -> Result<(), anyhow::Error> {
     let tag_tree = db_repository.tag_tree(db);
 
+    // any unreadable ref fails the scan, rather than having its tag removed from the index
     let git_tags: HashSet<_> = git_repository
         .references()
         .context("Failed to scan indexes on git repository")?
         .all()?
-        .filter_map(Result::ok)
-        .filter(|v| v.name().category() == Some(Category::Tag))
-        .map(|v| v.name().as_bstr().to_string())
-        .collect();
+        .filter_map_ok(|v| {
+            (v.name().category() == Some(Category::Tag)).then(|| v.name().as_bstr().to_string())
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|e| anyhow::anyhow!(e))?;
     let indexed_tags: HashSet<String> = tag_tree.list()?.into_iter().collect();
 
     // insert any git tags that are missing from the index
@@ -355,9 +1130,16 @@ fn tag_index_update(
         .context("Failed to read newly discovered tag")?;
 
     if let Ok(tag) = reference.peel_to_tag() {
-        info!("Inserting newly discovered tag to index");
+        debug!("Inserting newly discovered tag to index");
+
+        let target = match peel_nested_tag(&tag)?.0.kind {
+            Kind::Commit => TagTarget::Commit,
+            Kind::Tree => TagTarget::Tree,
+            Kind::Blob => TagTarget::Blob,
+            Kind::Tag => anyhow::bail!("Couldn't peel tag to its target"),
+        };
 
-        Tag::new(tag.tagger()?)?.insert(tag_tree, tag_name)?;
+        Tag::new(tag.tagger()?, target)?.insert(tag_tree, tag_name)?;
     }
 
     Ok(())
@@ -365,31 +1147,51 @@ fn tag_index_update(
 
 #[instrument(skip(tag_tree))]
 fn tag_index_delete(tag_name: &str, tag_tree: &TagTree) -> Result<(), anyhow::Error> {
-    info!("Removing stale tag from index");
+    debug!("Removing stale tag from index");
     tag_tree.remove(tag_name)?;
 
     Ok(())
 }
 
-#[instrument(skip(scan_path, db_repository, db))]
+/// Whether the repository is one that can only be listed, not indexed. Repositories that
+/// have gone from disk aren't, so [`open_repo`] can clean them up.
+fn is_unsupported(
+    scan_path: &Path,
+    relative_path: &str,
+    db_repository: &ArchivedRepository,
+) -> bool {
+    db_repository.unsupported_object_format.is_some() && scan_path.join(relative_path).exists()
+}
+
+#[instrument(skip(scan_path, db_repository, db, tombstone_retention))]
 fn open_repo<P: AsRef<Path> + Debug>(
     scan_path: &Path,
     relative_path: P,
     db_repository: &ArchivedRepository,
     db: &rocksdb::DB,
+    tombstone_retention: Duration,
 ) -> Option<gix::Repository> {
-    match gix::open(scan_path.join(relative_path.as_ref())) {
+    let path = scan_path.join(relative_path.as_ref());
+
+    match retry_transient(|| Ok(gix::open(&path)?)) {
         Ok(mut v) => {
             v.object_cache_size(10 * 1024 * 1024);
             Some(v)
         }
-        Err(gix::open::Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+        // files go missing from repositories whilst they're being repacked, so only the
+        // repository itself going missing means it's been deleted. everything goes missing at
+        // once if the scan path itself can't be read, which is left for the next run
+        Err(_) if !path.exists() && std::fs::read_dir(scan_path).is_ok() => {
             warn!("Repository gone from disk, removing from db");
 
-            if let Err(error) = db_repository.delete(db, relative_path) {
+            if let Err(error) = db_repository.delete(db, &relative_path) {
                 warn!(%error, "Failed to delete dangling index");
             }
 
+            if let Err(error) = record_tombstone(db, relative_path, tombstone_retention) {
+                warn!(%error, "Failed to record tombstone");
+            }
+
             None
         }
         Err(error) => {
@@ -399,11 +1201,198 @@ fn open_repo<P: AsRef<Path> + Debug>(
     }
 }
 
+/// Removes everything indexed under the ids of repositories that no longer have a row, which
+/// are left behind when a repository goes missing whilst its row has been cleared by a
+/// migration.
+#[instrument(skip(db, state))]
+fn remove_orphaned_ids(db: &rocksdb::DB, state: &RunState) {
+    let ids = match Repository::fetch_all_ids(db) {
+        Ok(v) => v,
+        Err(error) => {
+            error!(%error, "Failed to read repository ids");
+            return;
+        }
+    };
+
+    for (path, id) in ids {
+        // failures are left for the next run, their rows may just not have been written yet
+        if state.failed.contains(&path) || !matches!(Repository::open(db, &path), Ok(None)) {
+            continue;
+        }
+
+        info!("{path} is no longer indexed, removing its orphaned data");
+
+        if let Err(error) = Repository::delete_indexed(db, id, &path) {
+            warn!(%error, "Failed to remove orphaned data of {path}");
+        }
+    }
+}
+
+/// Remembers that the repository at `relative_path` has been deleted for `retention`, so
+/// requests for it are answered with `410 Gone` rather than `404 Not Found`.
+pub(super) fn record_tombstone<P: AsRef<Path>>(
+    db: &rocksdb::DB,
+    relative_path: P,
+    retention: Duration,
+) -> anyhow::Result<()> {
+    if retention.is_zero() {
+        return Ok(());
+    }
+
+    let now = OffsetDateTime::now_utc();
+
+    Tombstone {
+        deleted_at: now.unix_timestamp(),
+        expires_at: Some((now + retention).unix_timestamp()),
+        redirect: None,
+    }
+    .insert(db, relative_path)
+}
+
+/// Name of the file in the root of the scan path listing repositories that have been deleted
+/// or moved, one per line as `path [redirect]`.
+const TOMBSTONE_FILE: &str = "rgit-tombstone";
+
+/// Brings the tombstones up to date with `rgit-tombstone`, and drops those that have expired
+/// or whose repository has since reappeared.
+#[instrument(skip(db))]
+fn update_tombstones(scan_path: &Path, db: &rocksdb::DB) {
+    let tombstones = match Tombstone::fetch_all(db) {
+        Ok(v) => v,
+        Err(error) => {
+            error!(%error, "Failed to read tombstones");
+            return;
+        }
+    };
+
+    let listed = match std::fs::read_to_string(scan_path.join(TOMBSTONE_FILE)) {
+        Ok(contents) => Some(parse_tombstone_file(&contents)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Some(Vec::new()),
+        Err(error) => {
+            // leave explicit tombstones as they are rather than dropping them all
+            warn!(%error, "Failed to read {TOMBSTONE_FILE}");
+            None
+        }
+    };
+
+    let now = OffsetDateTime::now_utc();
+
+    for (path, tombstone) in &tombstones {
+        let unlisted = tombstone.is_explicit()
+            && listed
+                .as_ref()
+                .is_some_and(|listed| !listed.iter().any(|(v, _)| v == path));
+        let reappeared = matches!(Repository::open(db, path), Ok(Some(_)));
+
+        if unlisted || reappeared || tombstone.is_expired(now) {
+            debug!("Dropping tombstone of {path}");
+
+            if let Err(error) = Tombstone::delete(db, path) {
+                warn!(%error, "Failed to drop tombstone of {path}");
+            }
+        }
+    }
+
+    for (path, redirect) in listed.unwrap_or_default() {
+        let existing = tombstones.get(&path);
+        if existing.is_some_and(|v| v.is_explicit() && v.redirect == redirect)
+            || matches!(Repository::open(db, &path), Ok(Some(_)))
+        {
+            continue;
+        }
+
+        let tombstone = Tombstone {
+            deleted_at: existing.map_or(now.unix_timestamp(), |v| v.deleted_at),
+            expires_at: None,
+            redirect,
+        };
+
+        if let Err(error) = tombstone.insert(db, &path) {
+            warn!(%error, "Failed to record tombstone of {path}");
+        }
+    }
+}
+
+/// Parses the entries of `rgit-tombstone`, see [`TOMBSTONE_FILE`]. Blank lines and lines
+/// starting with `#` are ignored, as are entries that are malformed.
+fn parse_tombstone_file(contents: &str) -> Vec<(String, Option<String>)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let entry = parse_tombstone_entry(line);
+            if entry.is_none() {
+                warn!("Ignoring invalid {TOMBSTONE_FILE} entry {line:?}");
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Parses a single entry of `rgit-tombstone`. Redirects are either the path of another
+/// repository or an `http` or `https` URL.
+fn parse_tombstone_entry(line: &str) -> Option<(String, Option<String>)> {
+    let mut parts = line.split_whitespace();
+    let path = parse_tombstone_path(parts.next()?)?;
+
+    let redirect = match parts.next() {
+        None => None,
+        Some(redirect) if redirect.contains("://") => {
+            let url = url::Url::parse(redirect).ok()?;
+            if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+                return None;
+            }
+
+            Some(url.into())
+        }
+        Some(redirect) => Some(parse_tombstone_path(redirect)?),
+    };
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some((path, redirect))
+}
+
+/// A repository path within `rgit-tombstone`, relative to the scan path.
+fn parse_tombstone_path(path: &str) -> Option<String> {
+    let path = path.trim_matches('/');
+    let valid = !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|v| matches!(v, std::path::Component::Normal(_)));
+
+    valid.then(|| path.to_string())
+}
+
 fn get_relative_path<'a>(relative_to: &Path, full_path: &'a Path) -> Option<&'a Path> {
     full_path.strip_prefix(relative_to).ok()
 }
 
-fn discover_repositories(current: &Path, discovered_repos: &mut Vec<(PathBuf, gix::Repository)>) {
+/// Reads one of the `rgit.allow-*` keys straight out of the config of a repository gix can't
+/// open, allowing whatever isn't configured otherwise.
+fn configured_permission(git_dir: &Path, key: &str) -> bool {
+    gix::config::File::from_path_no_includes(git_dir.join("config"), gix::config::Source::Local)
+        .ok()
+        .and_then(|config| config.boolean(key))
+        .and_then(Result::ok)
+        .unwrap_or(true)
+}
+
+/// A repository found while scanning for repositories.
+enum DiscoveredRepository {
+    Supported(Box<gix::Repository>),
+    /// A repository using an object format that can't be read, these are still listed so
+    /// it's obvious why they can't be browsed.
+    UnsupportedObjectFormat(String),
+}
+
+fn discover_repositories(
+    current: &Path,
+    discovered_repos: &mut Vec<(PathBuf, DiscoveredRepository)>,
+) {
     let current = match std::fs::read_dir(current) {
         Ok(v) => v,
         Err(error) => {
@@ -421,14 +1410,248 @@ fn discover_repositories(current: &Path, discovered_repos: &mut Vec<(PathBuf, gi
         match gix::open_opts(&dir, gix::open::Options::default().open_path_as_is(true)) {
             Ok(mut repo) => {
                 repo.object_cache_size(10 * 1024 * 1024);
-                discovered_repos.push((dir, repo));
+                discovered_repos.push((dir, DiscoveredRepository::Supported(Box::new(repo))));
             }
             Err(gix::open::Error::NotARepository { .. }) => {
                 discover_repositories(&dir, discovered_repos);
             }
             Err(error) => {
+                if let Some(format) = unsupported_object_format(&dir) {
+                    warn!(
+                        "Repository {} uses the unsupported {format} object format, only listing it",
+                        dir.display()
+                    );
+                    discovered_repos
+                        .push((dir, DiscoveredRepository::UnsupportedObjectFormat(format)));
+                    continue;
+                }
+
                 warn!(%error, "Failed to open repository {} for indexing", dir.display());
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rocksdb::Options;
+    use tempfile::TempDir;
+    use time::OffsetDateTime;
+
+    use super::{
+        parse_link, parse_tombstone_entry, parse_tombstone_file, parse_tombstone_path,
+        record_tombstone,
+    };
+    use crate::{
+        database::schema::{
+            prefixes::TOMBSTONE_FAMILY, repository::Repository, tombstone::Tombstone,
+        },
+        testing::{self, TestRepo},
+    };
+
+    #[test]
+    fn links() {
+        let cases: &[(&str, Option<(&str, &str)>)] = &[
+            (
+                "CI|https://ci.example.com/rgit",
+                Some(("CI", "https://ci.example.com/rgit")),
+            ),
+            (
+                "  Issue tracker |  http://example.com  ",
+                Some(("Issue tracker", "http://example.com/")),
+            ),
+            (
+                "Docs|HTTPS://Example.COM/a b",
+                Some(("Docs", "https://example.com/a%20b")),
+            ),
+            // characters that could break out of the attribute are encoded by the url crate
+            (
+                "Quotes|https://example.com/\"<x>?q=\"'<y>#\"<z>",
+                Some((
+                    "Quotes",
+                    "https://example.com/%22%3Cx%3E?q=%22%27%3Cy%3E#%22%3Cz%3E",
+                )),
+            ),
+            // labels are left as they are, the template escapes them
+            (
+                "<b>Bold</b>|https://example.com",
+                Some(("<b>Bold</b>", "https://example.com/")),
+            ),
+            // anything but http and https
+            ("XSS|javascript:alert(1)", None),
+            ("XSS|JavaScript:alert(1)", None),
+            ("XSS| javascript:alert(1)", None),
+            ("XSS|data:text/html,<script>alert(1)</script>", None),
+            ("XSS|vbscript:msgbox(1)", None),
+            ("Mail|mailto:someone@example.com", None),
+            ("FTP|ftp://example.com", None),
+            ("File|file:///etc/passwd", None),
+            // malformed
+            ("https://example.com", None),
+            ("|https://example.com", None),
+            ("   |https://example.com", None),
+            ("Empty|", None),
+            ("Relative|/foo/bar", None),
+            ("Split|https|://example.com", None),
+            ("", None),
+        ];
+
+        for (entry, expected) in cases {
+            let link = parse_link(entry);
+
+            assert_eq!(
+                link.as_ref()
+                    .map(|link| (link.label.as_str(), link.url.as_str())),
+                *expected,
+                "{entry}",
+            );
+        }
+    }
+
+    #[test]
+    fn tombstone_paths() {
+        let cases = [
+            ("foo.git", Some("foo.git")),
+            ("/foo.git/", Some("foo.git")),
+            ("group/foo.git", Some("group/foo.git")),
+            ("", None),
+            ("/", None),
+            ("..", None),
+            ("../foo.git", None),
+            ("group/../../foo.git", None),
+            ("./foo.git", None),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(parse_tombstone_path(path).as_deref(), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn tombstone_entries() {
+        type Entry<'a> = (&'a str, Option<&'a str>);
+
+        let cases: &[(&str, Option<Entry>)] = &[
+            ("old.git", Some(("old.git", None))),
+            ("old.git new.git", Some(("old.git", Some("new.git")))),
+            (
+                "  /group/old.git\tgroup/new.git/ ",
+                Some(("group/old.git", Some("group/new.git"))),
+            ),
+            (
+                "old.git https://example.com/new",
+                Some(("old.git", Some("https://example.com/new"))),
+            ),
+            (
+                "old.git HTTP://Example.com",
+                Some(("old.git", Some("http://example.com/"))),
+            ),
+            // redirects anywhere but another repository or an http(s) URL
+            ("old.git javascript://alert(1)", None),
+            ("old.git ftp://example.com/new", None),
+            ("old.git ../new.git", None),
+            ("../old.git new.git", None),
+            // trailing garbage
+            ("old.git new.git newer.git", None),
+            ("", None),
+        ];
+
+        for (line, expected) in cases {
+            let entry = parse_tombstone_entry(line);
+
+            assert_eq!(
+                entry
+                    .as_ref()
+                    .map(|(path, redirect)| (path.as_str(), redirect.as_deref())),
+                *expected,
+                "{line}",
+            );
+        }
+
+        assert_eq!(
+            parse_tombstone_file("# moved\n\nold.git new.git\n../bad.git\ngone.git\n"),
+            [
+                ("old.git".to_string(), Some("new.git".to_string())),
+                ("gone.git".to_string(), None),
+            ],
+        );
+    }
+
+    #[test]
+    fn records_tombstones() {
+        let dir = TempDir::new().unwrap();
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let db = rocksdb::DB::open_cf(&options, dir.path(), [TOMBSTONE_FAMILY]).unwrap();
+
+        let retention = Duration::from_secs(5_000);
+        let before = OffsetDateTime::now_utc().unix_timestamp();
+        record_tombstone(&db, "deleted.git", retention).unwrap();
+        let after = OffsetDateTime::now_utc().unix_timestamp();
+
+        let tombstone = Tombstone::open(&db, "deleted.git").unwrap().unwrap();
+        assert!((before..=after).contains(&tombstone.deleted_at));
+        assert_eq!(tombstone.expires_at, Some(tombstone.deleted_at + 5_000));
+        assert_eq!(tombstone.redirect, None);
+        assert!(!tombstone.is_explicit());
+        assert!(!tombstone.is_expired(OffsetDateTime::now_utc()));
+        assert!(tombstone.is_expired(OffsetDateTime::now_utc() + retention));
+
+        // nothing is remembered with tombstones turned off
+        record_tombstone(&db, "forgotten.git", Duration::ZERO).unwrap();
+        assert_eq!(Tombstone::open(&db, "forgotten.git").unwrap(), None);
+    }
+
+    #[test]
+    fn restricted_downloads() {
+        // (has commits, uses an object format that can't be read)
+        for (committed, unsupported) in [(true, false), (false, false), (true, true)] {
+            let repo = TestRepo::new();
+            if committed {
+                repo.commit("README", "hello");
+            }
+            repo.git(&["config", "rgit.allow-snapshots", "false"]);
+            repo.git(&["config", "rgit.allow-clone", "false"]);
+            if unsupported {
+                repo.git(&["config", "core.repositoryFormatVersion", "1"]);
+                repo.git(&["config", "extensions.objectFormat", "sha256"]);
+            }
+
+            let db_dir = TempDir::new().unwrap();
+            let db = testing::database(db_dir.path());
+            testing::index(repo.scan_path(), &db);
+
+            let repository = Repository::open(&db, "repo.git").unwrap().unwrap();
+            let repository = repository.get();
+            let case = format!("committed: {committed}, unsupported: {unsupported}");
+            assert_eq!(repository.empty, !committed, "{case}");
+            assert_eq!(
+                repository.unsupported_object_format.is_some(),
+                unsupported,
+                "{case}"
+            );
+            assert!(!repository.allow_snapshots, "{case}");
+            assert!(!repository.allow_clone, "{case}");
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinks_outside_scan_path() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        let outside = TestRepo::new();
+        outside.commit("README", "hello");
+        std::os::unix::fs::symlink(outside.path(), repo.scan_path().join("escape.git")).unwrap();
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+
+        assert!(Repository::open(&db, "repo.git").unwrap().is_some());
+        assert!(Repository::open(&db, "escape.git").unwrap().is_none());
+    }
+}
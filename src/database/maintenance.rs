@@ -0,0 +1,556 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use gix::{bstr::ByteSlice, refs::Category, ObjectId};
+use rkyv::rancor;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+
+use crate::{
+    database::{
+        indexer::{self, peels_to_commit},
+        schema::{
+            commit::ArchivedAuthor,
+            repository::{ArchivedRepository, Repository},
+        },
+    },
+    git::retry_transient,
+    storage::Storage,
+};
+
+/// Inspects and maintains the index database, through the same schema the server reads it with.
+#[derive(clap::Args, Debug)]
+pub struct DbArgs {
+    /// Path to the `RocksDB` database to operate on
+    #[clap(short, long, value_parser)]
+    pub db_store: PathBuf,
+    #[clap(subcommand)]
+    pub command: DbCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum DbCommand {
+    /// Prints everything indexed for a repository as JSON
+    Dump {
+        /// Path of the repository relative to the scan path (eg. "foo.git")
+        #[clap(long)]
+        repo: String,
+        /// Most recent commits printed for each reference
+        #[clap(long, default_value_t = 20)]
+        commits: u64,
+    },
+    /// Compares the indexed tip of every branch and tag against the repositories on disk,
+    /// reporting any that have drifted. Exits with an error if any have
+    Verify {
+        /// The path in which your bare Git repositories reside
+        scan_path: PathBuf,
+    },
+    /// Removes repositories that are no longer on disk from the index
+    Prune {
+        /// The path in which your bare Git repositories reside
+        scan_path: PathBuf,
+        /// Lists the repositories that would be removed without removing them
+        #[clap(long)]
+        dry_run: bool,
+        /// How long requests for the removed repositories are answered with `410 Gone`, the
+        /// same as when the server finds them deleted (eg. "30days", or "0s" to disable)
+        #[clap(long, default_value_t = indexer::DEFAULT_TOMBSTONE_RETENTION.into())]
+        tombstone_retention: humantime::Duration,
+    },
+}
+
+impl DbCommand {
+    /// Whether the command only reads from the database, so it can be opened alongside a
+    /// running instance.
+    pub fn is_read_only(&self) -> bool {
+        !matches!(self, Self::Prune { dry_run: false, .. })
+    }
+}
+
+pub fn run(db: &Arc<rocksdb::DB>, command: DbCommand) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    match command {
+        DbCommand::Dump { repo, commits } => dump(db, &repo, commits, &mut stdout),
+        DbCommand::Verify { scan_path } => verify(db, &scan_path, &mut stdout),
+        DbCommand::Prune {
+            scan_path,
+            dry_run,
+            tombstone_retention,
+        } => prune(db, &scan_path, dry_run, *tombstone_retention, &mut stdout),
+    }
+}
+
+#[derive(Serialize)]
+struct RepositoryDump {
+    path: String,
+    repository: Repository,
+    references: BTreeMap<String, ReferenceDump>,
+    tags: BTreeMap<String, TagDump>,
+}
+
+#[derive(Serialize)]
+struct ReferenceDump {
+    /// Number of commits indexed.
+    indexed: u64,
+    /// Older history still to be indexed, if the first index was cut short.
+    backfill: Option<BackfillDump>,
+    /// The most recent commits indexed, newest first.
+    commits: Vec<CommitDump>,
+}
+
+#[derive(Serialize)]
+struct BackfillDump {
+    tip: String,
+    remaining: u64,
+}
+
+#[derive(Serialize)]
+struct CommitDump {
+    oid: String,
+    summary: String,
+    author: AuthorDump,
+    committer: AuthorDump,
+}
+
+#[derive(Serialize)]
+struct AuthorDump {
+    name: String,
+    email: String,
+    time: String,
+}
+
+impl From<&ArchivedAuthor> for AuthorDump {
+    fn from(author: &ArchivedAuthor) -> Self {
+        Self {
+            name: author.name.to_string(),
+            email: author.email.to_string(),
+            time: author.time().format(&Rfc3339).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TagDump {
+    target: &'static str,
+    tagger: Option<AuthorDump>,
+}
+
+fn dump(
+    db: &Arc<rocksdb::DB>,
+    path: &str,
+    commits: u64,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let repository =
+        Repository::open(db, path)?.with_context(|| format!("{path} isn't indexed"))?;
+    let archived = repository.get();
+
+    let mut references = BTreeMap::new();
+    for reference in indexed_references(archived, db)? {
+        let tree = archived.commit_tree(db.clone(), &reference);
+
+        references.insert(
+            reference,
+            ReferenceDump {
+                indexed: tree.len()?,
+                backfill: tree.backfill()?.map(|v| BackfillDump {
+                    tip: const_hex::encode(v.tip),
+                    remaining: v.remaining,
+                }),
+                commits: tree
+                    .fetch_latest(commits, 0)?
+                    .iter()
+                    .map(|commit| {
+                        let commit = commit.get();
+
+                        CommitDump {
+                            oid: const_hex::encode(commit.hash),
+                            summary: commit.summary.to_string(),
+                            author: (&commit.author).into(),
+                            committer: (&commit.committer).into(),
+                        }
+                    })
+                    .collect(),
+            },
+        );
+    }
+
+    let tags = archived
+        .tag_tree(db.clone())
+        .fetch_all()?
+        .into_iter()
+        .map(|(name, tag)| {
+            let tag = tag.get();
+
+            (
+                name.get().to_string(),
+                TagDump {
+                    target: tag.target().name(),
+                    tagger: tag.tagger.as_ref().map(Into::into),
+                },
+            )
+        })
+        .collect();
+
+    let dump = RepositoryDump {
+        path: path.to_string(),
+        repository: rkyv::deserialize::<Repository, rancor::Error>(*archived)?,
+        references,
+        tags,
+    };
+
+    serde_json::to_writer_pretty(&mut *out, &dump)?;
+    writeln!(out)?;
+
+    Ok(())
+}
+
+/// Every reference the indexer has indexed the history of, as the server would list them.
+fn indexed_references(
+    repository: &ArchivedRepository,
+    db: &rocksdb::DB,
+) -> anyhow::Result<Vec<String>> {
+    Ok(repository
+        .heads(db)?
+        .map(|heads| {
+            heads
+                .get()
+                .0
+                .iter()
+                .map(|v| v.as_str().to_string())
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+fn verify(db: &Arc<rocksdb::DB>, scan_path: &Path, out: &mut impl Write) -> anyhow::Result<()> {
+    ensure_available(scan_path)?;
+
+    let mut drifted = 0_usize;
+    let repositories = Repository::fetch_all(db)?;
+
+    for (path, repository) in &repositories {
+        let repository = repository.get();
+
+        // only the metadata of these is indexed, so there's nothing to drift
+        if repository.unsupported_object_format.is_some() {
+            continue;
+        }
+
+        let problems = match gix::open(scan_path.join(path)) {
+            Ok(git_repository) => verify_repository(db, repository, &git_repository)?,
+            Err(_) if !scan_path.join(path).exists() => vec!["missing from disk".to_string()],
+            Err(error) => vec![format!("can't be opened: {error}")],
+        };
+
+        if !problems.is_empty() {
+            drifted += 1;
+        }
+
+        for problem in problems {
+            writeln!(out, "{path}: {problem}")?;
+        }
+    }
+
+    writeln!(
+        out,
+        "{} repositories checked, {drifted} drifted from the index",
+        repositories.len()
+    )?;
+
+    if drifted > 0 {
+        return Err(anyhow!(
+            "Index has drifted from {drifted} repositories, it'll catch up on the next index"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Compares the tips indexed for `repository` with where its references actually point.
+fn verify_repository(
+    db: &Arc<rocksdb::DB>,
+    repository: &ArchivedRepository,
+    git_repository: &gix::Repository,
+) -> anyhow::Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let indexed = indexed_references(repository, db)?;
+
+    for reference in &indexed {
+        let tip = repository
+            .commit_tree(db.clone(), reference)
+            .fetch_latest_one()?
+            .map(|commit| ObjectId::from(commit.get().hash));
+        let actual = actual_tip(git_repository, reference)?;
+
+        match (tip, actual) {
+            (Some(tip), Some(actual)) if tip != actual => problems.push(format!(
+                "{reference} is indexed at {tip} but points at {actual}"
+            )),
+            (None, Some(actual)) => problems.push(format!(
+                "{reference} has no commits indexed but points at {actual}"
+            )),
+            (_, None) => problems.push(format!("{reference} is indexed but no longer exists")),
+            _ => {}
+        }
+    }
+
+    let references = retry_transient(|| {
+        let mut names = Vec::new();
+
+        for reference in git_repository.references()?.all()? {
+            let reference = reference.map_err(|e| anyhow!(e))?;
+            let name = reference.name();
+
+            if matches!(name.category(), Some(Category::Tag | Category::LocalBranch))
+                && peels_to_commit(&reference)
+            {
+                names.push(name.as_bstr().to_str_lossy().into_owned());
+            }
+        }
+
+        Ok(names)
+    })?;
+
+    for reference in references {
+        if !indexed.contains(&reference) {
+            problems.push(format!("{reference} exists but isn't indexed"));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// The commit `reference` points at in the repository, `None` if it doesn't exist. `HEAD` is
+/// only indexed whilst detached, so it only counts as existing whilst it is.
+fn actual_tip(
+    git_repository: &gix::Repository,
+    reference: &str,
+) -> anyhow::Result<Option<ObjectId>> {
+    if reference == "HEAD" {
+        let head = git_repository.head()?;
+
+        return Ok(head
+            .is_detached()
+            .then(|| head.id())
+            .flatten()
+            .map(gix::Id::detach));
+    }
+
+    let Some(mut reference) = git_repository.try_find_reference(reference)? else {
+        return Ok(None);
+    };
+
+    Ok(reference.peel_to_commit().ok().map(|commit| commit.id))
+}
+
+fn prune(
+    db: &Arc<rocksdb::DB>,
+    scan_path: &Path,
+    dry_run: bool,
+    tombstone_retention: Duration,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    // every repository looks deleted if the scan path can't be read
+    ensure_available(scan_path)?;
+
+    let mut pruned = 0_usize;
+
+    for (path, repository) in Repository::fetch_all(db)? {
+        if scan_path.join(&path).exists() {
+            continue;
+        }
+
+        if !dry_run {
+            repository
+                .get()
+                .delete(db, &path)
+                .with_context(|| format!("Failed to remove {path} from the index"))?;
+
+            indexer::record_tombstone(db, &path, tombstone_retention)
+                .with_context(|| format!("Failed to record tombstone of {path}"))?;
+        }
+
+        writeln!(out, "{path}")?;
+        pruned += 1;
+    }
+
+    if dry_run {
+        writeln!(out, "{pruned} repositories would be removed")?;
+    } else {
+        writeln!(out, "{pruned} repositories removed")?;
+    }
+
+    Ok(())
+}
+
+fn ensure_available(scan_path: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        Storage::new(scan_path).check(),
+        "Scan path {} can't be read",
+        scan_path.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    use super::{dump, prune, verify, DbCommand};
+    use crate::{
+        database::schema::repository::Repository,
+        testing::{self, TestRepo},
+        Cli, Command,
+    };
+
+    fn output(out: Vec<u8>) -> String {
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn serve_by_default() {
+        let cli = Cli::try_parse_from(["rgit", "-d", "/tmp/db", "[::]:3333", "/srv/git"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(cli.serve.is_some());
+
+        let cli = Cli::try_parse_from(["rgit", "db", "-d", "/tmp/db", "dump", "--repo", "foo.git"])
+            .unwrap();
+        let Some(Command::Db(args)) = cli.command else {
+            panic!("expected a db command, got {cli:?}");
+        };
+        assert!(matches!(
+            args.command,
+            DbCommand::Dump { ref repo, commits: 20 } if repo == "foo.git"
+        ));
+        assert!(args.command.is_read_only());
+
+        let cli =
+            Cli::try_parse_from(["rgit", "db", "-d", "/tmp/db", "prune", "/srv/git"]).unwrap();
+        let Some(Command::Db(args)) = cli.command else {
+            panic!("expected a db command, got {cli:?}");
+        };
+        assert!(!args.command.is_read_only());
+    }
+
+    #[test]
+    fn dump_indexed() {
+        let repo = TestRepo::new();
+        let first = repo.commit("README", "hello");
+        let tip = repo.commit("README", "world");
+        repo.git(&["tag", "-m", "release", "v1", &first]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+
+        let mut out = Vec::new();
+        dump(&db, "repo.git", 1, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&output(out)).unwrap();
+
+        assert_eq!(json["path"], "repo.git");
+        let main = &json["references"]["refs/heads/main"];
+        assert_eq!(main["indexed"], 2);
+        assert!(main["backfill"].is_null());
+        assert_eq!(main["commits"].as_array().unwrap().len(), 1);
+        assert_eq!(main["commits"][0]["oid"], tip.as_str());
+        assert_eq!(main["commits"][0]["summary"], "Update README");
+        assert_eq!(json["references"]["refs/tags/v1"]["indexed"], 1);
+        assert_eq!(json["tags"]["v1"]["target"], "commit");
+        assert_eq!(json["tags"]["v1"]["tagger"]["name"], "rgit");
+
+        assert!(dump(&db, "missing.git", 1, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn verify_drift() {
+        let repo = TestRepo::new();
+        let indexed = repo.commit("README", "hello");
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+
+        let mut out = Vec::new();
+        verify(&db, repo.scan_path(), &mut out).unwrap();
+        assert_eq!(
+            output(out),
+            "1 repositories checked, 0 drifted from the index\n"
+        );
+
+        // pushed to since the last index
+        let pushed = repo.commit("README", "world");
+        repo.git(&["branch", "feature", &pushed]);
+
+        let mut out = Vec::new();
+        assert!(verify(&db, repo.scan_path(), &mut out).is_err());
+        let out = output(out);
+        assert!(
+            out.contains(&format!(
+                "repo.git: refs/heads/main is indexed at {indexed} but points at {pushed}\n"
+            )),
+            "{out}"
+        );
+        assert!(out.contains("repo.git: refs/heads/feature exists but isn't indexed\n"));
+        assert!(out.ends_with("1 repositories checked, 1 drifted from the index\n"));
+
+        // and it agrees with the indexer once it's caught up
+        testing::index(repo.scan_path(), &db);
+        verify(&db, repo.scan_path(), &mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn prune_missing() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        let other = repo.clone("other.git", &[]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+
+        std::fs::remove_dir_all(&other).unwrap();
+
+        let mut out = Vec::new();
+        verify(&db, repo.scan_path(), &mut out).unwrap_err();
+        assert!(output(out).contains("other.git: missing from disk\n"));
+
+        let mut out = Vec::new();
+        prune(&db, repo.scan_path(), true, Duration::ZERO, &mut out).unwrap();
+        assert_eq!(output(out), "other.git\n1 repositories would be removed\n");
+        assert!(Repository::open(&db, "other.git").unwrap().is_some());
+
+        let mut out = Vec::new();
+        prune(&db, repo.scan_path(), false, Duration::ZERO, &mut out).unwrap();
+        assert_eq!(output(out), "other.git\n1 repositories removed\n");
+        assert!(Repository::open(&db, "other.git").unwrap().is_none());
+        assert!(Repository::open(&db, "repo.git").unwrap().is_some());
+        verify(&db, repo.scan_path(), &mut Vec::new()).unwrap();
+
+        // every repository would look deleted
+        let away = repo.scan_path().with_extension("away");
+        std::fs::rename(repo.scan_path(), &away).unwrap();
+        let result = prune(
+            &db,
+            repo.scan_path(),
+            false,
+            Duration::ZERO,
+            &mut Vec::new(),
+        );
+        std::fs::rename(&away, repo.scan_path()).unwrap();
+
+        assert!(result.is_err());
+        assert!(Repository::open(&db, "repo.git").unwrap().is_some());
+    }
+}
@@ -0,0 +1,425 @@
+//! Per column family versioning of the database, allowing format changes to migrate (or at
+//! worst clear) only the data that actually changed rather than throwing away the entire
+//! index on every upgrade.
+
+use anyhow::Context;
+use rocksdb::{IteratorMode, WriteBatch};
+use tracing::{info, warn};
+
+use crate::database::schema::{
+    prefixes::{
+        COMMIT_COUNT_FAMILY, COMMIT_FAMILY, REFERENCE_FAMILY, REPOSITORY_FAMILY,
+        REPOSITORY_ID_FAMILY, TAG_FAMILY, TOMBSTONE_FAMILY,
+    },
+    SCHEMA_VERSION,
+};
+
+/// The current version of the data stored in each column family. Bump the version of a
+/// family whenever the format of its keys or values changes, and add a step to
+/// [`MIGRATIONS`] to bring old data up to date. Families without a migration path are
+/// cleared and rebuilt on the next index.
+const FAMILY_VERSIONS: &[(&str, u32)] = &[
+    (COMMIT_FAMILY, 1),
+    (COMMIT_COUNT_FAMILY, 1),
+    (REPOSITORY_FAMILY, 9),
+    (TAG_FAMILY, 2),
+    (REFERENCE_FAMILY, 1),
+    (TOMBSTONE_FAMILY, 1),
+    (REPOSITORY_ID_FAMILY, 1),
+];
+
+/// Families keyed by repository id. Clearing the repository family leaves these be, as the
+/// indexer picks the same ids back up from [`REPOSITORY_ID_FAMILY`] when it rebuilds it. They
+/// only have to be cleared alongside it when there are no ids to pick back up, as in
+/// databases from before ids were kept apart, since new ids would be handed out instead.
+const REPOSITORY_KEYED_FAMILIES: &[&str] = &[
+    COMMIT_FAMILY,
+    COMMIT_COUNT_FAMILY,
+    TAG_FAMILY,
+    REFERENCE_FAMILY,
+];
+
+/// The global schema version that predates per-family versioning, every family in a
+/// database at this version without any family versions is in the format of version 1.
+const LEGACY_SCHEMA_VERSION: &[u8] = b"3";
+
+/// A migration step for a family, `(family, from_version, migration)`. The migration moves
+/// the family from `from_version` to `from_version + 1` and must be idempotent, as a crash
+/// halfway through will cause it to be ran again on the next startup.
+type Migration = (&'static str, u32, fn(&rocksdb::DB) -> anyhow::Result<()>);
+
+const MIGRATIONS: &[Migration] = &[];
+
+/// The result of attempting to bring the database up to date.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The database is now at the current version of every family.
+    UpToDate,
+    /// The database can't be migrated and needs to be destroyed.
+    Unmigratable,
+}
+
+/// Brings every family in the database up to its current version, clearing only the
+/// families that have no migration path.
+pub fn migrate(db: &rocksdb::DB) -> anyhow::Result<Outcome> {
+    let schema_version = db.get("schema_version")?;
+
+    let Some(schema_version) = schema_version else {
+        // brand new database, nothing to migrate
+        db.put("schema_version", SCHEMA_VERSION)?;
+        write_family_versions(db)?;
+        return Ok(Outcome::UpToDate);
+    };
+
+    if schema_version.as_slice() != SCHEMA_VERSION.as_bytes() {
+        warn!(
+            "Database schema version {} has no migration path to {SCHEMA_VERSION}",
+            String::from_utf8_lossy(&schema_version)
+        );
+        return Ok(Outcome::Unmigratable);
+    }
+
+    let legacy = is_legacy(db, &schema_version)?;
+    let mut to_clear = Vec::new();
+
+    for &(family, current_version) in FAMILY_VERSIONS {
+        let Some(mut version) = initial_version(db, family, current_version, legacy)? else {
+            warn!("Database is missing a version for {family}");
+            return Ok(Outcome::Unmigratable);
+        };
+
+        if version > current_version {
+            warn!("Database version of {family} (v{version}) is newer than this build (v{current_version})");
+            return Ok(Outcome::Unmigratable);
+        }
+
+        while version < current_version {
+            let Some((_, _, migration)) = MIGRATIONS
+                .iter()
+                .find(|(f, from, _)| *f == family && *from == version)
+            else {
+                warn!("No migration path for {family} from v{version}, it will be reindexed");
+                to_clear.push(family);
+                break;
+            };
+
+            info!("Migrating {family} from v{version} to v{}", version + 1);
+            migration(db).with_context(|| format!("Failed to migrate {family}"))?;
+
+            version += 1;
+            write_family_version(db, family, version)?;
+        }
+    }
+
+    if to_clear.contains(&REPOSITORY_FAMILY) && is_empty(db, REPOSITORY_ID_FAMILY)? {
+        to_clear.extend(REPOSITORY_KEYED_FAMILIES);
+    }
+
+    to_clear.sort_unstable();
+    to_clear.dedup();
+
+    for family in to_clear {
+        info!("Clearing outdated {family}");
+        clear_family(db, family)?;
+    }
+
+    write_family_versions(db)?;
+
+    Ok(Outcome::UpToDate)
+}
+
+/// Checks whether every family in the database is already at its current version, without
+/// writing anything. Used when the database is opened read-only and can't be migrated.
+pub fn is_up_to_date(db: &rocksdb::DB) -> anyhow::Result<bool> {
+    let Some(schema_version) = db.get("schema_version")? else {
+        return Ok(false);
+    };
+
+    if schema_version.as_slice() != SCHEMA_VERSION.as_bytes() {
+        return Ok(false);
+    }
+
+    let legacy = is_legacy(db, &schema_version)?;
+
+    for &(family, current_version) in FAMILY_VERSIONS {
+        if initial_version(db, family, current_version, legacy)? != Some(current_version) {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Whether the database predates per-family versioning, in which case none of its families
+/// have a version of their own.
+fn is_legacy(db: &rocksdb::DB, schema_version: &[u8]) -> anyhow::Result<bool> {
+    if schema_version != LEGACY_SCHEMA_VERSION {
+        return Ok(false);
+    }
+
+    for &(family, _) in FAMILY_VERSIONS {
+        if stored_family_version(db, family)?.is_some() {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// The version `family` is at before any migrations are ran, `None` if it can't be known.
+fn initial_version(
+    db: &rocksdb::DB,
+    family: &str,
+    current_version: u32,
+    legacy: bool,
+) -> anyhow::Result<Option<u32>> {
+    Ok(match stored_family_version(db, family)? {
+        Some(v) => Some(v),
+        None if legacy => Some(1),
+        // families added since the database was created start out empty at the current
+        // version
+        None if is_empty(db, family)? => Some(current_version),
+        None => None,
+    })
+}
+
+fn family_version_key(family: &str) -> String {
+    format!("schema_version:{family}")
+}
+
+fn stored_family_version(db: &rocksdb::DB, family: &str) -> anyhow::Result<Option<u32>> {
+    let Some(value) = db.get(family_version_key(family))? else {
+        return Ok(None);
+    };
+
+    let value: [u8; std::mem::size_of::<u32>()] = value
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid version for {family}"))?;
+
+    Ok(Some(u32::from_be_bytes(value)))
+}
+
+fn write_family_version(db: &rocksdb::DB, family: &str, version: u32) -> anyhow::Result<()> {
+    db.put(family_version_key(family), version.to_be_bytes())?;
+    Ok(())
+}
+
+fn write_family_versions(db: &rocksdb::DB) -> anyhow::Result<()> {
+    for &(family, version) in FAMILY_VERSIONS {
+        write_family_version(db, family, version)?;
+    }
+
+    Ok(())
+}
+
+/// Whether `family` holds no keys at all.
+fn is_empty(db: &rocksdb::DB, family: &str) -> anyhow::Result<bool> {
+    let cf = db
+        .cf_handle(family)
+        .with_context(|| format!("{family} column family missing"))?;
+
+    Ok(db.iterator_cf(cf, IteratorMode::Start).next().is_none())
+}
+
+/// Deletes every key within `family`, in batches so we don't have to hold the entire
+/// family in memory.
+fn clear_family(db: &rocksdb::DB, family: &str) -> anyhow::Result<()> {
+    let cf = db
+        .cf_handle(family)
+        .with_context(|| format!("{family} column family missing"))?;
+
+    let mut batch = WriteBatch::default();
+    let mut cleared = 0_u64;
+
+    for item in db.iterator_cf(cf, IteratorMode::Start) {
+        let (key, _) = item?;
+        batch.delete_cf(cf, key);
+        cleared += 1;
+
+        if batch.len() >= 10_000 {
+            db.write(std::mem::take(&mut batch))?;
+            info!("Cleared {cleared} keys from {family}");
+        }
+    }
+
+    db.write(batch)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rocksdb::Options;
+    use tempfile::TempDir;
+
+    use super::{migrate, Outcome, FAMILY_VERSIONS};
+    use crate::database::schema::{
+        prefixes::{
+            COMMIT_FAMILY, REPOSITORY_FAMILY, REPOSITORY_ID_FAMILY, TAG_FAMILY, TOMBSTONE_FAMILY,
+        },
+        SCHEMA_VERSION,
+    };
+
+    fn open(dir: &TempDir) -> rocksdb::DB {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+
+        rocksdb::DB::open_cf(
+            &options,
+            dir.path(),
+            FAMILY_VERSIONS.iter().map(|(family, _)| *family),
+        )
+        .unwrap()
+    }
+
+    fn current_version(family: &str) -> u32 {
+        FAMILY_VERSIONS
+            .iter()
+            .find(|(f, _)| *f == family)
+            .map(|(_, version)| *version)
+            .unwrap()
+    }
+
+    /// Writes a database as it'd be left by an older build, with every family at its current
+    /// version except for those in `versions`, which are left without a version if `None`.
+    fn write_old_database(db: &rocksdb::DB, versions: &[(&str, Option<u32>)]) {
+        db.put("schema_version", SCHEMA_VERSION).unwrap();
+
+        for &(family, current) in FAMILY_VERSIONS {
+            let version = versions
+                .iter()
+                .find(|(f, _)| *f == family)
+                .map_or(Some(current), |(_, version)| *version);
+
+            if let Some(version) = version {
+                super::write_family_version(db, family, version).unwrap();
+            }
+        }
+
+        put(db, REPOSITORY_FAMILY, b"foo.git", b"row in an old format");
+        put(db, COMMIT_FAMILY, &commit_key(), b"commit");
+    }
+
+    fn commit_key() -> Vec<u8> {
+        let mut key = 1234_u64.to_be_bytes().to_vec();
+        key.extend_from_slice(b"refs/heads/main\0");
+        key.extend_from_slice(&0_u64.to_be_bytes());
+        key
+    }
+
+    fn put(db: &rocksdb::DB, family: &str, key: &[u8], value: &[u8]) {
+        db.put_cf(db.cf_handle(family).unwrap(), key, value)
+            .unwrap();
+    }
+
+    fn get(db: &rocksdb::DB, family: &str, key: &[u8]) -> Option<Vec<u8>> {
+        db.get_cf(db.cf_handle(family).unwrap(), key).unwrap()
+    }
+
+    #[test]
+    fn outdated_repository_family_keeps_history_when_ids_are_known() {
+        let dir = TempDir::new().unwrap();
+        let db = open(&dir);
+
+        write_old_database(
+            &db,
+            &[(
+                REPOSITORY_FAMILY,
+                Some(current_version(REPOSITORY_FAMILY) - 1),
+            )],
+        );
+        put(
+            &db,
+            REPOSITORY_ID_FAMILY,
+            b"foo.git",
+            &1234_u64.to_be_bytes(),
+        );
+
+        assert_eq!(migrate(&db).unwrap(), Outcome::UpToDate);
+
+        assert_eq!(get(&db, REPOSITORY_FAMILY, b"foo.git"), None);
+        assert!(get(&db, REPOSITORY_ID_FAMILY, b"foo.git").is_some());
+        assert_eq!(
+            get(&db, COMMIT_FAMILY, &commit_key()).as_deref(),
+            Some(&b"commit"[..])
+        );
+        assert_eq!(
+            super::stored_family_version(&db, REPOSITORY_FAMILY).unwrap(),
+            Some(current_version(REPOSITORY_FAMILY))
+        );
+    }
+
+    #[test]
+    fn outdated_repository_family_clears_history_without_ids() {
+        let dir = TempDir::new().unwrap();
+        let db = open(&dir);
+
+        write_old_database(
+            &db,
+            &[(
+                REPOSITORY_FAMILY,
+                Some(current_version(REPOSITORY_FAMILY) - 1),
+            )],
+        );
+
+        assert_eq!(migrate(&db).unwrap(), Outcome::UpToDate);
+
+        assert_eq!(get(&db, REPOSITORY_FAMILY, b"foo.git"), None);
+        assert_eq!(get(&db, COMMIT_FAMILY, &commit_key()), None);
+    }
+
+    #[test]
+    fn legacy_database_is_at_version_one() {
+        let dir = TempDir::new().unwrap();
+        let db = open(&dir);
+
+        let unversioned: Vec<_> = FAMILY_VERSIONS
+            .iter()
+            .map(|(family, _)| (*family, None))
+            .collect();
+        write_old_database(&db, &unversioned);
+
+        assert_eq!(migrate(&db).unwrap(), Outcome::UpToDate);
+
+        // every family is at version 1, so the ones past it have been cleared
+        assert_eq!(get(&db, REPOSITORY_FAMILY, b"foo.git"), None);
+        assert_eq!(get(&db, COMMIT_FAMILY, &commit_key()), None);
+
+        for &(family, version) in FAMILY_VERSIONS {
+            assert_eq!(
+                super::stored_family_version(&db, family).unwrap(),
+                Some(version)
+            );
+        }
+    }
+
+    #[test]
+    fn new_family_starts_at_current_version() {
+        let dir = TempDir::new().unwrap();
+        let db = open(&dir);
+
+        write_old_database(&db, &[(TOMBSTONE_FAMILY, None)]);
+
+        assert_eq!(migrate(&db).unwrap(), Outcome::UpToDate);
+
+        assert!(get(&db, REPOSITORY_FAMILY, b"foo.git").is_some());
+        assert!(get(&db, COMMIT_FAMILY, &commit_key()).is_some());
+        assert_eq!(
+            super::stored_family_version(&db, TOMBSTONE_FAMILY).unwrap(),
+            Some(current_version(TOMBSTONE_FAMILY))
+        );
+    }
+
+    #[test]
+    fn unversioned_family_with_data_is_unmigratable() {
+        let dir = TempDir::new().unwrap();
+        let db = open(&dir);
+
+        write_old_database(&db, &[(TAG_FAMILY, None)]);
+        put(&db, TAG_FAMILY, b"tag", b"tag");
+
+        assert_eq!(migrate(&db).unwrap(), Outcome::Unmigratable);
+    }
+}
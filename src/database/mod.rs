@@ -1,2 +1,168 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::Context;
+use rocksdb::ErrorKind;
+use time::OffsetDateTime;
+use tracing::debug;
+
+use crate::database::schema::prefixes::{
+    COMMIT_COUNT_FAMILY, COMMIT_FAMILY, REFERENCE_FAMILY, REPOSITORY_FAMILY, REPOSITORY_ID_FAMILY,
+    TAG_FAMILY, TOMBSTONE_FAMILY,
+};
+
 pub mod indexer;
+pub mod maintenance;
+pub mod migration;
+pub mod refs;
 pub mod schema;
+
+/// Sums the size of every file within the database directory.
+pub fn size_on_disk(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+
+    for entry in std::fs::read_dir(path)? {
+        let metadata = entry?.metadata()?;
+
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Attaches what likely caused the database at `path` to fail to open, and what can be done
+/// about it, to `error`.
+pub fn explain_open_error(path: &Path, error: rocksdb::Error) -> anyhow::Error {
+    let path = path.display();
+
+    let explanation = match error.kind() {
+        ErrorKind::Corruption => format!(
+            "Database at {path} is corrupted, most likely by an unclean shutdown or failing \
+             storage. It only holds an index of your repositories, so it can be deleted and \
+             will be rebuilt on the next start, or rgit can do this itself when started with \
+             --recreate-db-on-corruption"
+        ),
+        ErrorKind::IOError if error.as_ref().contains("lock") => format!(
+            "Database at {path} is locked, another instance of rgit is likely already using \
+             it. Stop it or point this instance at a different --db-store"
+        ),
+        ErrorKind::IOError => format!(
+            "Database at {path} couldn't be read or written, check rgit has permission to \
+             access it and that there's space left on the device"
+        ),
+        _ => format!("Failed to open database at {path}"),
+    };
+
+    anyhow::Error::new(error).context(explanation)
+}
+
+/// Moves the database at `path` aside so a fresh one can be created in its place, keeping it
+/// around for inspection. Returns where the database was moved to.
+pub fn set_aside(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut name = path
+        .file_name()
+        .context("Database path has no file name")?
+        .to_os_string();
+    name.push(format!(
+        ".corrupt-{}",
+        OffsetDateTime::now_utc().unix_timestamp()
+    ));
+
+    let destination = path.with_file_name(name);
+    std::fs::rename(path, &destination).with_context(|| {
+        format!(
+            "Failed to move corrupted database at {} aside",
+            path.display()
+        )
+    })?;
+
+    Ok(destination)
+}
+
+/// Flushes the memtables of every column family to disk, so an abrupt kill doesn't force
+/// the work of the last index to be replayed or redone.
+pub fn flush(db: &rocksdb::DB) -> anyhow::Result<()> {
+    let start = Instant::now();
+
+    db.flush()?;
+
+    for family in [
+        COMMIT_FAMILY,
+        COMMIT_COUNT_FAMILY,
+        REPOSITORY_FAMILY,
+        TAG_FAMILY,
+        REFERENCE_FAMILY,
+        REPOSITORY_ID_FAMILY,
+        TOMBSTONE_FAMILY,
+    ] {
+        if let Some(cf) = db.cf_handle(family) {
+            db.flush_cf(cf)?;
+        }
+    }
+
+    db.flush_wal(true)?;
+
+    debug!("Flushed database in {:?}", start.elapsed());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::{
+        database::schema::repository::Repository,
+        testing::{self, TestRepo},
+    };
+
+    #[test]
+    fn corrupted_databases() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("db");
+        let db = testing::database(&path);
+        testing::index(repo.scan_path(), &db);
+        drop(db);
+
+        // like an unclean shutdown cutting a write short
+        for entry in std::fs::read_dir(&path).unwrap() {
+            let entry = entry.unwrap().path();
+            if entry.extension().is_some_and(|v| v == "sst") {
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&entry)
+                    .unwrap();
+                file.set_len(file.metadata().unwrap().len() / 2).unwrap();
+            }
+        }
+
+        // without being told to, it's left alone for the operator to look at
+        let error = crate::open_db(&path, None, false, false).err().unwrap();
+        let message = error.to_string();
+        assert!(message.contains("is corrupted"), "{message}");
+        assert!(message.contains("--recreate-db-on-corruption"), "{message}");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // moved aside and rebuilt by the index run at startup
+        let db = crate::open_db(&path, None, false, true).unwrap();
+        assert!(Repository::fetch_all(&db).unwrap().is_empty());
+        testing::index(repo.scan_path(), &db);
+        assert!(Repository::open(&db, "repo.git").unwrap().is_some());
+
+        let moved: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != "db")
+            .collect();
+        assert_eq!(moved.len(), 1, "{moved:?}");
+        assert!(moved[0].starts_with("db.corrupt-"), "{moved:?}");
+        assert!(dir.path().join(&moved[0]).join("CURRENT").exists());
+    }
+}
@@ -0,0 +1,219 @@
+//! The branches and tags of a repository, read back from the index.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use rkyv::string::ArchivedString;
+
+use crate::database::schema::{
+    commit::YokedCommit,
+    repository::ArchivedRepository,
+    tag::{YokedString, YokedTag},
+};
+
+/// Every branch and tag of a repository, as of the last index.
+pub struct Refs {
+    pub heads: BTreeMap<String, YokedCommit>,
+    pub tags: Vec<(YokedString, YokedTag)>,
+    /// The oid of the commit each tag points to, keyed by the tag's name.
+    pub tag_targets: BTreeMap<String, [u8; 20]>,
+    /// Names of the branches merged into the default branch, only worked out for the refs
+    /// page.
+    pub merged: HashSet<String>,
+    digest: u64,
+}
+
+/// A ref pointing at a commit, shown alongside the commit in lists of commits.
+pub enum Decoration {
+    Branch(String),
+    Tag(String),
+}
+
+impl Refs {
+    /// Loads every branch and tag of the repository from the index.
+    pub fn fetch(repository: &ArchivedRepository, db: &Arc<rocksdb::DB>) -> anyhow::Result<Self> {
+        let mut heads = BTreeMap::new();
+        let mut tag_targets = BTreeMap::new();
+
+        if let Some(heads_db) = repository.heads(db)? {
+            for head in heads_db
+                .get()
+                .0
+                .as_slice()
+                .iter()
+                .map(ArchivedString::as_str)
+            {
+                let commit_tree = repository.commit_tree(db.clone(), head);
+
+                if let Some(name) = head.strip_prefix("refs/heads/") {
+                    if let Some(commit) = commit_tree.fetch_latest_one()? {
+                        heads.insert(name.to_string(), commit);
+                    }
+                } else if let Some(name) = head.strip_prefix("refs/tags/") {
+                    // tags have their history indexed just like branches, so the tip of that
+                    // history is what the tag points to
+                    if let Some(commit) = commit_tree.fetch_latest_one()? {
+                        tag_targets.insert(name.to_string(), commit.get().hash);
+                    }
+                }
+            }
+        }
+
+        let tags = repository.tag_tree(db.clone()).fetch_all()?;
+
+        Ok(Self::new(heads, tags, tag_targets))
+    }
+
+    fn new(
+        heads: BTreeMap<String, YokedCommit>,
+        tags: Vec<(YokedString, YokedTag)>,
+        tag_targets: BTreeMap<String, [u8; 20]>,
+    ) -> Self {
+        let mut identity = Vec::new();
+
+        for (name, commit) in &heads {
+            identity.extend_from_slice(name.as_bytes());
+            identity.push(b'\0');
+            identity.extend_from_slice(commit.get().hash.as_slice());
+        }
+
+        // the index doesn't hold the targets of tags, so the tagger's timestamp stands in
+        // for it, recreating a tag will move it along
+        for (name, tag) in &tags {
+            identity.extend_from_slice(name.get().as_bytes());
+            identity.push(b'\0');
+
+            if let Some(tagger) = tag.get().tagger.as_ref() {
+                identity.extend_from_slice(&tagger.time.0.to_native().to_be_bytes());
+            }
+        }
+
+        Self {
+            heads,
+            tags,
+            tag_targets,
+            merged: HashSet::new(),
+            digest: xxhash_rust::const_xxh3::xxh3_64(&identity),
+        }
+    }
+
+    /// A digest of every ref and what it points to, changing whenever a ref is created,
+    /// deleted or moved.
+    pub fn digest(&self) -> u64 {
+        self.digest
+    }
+
+    /// Maps the oid of every commit a branch or tag points to onto the refs pointing at it,
+    /// like `git log --decorate`. Branches come before tags, each sorted by name.
+    pub fn decorations(&self) -> HashMap<[u8; 20], Vec<Decoration>> {
+        let mut decorations: HashMap<_, Vec<_>> = HashMap::new();
+
+        for (name, commit) in &self.heads {
+            decorations
+                .entry(commit.get().hash)
+                .or_default()
+                .push(Decoration::Branch(name.clone()));
+        }
+
+        for (name, target) in &self.tag_targets {
+            decorations
+                .entry(*target)
+                .or_default()
+                .push(Decoration::Tag(name.clone()));
+        }
+
+        decorations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use yoke::Yoke;
+
+    use super::{Decoration, Refs};
+    use crate::database::schema::commit::{Author, Commit, YokedCommit};
+
+    fn commit(hash: u8) -> YokedCommit {
+        let author = || Author {
+            name: "A U Thor".to_string(),
+            email: "author@example.com".to_string(),
+            time: (0, 0),
+        };
+
+        let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&Commit {
+            summary: String::new(),
+            message: String::new(),
+            author: author(),
+            committer: author(),
+            hash: [hash; 20],
+        })
+        .unwrap();
+
+        Yoke::try_attach_to_cart(bytes.to_vec().into_boxed_slice(), |data| {
+            rkyv::access::<_, rkyv::rancor::Error>(data)
+        })
+        .unwrap()
+    }
+
+    fn refs(heads: &[(&str, u8)], tags: &[(&str, u8)]) -> Refs {
+        Refs::new(
+            heads
+                .iter()
+                .map(|(name, hash)| ((*name).to_string(), commit(*hash)))
+                .collect(),
+            Vec::new(),
+            tags.iter()
+                .map(|(name, hash)| ((*name).to_string(), [*hash; 20]))
+                .collect(),
+        )
+    }
+
+    fn describe(decorations: &[Decoration]) -> Vec<String> {
+        decorations
+            .iter()
+            .map(|v| match v {
+                Decoration::Branch(name) => format!("branch {name}"),
+                Decoration::Tag(name) => format!("tag {name}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decorations_group_refs_by_commit() {
+        let refs = refs(
+            &[("main", 1), ("feature", 2), ("develop", 1)],
+            &[("v1.0", 1), ("v0.9", 3)],
+        );
+
+        let decorations: BTreeMap<_, _> = refs
+            .decorations()
+            .into_iter()
+            .map(|(oid, v)| (oid, describe(&v)))
+            .collect();
+
+        assert_eq!(
+            decorations,
+            BTreeMap::from([
+                (
+                    [1; 20],
+                    vec![
+                        "branch develop".to_string(),
+                        "branch main".to_string(),
+                        "tag v1.0".to_string(),
+                    ]
+                ),
+                ([2; 20], vec!["branch feature".to_string()]),
+                ([3; 20], vec!["tag v0.9".to_string()]),
+            ])
+        );
+    }
+
+    #[test]
+    fn decorations_are_empty_without_refs() {
+        assert!(refs(&[], &[]).decorations().is_empty());
+    }
+}
@@ -4,14 +4,17 @@ use anyhow::Context;
 use gix::{actor::SignatureRef, objs::CommitRef, ObjectId};
 use rkyv::{Archive, Serialize};
 use rocksdb::{IteratorMode, ReadOptions, WriteBatch};
-use time::{OffsetDateTime, UtcOffset};
+use time::OffsetDateTime;
 use tracing::debug;
 use yoke::{Yoke, Yokeable};
 
-use crate::database::schema::{
-    prefixes::{COMMIT_COUNT_FAMILY, COMMIT_FAMILY},
-    repository::RepositoryId,
-    Yoked,
+use crate::{
+    database::schema::{
+        prefixes::{COMMIT_COUNT_FAMILY, COMMIT_FAMILY},
+        repository::RepositoryId,
+        Yoked,
+    },
+    git::signature_time,
 };
 
 #[derive(Serialize, Archive, Debug, Yokeable)]
@@ -57,9 +60,17 @@ pub struct Author {
 
 impl ArchivedAuthor {
     pub fn time(&self) -> OffsetDateTime {
-        OffsetDateTime::from_unix_timestamp(self.time.0.to_native())
-            .unwrap()
-            .to_offset(UtcOffset::from_whole_seconds(self.time.1.to_native()).unwrap())
+        signature_time(self.time.0.to_native(), self.time.1.to_native())
+    }
+}
+
+impl ArchivedCommit {
+    /// Whether the commit was committed by someone other than its author, or at another time,
+    /// as happens to rebased and cherry-picked commits.
+    pub fn committed_separately(&self) -> bool {
+        self.author.name != self.committer.name
+            || self.author.email != self.committer.email
+            || self.author.time != self.committer.time
     }
 }
 
@@ -75,6 +86,19 @@ impl TryFrom<SignatureRef<'_>> for Author {
     }
 }
 
+/// Appended to the prefix of a [`CommitTree`] to key its [`Backfill`] cursor, references
+/// can't contain NUL so this can't clash with another tree's prefix.
+const BACKFILL_SUFFIX: &[u8] = b"backfill";
+
+/// Progress through indexing the older history of a reference whose first index was cut short
+/// by the indexer's commit budget. Commits are keyed by their position in the history walked
+/// from `tip`, so the `remaining` oldest of them are yet to be indexed.
+#[derive(Debug, Clone, Copy)]
+pub struct Backfill {
+    pub tip: [u8; 20],
+    pub remaining: u64,
+}
+
 pub struct CommitTree {
     db: Arc<rocksdb::DB>,
     pub prefix: Box<[u8]>,
@@ -110,6 +134,54 @@ impl CommitTree {
             .cf_handle(COMMIT_COUNT_FAMILY)
             .context("missing column family")?;
         self.db.delete_cf(commit_count_cf, &self.prefix)?;
+        self.db.delete_cf(commit_count_cf, self.backfill_key())?;
+
+        Ok(())
+    }
+
+    fn backfill_key(&self) -> Vec<u8> {
+        let mut key = self.prefix.to_vec();
+        key.extend_from_slice(BACKFILL_SUFFIX);
+        key
+    }
+
+    /// Fetches how much older history is still to be indexed, if any.
+    pub fn backfill(&self) -> anyhow::Result<Option<Backfill>> {
+        let cf = self
+            .db
+            .cf_handle(COMMIT_COUNT_FAMILY)
+            .context("missing column family")?;
+
+        let Some(res) = self.db.get_pinned_cf(cf, self.backfill_key())? else {
+            return Ok(None);
+        };
+
+        let (tip, remaining) = res
+            .split_first_chunk::<20>()
+            .context("invalid backfill cursor")?;
+        let remaining: [u8; std::mem::size_of::<u64>()] = remaining.try_into()?;
+
+        Ok(Some(Backfill {
+            tip: *tip,
+            remaining: u64::from_be_bytes(remaining),
+        }))
+    }
+
+    /// Records how much older history is still to be indexed, clearing the cursor once there's
+    /// none left.
+    pub fn update_backfill(&self, backfill: &Backfill, tx: &mut WriteBatch) -> anyhow::Result<()> {
+        let cf = self
+            .db
+            .cf_handle(COMMIT_COUNT_FAMILY)
+            .context("missing column family")?;
+
+        if backfill.remaining == 0 {
+            tx.delete_cf(cf, self.backfill_key());
+        } else {
+            let mut value = backfill.tip.to_vec();
+            value.extend_from_slice(&backfill.remaining.to_be_bytes());
+            tx.put_cf(cf, self.backfill_key(), value);
+        }
 
         Ok(())
     }
@@ -6,6 +6,7 @@ pub mod commit;
 pub mod prefixes;
 pub mod repository;
 pub mod tag;
+pub mod tombstone;
 
 pub type Yoked<T> = Yoke<T, Box<[u8]>>;
 
@@ -3,3 +3,5 @@ pub const COMMIT_COUNT_FAMILY: &str = "commit_count";
 pub const REPOSITORY_FAMILY: &str = "repository";
 pub const TAG_FAMILY: &str = "tag";
 pub const REFERENCE_FAMILY: &str = "repository_refs";
+pub const REPOSITORY_ID_FAMILY: &str = "repository_id";
+pub const TOMBSTONE_FAMILY: &str = "tombstone";
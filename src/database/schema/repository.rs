@@ -1,19 +1,29 @@
-use std::{collections::BTreeMap, ops::Deref, path::Path, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    ops::{Deref, RangeInclusive},
+    path::Path,
+    sync::Arc,
+};
 
 use anyhow::{Context, Result};
 use rand::random;
-use rkyv::{Archive, Serialize};
+use rkyv::{Archive, Deserialize, Serialize};
 use rocksdb::IteratorMode;
 use yoke::{Yoke, Yokeable};
 
 use crate::database::schema::{
     commit::CommitTree,
-    prefixes::{COMMIT_FAMILY, REFERENCE_FAMILY, REPOSITORY_FAMILY, TAG_FAMILY},
+    prefixes::{
+        COMMIT_FAMILY, REFERENCE_FAMILY, REPOSITORY_FAMILY, REPOSITORY_ID_FAMILY, TAG_FAMILY,
+    },
     tag::TagTree,
     Yoked,
 };
 
-#[derive(Serialize, Archive, Debug, PartialEq, Eq, Hash, Yokeable)]
+#[derive(
+    Serialize, Deserialize, Archive, serde::Serialize, Debug, PartialEq, Eq, Hash, Yokeable,
+)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Repository {
     /// The ID of the repository, as stored in `RocksDB`
     pub id: RepositoryId,
@@ -32,20 +42,84 @@ pub struct Repository {
     ///
     /// This is set to `true` based on the presence of `git-daemon-export-ok` in the repository
     pub exported: bool,
+    /// Whether search engines should be asked not to index the repository's pages
+    ///
+    /// This is set by `rgit.noindex` in the repository configuration
+    pub noindex: bool,
+    /// The object format of the repository if it's one rgit can't read, such as `sha256`.
+    /// Nothing besides the metadata above is indexed for these repositories
+    pub unsupported_object_format: Option<String>,
+    /// How many objects the repository holds and how much space they take up, `None` if they
+    /// couldn't be counted
+    pub objects: Option<ObjectStats>,
+    /// The remotes configured in the repository, with any credentials removed from their
+    /// URLs
+    pub remotes: Vec<Remote>,
+    /// How many commits the repository's feeds hold, overriding `--feed-items`
+    ///
+    /// This is set by `rgit.feed-items` in the repository configuration
+    pub feed_items: Option<u64>,
+    /// How many commits each page of the repository's log holds, overriding `--log-page-size`
+    ///
+    /// This is set by `rgit.log-page-size` in the repository configuration
+    pub log_page_size: Option<u64>,
+    /// Whether nothing has been pushed to the repository yet
+    pub empty: bool,
+    /// Whether snapshots of the repository's trees can be downloaded
+    ///
+    /// This is set by `rgit.allow-snapshots` in the repository configuration
+    pub allow_snapshots: bool,
+    /// Whether the repository can be cloned or fetched from, over smart HTTP or as a bundle
+    ///
+    /// This is set by `rgit.allow-clone` in the repository configuration
+    pub allow_clone: bool,
+    /// Links out to the repository's CI, documentation, issue tracker and the like, shown in
+    /// the header of every page of the repository
+    ///
+    /// This is set by repeated `rgit.link` entries in the repository configuration
+    pub links: Vec<Link>,
 }
 
-pub type YokedRepository = Yoked<&'static <Repository as Archive>::Archived>;
+/// The number of commits a feed can be configured to hold, globally or per repository
+pub const FEED_ITEMS_RANGE: RangeInclusive<u64> = 1..=500;
+
+/// The number of commits a page of the log can be configured to hold, globally or per
+/// repository
+pub const LOG_PAGE_SIZE_RANGE: RangeInclusive<u64> = 10..=1000;
+
+/// Statistics about the objects in a repository's object store, gathered by the indexer
+#[derive(
+    Serialize, Deserialize, Archive, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash,
+)]
+pub struct ObjectStats {
+    /// The number of loose and packed objects, objects held by more than one pack are
+    /// counted once for each
+    pub count: u64,
+    /// The size of the object store on disk, in bytes
+    pub size: u64,
+}
 
-impl Repository {
-    pub fn exists<P: AsRef<Path>>(database: &rocksdb::DB, path: P) -> Result<bool> {
-        let cf = database
-            .cf_handle(REPOSITORY_FAMILY)
-            .context("repository column family missing")?;
-        let path = path.as_ref().to_str().context("invalid path")?;
+/// A remote configured in a repository, such as the upstream of a mirror
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Remote {
+    /// The name of the remote (ie. `origin`)
+    pub name: String,
+    /// The URL the remote is fetched from
+    pub url: String,
+}
 
-        Ok(database.get_pinned_cf(cf, path)?.is_some())
-    }
+/// A link configured for a repository, see [`Repository::links`]
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Link {
+    /// The text the link is shown as
+    pub label: String,
+    /// Where the link goes, always an `http` or `https` URL
+    pub url: String,
+}
+
+pub type YokedRepository = Yoked<&'static <Repository as Archive>::Archived>;
 
+impl Repository {
     pub fn fetch_all(database: &rocksdb::DB) -> Result<BTreeMap<String, YokedRepository>> {
         let cf = database
             .cf_handle(REPOSITORY_FAMILY)
@@ -65,41 +139,113 @@ impl Repository {
             .collect()
     }
 
+    /// Finds the paths of indexed repositories matching `path` when compared
+    /// case-insensitively, so `MyRepo` can find `myrepo.git`. Repositories whose path matches
+    /// exactly, ignoring case, are preferred over ones which only match with a `.git` suffix.
+    pub fn find_case_insensitive<P: AsRef<Path>>(
+        database: &rocksdb::DB,
+        path: P,
+    ) -> Result<Vec<String>> {
+        let cf = database
+            .cf_handle(REPOSITORY_FAMILY)
+            .context("repository column family missing")?;
+
+        let path = path
+            .as_ref()
+            .to_str()
+            .context("invalid path")?
+            .to_lowercase();
+        let suffixed = format!("{path}.git");
+
+        let mut exact = Vec::new();
+        let mut with_suffix = Vec::new();
+
+        for (key, _) in database
+            .iterator_cf(cf, IteratorMode::Start)
+            .filter_map(Result::ok)
+        {
+            let Ok(key) = String::from_utf8(key.into_vec()) else {
+                continue;
+            };
+
+            let lowercase = key.to_lowercase();
+
+            if lowercase == path {
+                exact.push(key);
+            } else if lowercase == suffixed {
+                with_suffix.push(key);
+            }
+        }
+
+        Ok(if exact.is_empty() { with_suffix } else { exact })
+    }
+
     pub fn insert<P: AsRef<Path>>(&self, database: &rocksdb::DB, path: P) -> Result<()> {
         let cf = database
             .cf_handle(REPOSITORY_FAMILY)
             .context("repository column family missing")?;
+        let id_cf = database
+            .cf_handle(REPOSITORY_ID_FAMILY)
+            .context("repository id column family missing")?;
         let path = path.as_ref().to_str().context("invalid path")?;
 
         database.put_cf(cf, path, rkyv::to_bytes::<rkyv::rancor::Error>(self)?)?;
+        database.put_cf(id_cf, path, self.id.0.to_be_bytes())?;
 
         Ok(())
     }
 
-    pub fn open<P: AsRef<Path>>(
+    /// Finds the id the repository at `path` was last indexed under. Ids are kept apart from
+    /// the rest of the repository so they outlive its row being cleared by a migration,
+    /// otherwise everything keyed by the id would have to be reindexed along with it.
+    pub fn find_id<P: AsRef<Path>>(
         database: &rocksdb::DB,
         path: P,
-    ) -> Result<Option<YokedRepository>> {
+    ) -> Result<Option<RepositoryId>> {
         let cf = database
-            .cf_handle(REPOSITORY_FAMILY)
-            .context("repository column family missing")?;
+            .cf_handle(REPOSITORY_ID_FAMILY)
+            .context("repository id column family missing")?;
 
         let path = path.as_ref().to_str().context("invalid path")?;
         let Some(value) = database.get_cf(cf, path)? else {
             return Ok(None);
         };
 
-        Yoke::try_attach_to_cart(value.into_boxed_slice(), |data| {
-            rkyv::access::<_, rkyv::rancor::Error>(data)
-        })
-        .map(Some)
-        .context("Failed to open repository")
+        let value: [u8; std::mem::size_of::<u64>()] = value
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid repository id for {path}"))?;
+
+        Ok(Some(RepositoryId(u64::from_be_bytes(value))))
     }
-}
 
-impl ArchivedRepository {
-    pub fn delete<P: AsRef<Path>>(&self, database: &rocksdb::DB, path: P) -> Result<()> {
-        let start_id = self.id.0.to_native().to_be_bytes();
+    /// Every repository id that's been handed out, by the path of its repository.
+    pub fn fetch_all_ids(database: &rocksdb::DB) -> Result<BTreeMap<String, RepositoryId>> {
+        let cf = database
+            .cf_handle(REPOSITORY_ID_FAMILY)
+            .context("repository id column family missing")?;
+
+        database
+            .iterator_cf(cf, IteratorMode::Start)
+            .filter_map(Result::ok)
+            .map(|(key, value)| {
+                let key = String::from_utf8(key.into_vec()).context("invalid repo name")?;
+                let value: [u8; std::mem::size_of::<u64>()] = (*value)
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("invalid repository id for {key}"))?;
+
+                Ok((key, RepositoryId(u64::from_be_bytes(value))))
+            })
+            .collect()
+    }
+
+    /// Removes everything indexed under `id` for the repository at `path`.
+    pub fn delete_indexed<P: AsRef<Path>>(
+        database: &rocksdb::DB,
+        id: RepositoryId,
+        path: P,
+    ) -> Result<()> {
+        let start_id = id.0.to_be_bytes();
         let mut end_id = start_id;
         *end_id.last_mut().unwrap() += 1;
 
@@ -119,12 +265,48 @@ impl ArchivedRepository {
         let repo_cf = database
             .cf_handle(REPOSITORY_FAMILY)
             .context("repository column family missing")?;
+        let id_cf = database
+            .cf_handle(REPOSITORY_ID_FAMILY)
+            .context("repository id column family missing")?;
         let path = path.as_ref().to_str().context("invalid path")?;
         database.delete_cf(repo_cf, path)?;
+        database.delete_cf(id_cf, path)?;
 
         Ok(())
     }
 
+    pub fn open<P: AsRef<Path>>(
+        database: &rocksdb::DB,
+        path: P,
+    ) -> Result<Option<YokedRepository>> {
+        let cf = database
+            .cf_handle(REPOSITORY_FAMILY)
+            .context("repository column family missing")?;
+
+        let path = path.as_ref().to_str().context("invalid path")?;
+        let Some(value) = database.get_cf(cf, path)? else {
+            return Ok(None);
+        };
+
+        Yoke::try_attach_to_cart(value.into_boxed_slice(), |data| {
+            rkyv::access::<_, rkyv::rancor::Error>(data)
+        })
+        .map(Some)
+        .context("Failed to open repository")
+    }
+}
+
+impl ArchivedRepository {
+    /// Whether the repository is served at all, repositories that weren't exported with
+    /// `git-daemon-export-ok` when they were indexed are hidden unless `--export-all` is set.
+    pub fn is_visible(&self, export_all: bool) -> bool {
+        export_all || self.exported
+    }
+
+    pub fn delete<P: AsRef<Path>>(&self, database: &rocksdb::DB, path: P) -> Result<()> {
+        Repository::delete_indexed(database, RepositoryId(self.id.0.to_native()), path)
+    }
+
     pub fn commit_tree(&self, database: Arc<rocksdb::DB>, reference: &str) -> CommitTree {
         CommitTree::new(database, RepositoryId(self.id.0.to_native()), reference)
     }
@@ -171,7 +353,9 @@ impl ArchivedRepository {
 #[derive(Serialize, Archive, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Heads(pub Vec<String>);
 
-#[derive(Serialize, Archive, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(
+    Serialize, Deserialize, Archive, serde::Serialize, Debug, Copy, Clone, PartialEq, Eq, Hash,
+)]
 pub struct RepositoryId(pub u64);
 
 impl RepositoryId {
@@ -187,3 +371,66 @@ impl Deref for RepositoryId {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rocksdb::Options;
+    use tempfile::TempDir;
+
+    use super::Repository;
+    use crate::database::schema::prefixes::REPOSITORY_FAMILY;
+
+    fn open(dir: &TempDir, paths: &[&str]) -> rocksdb::DB {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+
+        let db = rocksdb::DB::open_cf(&options, dir.path(), [REPOSITORY_FAMILY]).unwrap();
+
+        // only the keys are looked at when matching, so the rows needn't be real
+        for path in paths {
+            db.put_cf(db.cf_handle(REPOSITORY_FAMILY).unwrap(), path, b"")
+                .unwrap();
+        }
+
+        db
+    }
+
+    #[test]
+    fn find_case_insensitive() {
+        let cases: &[(&[&str], &str, &[&str])] = &[
+            // exact match, ignoring case
+            (&["MyRepo.git"], "myrepo.git", &["MyRepo.git"]),
+            (
+                &["nested/Repo.git"],
+                "NESTED/repo.GIT",
+                &["nested/Repo.git"],
+            ),
+            // unique match with a `.git` suffix
+            (&["MyRepo.git", "other.git"], "myrepo", &["MyRepo.git"]),
+            // exact matches are preferred over suffixed ones
+            (&["myrepo", "MyRepo.git"], "MYREPO", &["myrepo"]),
+            // ambiguous matches are all returned, for the caller to refuse
+            (
+                &["MyRepo.git", "myrepo.git"],
+                "MyRepo",
+                &["MyRepo.git", "myrepo.git"],
+            ),
+            // no match
+            (&["MyRepo.git"], "myrepo.gi", &[]),
+            (&["MyRepo.git"], "other", &[]),
+            (&[], "myrepo", &[]),
+        ];
+
+        for &(paths, path, expected) in cases {
+            let dir = TempDir::new().unwrap();
+            let db = open(&dir, paths);
+
+            assert_eq!(
+                Repository::find_case_insensitive(&db, path).unwrap(),
+                expected,
+                "{path} in {paths:?}",
+            );
+        }
+    }
+}
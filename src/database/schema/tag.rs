@@ -15,12 +15,14 @@ use crate::database::schema::{
 #[derive(Serialize, Archive, Debug, Yokeable)]
 pub struct Tag {
     pub tagger: Option<Author>,
+    pub target: TagTarget,
 }
 
 impl Tag {
-    pub fn new(tagger: Option<SignatureRef<'_>>) -> Result<Self, anyhow::Error> {
+    pub fn new(tagger: Option<SignatureRef<'_>>, target: TagTarget) -> Result<Self, anyhow::Error> {
         Ok(Self {
             tagger: tagger.map(TryFrom::try_from).transpose()?,
+            target,
         })
     }
 
@@ -29,6 +31,45 @@ impl Tag {
     }
 }
 
+/// The type of object a tag points to, once any nested tags have been peeled.
+#[derive(Serialize, Archive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TagTarget {
+    Commit,
+    Tree,
+    Blob,
+}
+
+impl TagTarget {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Commit => "commit",
+            Self::Tree => "tree",
+            Self::Blob => "blob",
+        }
+    }
+
+    /// Whether the tag can be downloaded as a snapshot, which tags of blobs can't.
+    pub fn has_snapshot(self) -> bool {
+        self != Self::Blob
+    }
+}
+
+impl From<&ArchivedTagTarget> for TagTarget {
+    fn from(value: &ArchivedTagTarget) -> Self {
+        match value {
+            ArchivedTagTarget::Commit => Self::Commit,
+            ArchivedTagTarget::Tree => Self::Tree,
+            ArchivedTagTarget::Blob => Self::Blob,
+        }
+    }
+}
+
+impl ArchivedTag {
+    pub fn target(&self) -> TagTarget {
+        (&self.target).into()
+    }
+}
+
 pub struct TagTree {
     db: Arc<rocksdb::DB>,
     prefix: RepositoryId,
@@ -0,0 +1,143 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{Context, Result};
+use rkyv::{Archive, Deserialize, Serialize};
+use rocksdb::IteratorMode;
+use time::OffsetDateTime;
+
+use crate::database::schema::prefixes::TOMBSTONE_FAMILY;
+
+/// A record of a repository that used to be served, so requests for it can be told it's gone
+/// rather than that it never existed.
+#[derive(Serialize, Deserialize, Archive, Debug, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    /// When the repository was found to have been deleted, as a unix timestamp
+    pub deleted_at: i64,
+    /// When the tombstone is dropped, as a unix timestamp. `None` for tombstones listed in
+    /// `rgit-tombstone`, which last for as long as they're listed
+    pub expires_at: Option<i64>,
+    /// Where the repository can now be found, either the path of another repository or an
+    /// `http` or `https` URL
+    pub redirect: Option<String>,
+}
+
+impl Tombstone {
+    /// Whether the tombstone came from `rgit-tombstone` rather than the repository having
+    /// been found missing by the indexer.
+    pub fn is_explicit(&self) -> bool {
+        self.expires_at.is_none()
+    }
+
+    pub fn is_expired(&self, now: OffsetDateTime) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= now.unix_timestamp())
+    }
+
+    pub fn deleted_at(&self) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(self.deleted_at).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+    }
+
+    pub fn fetch_all(database: &rocksdb::DB) -> Result<BTreeMap<String, Self>> {
+        let cf = database
+            .cf_handle(TOMBSTONE_FAMILY)
+            .context("tombstone column family missing")?;
+
+        database
+            .iterator_cf(cf, IteratorMode::Start)
+            .filter_map(Result::ok)
+            .map(|(key, value)| {
+                let key = String::from_utf8(key.into_vec()).context("invalid repo name")?;
+                let value = rkyv::from_bytes::<_, rkyv::rancor::Error>(&value)?;
+
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    pub fn open<P: AsRef<Path>>(database: &rocksdb::DB, path: P) -> Result<Option<Self>> {
+        let cf = database
+            .cf_handle(TOMBSTONE_FAMILY)
+            .context("tombstone column family missing")?;
+
+        let path = path.as_ref().to_str().context("invalid path")?;
+        let Some(value) = database.get_cf(cf, path)? else {
+            return Ok(None);
+        };
+
+        rkyv::from_bytes::<_, rkyv::rancor::Error>(&value)
+            .map(Some)
+            .context("Failed to open tombstone")
+    }
+
+    pub fn insert<P: AsRef<Path>>(&self, database: &rocksdb::DB, path: P) -> Result<()> {
+        let cf = database
+            .cf_handle(TOMBSTONE_FAMILY)
+            .context("tombstone column family missing")?;
+        let path = path.as_ref().to_str().context("invalid path")?;
+
+        database.put_cf(cf, path, rkyv::to_bytes::<rkyv::rancor::Error>(self)?)?;
+
+        Ok(())
+    }
+
+    pub fn delete<P: AsRef<Path>>(database: &rocksdb::DB, path: P) -> Result<()> {
+        let cf = database
+            .cf_handle(TOMBSTONE_FAMILY)
+            .context("tombstone column family missing")?;
+        let path = path.as_ref().to_str().context("invalid path")?;
+
+        database.delete_cf(cf, path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use super::Tombstone;
+
+    fn at(timestamp: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    #[test]
+    fn expiry() {
+        let found_deleted = Tombstone {
+            deleted_at: 1_000,
+            expires_at: Some(2_000),
+            redirect: None,
+        };
+
+        assert!(!found_deleted.is_explicit());
+        assert!(!found_deleted.is_expired(at(1_000)));
+        assert!(!found_deleted.is_expired(at(1_999)));
+        assert!(found_deleted.is_expired(at(2_000)));
+        assert!(found_deleted.is_expired(at(3_000)));
+
+        // listed in `rgit-tombstone`, which last for as long as they're listed
+        let listed = Tombstone {
+            deleted_at: 1_000,
+            expires_at: None,
+            redirect: Some("new.git".to_string()),
+        };
+
+        assert!(listed.is_explicit());
+        assert!(!listed.is_expired(at(1_000)));
+        assert!(!listed.is_expired(at(i64::from(i32::MAX))));
+    }
+
+    #[test]
+    fn deleted_at() {
+        let tombstone = |deleted_at| Tombstone {
+            deleted_at,
+            expires_at: None,
+            redirect: None,
+        };
+
+        assert_eq!(tombstone(1_000).deleted_at(), at(1_000));
+        // a timestamp out of range falls back to the epoch rather than failing the page
+        assert_eq!(tombstone(i64::MAX).deleted_at(), OffsetDateTime::UNIX_EPOCH);
+    }
+}
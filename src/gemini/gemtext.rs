@@ -0,0 +1,356 @@
+//! Assembles gemtext documents, the line-oriented markup served over Gemini.
+//!
+//! Gemtext has no way of escaping anything, so text that would otherwise be mistaken for a
+//! link, heading, list item, quote or preformatting toggle is nudged along by a space.
+
+use std::{borrow::Cow, fmt::Write};
+
+use comrak::{
+    nodes::{AstNode, NodeValue},
+    Arena, Options,
+};
+use itertools::Itertools;
+
+use crate::git::ReadmeLinks;
+
+/// A gemtext document, built up a line at a time.
+#[derive(Default)]
+pub struct Document(String);
+
+impl Document {
+    pub fn heading(&mut self, level: u8, text: &str) {
+        let marker = &"###"[..usize::from(level.clamp(1, 3))];
+        let _ = writeln!(self.0, "{marker} {}", single_line(text));
+    }
+
+    pub fn text(&mut self, text: &str) {
+        let text = single_line(text);
+
+        if has_line_marker(&text) {
+            self.0.push(' ');
+        }
+
+        self.0.push_str(&text);
+        self.0.push('\n');
+    }
+
+    pub fn link(&mut self, url: &str, label: &str) {
+        // whitespace separates the url from its label, so can't appear within it
+        let url = url.replace(char::is_whitespace, "%20");
+        let label = single_line(label);
+
+        if label.is_empty() {
+            let _ = writeln!(self.0, "=> {url}");
+        } else {
+            let _ = writeln!(self.0, "=> {url} {label}");
+        }
+    }
+
+    pub fn item(&mut self, text: &str) {
+        let _ = writeln!(self.0, "* {}", single_line(text));
+    }
+
+    pub fn quote(&mut self, text: &str) {
+        let _ = writeln!(self.0, "> {}", single_line(text));
+    }
+
+    /// Adds `content` verbatim, with `alt` describing it to clients that don't show
+    /// preformatted text as it is.
+    pub fn preformatted(&mut self, alt: &str, content: &str) {
+        let _ = writeln!(self.0, "```{}", single_line(alt));
+
+        for line in content.lines() {
+            // a line starting with the toggle would end the block early
+            if line.starts_with("```") {
+                self.0.push(' ');
+            }
+
+            self.0.push_str(line);
+            self.0.push('\n');
+        }
+
+        self.0.push_str("```\n");
+    }
+
+    pub fn blank(&mut self) {
+        self.0.push('\n');
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+/// Collapses `text` onto a single line, since every line of gemtext stands on its own.
+fn single_line(text: &str) -> Cow<'_, str> {
+    if text.contains(['\r', '\n']) {
+        Cow::Owned(text.split(['\r', '\n']).filter(|v| !v.is_empty()).join(" "))
+    } else {
+        Cow::Borrowed(text.trim_end())
+    }
+}
+
+/// Whether a line of text starts with something a client would take as markup.
+fn has_line_marker(line: &str) -> bool {
+    ["=>", "#", "*", ">", "```"]
+        .iter()
+        .any(|marker| line.starts_with(marker))
+}
+
+/// Converts a markdown readme to gemtext, pointing relative links at the Gemini pages for the
+/// files they name and images at the raw files over HTTP, since Gemini clients can't show
+/// them inline anyway.
+pub fn markdown(document: &mut Document, source: &str, links: &ReadmeLinks, http_url: &str) {
+    let mut options = Options::default();
+    options.extension.autolink = true;
+    options.extension.strikethrough = true;
+    options.extension.table = true;
+    options.extension.tasklist = true;
+
+    let arena = Arena::new();
+    let root = comrak::parse_document(&arena, source, &options);
+
+    MarkdownWriter {
+        document,
+        links,
+        http_url,
+        pending_links: Vec::new(),
+    }
+    .block(root, false);
+}
+
+struct MarkdownWriter<'a> {
+    document: &'a mut Document,
+    links: &'a ReadmeLinks,
+    http_url: &'a str,
+    /// Links found within the block being written, gemtext only has links on lines of their
+    /// own so these are listed after it.
+    pending_links: Vec<(String, String)>,
+}
+
+impl<'a> MarkdownWriter<'a> {
+    fn block(&mut self, node: &'a AstNode<'a>, quoted: bool) {
+        match &node.data.borrow().value {
+            NodeValue::Heading(heading) => {
+                let text = self.inline(node);
+                self.document.heading(heading.level, &text);
+                self.flush_links();
+            }
+            NodeValue::Paragraph => {
+                let text = self.inline(node);
+                if quoted {
+                    self.document.quote(&text);
+                } else {
+                    self.document.text(&text);
+                }
+                self.flush_links();
+            }
+            NodeValue::CodeBlock(code) => {
+                self.document.preformatted(&code.info, &code.literal);
+            }
+            NodeValue::List(_) => {
+                for item in node.children() {
+                    self.list_item(item);
+                }
+            }
+            NodeValue::BlockQuote => {
+                for child in node.children() {
+                    self.block(child, true);
+                }
+            }
+            NodeValue::Table(_) => {
+                let rows = node
+                    .children()
+                    .map(|row| {
+                        row.children()
+                            .map(|cell| self.inline(cell))
+                            .collect::<Vec<_>>()
+                            .join(" | ")
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+
+                self.document.preformatted("table", &rows);
+                self.flush_links();
+            }
+            NodeValue::ThematicBreak => self.document.blank(),
+            // there's nothing to be done with raw html in gemtext
+            NodeValue::HtmlBlock(_) => {}
+            _ => {
+                for child in node.children() {
+                    self.block(child, quoted);
+                }
+            }
+        }
+    }
+
+    /// Writes a list item as a single line, with anything nested beneath it following on.
+    fn list_item(&mut self, item: &'a AstNode<'a>) {
+        let mut text = match &item.data.borrow().value {
+            NodeValue::TaskItem(Some(_)) => String::from("[x] "),
+            NodeValue::TaskItem(None) => String::from("[ ] "),
+            _ => String::new(),
+        };
+
+        let mut nested = Vec::new();
+        for child in item.children() {
+            if matches!(child.data.borrow().value, NodeValue::Paragraph) {
+                if !text.trim_end().is_empty() && !text.ends_with(' ') {
+                    text.push(' ');
+                }
+                self.collect_inline(child, &mut text);
+            } else {
+                nested.push(child);
+            }
+        }
+
+        self.document.item(&text);
+        self.flush_links();
+
+        for child in nested {
+            self.block(child, false);
+        }
+    }
+
+    fn inline(&mut self, node: &'a AstNode<'a>) -> String {
+        let mut out = String::new();
+        self.collect_inline(node, &mut out);
+        out
+    }
+
+    fn collect_inline(&mut self, node: &'a AstNode<'a>, out: &mut String) {
+        for child in node.children() {
+            match &child.data.borrow().value {
+                NodeValue::Text(text) => out.push_str(text),
+                NodeValue::Code(code) => {
+                    out.push('`');
+                    out.push_str(&code.literal);
+                    out.push('`');
+                }
+                NodeValue::SoftBreak | NodeValue::LineBreak => out.push(' '),
+                NodeValue::HtmlInline(_) => {}
+                NodeValue::Link(link) => {
+                    let label = self.inline(child);
+                    out.push_str(&label);
+
+                    // anchors within the readme have nowhere to point to in gemtext
+                    if !link.url.starts_with('#') {
+                        let url = self.links.resolve(&link.url, false);
+                        self.pending_links.push((url, label));
+                    }
+                }
+                NodeValue::Image(link) => {
+                    let alt = self.inline(child);
+                    out.push_str(&alt);
+
+                    let mut url = self.links.resolve(&link.url, true);
+                    if url.starts_with('/') {
+                        url.insert_str(0, self.http_url.trim_end_matches('/'));
+                    }
+
+                    let label = if alt.is_empty() { "Image" } else { &alt };
+                    self.pending_links.push((url, format!("{label} (image)")));
+                }
+                _ => self.collect_inline(child, out),
+            }
+        }
+    }
+
+    fn flush_links(&mut self) {
+        for (url, label) in std::mem::take(&mut self.pending_links) {
+            self.document.link(&url, &label);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::{markdown, Document};
+    use crate::git::ReadmeLinks;
+
+    #[test]
+    fn lines_escaped() {
+        let mut document = Document::default();
+        document.heading(0, "Title");
+        document.heading(6, "Deep\nheading");
+        document.text("=> not a link");
+        document.text("# not a heading\nspread over lines");
+        document.text("plain");
+        document.link("/a b/c", "label\r\nbroken");
+        document.link("/empty", "");
+        document.item("item");
+        document.quote("quote");
+        document.blank();
+        document.preformatted("alt\ntext", "```\ncode\n");
+
+        assert_eq!(
+            document.into_string(),
+            "# Title\n\
+             ### Deep heading\n \
+             => not a link\n \
+             # not a heading spread over lines\n\
+             plain\n\
+             => /a%20b/c label broken\n\
+             => /empty\n\
+             * item\n\
+             > quote\n\
+             \n\
+             ```alt text\n \
+             ```\n\
+             code\n\
+             ```\n"
+        );
+    }
+
+    #[test]
+    fn markdown_converted() {
+        let source = "# Readme\n\
+            \n\
+            Some *text* with a [link](docs/guide.md), an [anchor](#usage) and \
+            ![a logo](logo.png).\n\
+            \n\
+            > quoted [away](https://example.com)\n\
+            \n\
+            - [x] done\n\
+            - [ ] to do\n\
+            \n\
+            ```rust\n\
+            fn main() {}\n\
+            ```\n\
+            \n\
+            | a | b |\n\
+            |---|---|\n\
+            | 1 | 2 |\n\
+            \n\
+            <div>html</div>\n";
+
+        let links = ReadmeLinks {
+            repository: Arc::from("repo.git"),
+            directory: PathBuf::new(),
+            branch: Some(Arc::from("dev")),
+        };
+        let mut document = Document::default();
+        markdown(&mut document, source, &links, "https://example.com/");
+
+        assert_eq!(
+            document.into_string(),
+            "# Readme\n\
+             Some text with a link, an anchor and a logo.\n\
+             => /repo.git/tree/docs/guide.md?h=dev link\n\
+             => https://example.com/repo.git/tree/logo.png?raw=true&h=dev a logo (image)\n\
+             > quoted away\n\
+             => https://example.com away\n\
+             * [x] done\n\
+             * [ ] to do\n\
+             ```rust\n\
+             fn main() {}\n\
+             ```\n\
+             ```table\n\
+             a | b\n\
+             1 | 2\n\
+             ```\n"
+        );
+    }
+}
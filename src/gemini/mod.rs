@@ -0,0 +1,989 @@
+//! A read-only mirror of the repository pages served over Gemini, enabled by `--gemini-bind`.
+//!
+//! Only the pages that make sense as text are mirrored: the index, each repository's summary
+//! and readme, the log and the tree. Anything else, such as diffs and binary files, is linked
+//! to on the web interface instead.
+
+mod gemtext;
+
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use path_clean::PathClean;
+use percent_encoding::utf8_percent_encode;
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{debug, error, info, warn};
+
+use self::gemtext::Document;
+use crate::{
+    database::schema::{commit::YokedCommit, repository::YokedRepository, tombstone::Tombstone},
+    git::{
+        is_export_ok, CommitNotFound, Content, EmptyRepository, Git, PathDestination, ReadmeFormat,
+        ReadmeLinks, RepositoryBusy, TreeItem, LINK_ENCODE_SET,
+    },
+    methods::repo::{fetch_commits, CloneUrls, EntryLimits, Repository, ScanRoot},
+    storage::Storage,
+};
+
+/// Longest URL a client may request, as set by the Gemini specification.
+const MAX_REQUEST_LENGTH: usize = 1024;
+
+/// Number of commits listed on the summary of each repository.
+const SUMMARY_COMMITS: u64 = 10;
+
+const SUCCESS: u8 = 20;
+const REDIRECT_PERMANENT: u8 = 31;
+const TEMPORARY_FAILURE: u8 = 40;
+const SERVER_UNAVAILABLE: u8 = 41;
+const PERMANENT_FAILURE: u8 = 50;
+const NOT_FOUND: u8 = 51;
+const GONE: u8 = 52;
+const PROXY_REQUEST_REFUSED: u8 = 53;
+const BAD_REQUEST: u8 = 59;
+
+/// Everything the Gemini mirror shares with the web interface.
+pub struct State {
+    pub db: Arc<rocksdb::DB>,
+    pub git: Arc<Git>,
+    pub scan_path: Arc<PathBuf>,
+    pub scan_root: ScanRoot,
+    /// Whether repositories are served without a `git-daemon-export-ok` file.
+    pub export_all: bool,
+    pub limits: EntryLimits,
+    /// Base URL of the web interface that pages link to for anything they can't show, or
+    /// `None` to link to the host the Gemini request was made to.
+    pub http_url: Option<String>,
+    /// Clone URLs shown for repositories, falling back to the web interface if there are none.
+    pub clone_urls: CloneUrls,
+    /// Whether the scan path can be read, repository pages are refused whilst it can't be.
+    pub storage: Storage,
+    /// How long a client has to send its request and receive the response.
+    pub timeout: Duration,
+}
+
+/// Loads the certificate chain and private key the mirror presents to clients, both PEM
+/// encoded.
+pub fn tls_acceptor(cert: &Path, key: &Path) -> anyhow::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .with_context(|| format!("Failed to read Gemini certificate {}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read Gemini private key {}", key.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid Gemini certificate or private key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts Gemini connections on `listener` until the process exits, each handled on a task
+/// of its own.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    state: Arc<State>,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(v) => v,
+            Err(error) => {
+                // usually running out of file descriptors, which takes a moment to recover from
+                warn!(%error, "Failed to accept Gemini connection");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let timeout = state.timeout;
+
+            match tokio::time::timeout(timeout, handle_connection(acceptor, stream, peer, &state))
+                .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => debug!(%peer, %error, "Gemini connection failed"),
+                Err(_) => debug!(%peer, "Gemini connection timed out"),
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+    peer: SocketAddr,
+    state: &State,
+) -> std::io::Result<()> {
+    let mut stream = acceptor.accept(stream).await?;
+    let start = Instant::now();
+
+    let response = match read_request(&mut stream).await? {
+        Some(url) => {
+            let response = respond(state, &url).await;
+            info!(
+                %peer,
+                url,
+                status = response.status,
+                duration = ?start.elapsed(),
+                "Served Gemini request"
+            );
+            response
+        }
+        None => Response::failure(BAD_REQUEST, "Invalid request"),
+    };
+
+    response.write(&mut stream).await
+}
+
+/// Reads the URL the client is requesting, which is all a Gemini request consists of.
+/// Returns `None` if the request is too long or isn't valid UTF-8.
+async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<String>> {
+    let mut request = Vec::with_capacity(MAX_REQUEST_LENGTH + 2);
+    let mut chunk = [0; 256];
+
+    loop {
+        if let Some(end) = memchr::memmem::find(&request, b"\r\n") {
+            request.truncate(end);
+            return Ok(String::from_utf8(request).ok());
+        }
+
+        if request.len() >= MAX_REQUEST_LENGTH + 2 {
+            return Ok(None);
+        }
+
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        request.extend_from_slice(&chunk[..read]);
+    }
+}
+
+struct Response {
+    status: u8,
+    /// The MIME type of the body on success, otherwise a message for the user.
+    meta: Cow<'static, str>,
+    body: Option<Vec<u8>>,
+}
+
+impl Response {
+    fn gemtext(document: Document) -> Self {
+        Self {
+            status: SUCCESS,
+            meta: Cow::Borrowed("text/gemini; charset=utf-8"),
+            body: Some(document.into_string().into_bytes()),
+        }
+    }
+
+    fn plain_text(body: String) -> Self {
+        Self {
+            status: SUCCESS,
+            meta: Cow::Borrowed("text/plain; charset=utf-8"),
+            body: Some(body.into_bytes()),
+        }
+    }
+
+    fn redirect(location: String) -> Self {
+        Self {
+            status: REDIRECT_PERMANENT,
+            meta: Cow::Owned(location),
+            body: None,
+        }
+    }
+
+    fn failure(status: u8, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            status,
+            meta: message.into(),
+            body: None,
+        }
+    }
+
+    async fn write<S: AsyncWrite + Unpin>(self, stream: &mut S) -> std::io::Result<()> {
+        stream
+            .write_all(format!("{} {}\r\n", self.status, self.meta).as_bytes())
+            .await?;
+
+        if let Some(body) = self.body {
+            stream.write_all(&body).await?;
+        }
+
+        stream.shutdown().await
+    }
+}
+
+/// The parts of a requested URL the mirror cares about.
+struct Request<'a> {
+    /// The host the request was made to, without its port.
+    host: &'a str,
+    path: &'a str,
+    query: &'a str,
+}
+
+impl<'a> Request<'a> {
+    /// Splits a `gemini://` URL into its parts, returning `None` for any other scheme.
+    fn parse(url: &'a str) -> Option<Self> {
+        let (scheme, rest) = url.split_once("://")?;
+        if !scheme.eq_ignore_ascii_case("gemini") {
+            return None;
+        }
+
+        let rest = rest.split_once('#').map_or(rest, |(rest, _)| rest);
+        let (authority, path) = rest
+            .find('/')
+            .map_or((rest, "/"), |idx| (&rest[..idx], &rest[idx..]));
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+        // ipv6 addresses are bracketed, so their colons don't look like a port
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if port.bytes().all(|c| c.is_ascii_digit()) => host,
+            _ => authority,
+        };
+
+        Some(Self { host, path, query })
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct PageQuery {
+    #[serde(rename = "ofs")]
+    offset: Option<u64>,
+    #[serde(rename = "h")]
+    branch: Option<String>,
+}
+
+/// A page of the mirror, named by the path of the request.
+enum Page<'a> {
+    Index,
+    Repository(&'a str, Action<'a>),
+}
+
+/// A page belonging to a single repository.
+enum Action<'a> {
+    Summary,
+    Log,
+    /// The tree, or a path within it.
+    Tree(Option<&'a str>),
+}
+
+impl<'a> Page<'a> {
+    fn route(path: &'a str) -> Self {
+        let path = path.trim_matches('/');
+
+        if path.is_empty() {
+            Self::Index
+        } else if let Some(idx) = path.find("/tree/") {
+            // 6 is the length of /tree/
+            let child = Some(&path[idx + 6..]).filter(|v| !v.is_empty());
+            Self::Repository(&path[..idx], Action::Tree(child))
+        } else if let Some(repository) = path.strip_suffix("/tree") {
+            Self::Repository(repository, Action::Tree(None))
+        } else if let Some(repository) = path.strip_suffix("/log") {
+            Self::Repository(repository, Action::Log)
+        } else {
+            Self::Repository(path, Action::Summary)
+        }
+    }
+}
+
+async fn respond(state: &State, url: &str) -> Response {
+    let Some(request) = Request::parse(url) else {
+        return Response::failure(PROXY_REQUEST_REFUSED, "Only gemini:// URLs are served");
+    };
+
+    let http_url = match &state.http_url {
+        Some(url) => Cow::Borrowed(url.trim_end_matches('/')),
+        None => Cow::Owned(format!("https://{}", request.host)),
+    };
+
+    let Ok(path) = percent_encoding::percent_decode_str(request.path).decode_utf8() else {
+        return Response::failure(BAD_REQUEST, "Invalid request");
+    };
+    if path.contains('\0') {
+        return Response::failure(BAD_REQUEST, "Invalid request");
+    }
+
+    let Ok(query) = serde_urlencoded::from_str::<PageQuery>(request.query) else {
+        return Response::failure(BAD_REQUEST, "Invalid query");
+    };
+
+    let (repository, action) = match Page::route(&path) {
+        Page::Index => return index(state).await.unwrap_or_else(|e| error_response(&e)),
+        Page::Repository(repository, action) => (repository, action),
+    };
+
+    if matches!(state.storage.check_async().await, Ok(false)) {
+        return Response::failure(SERVER_UNAVAILABLE, "Repository storage is unavailable");
+    }
+
+    let Some((repo, repository)) = open_repository(state, repository).await else {
+        return repository_not_found(state, repository, &path, request.query);
+    };
+
+    if let Some(format) = repository.get().unsupported_object_format.as_ref() {
+        return Response::failure(
+            PERMANENT_FAILURE,
+            format!("Repository uses the {format} object format, which is unsupported"),
+        );
+    }
+
+    let page = RepositoryPage {
+        state,
+        path: state.scan_path.join(&*repo),
+        encoded: utf8_percent_encode(&repo.to_string_lossy(), LINK_ENCODE_SET).to_string(),
+        repo,
+        repository,
+        http_url: &http_url,
+    };
+
+    let result = match action {
+        Action::Summary => page.summary().await,
+        Action::Log => page.log(query).await,
+        Action::Tree(child) => page.tree(query, child).await,
+    };
+
+    result.unwrap_or_else(|e| error_response(&e))
+}
+
+fn error_response(error: &anyhow::Error) -> Response {
+    if error.is::<RepositoryBusy>() {
+        return Response::failure(
+            TEMPORARY_FAILURE,
+            "Repository is being modified, try again shortly",
+        );
+    }
+
+    if error.is::<CommitNotFound>() {
+        return Response::failure(NOT_FOUND, "Commit not found");
+    }
+
+    if error.is::<EmptyRepository>() {
+        return Response::failure(NOT_FOUND, "Repository is empty");
+    }
+
+    error!(?error, "Failed to serve Gemini request");
+    Response::failure(
+        TEMPORARY_FAILURE,
+        "Something went wrong reading the repository",
+    )
+}
+
+/// Answers for a repository that can't be found, telling apart those that have been deleted
+/// and sending clients on to where they've moved if that's known, as the web interface does.
+fn repository_not_found(state: &State, uri: &str, path: &str, query: &str) -> Response {
+    let tombstone = match Tombstone::open(&state.db, Path::new(uri).clean()) {
+        Ok(Some(tombstone)) if !tombstone.is_expired(OffsetDateTime::now_utc()) => tombstone,
+        _ => return Response::failure(NOT_FOUND, "Repository not found"),
+    };
+
+    match tombstone.redirect {
+        Some(redirect) => Response::redirect(moved_location(uri, &redirect, path, query)),
+        None => Response::failure(
+            GONE,
+            format!(
+                "Repository was deleted on {}",
+                tombstone.deleted_at().date()
+            ),
+        ),
+    }
+}
+
+/// Where a request for `path` is sent once the repository at `uri` has moved to `redirect`,
+/// either a URL or the path of another repository. The rest of the path and the query are
+/// kept when it's another repository.
+fn moved_location(uri: &str, redirect: &str, path: &str, query: &str) -> String {
+    if redirect.contains("://") {
+        return redirect.to_string();
+    }
+
+    let rest = path.trim_matches('/').strip_prefix(uri).unwrap_or_default();
+    let mut location = format!(
+        "/{}{}",
+        utf8_percent_encode(redirect, LINK_ENCODE_SET),
+        utf8_percent_encode(rest, LINK_ENCODE_SET),
+    );
+
+    if !query.is_empty() {
+        location.push('?');
+        location.push_str(query);
+    }
+
+    location
+}
+
+/// Looks up the repository at `uri` in the index, turning away anything the web interface
+/// would, such as paths escaping the scan path.
+async fn open_repository(state: &State, uri: &str) -> Option<(Repository, YokedRepository)> {
+    let uri = Path::new(uri).clean();
+
+    if uri.as_os_str().is_empty() || uri.is_absolute() || uri.starts_with("..") {
+        return None;
+    }
+
+    if state.git.is_known_missing(&uri) {
+        return None;
+    }
+
+    let Ok(Some(repository)) =
+        crate::database::schema::repository::Repository::open(&state.db, &uri)
+    else {
+        state.git.remember_missing(uri).await;
+        return None;
+    };
+
+    let path = state.scan_path.join(&uri);
+
+    // the marker is checked on disk too, as the web interface does
+    let exported = state.export_all || (repository.get().is_visible(false) && is_export_ok(&path));
+
+    if !exported {
+        return None;
+    }
+
+    if !state.scan_root.contains(&path).await {
+        return None;
+    }
+
+    Some((Repository(uri), repository))
+}
+
+async fn index(state: &State) -> anyhow::Result<Response> {
+    let db = state.db.clone();
+    let repositories = tokio::task::spawn_blocking(move || {
+        crate::database::schema::repository::Repository::fetch_all(&db)
+    })
+    .await
+    .context("Failed to join Tokio task")??;
+
+    let mut document = Document::default();
+    document.heading(1, "Repositories");
+    document.blank();
+
+    for (name, repository) in &repositories {
+        if !repository.get().is_visible(state.export_all) {
+            continue;
+        }
+
+        let url = format!("/{}/", utf8_percent_encode(name, LINK_ENCODE_SET));
+
+        match repository.get().description.as_deref() {
+            Some(description) => document.link(&url, &format!("{name} - {description}")),
+            None => document.link(&url, name),
+        }
+    }
+
+    Ok(Response::gemtext(document))
+}
+
+/// Renders the pages of a single repository.
+struct RepositoryPage<'a> {
+    state: &'a State,
+    repo: Repository,
+    repository: YokedRepository,
+    /// Where the repository is on disk.
+    path: PathBuf,
+    /// The name of the repository, encoded to be used in URLs.
+    encoded: String,
+    http_url: &'a str,
+}
+
+impl RepositoryPage<'_> {
+    fn heading(&self, document: &mut Document, suffix: Option<&str>) {
+        let name = self.repo.to_string_lossy();
+
+        match suffix {
+            Some(suffix) => document.heading(1, &format!("{name}: {suffix}")),
+            None => document.heading(1, &name),
+        }
+
+        if let Some(description) = self.repository.get().description.as_deref() {
+            document.text(description);
+        }
+
+        document.blank();
+    }
+
+    fn commit_link(&self, document: &mut Document, commit: &YokedCommit) {
+        let commit = commit.get();
+
+        document.link(
+            &format!(
+                "{}/{}/commit?id={}",
+                self.http_url,
+                self.encoded,
+                const_hex::encode(commit.hash)
+            ),
+            &format!(
+                "{} {} ({})",
+                commit.committer.time().date(),
+                commit.summary,
+                commit.author.name
+            ),
+        );
+    }
+
+    async fn summary(self) -> anyhow::Result<Response> {
+        let mut document = Document::default();
+        self.heading(&mut document, None);
+
+        document.link(&format!("/{}/tree/", self.encoded), "Tree");
+        document.link(&format!("/{}/log", self.encoded), "Log");
+        document.link(
+            &format!("{}/{}", self.http_url, self.encoded),
+            "Web interface",
+        );
+
+        if self.repository.get().exported && self.repository.get().allow_clone {
+            let mut urls = self.state.clone_urls.configured(&self.repo);
+            if urls.is_empty() {
+                urls.push(format!("{}/{}", self.http_url, self.encoded));
+            }
+
+            document.blank();
+            document.preformatted(
+                "clone",
+                &urls
+                    .iter()
+                    .map(|url| format!("git clone {url}"))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            );
+        }
+
+        let commits = fetch_commits(
+            self.repo.clone(),
+            self.state.db.clone(),
+            self.state.git.clone(),
+            None,
+            SUMMARY_COMMITS,
+            0,
+        )
+        .await?;
+
+        if !commits.is_empty() {
+            document.blank();
+            document.heading(2, "Recent commits");
+
+            for commit in commits.iter().take(usize::try_from(SUMMARY_COMMITS)?) {
+                self.commit_link(&mut document, commit);
+            }
+        }
+
+        let open_repo = self.state.git.clone().repo(self.path.clone(), None).await?;
+        let readme = open_repo
+            .clone()
+            .readme(Arc::from(self.repo.to_string_lossy()))
+            .await?;
+
+        if let Some(readme) = readme {
+            let file = open_repo
+                .path(Some(PathBuf::from(&*readme.name)), None, false, false, None)
+                .await?;
+
+            if let PathDestination::File(file) = file {
+                if let Content::Text(source) = file.content {
+                    document.blank();
+                    document.heading(2, &readme.name);
+
+                    match readme.format {
+                        ReadmeFormat::Markdown => gemtext::markdown(
+                            &mut document,
+                            &source,
+                            &ReadmeLinks {
+                                repository: Arc::from(self.repo.to_string_lossy()),
+                                directory: PathBuf::new(),
+                                branch: None,
+                            },
+                            self.http_url,
+                        ),
+                        ReadmeFormat::Plaintext => document.preformatted(&readme.name, &source),
+                    }
+                }
+            }
+        }
+
+        Ok(Response::gemtext(document))
+    }
+
+    async fn log(self, query: PageQuery) -> anyhow::Result<Response> {
+        let open_repo = self
+            .state
+            .git
+            .clone()
+            .repo(self.path.clone(), query.branch.as_deref().map(Arc::from))
+            .await?;
+        if open_repo.missing_branch().await?.is_some() {
+            return Ok(Response::failure(NOT_FOUND, "Branch not found"));
+        }
+
+        let page_size = self
+            .repository
+            .get()
+            .log_page_size
+            .as_ref()
+            .map_or(self.state.limits.log_page_size, |v| v.to_native());
+        let offset = query.offset.unwrap_or(0);
+
+        // one more than a page is fetched to tell whether there's a next page
+        let commits = fetch_commits(
+            self.repo.clone(),
+            self.state.db.clone(),
+            self.state.git.clone(),
+            query.branch.clone(),
+            page_size + 1,
+            offset,
+        )
+        .await?;
+
+        let mut document = Document::default();
+        self.heading(&mut document, Some("log"));
+
+        if let Some(branch) = &query.branch {
+            document.text(&format!("Branch: {branch}"));
+            document.blank();
+        }
+
+        let page_len = usize::try_from(page_size).unwrap_or(usize::MAX);
+        for commit in commits.iter().take(page_len) {
+            self.commit_link(&mut document, commit);
+        }
+
+        if commits.len() > page_len {
+            let mut next = format!("/{}/log?ofs={}", self.encoded, offset + page_size);
+            if let Some(branch) = &query.branch {
+                next.push_str("&h=");
+                next.extend(utf8_percent_encode(branch, LINK_ENCODE_SET));
+            }
+
+            document.blank();
+            document.link(&next, "Older commits");
+        }
+
+        Ok(Response::gemtext(document))
+    }
+
+    async fn tree(self, query: PageQuery, child: Option<&str>) -> anyhow::Result<Response> {
+        let child = child.map(|v| Path::new(v).clean());
+        if child
+            .as_deref()
+            .is_some_and(|v| v.is_absolute() || v.starts_with(".."))
+        {
+            return Ok(Response::failure(BAD_REQUEST, "Invalid request"));
+        }
+
+        let open_repo = self
+            .state
+            .git
+            .clone()
+            .repo(self.path.clone(), query.branch.as_deref().map(Arc::from))
+            .await?;
+        if open_repo.clone().missing_branch().await?.is_some() {
+            return Ok(Response::failure(NOT_FOUND, "Branch not found"));
+        }
+
+        let branch_query = query.branch.as_deref().map_or(String::new(), |branch| {
+            format!("?h={}", utf8_percent_encode(branch, LINK_ENCODE_SET))
+        });
+        let tree_url = |path: &Path, directory: bool| {
+            format!(
+                "/{}/tree/{}{}{branch_query}",
+                self.encoded,
+                utf8_percent_encode(&path.to_string_lossy(), LINK_ENCODE_SET),
+                if directory { "/" } else { "" },
+            )
+        };
+
+        let items = match open_repo
+            .path(child.clone(), None, false, false, None)
+            .await?
+        {
+            PathDestination::Tree { items, .. } => items,
+            PathDestination::File(file) => {
+                return Ok(match file.content {
+                    Content::Text(text) if !text.contains('\0') => {
+                        Response::plain_text(text.into_owned())
+                    }
+                    // binary and oversized files are left to the web interface
+                    _ => {
+                        let mut document = Document::default();
+                        self.heading(&mut document, Some(&file.metadata.name));
+                        document.text("This file can't be shown over Gemini.");
+                        document.link(
+                            &format!(
+                                "{}{}{}raw=true",
+                                self.http_url,
+                                tree_url(&file.metadata.path, false),
+                                if branch_query.is_empty() { '?' } else { '&' },
+                            ),
+                            &format!("Download {}", file.metadata.name),
+                        );
+                        Response::gemtext(document)
+                    }
+                });
+            }
+        };
+
+        let mut document = Document::default();
+        let location = child
+            .as_deref()
+            .map(|v| format!("{}/", v.display()))
+            .unwrap_or_default();
+        self.heading(&mut document, Some(&format!("tree /{location}")));
+
+        if let Some(parent) = child.as_deref().and_then(Path::parent) {
+            if parent.as_os_str().is_empty() {
+                document.link(&format!("/{}/tree/{branch_query}", self.encoded), "../");
+            } else {
+                document.link(&tree_url(parent, true), "../");
+            }
+        }
+
+        for item in items {
+            match item {
+                TreeItem::Tree(tree) => {
+                    // joined a component at a time, since joining an empty path of children
+                    // would leave a trailing slash behind
+                    let path: PathBuf = tree.path.iter().chain(&tree.children).collect();
+                    let name: PathBuf =
+                        Path::new(&tree.name).iter().chain(&tree.children).collect();
+                    document.link(&tree_url(&path, true), &format!("{}/", name.display()));
+                }
+                TreeItem::File(file) => {
+                    document.link(
+                        &tree_url(&file.path, false),
+                        &format!("{} ({} bytes)", file.name, file.size),
+                    );
+                }
+                TreeItem::Submodule(submodule) => {
+                    document.link(
+                        &submodule.url.to_bstring().to_string(),
+                        &format!("{} @ {}", submodule.name, submodule.oid.to_hex_with_len(7)),
+                    );
+                }
+            }
+        }
+
+        Ok(Response::gemtext(document))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tempfile::TempDir;
+
+    use super::{moved_location, respond, State, NOT_FOUND, SUCCESS};
+    use crate::{
+        git::Git,
+        methods::repo::{CloneUrls, EntryLimits, ScanRoot},
+        storage::Storage,
+        testing::{self, TestRepo},
+    };
+
+    /// Requests `url` from the mirror, returning the response's meta and body.
+    async fn page(state: &State, url: &str) -> (String, String) {
+        let response = respond(state, url).await;
+        assert_eq!(response.status, SUCCESS, "{url}: {}", response.meta);
+
+        (
+            response.meta.into_owned(),
+            String::from_utf8(response.body.unwrap()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn pages() {
+        let repo = TestRepo::new();
+        repo.commit("README.md", "# Hello\n\nSee [the code](src/main.rs).\n");
+        repo.commit("src/main.rs", "fn main() {}\n");
+        repo.commit("docs/api/index.md", "# API\n");
+        std::fs::write(repo.path().join("description"), "A test repository\n").unwrap();
+        std::fs::write(repo.path().join("git-daemon-export-ok"), "").unwrap();
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+
+        let state = State {
+            db,
+            git: Arc::new(Git::default()),
+            scan_path: Arc::new(repo.scan_path().to_path_buf()),
+            scan_root: ScanRoot::new(repo.scan_path(), false).unwrap(),
+            export_all: true,
+            limits: EntryLimits::default(),
+            http_url: None,
+            clone_urls: CloneUrls(Arc::from([])),
+            storage: Storage::new(repo.scan_path()),
+            timeout: Duration::from_secs(5),
+        };
+
+        let (meta, index) = page(&state, "gemini://example.com/").await;
+        assert_eq!(meta, "text/gemini; charset=utf-8");
+        assert_eq!(
+            index,
+            "# Repositories\n\n=> /repo.git/ repo.git - A test repository\n"
+        );
+
+        let (_, summary) = page(&state, "gemini://example.com/repo.git/").await;
+        assert!(
+            summary.starts_with(
+                "# repo.git\n\
+                 A test repository\n\
+                 \n\
+                 => /repo.git/tree/ Tree\n\
+                 => /repo.git/log Log\n\
+                 => https://example.com/repo.git Web interface\n\
+                 \n\
+                 ```clone\n\
+                 git clone https://example.com/repo.git\n\
+                 ```\n\
+                 \n\
+                 ## Recent commits\n"
+            ),
+            "{summary}"
+        );
+        assert!(
+            summary.contains(" Update src/main.rs (rgit)\n"),
+            "{summary}"
+        );
+        assert!(
+            summary.ends_with(
+                "## README.md\n\
+                 # Hello\n\
+                 See the code.\n\
+                 => /repo.git/tree/src/main.rs the code\n"
+            ),
+            "{summary}"
+        );
+
+        let (_, tree) = page(&state, "gemini://example.com/repo.git/tree/").await;
+        assert_eq!(
+            tree,
+            "# repo.git: tree /\n\
+             A test repository\n\
+             \n\
+             => /repo.git/tree/README.md README.md (38 bytes)\n\
+             => /repo.git/tree/docs/api/ docs/api/\n\
+             => /repo.git/tree/src/ src/\n"
+        );
+
+        let (_, tree) = page(&state, "gemini://example.com/repo.git/tree/src/?h=main").await;
+        assert_eq!(
+            tree,
+            "# repo.git: tree /src/\n\
+             A test repository\n\
+             \n\
+             => /repo.git/tree/?h=main ../\n\
+             => /repo.git/tree/src/main.rs?h=main main.rs (13 bytes)\n"
+        );
+
+        let (meta, file) = page(&state, "gemini://example.com/repo.git/tree/src/main.rs").await;
+        assert_eq!(meta, "text/plain; charset=utf-8");
+        assert_eq!(file, "fn main() {}\n");
+    }
+
+    #[test]
+    fn moved_locations() {
+        let cases = [
+            // moved to another repository, keeping the page asked for
+            ("old.git", "new.git", "/old.git", "", "/new.git"),
+            ("old.git", "new.git", "/old.git/", "", "/new.git"),
+            ("old.git", "new.git", "/old.git/log", "", "/new.git/log"),
+            (
+                "old.git",
+                "group/new.git",
+                "/old.git/tree/src/main.rs",
+                "h=main",
+                "/group/new.git/tree/src/main.rs?h=main",
+            ),
+            (
+                "group/old.git",
+                "new repo.git",
+                "/group/old.git/tree/a b?c",
+                "",
+                "/new%20repo.git/tree/a%20b%3Fc",
+            ),
+            // moved off the forge altogether, nothing of the request carries over
+            (
+                "old.git",
+                "https://example.com/new",
+                "/old.git/tree",
+                "h=main",
+                "https://example.com/new",
+            ),
+        ];
+
+        for (uri, redirect, path, query, expected) in cases {
+            assert_eq!(
+                moved_location(uri, redirect, path, query),
+                expected,
+                "{uri} -> {redirect} for {path}?{query}",
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn export_ok_only() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        repo.clone("hidden.git", &[]);
+        std::fs::write(repo.path().join("description"), "Public\n").unwrap();
+        std::fs::write(repo.path().join("git-daemon-export-ok"), "").unwrap();
+
+        // indexed whilst everything was exported, before the server was restarted without
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+
+        let state = State {
+            db,
+            git: Arc::new(Git::default()),
+            scan_path: Arc::new(repo.scan_path().to_path_buf()),
+            scan_root: ScanRoot::new(repo.scan_path(), false).unwrap(),
+            export_all: false,
+            limits: EntryLimits::default(),
+            http_url: None,
+            clone_urls: CloneUrls(Arc::from([])),
+            storage: Storage::new(repo.scan_path()),
+            timeout: Duration::from_secs(5),
+        };
+
+        let (_, index) = page(&state, "gemini://example.com/").await;
+        assert_eq!(index, "# Repositories\n\n=> /repo.git/ repo.git - Public\n");
+        page(&state, "gemini://example.com/repo.git/").await;
+
+        for url in [
+            "gemini://example.com/hidden.git/",
+            "gemini://example.com/hidden.git/tree/",
+            "gemini://example.com/hidden.git/log",
+        ] {
+            let response = respond(&state, url).await;
+            assert_eq!(response.status, NOT_FOUND, "{url}");
+        }
+    }
+}
@@ -7,71 +7,214 @@ use gix::{
     actor::SignatureRef,
     bstr::{BStr, BString, ByteSlice, ByteVec},
     diff::blob::{platform::prepare_diff::Operation, Sink},
-    object::{tree::EntryKind, Kind},
+    object::{
+        tree::{EntryKind, EntryMode},
+        Kind,
+    },
     objs::{tree::EntryRef, CommitRef, TagRef},
-    prelude::TreeEntryRefExt,
+    prelude::{ObjectIdExt, TreeEntryRefExt},
+    refs::Category,
     traverse::tree::visit::Action,
     url::Scheme,
     ObjectId, ThreadSafeRepository, Url,
 };
 use itertools::{Either, Itertools};
 use moka::future::Cache;
+use path_clean::PathClean;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Deserialize;
 use std::{
     borrow::Cow,
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     ffi::OsStr,
     fmt::{self, Arguments, Write},
-    io::ErrorKind,
+    future::Future,
+    io::{ErrorKind, Read},
     iter::Copied,
     path::{Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 use tar::Builder;
-use time::{OffsetDateTime, UtcOffset};
-use tracing::{error, instrument, warn};
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, instrument, warn};
 use yoke::{Yoke, Yokeable};
 
 use crate::{
+    database::{
+        refs::Refs,
+        schema::{
+            commit::YokedCommit,
+            repository::{ObjectStats, Remote},
+        },
+    },
     methods::filters::DisplayHexBuffer,
-    syntax_highlight::{format_file, format_file_inner, ComrakHighlightAdapter, FileIdentifier},
+    syntax_highlight::{
+        format_file_inner, format_file_with_anchors, highlight_lines, ComrakHighlightAdapter,
+        FileIdentifier,
+    },
     unified_diff_builder::{Callback, UnifiedDiffBuilder},
+    zip_archive::ZipBuilder,
 };
 
-type ReadmeCacheKey = (PathBuf, Option<Arc<str>>);
+type ReadmeCacheKey = (ObjectId, Arc<str>, ReadmeLinks);
+type SummaryCacheKey = (PathBuf, u64);
+type RecentCommitsCacheKey = (PathBuf, Option<String>);
+/// The repository path, branch, number of commits and offset of a page of the log.
+type CommitPageCacheKey = (PathBuf, Option<String>, u64, u64);
 
 pub struct Git {
-    commits: Cache<(ObjectId, bool), Arc<Commit>, hashbrown::hash_map::DefaultHashBuilder>,
-    readme_cache: Cache<
-        ReadmeCacheKey,
-        Option<(ReadmeFormat, Arc<str>)>,
-        hashbrown::hash_map::DefaultHashBuilder,
-    >,
+    commits: Cache<(ObjectId, DiffFormat), Arc<Commit>, hashbrown::hash_map::DefaultHashBuilder>,
+    readme_cache: Cache<ReadmeCacheKey, Option<Readme>, hashbrown::hash_map::DefaultHashBuilder>,
     open_repositories:
         Cache<PathBuf, ThreadSafeRepository, hashbrown::hash_map::DefaultHashBuilder>,
+    summaries: Cache<SummaryCacheKey, Arc<Summary>, hashbrown::hash_map::DefaultHashBuilder>,
+    recent_commits:
+        Cache<RecentCommitsCacheKey, Arc<[YokedCommit]>, hashbrown::hash_map::DefaultHashBuilder>,
+    commit_pages:
+        Cache<CommitPageCacheKey, Arc<[YokedCommit]>, hashbrown::hash_map::DefaultHashBuilder>,
+    path_lists: Cache<ObjectId, Arc<PathList>, hashbrown::hash_map::DefaultHashBuilder>,
+    reachable: Cache<(PathBuf, ObjectId), bool, hashbrown::hash_map::DefaultHashBuilder>,
+    abbreviations: Cache<(PathBuf, ObjectId), usize, hashbrown::hash_map::DefaultHashBuilder>,
+    merged: Cache<(ObjectId, ObjectId), bool, hashbrown::hash_map::DefaultHashBuilder>,
+    rendered_blobs: Cache<
+        (ObjectId, RichFormat),
+        Option<Arc<RenderedBlob>>,
+        hashbrown::hash_map::DefaultHashBuilder,
+    >,
+    missing_repositories: Cache<PathBuf, (), hashbrown::hash_map::DefaultHashBuilder>,
+    missing_repository_hits: AtomicU64,
+    /// Sizes of blobs shown in tree listings, looked up from the blocking pool so this one
+    /// isn't async.
+    blob_sizes: moka::sync::Cache<ObjectId, u64, hashbrown::hash_map::DefaultHashBuilder>,
+    /// Raw blobs larger than this many bytes are streamed rather than read into memory.
+    streaming_threshold: u64,
+}
+
+/// Most blob sizes kept by [`Git`], each entry is only a few dozen bytes so this is enough to
+/// cover the trees of many large repositories.
+const BLOB_SIZE_CAPACITY: u64 = 500_000;
+
+/// How long a path is remembered not to be a repository, kept short so a repository created in
+/// between index runs isn't hidden for long.
+#[cfg(not(test))]
+const MISSING_REPOSITORY_TTL: Duration = Duration::from_secs(30);
+#[cfg(test)]
+const MISSING_REPOSITORY_TTL: Duration = Duration::from_secs(1);
+
+/// Lifetimes and capacities of the in-memory caches held by [`Git`].
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    /// How long a summary (refs and latest commits), list of a branch's latest commits or
+    /// reachability check is kept, and how long an unused object id abbreviation is kept.
+    /// These are also invalidated after every index, and only the abbreviations outlive refs
+    /// being updated outside of one.
+    pub refs_ttl: Duration,
+    pub refs_capacity: u64,
+    /// How long a parsed commit is kept.
+    pub commit_ttl: Duration,
+    pub commit_capacity: u64,
+    /// How long rendered content, such as readmes and file listings, is kept.
+    pub content_ttl: Duration,
+    pub content_capacity: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            refs_ttl: Duration::from_mins(1),
+            refs_capacity: 100,
+            commit_ttl: Duration::from_secs(30),
+            commit_capacity: 100,
+            content_ttl: Duration::from_secs(30),
+            content_capacity: 100,
+        }
+    }
 }
 
 impl Git {
     #[instrument]
-    pub fn new() -> Self {
+    pub fn new(config: CacheConfig, streaming_threshold: u64) -> Self {
         Self {
             commits: Cache::builder()
-                .time_to_live(Duration::from_secs(30))
-                .max_capacity(100)
+                .time_to_live(config.commit_ttl)
+                .max_capacity(config.commit_capacity)
                 .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
             readme_cache: Cache::builder()
-                .time_to_live(Duration::from_secs(30))
-                .max_capacity(100)
+                .time_to_live(config.content_ttl)
+                .max_capacity(config.content_capacity)
                 .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
             open_repositories: Cache::builder()
-                .time_to_idle(Duration::from_secs(120))
+                .time_to_idle(Duration::from_mins(2))
                 .max_capacity(100)
                 .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            // refs can be updated without an index run, so these expire however often they're
+            // used
+            summaries: Cache::builder()
+                .time_to_live(config.refs_ttl)
+                .max_capacity(config.refs_capacity)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            recent_commits: Cache::builder()
+                .time_to_live(config.refs_ttl)
+                .max_capacity(config.refs_capacity)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            commit_pages: Cache::builder()
+                .time_to_live(config.refs_ttl)
+                .max_capacity(config.refs_capacity)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            reachable: Cache::builder()
+                .time_to_live(config.refs_ttl)
+                .max_capacity(config.refs_capacity)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            // new objects can make an abbreviation ambiguous, but they only turn up alongside
+            // a push which the indexer will pick up
+            abbreviations: Cache::builder()
+                .time_to_idle(config.refs_ttl)
+                .max_capacity(config.refs_capacity)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            // whether one commit is an ancestor of another never changes, so these are only
+            // dropped once unused
+            merged: Cache::builder()
+                .time_to_idle(config.refs_ttl)
+                .max_capacity(config.refs_capacity)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            // trees are immutable so there's no need for these to ever expire, they're only
+            // dropped once they've gone unused for a while
+            path_lists: Cache::builder()
+                .time_to_idle(config.content_ttl)
+                .max_capacity(config.content_capacity)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            // blobs are immutable too, so these only need dropping once unused
+            rendered_blobs: Cache::builder()
+                .time_to_idle(config.content_ttl)
+                .max_capacity(config.content_capacity)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            // kept small so crawlers probing made up paths can't hold on to much
+            missing_repositories: Cache::builder()
+                .time_to_live(MISSING_REPOSITORY_TTL)
+                .max_capacity(1000)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            missing_repository_hits: AtomicU64::new(0),
+            // blobs are immutable, so their sizes never need to expire
+            blob_sizes: moka::sync::Cache::builder()
+                .max_capacity(BLOB_SIZE_CAPACITY)
+                .build_with_hasher(hashbrown::hash_map::DefaultHashBuilder::default()),
+            streaming_threshold,
         }
     }
 }
 
+impl Default for Git {
+    fn default() -> Self {
+        Self::new(CacheConfig::default(), DEFAULT_STREAMING_THRESHOLD)
+    }
+}
+
 impl Git {
     #[instrument(skip(self))]
     pub async fn repo(
@@ -84,19 +227,19 @@ impl Git {
             .open_repositories
             .try_get_with_by_ref(&repo_path, async move {
                 tokio::task::spawn_blocking(move || {
-                    gix::open::Options::isolated()
-                        .open_path_as_is(true)
-                        .open(&repo)
+                    retry_transient(|| {
+                        gix::open::Options::isolated()
+                            .open_path_as_is(true)
+                            .open(&repo)
+                            .context("Failed to open repository")
+                    })
                 })
                 .await
-                .context("Failed to join Tokio task")
-                .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?
-                .map_err(|err| {
-                    error!("{}", err);
-                    std::io::Error::new(ErrorKind::Other, "Failed to open repository")
-                })
+                .context("Failed to join Tokio task")?
+                .inspect_err(|err| error!("{err:#}"))
             })
-            .await?;
+            .await
+            .map_err(|e| clone_shared_error(&e))?;
 
         Ok(Arc::new(OpenRepository {
             git: self,
@@ -105,6 +248,128 @@ impl Git {
             branch,
         }))
     }
+
+    /// Fetches the summary page data for the repository at `repository_path`, building it
+    /// with `init` if there's no snapshot for the given `refs_digest`, see
+    /// [`OpenRepository::refs_digest`].
+    ///
+    /// Snapshots live until any of the repository's refs move or the indexer invalidates
+    /// them via [`Git::invalidate_indexed`].
+    #[instrument(skip(self, init))]
+    pub async fn summary(
+        &self,
+        repository_path: PathBuf,
+        refs_digest: u64,
+        init: impl Future<Output = Result<Arc<Summary>>>,
+    ) -> Result<Arc<Summary>, Arc<anyhow::Error>> {
+        self.summaries
+            .try_get_with((repository_path, refs_digest), init)
+            .await
+    }
+
+    /// Fetches the latest commits of `branch`, or of the default branch if `None`, of the
+    /// repository at `repository_path`, reading them from the index with `init` if they
+    /// aren't cached. Concurrent requests for the same branch share a single read.
+    ///
+    /// The index only changes when the indexer runs, so these live until it invalidates
+    /// them via [`Git::invalidate_indexed`].
+    #[instrument(skip(self, init))]
+    pub async fn recent_commits(
+        &self,
+        repository_path: PathBuf,
+        branch: Option<String>,
+        init: impl Future<Output = Result<Arc<[YokedCommit]>>>,
+    ) -> Result<Arc<[YokedCommit]>, Arc<anyhow::Error>> {
+        self.recent_commits
+            .try_get_with((repository_path, branch), init)
+            .await
+    }
+
+    /// Fetches `amount` commits of `branch` starting `offset` commits in, reading them from
+    /// the index with `init` if they aren't cached. Like [`Git::recent_commits`], concurrent
+    /// requests for the same page share a single read and the pages live until the indexer
+    /// invalidates them.
+    #[instrument(skip(self, init))]
+    pub async fn commit_page(
+        &self,
+        repository_path: PathBuf,
+        branch: Option<String>,
+        amount: u64,
+        offset: u64,
+        init: impl Future<Output = Result<Arc<[YokedCommit]>>>,
+    ) -> Result<Arc<[YokedCommit]>, Arc<anyhow::Error>> {
+        self.commit_pages
+            .try_get_with((repository_path, branch, amount, offset), init)
+            .await
+    }
+
+    /// Caches the latest commits of `branch` ahead of them being requested.
+    pub async fn prime_recent_commits(
+        &self,
+        repository_path: PathBuf,
+        branch: Option<String>,
+        commits: Arc<[YokedCommit]>,
+    ) {
+        self.recent_commits
+            .insert((repository_path, branch), commits)
+            .await;
+    }
+
+    /// Whether `path` was recently looked up and found not to be a repository, saving another
+    /// trip to the index (and a scan of it for case-insensitive paths) for the same miss.
+    pub fn is_known_missing(&self, path: &Path) -> bool {
+        if !self.missing_repositories.contains_key(path) {
+            return false;
+        }
+
+        let hits = self.missing_repository_hits.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!(path = %path.display(), hits, "Repository lookup served from negative cache");
+
+        true
+    }
+
+    /// Remembers that `path` isn't a repository until the cache entry expires or the indexer
+    /// next runs.
+    pub async fn remember_missing(&self, path: PathBuf) {
+        self.missing_repositories.insert(path, ()).await;
+    }
+
+    /// Drops all cached summary snapshots, lists of commits, reachability checks,
+    /// abbreviations and repository misses, called whenever the indexer has finished a run
+    /// since the refs they were built from may have since moved, or new repositories turned up.
+    pub fn invalidate_indexed(&self) {
+        self.missing_repositories.invalidate_all();
+        self.summaries.invalidate_all();
+        self.recent_commits.invalidate_all();
+        self.commit_pages.invalidate_all();
+        self.reachable.invalidate_all();
+        self.abbreviations.invalidate_all();
+    }
+}
+
+/// Everything the summary page needs to render, assembled once for each state of the
+/// repository's refs and cached in [`Git`].
+pub struct Summary {
+    pub refs: Refs,
+    pub commit_list: Arc<[YokedCommit]>,
+    /// The commit HEAD points at if it's detached rather than pointing at a branch.
+    pub detached_head: Option<YokedCommit>,
+    pub exported: bool,
+    /// Object statistics gathered by the indexer, shown alongside the clone URL.
+    pub objects: Option<ObjectStats>,
+    /// The remotes configured in the repository, listed below the clone URL.
+    pub remotes: Vec<Remote>,
+    /// Short name of the branch the repository presents as its default, shown alongside the
+    /// clone URL. `None` if HEAD is detached or nothing has been pushed yet.
+    pub default_branch: Option<String>,
+}
+
+impl Summary {
+    /// Whether the repository has no commits to show, in which case only the clone box is
+    /// rendered.
+    pub fn is_empty(&self) -> bool {
+        self.commit_list.is_empty() && self.refs.heads.is_empty()
+    }
 }
 
 pub struct OpenRepository {
@@ -121,6 +386,8 @@ impl OpenRepository {
         path: Option<PathBuf>,
         tree_id: Option<&str>,
         formatted: bool,
+        rich: bool,
+        readme: Option<ReadmeLinks>,
     ) -> Result<PathDestination> {
         let tree_id = tree_id
             .map(ObjectId::from_str)
@@ -128,402 +395,2244 @@ impl OpenRepository {
             .context("Failed to parse tree hash")?;
 
         tokio::task::spawn_blocking(move || {
-            let repo = self.repo.to_thread_local();
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+
+                let mut tree = if let Some(tree_id) = tree_id {
+                    repo.find_tree(tree_id)
+                        .context("Couldn't find tree with given id")?
+                } else if let Some(branch) = &self.branch {
+                    find_reference_commit(&repo, branch)?
+                        .tree()
+                        .context("Couldn't find tree for reference")?
+                } else {
+                    find_default_reference(&repo)?
+                        .peel_to_tree()
+                        .context("Couldn't find HEAD for reference")?
+                };
 
-            let mut tree = if let Some(tree_id) = tree_id {
-                repo.find_tree(tree_id)
-                    .context("Couldn't find tree with given id")?
-            } else if let Some(branch) = &self.branch {
-                repo.find_reference(branch.as_ref())?
-                    .peel_to_tree()
-                    .context("Couldn't find tree for reference")?
-            } else {
-                repo.find_reference("HEAD")
-                    .context("Failed to find HEAD")?
-                    .peel_to_tree()
-                    .context("Couldn't find HEAD for reference")?
-            };
+                if let Some(path) = path.as_ref() {
+                    let item = tree
+                        .peel_to_entry_by_path(path)?
+                        .context("Path doesn't exist in tree")?;
 
-            if let Some(path) = path.as_ref() {
-                let item = tree
-                    .peel_to_entry_by_path(path)?
-                    .context("Path doesn't exist in tree")?;
-                let object = item.object().context("Path in tree isn't an object")?;
-
-                match object.kind {
-                    Kind::Blob => {
-                        let mut blob = object.into_blob();
-
-                        let size = blob.data.len();
-
-                        let content = match (formatted, simdutf8::basic::from_utf8(&blob.data)) {
-                            (true, Err(_)) => Content::Binary(vec![]),
-                            (true, Ok(data)) => Content::Text(Cow::Owned(format_file(
-                                data,
-                                FileIdentifier::Path(path.as_path()),
-                            )?)),
-                            (false, Err(_)) => Content::Binary(blob.take_data()),
-                            (false, Ok(_data)) => Content::Text(Cow::Owned(unsafe {
-                                String::from_utf8_unchecked(blob.take_data())
-                            })),
+                    if item.mode().is_blob() {
+                        let Some(header) = repo
+                            .try_find_header(item.object_id())
+                            .context("Couldn't read blob header")?
+                        else {
+                            return Ok(PathDestination::File(FileWithContent {
+                                metadata: File {
+                                    mode: item.mode().0,
+                                    size: 0,
+                                    path: path.clone(),
+                                    name: item.filename().to_string(),
+                                },
+                                content: Content::Unavailable,
+                            }));
                         };
 
-                        return Ok(PathDestination::File(FileWithContent {
-                            metadata: File {
-                                mode: item.mode().0,
-                                size,
-                                path: path.clone(),
-                                name: item.filename().to_string(),
-                            },
-                            content,
-                        }));
+                        // large blobs are never loaded into memory for raw requests,
+                        // they're streamed straight out of the object database instead
+                        if !formatted && header.size() > self.git.streaming_threshold {
+                            return Ok(PathDestination::File(FileWithContent {
+                                metadata: File {
+                                    mode: item.mode().0,
+                                    size: usize::try_from(header.size())?,
+                                    path: path.clone(),
+                                    name: item.filename().to_string(),
+                                },
+                                content: Content::Stream(BlobStream {
+                                    repository_path: self.cache_key.clone(),
+                                    oid: item.object_id(),
+                                    size: header.size(),
+                                }),
+                            }));
+                        }
                     }
-                    Kind::Tree => {
-                        tree = object.into_tree();
+
+                    let object = item.object().context("Path in tree isn't an object")?;
+
+                    match object.kind {
+                        Kind::Blob => {
+                            let blob = object.into_blob();
+                            let size = blob.data.len();
+                            let content = self.blob_content(blob, path, formatted, rich)?;
+
+                            return Ok(PathDestination::File(FileWithContent {
+                                metadata: File {
+                                    mode: item.mode().0,
+                                    size,
+                                    path: path.clone(),
+                                    name: item.filename().to_string(),
+                                },
+                                content,
+                            }));
+                        }
+                        Kind::Tree => {
+                            tree = object.into_tree();
+                        }
+                        _ => anyhow::bail!("bad object of type {:?}", object.kind),
                     }
-                    _ => anyhow::bail!("bad object of type {:?}", object.kind),
                 }
-            }
 
-            let mut tree_items = Vec::new();
-            let submodules = repo
-                .submodules()?
-                .into_iter()
-                .flatten()
-                .filter_map(|v| Some((v.name().to_path_lossy().to_path_buf(), v.url().ok()?)))
-                .collect::<BTreeMap<_, _>>();
-
-            for item in tree.iter() {
-                let item = item?;
-
-                let path = path
-                    .clone()
-                    .unwrap_or_default()
-                    .join(item.filename().to_path_lossy());
-
-                match item.mode().kind() {
-                    EntryKind::Tree
-                    | EntryKind::Blob
-                    | EntryKind::BlobExecutable
-                    | EntryKind::Link => {
-                        let mut object = item
-                            .object()
-                            .context("Expected item in tree to be object but it wasn't")?;
-
-                        tree_items.push(match object.kind {
-                            Kind::Blob => TreeItem::File(File {
+                // read the sizes of every blob up front, rather than interleaved with the
+                // subtrees being flattened below
+                let blob_sizes = self.blob_sizes(
+                    &repo,
+                    tree.iter()
+                        .filter_map(Result::ok)
+                        .filter(|item| item.mode().is_blob_or_symlink())
+                        .map(|item| item.object_id()),
+                )?;
+
+                let mut tree_items = Vec::new();
+                let submodules = repo
+                    .submodules()?
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|v| Some((v.name().to_path_lossy().to_path_buf(), v.url().ok()?)))
+                    .collect::<BTreeMap<_, _>>();
+
+                for item in tree.iter() {
+                    let item = item?;
+
+                    let path = path
+                        .clone()
+                        .unwrap_or_default()
+                        .join(item.filename().to_path_lossy());
+
+                    match item.mode().kind() {
+                        EntryKind::Blob | EntryKind::BlobExecutable | EntryKind::Link => {
+                            let size = blob_sizes.get(&item.object_id()).copied().unwrap_or(0);
+
+                            tree_items.push(TreeItem::File(File {
                                 mode: item.mode().0,
-                                size: object.into_blob().data.len(),
+                                size: usize::try_from(size)?,
                                 path,
                                 name: item.filename().to_string(),
-                            }),
-                            Kind::Tree => {
-                                let mut children = PathBuf::new();
-
-                                // if the tree only has one child, flatten it down
-                                while let Ok(Some(Ok(item))) = object
-                                    .try_into_tree()
-                                    .iter()
-                                    .flat_map(gix::Tree::iter)
-                                    .at_most_one()
-                                {
-                                    let nested_object = item.object().context(
-                                        "Expected item in tree to be object but it wasn't",
-                                    )?;
-
-                                    if nested_object.kind != Kind::Tree {
-                                        break;
-                                    }
-
-                                    object = nested_object;
-                                    children.push(item.filename().to_path_lossy());
+                            }));
+                        }
+                        EntryKind::Tree => {
+                            let mut children = PathBuf::new();
+                            let mut tree_id = item.object_id();
+
+                            // if the tree only has one child, flatten it down. trees missing from
+                            // partial clones are left as they are
+                            while let Some(object) = repo.try_find_object(tree_id)? {
+                                let Ok(nested) = object.try_into_tree() else {
+                                    break;
+                                };
+
+                                let Ok(Some(Ok(child))) = nested.iter().at_most_one() else {
+                                    break;
+                                };
+
+                                if !child.mode().is_tree() {
+                                    break;
                                 }
 
-                                TreeItem::Tree(Tree {
-                                    mode: item.mode().0,
-                                    path,
-                                    children,
-                                    name: item.filename().to_string(),
-                                })
-                            }
-                            _ => continue,
-                        });
-                    }
-                    EntryKind::Commit => {
-                        if let Some(mut url) = submodules.get(path.as_path()).cloned() {
-                            if matches!(url.scheme, Scheme::Git | Scheme::Ssh) {
-                                url.scheme = Scheme::Https;
+                                children.push(child.filename().to_path_lossy());
+                                tree_id = child.object_id();
                             }
 
-                            tree_items.push(TreeItem::Submodule(Submodule {
+                            tree_items.push(TreeItem::Tree(Tree {
                                 mode: item.mode().0,
+                                path,
+                                children,
                                 name: item.filename().to_string(),
-                                url,
-                                oid: item.object_id(),
                             }));
+                        }
+                        EntryKind::Commit => {
+                            if let Some(mut url) = submodules.get(path.as_path()).cloned() {
+                                if matches!(url.scheme, Scheme::Git | Scheme::Ssh) {
+                                    url.scheme = Scheme::Https;
+                                }
+
+                                tree_items.push(TreeItem::Submodule(Submodule {
+                                    mode: item.mode().0,
+                                    name: item.filename().to_string(),
+                                    url,
+                                    oid: item.object_id(),
+                                }));
 
-                            continue;
+                                continue;
+                            }
                         }
                     }
                 }
-            }
 
-            Ok(PathDestination::Tree(tree_items))
+                let readme = match &readme {
+                    Some(links) => self.find_readme(&repo, &tree, links)?,
+                    None => None,
+                };
+
+                Ok(PathDestination::Tree {
+                    items: tree_items,
+                    readme,
+                })
+            })
         })
         .await
         .context("Failed to join Tokio task")?
     }
 
-    #[instrument(skip(self))]
-    pub async fn tag_info(self: Arc<Self>) -> Result<Yoke<DetailedTag<'static>, Vec<u8>>> {
+    /// Looks up the sizes of the blobs `oids`, only reading the headers of those that haven't
+    /// been looked up before. Blobs missing from partial clones are given a size of 0, and
+    /// aren't cached in case they're fetched later.
+    fn blob_sizes(
+        &self,
+        repo: &gix::Repository,
+        oids: impl Iterator<Item = ObjectId>,
+    ) -> Result<HashMap<ObjectId, u64>> {
+        let mut sizes = HashMap::new();
+        let mut uncached = Vec::new();
+
+        for oid in oids {
+            match self.git.blob_sizes.get(&oid) {
+                Some(size) => {
+                    sizes.insert(oid, size);
+                }
+                None => uncached.push(oid),
+            }
+        }
+
+        // the same blob can appear more than once in a tree
+        uncached.sort_unstable();
+        uncached.dedup();
+
+        for oid in uncached {
+            // only the header is read, the blob itself is never loaded
+            let size = match repo.try_find_header(oid)? {
+                Some(header) => {
+                    self.git.blob_sizes.insert(oid, header.size());
+                    header.size()
+                }
+                None => 0,
+            };
+
+            sizes.insert(oid, size);
+        }
+
+        Ok(sizes)
+    }
+
+    /// Reads the blob `oid` directly, for blobs that can't be reached by a path, such as the
+    /// targets of tags. The oid stands in for the blob's name.
+    pub async fn blob(
+        self: Arc<Self>,
+        oid: &str,
+        formatted: bool,
+        rich: bool,
+    ) -> Result<FileWithContent> {
+        let oid = ObjectId::from_str(oid).context("Failed to parse blob hash")?;
+
         tokio::task::spawn_blocking(move || {
-            let tag_name = self.branch.clone().context("no tag given")?;
-            let repo = self.repo.to_thread_local();
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+                let path = PathBuf::from(oid.to_string());
+                let mut metadata = File {
+                    mode: EntryKind::Blob as u16,
+                    size: 0,
+                    name: oid.to_string(),
+                    path: path.clone(),
+                };
 
-            let tag = repo
-                .find_reference(&format!("refs/tags/{tag_name}"))
-                .context("Given tag does not exist in repository")?
-                .peel_to_tag()
-                .context("Couldn't get to a tag from the given reference")?
-                .detach()
-                .data;
-
-            Yoke::try_attach_to_cart(tag, move |tag| {
-                let tag = TagRef::from_bytes(tag)?;
-
-                let tagged_object = match tag.target_kind {
-                    Kind::Commit => Some(TaggedObject::Commit(tag.target)),
-                    Kind::Tree => Some(TaggedObject::Tree(tag.target)),
-                    _ => None,
+                let Some(header) = repo
+                    .try_find_header(oid)
+                    .context("Couldn't read blob header")?
+                else {
+                    return Ok(FileWithContent {
+                        metadata,
+                        content: Content::Unavailable,
+                    });
                 };
 
-                Ok::<_, anyhow::Error>(DetailedTag {
-                    name: tag_name,
-                    tagger: tag.tagger.map(TryInto::try_into).transpose()?,
-                    tagged_object,
-                    message: tag.message,
-                })
+                anyhow::ensure!(header.kind() == Kind::Blob, "{oid} isn't a blob");
+                metadata.size = usize::try_from(header.size())?;
+
+                if !formatted && header.size() > self.git.streaming_threshold {
+                    return Ok(FileWithContent {
+                        metadata,
+                        content: Content::Stream(BlobStream {
+                            repository_path: self.cache_key.clone(),
+                            oid,
+                            size: header.size(),
+                        }),
+                    });
+                }
+
+                let blob = repo.find_blob(oid).context("Couldn't find blob")?;
+                let content = self.blob_content(blob, &path, formatted, rich)?;
+
+                Ok(FileWithContent { metadata, content })
             })
         })
         .await
         .context("Failed to join Tokio task")?
     }
 
-    #[instrument(skip(self))]
-    pub async fn readme(
-        self: Arc<Self>,
-    ) -> Result<Option<(ReadmeFormat, Arc<str>)>, Arc<anyhow::Error>> {
-        const README_FILES: &[&str] = &["README.md", "README", "README.txt"];
-
-        let git = self.git.clone();
-
-        git.readme_cache
-            .try_get_with((self.cache_key.clone(), self.branch.clone()), async move {
-                tokio::task::spawn_blocking(move || {
-                    let repo = self.repo.to_thread_local();
+    /// Builds the content of `blob` for display, `path` decides how it's highlighted or
+    /// rendered.
+    fn blob_content(
+        &self,
+        mut blob: gix::Blob<'_>,
+        path: &Path,
+        formatted: bool,
+        rich: bool,
+    ) -> Result<Content> {
+        let rendered = RichFormat::from_path(path)
+            .filter(|_| formatted && rich)
+            .and_then(|format| self.render_blob(format, blob.id, &blob.data));
+
+        if let Some(rendered) = rendered {
+            return Ok(Content::Rendered(rendered));
+        }
 
-                    let mut head = if let Some(reference) = &self.branch {
-                        repo.find_reference(reference.as_ref())?
-                    } else {
-                        repo.find_reference("HEAD")
-                            .context("Couldn't find HEAD of repository")?
-                    };
+        if formatted && is_binary(&blob.data) {
+            return Ok(Content::Binary(vec![]));
+        }
 
-                    let commit = head.peel_to_commit().context(
-                        "Couldn't find the commit that the HEAD of the repository refers to",
-                    )?;
-                    let mut tree = commit
-                        .tree()
-                        .context("Couldn't get the tree that the HEAD refers to")?;
+        Ok(match (formatted, simdutf8::basic::from_utf8(&blob.data)) {
+            (true, Err(_)) => Content::Lossy(Cow::Owned(format_file_with_anchors(
+                &String::from_utf8_lossy(&blob.data),
+                FileIdentifier::Path(path),
+            )?)),
+            (true, Ok(data)) => Content::Text(Cow::Owned(format_file_with_anchors(
+                data,
+                FileIdentifier::Path(path),
+            )?)),
+            (false, Err(_)) => Content::Binary(blob.take_data()),
+            (false, Ok(_data)) => Content::Text(Cow::Owned(unsafe {
+                String::from_utf8_unchecked(blob.take_data())
+            })),
+        })
+    }
 
-                    for name in README_FILES {
-                        let Some(tree_entry) = tree.peel_to_entry_by_path(name)? else {
-                            continue;
-                        };
+    /// Resolves `spec`, a ref or commit oid followed by the path of a file within it, to the
+    /// file's raw content. See [`split_reference_path`] for how the two are told apart.
+    ///
+    /// Returns `None` if no prefix names a ref, or if the path isn't a file within it.
+    pub async fn raw(self: Arc<Self>, spec: String) -> Result<Option<RawFile>> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
 
-                        let Some(blob) = tree_entry
-                            .object()
-                            .ok()
-                            .and_then(|v| v.try_into_blob().ok())
-                        else {
-                            continue;
-                        };
+                let Some((reference, path, mut tree)) = split_reference_path(&repo, &spec)? else {
+                    return Ok(None);
+                };
+                let path = Path::new(path);
 
-                        let Ok(content) = simdutf8::basic::from_utf8(&blob.data) else {
-                            continue;
-                        };
+                let Some(entry) = tree.peel_to_entry_by_path(path)? else {
+                    return Ok(None);
+                };
 
-                        if Path::new(name).extension().and_then(OsStr::to_str) == Some("md") {
-                            let value = parse_and_transform_markdown(content);
-                            return Ok(Some((ReadmeFormat::Markdown, Arc::from(value))));
-                        }
+                if !entry.mode().is_blob_or_symlink() {
+                    return Ok(None);
+                }
 
-                        return Ok(Some((ReadmeFormat::Plaintext, Arc::from(content))));
+                let oid = entry.object_id();
+                let content = match repo.try_find_header(oid)? {
+                    None => Content::Unavailable,
+                    Some(header) if header.size() > self.git.streaming_threshold => {
+                        Content::Stream(BlobStream {
+                            repository_path: self.cache_key.clone(),
+                            oid,
+                            size: header.size(),
+                        })
+                    }
+                    Some(_) => {
+                        self.blob_content(entry.object()?.into_blob(), path, false, false)?
                     }
+                };
 
-                    Ok(None)
-                })
-                .await
-                .context("Failed to join Tokio task")?
+                Ok(Some(RawFile {
+                    reference: reference.to_string(),
+                    oid,
+                    path: path.to_path_buf(),
+                    content,
+                }))
             })
-            .await
+        })
+        .await
+        .context("Failed to join Tokio task")?
     }
 
-    pub async fn default_branch(self: Arc<Self>) -> Result<Option<String>> {
+    /// Splits `spec`, a ref or commit oid followed by a path within it, into the two. See
+    /// [`split_reference_path`] for how they're told apart.
+    pub async fn split_reference_path(
+        self: Arc<Self>,
+        spec: String,
+    ) -> Result<Option<(String, PathBuf)>> {
         tokio::task::spawn_blocking(move || {
-            let repo = self.repo.to_thread_local();
-            let head = repo.head().context("Couldn't find HEAD of repository")?;
-            Ok(head.referent_name().map(|v| v.shorten().to_string()))
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+                let split = split_reference_path(&repo, &spec)?
+                    .map(|(reference, path, _)| (reference.to_string(), PathBuf::from(path)));
+
+                Ok(split)
+            })
         })
         .await
         .context("Failed to join Tokio task")?
     }
 
-    #[instrument(skip(self))]
-    pub async fn latest_commit(self: Arc<Self>, highlighted: bool) -> Result<Commit> {
-        tokio::task::spawn_blocking(move || {
-            let repo = self.repo.to_thread_local();
+    /// Finds the shortest unique abbreviation of each of `oids`, see [`abbreviation_len`].
+    pub async fn abbreviations(
+        self: Arc<Self>,
+        oids: impl IntoIterator<Item = ObjectId>,
+    ) -> Result<Abbreviations> {
+        let mut lengths = HashMap::new();
+        let mut missing = Vec::new();
+
+        for oid in oids {
+            match self
+                .git
+                .abbreviations
+                .get(&(self.cache_key.clone(), oid))
+                .await
+            {
+                Some(len) => {
+                    lengths.insert(oid, len);
+                }
+                None => missing.push(oid),
+            }
+        }
 
-            let mut head = if let Some(reference) = &self.branch {
-                repo.find_reference(reference.as_ref())?
-            } else {
-                repo.find_reference("HEAD")
-                    .context("Couldn't find HEAD of repository")?
-            };
+        if missing.is_empty() {
+            return Ok(Abbreviations(lengths));
+        }
+
+        let open_repo = self.clone();
+        let found = tokio::task::spawn_blocking(move || {
+            let repo = open_repo.repo.to_thread_local();
+
+            missing
+                .into_iter()
+                .map(|oid| (oid, abbreviation_len(&repo, oid)))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .context("Failed to join Tokio task")?;
+
+        for (oid, len) in found {
+            self.git
+                .abbreviations
+                .insert((self.cache_key.clone(), oid), len)
+                .await;
+            lengths.insert(oid, len);
+        }
+
+        Ok(Abbreviations(lengths))
+    }
+
+    /// Finds which of the branch tips `tips` have been merged into the default branch, their
+    /// commit being its tip or one of its ancestors.
+    pub async fn merged(self: Arc<Self>, tips: Vec<ObjectId>) -> Result<HashSet<ObjectId>> {
+        let Some(default_tip) = self.clone().head_id().await? else {
+            return Ok(HashSet::new());
+        };
+
+        let mut merged = HashSet::new();
+        let mut missing = Vec::new();
+
+        for tip in tips {
+            match self.git.merged.get(&(tip, default_tip)).await {
+                Some(true) => {
+                    merged.insert(tip);
+                }
+                Some(false) => {}
+                None => missing.push(tip),
+            }
+        }
 
-            let commit = head
-                .peel_to_commit()
-                .context("Couldn't find commit HEAD of repository refers to")?;
-            let (diff_output, diff_stats) = fetch_diff_and_stats(&repo, &commit, highlighted)?;
+        if missing.is_empty() {
+            return Ok(merged);
+        }
 
-            let oid = take_oid(commit.id);
-            let inner = Yoke::try_attach_to_cart(commit.detach().data, |commit| {
-                CommitInner::new(CommitRef::from_bytes(commit)?, oid)
-            })?;
+        let open_repo = self.clone();
+        let found = tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = open_repo.repo.to_thread_local();
 
-            Ok(Commit {
-                inner,
-                diff_stats,
-                diff: diff_output,
+                missing
+                    .iter()
+                    .map(|&tip| Ok((tip, is_ancestor(&repo, tip, default_tip)?)))
+                    .collect::<Result<Vec<_>>>()
             })
         })
         .await
-        .context("Failed to join Tokio task")?
+        .context("Failed to join Tokio task")??;
+
+        for (tip, is_merged) in found {
+            self.git.merged.insert((tip, default_tip), is_merged).await;
+
+            if is_merged {
+                merged.insert(tip);
+            }
+        }
+
+        Ok(merged)
     }
 
-    #[instrument(skip_all)]
-    pub async fn archive(
+    /// Works out what a bundle of every branch and tag, or of just `reference` if given, is
+    /// built from, resolving `since`, a ref or commit oid, to the commit the bundle builds on
+    /// top of.
+    ///
+    /// Returns `None` if `since` doesn't name a commit, or `reference` a branch or tag.
+    pub async fn bundle_basis(
         self: Arc<Self>,
-        res: tokio::sync::mpsc::Sender<Result<Bytes, anyhow::Error>>,
-        cont: tokio::sync::oneshot::Sender<()>,
-        commit: Option<&str>,
-    ) -> Result<(), anyhow::Error> {
-        let commit = commit
-            .map(ObjectId::from_str)
-            .transpose()
-            .context("failed to build oid")?;
-
+        reference: Option<String>,
+        since: Option<String>,
+    ) -> Result<Option<BundleBasis>> {
         tokio::task::spawn_blocking(move || {
-            let repo = self.repo.to_thread_local();
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+
+                let since = match since.as_deref() {
+                    Some(since) => match find_commit_id(&repo, since)? {
+                        Some(oid) => Some(oid),
+                        None => return Ok(None),
+                    },
+                    None => None,
+                };
 
-            let tree = if let Some(commit) = commit {
-                repo.find_commit(commit)?.tree()?
-            } else if let Some(reference) = &self.branch {
-                repo.find_reference(reference.as_ref())?.peel_to_tree()?
-            } else {
-                repo.find_reference("HEAD")
-                    .context("Couldn't find HEAD of repository")?
-                    .peel_to_tree()?
-            };
+                let mut refs = BTreeMap::new();
 
-            // tell the web server it can send response headers to the requester
-            if cont.send(()).is_err() {
-                return Err(anyhow!("requester gone"));
-            }
+                if let Some(reference) = reference.as_deref() {
+                    // names git wouldn't accept as a ref are treated the same as refs that
+                    // don't exist
+                    let Some(reference) = repo.try_find_reference(reference).ok().flatten() else {
+                        return Ok(None);
+                    };
 
-            let buffer = BytesMut::with_capacity(BUFFER_CAP + 1024);
-            let mut visitor = ArchivalVisitor {
-                repository: &repo,
-                res,
-                archive: Builder::new(GzEncoder::new(buffer.writer(), flate2::Compression::fast())),
-                path_deque: VecDeque::new(),
-                path: BString::default(),
-            };
+                    if !matches!(
+                        reference.name().category(),
+                        Some(Category::LocalBranch | Category::Tag)
+                    ) {
+                        return Ok(None);
+                    }
 
-            tree.traverse().breadthfirst(&mut visitor)?;
+                    let Some(id) = reference.try_id() else {
+                        return Ok(None);
+                    };
 
-            visitor.res.blocking_send(Ok(visitor
-                .archive
-                .into_inner()?
-                .finish()?
-                .into_inner()
-                .freeze()))?;
+                    refs.insert(reference.name().as_bstr().to_owned(), id.detach());
 
-            Ok::<_, anyhow::Error>(())
-        })
-        .await??;
+                    return Ok(Some(BundleBasis {
+                        refs_digest: refs_digest(None, &refs),
+                        head: false,
+                        refs: refs.into_keys().collect(),
+                        since,
+                    }));
+                }
 
-        Ok(())
+                let head = repo.head_id().ok().map(gix::Id::detach);
+                let refs = branches_and_tags(&repo)?;
+
+                Ok(Some(BundleBasis {
+                    refs_digest: refs_digest(head, &refs),
+                    head: head.is_some(),
+                    refs: Vec::new(),
+                    since,
+                }))
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
     }
 
+    /// Fetches the path of every file within the tree of the branch, or HEAD if no branch was
+    /// given, sorted by path.
+    ///
+    /// The list is cached by the oid of the tree so it only needs to be built once per tree,
+    /// regardless of how many refs point at it.
     #[instrument(skip(self))]
-    pub async fn commit(
-        self: Arc<Self>,
-        commit: &str,
-        highlighted: bool,
-    ) -> Result<Arc<Commit>, Arc<anyhow::Error>> {
-        let commit = ObjectId::from_str(commit)
-            .map_err(anyhow::Error::from)
-            .map_err(Arc::new)?;
+    pub async fn path_list(self: Arc<Self>) -> Result<Arc<PathList>, Arc<anyhow::Error>> {
+        let tree_id = tokio::task::spawn_blocking({
+            let this = self.clone();
 
-        let git = self.git.clone();
+            move || {
+                let repo = this.repo.to_thread_local();
 
-        git.commits
-            .try_get_with((commit, highlighted), async move {
-                tokio::task::spawn_blocking(move || {
-                    let repo = self.repo.to_thread_local();
+                let tree = if let Some(branch) = &this.branch {
+                    find_reference_commit(&repo, branch)?
+                        .tree()
+                        .context("Couldn't find tree for reference")?
+                } else {
+                    find_default_reference(&repo)?
+                        .peel_to_tree()
+                        .context("Couldn't find HEAD for reference")?
+                };
+
+                Ok::<_, anyhow::Error>(tree.id)
+            }
+        })
+        .await
+        .context("Failed to join Tokio task")
+        .and_then(|v| v)
+        .map_err(Arc::new)?;
 
-                    let commit = repo.find_commit(commit)?;
+        let git = self.git.clone();
 
-                    let (diff_output, diff_stats) =
-                        fetch_diff_and_stats(&repo, &commit, highlighted)?;
+        git.path_lists
+            .try_get_with(tree_id, async move {
+                tokio::task::spawn_blocking(move || {
+                    retry_transient(|| {
+                        let repo = self.repo.to_thread_local();
+                        let tree = repo.find_tree(tree_id)?;
+
+                        let mut visitor = PathListVisitor {
+                            paths: Vec::new(),
+                            truncated: false,
+                            path_deque: VecDeque::new(),
+                            path: BString::default(),
+                        };
 
-                    let oid = take_oid(commit.id);
+                        // the visitor cancels the traversal itself once it's seen enough paths
+                        if let Err(error) = tree.traverse().breadthfirst(&mut visitor) {
+                            if !visitor.truncated {
+                                return Err(error.into());
+                            }
+                        }
 
-                    let inner = Yoke::try_attach_to_cart(commit.detach().data, |commit| {
-                        CommitInner::new(CommitRef::from_bytes(commit)?, oid)
-                    })?;
+                        visitor.paths.sort_unstable();
 
-                    Ok(Arc::new(Commit {
-                        inner,
-                        diff_stats,
-                        diff: diff_output,
-                    }))
+                        Ok(Arc::new(PathList {
+                            paths: visitor.paths,
+                            truncated: visitor.truncated,
+                        }))
+                    })
                 })
                 .await
                 .context("Failed to join Tokio task")?
             })
             .await
     }
+
+    /// Renders a blob in a richer format than highlighted text, returning `None` if the blob
+    /// can't be rendered in that format. Renderings, and failures to render, are cached by the
+    /// oid of the blob.
+    ///
+    /// Must be called from a blocking task.
+    fn render_blob(
+        &self,
+        format: RichFormat,
+        oid: ObjectId,
+        data: &[u8],
+    ) -> Option<Arc<RenderedBlob>> {
+        let render = async {
+            let content = simdutf8::basic::from_utf8(data).ok()?;
+
+            match format.render(content) {
+                Ok(rendered) => Some(Arc::new(rendered)),
+                Err(error) => {
+                    debug!(%error, ?format, "Failed to render blob, falling back to text");
+                    None
+                }
+            }
+        };
+
+        tokio::runtime::Handle::current()
+            .block_on(self.git.rendered_blobs.get_with((oid, format), render))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn tag_info(self: Arc<Self>) -> Result<Yoke<DetailedTag<'static>, Vec<u8>>> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let tag_name = self.branch.clone().context("no tag given")?;
+                let repo = self.repo.to_thread_local();
+
+                let tag = repo
+                    .find_reference(&format!("refs/tags/{tag_name}"))
+                    .context("Given tag does not exist in repository")?
+                    .peel_to_tag()
+                    .context("Couldn't get to a tag from the given reference")?;
+
+                let (target, via) = peel_nested_tag(&tag)?;
+                let tagged_object = match target.kind {
+                    Kind::Commit => TaggedObject::Commit(target.id),
+                    Kind::Tree => TaggedObject::Tree(target.id),
+                    Kind::Blob => TaggedObject::Blob(target.id),
+                    Kind::Tag => anyhow::bail!("Couldn't peel tag to its target"),
+                };
+
+                Yoke::try_attach_to_cart(tag.detach().data, move |tag| {
+                    let tag = TagRef::from_bytes(tag)?;
+
+                    Ok::<_, anyhow::Error>(DetailedTag {
+                        name: tag_name,
+                        tagger: tag.tagger.map(TryInto::try_into).transpose()?,
+                        tagged_object,
+                        via,
+                        message: tag.message,
+                    })
+                })
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Reads the messages of the given annotated tags, tags that can't be peeled to an
+    /// annotated tag are left out of the result.
+    #[instrument(skip(self))]
+    pub async fn tag_messages(
+        self: Arc<Self>,
+        names: Vec<String>,
+    ) -> Result<BTreeMap<String, String>> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+                let mut messages = BTreeMap::new();
+
+                for name in &names {
+                    let Ok(mut reference) = repo.find_reference(&format!("refs/tags/{name}"))
+                    else {
+                        continue;
+                    };
+
+                    let Ok(tag) = reference.peel_to_tag() else {
+                        continue;
+                    };
+
+                    let message = tag.decode()?.message.to_string();
+                    messages.insert(name.clone(), message);
+                }
+
+                Ok(messages)
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Whether `oid` can be reached from a branch or tag of the repository, either as a
+    /// commit in their history, the tree of one of those commits or as the target of a tag.
+    /// Objects only reachable from refs we don't list, such as `refs/pull/*` mirrors, aren't.
+    ///
+    /// This can mean walking the entire history of the repository, so the result is cached.
+    #[instrument(skip(self))]
+    pub async fn is_reachable(self: Arc<Self>, oid: ObjectId) -> Result<bool, Arc<anyhow::Error>> {
+        let git = self.git.clone();
+
+        git.reachable
+            .try_get_with((self.cache_key.clone(), oid), async move {
+                tokio::task::spawn_blocking(move || {
+                    retry_transient(|| {
+                        let repo = self.repo.to_thread_local();
+                        is_reachable(&repo, oid)
+                    })
+                })
+                .await
+                .context("Failed to join Tokio task")?
+            })
+            .await
+    }
+
+    /// Finds and renders the readme in the root of the tree, with relative links within it
+    /// pointing into `repository`.
+    #[instrument(skip(self))]
+    pub async fn readme(self: Arc<Self>, repository: Arc<str>) -> Result<Option<Readme>> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+
+                let commit = if let Some(reference) = &self.branch {
+                    find_reference_commit(&repo, reference)?
+                } else {
+                    match find_default_reference(&repo) {
+                        Ok(mut v) => v.peel_to_commit().context(
+                            "Couldn't find the commit that the HEAD of the repository refers to",
+                        )?,
+                        Err(e) if e.is::<EmptyRepository>() => return Ok(None),
+                        Err(e) => return Err(e),
+                    }
+                };
+                let tree = commit
+                    .tree()
+                    .context("Couldn't get the tree that the HEAD refers to")?;
+
+                self.find_readme(
+                    &repo,
+                    &tree,
+                    &ReadmeLinks {
+                        repository: repository.clone(),
+                        directory: PathBuf::new(),
+                        branch: self.branch.clone(),
+                    },
+                )
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Finds the readme directly within `tree`, going through the cache of rendered readmes
+    /// so the same readme is only rendered once however many times it's shown.
+    fn find_readme(
+        &self,
+        repo: &gix::Repository,
+        tree: &gix::Tree<'_>,
+        links: &ReadmeLinks,
+    ) -> Result<Option<Readme>> {
+        let mut blobs = Vec::new();
+        for entry in tree.iter() {
+            let entry = entry?;
+
+            if entry.mode().is_blob() {
+                blobs.push((entry.filename().to_string(), entry.object_id()));
+            }
+        }
+
+        for (candidate, format) in README_FILES {
+            for (name, oid) in &blobs {
+                if !name.eq_ignore_ascii_case(candidate) {
+                    continue;
+                }
+
+                let name = Arc::<str>::from(name.as_str());
+                let render = async {
+                    let Some(blob) = repo
+                        .try_find_object(*oid)?
+                        .and_then(|v| v.try_into_blob().ok())
+                    else {
+                        return Ok(None);
+                    };
+
+                    if is_binary(&blob.data) {
+                        return Ok(None);
+                    }
+
+                    let content = String::from_utf8_lossy(&blob.data);
+                    let lossy = matches!(content, Cow::Owned(_));
+
+                    let content = match format {
+                        ReadmeFormat::Markdown => Arc::from(render_readme(&content, links)),
+                        ReadmeFormat::Plaintext => Arc::from(content),
+                    };
+
+                    Ok::<_, anyhow::Error>(Some(Readme {
+                        name: name.clone(),
+                        format: *format,
+                        content,
+                        lossy,
+                    }))
+                };
+
+                let readme = tokio::runtime::Handle::current()
+                    .block_on(
+                        self.git
+                            .readme_cache
+                            .try_get_with((*oid, name.clone(), links.clone()), render),
+                    )
+                    .map_err(|e| clone_shared_error(&e))?;
+
+                if readme.is_some() {
+                    return Ok(readme);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Resolves the object id that the default branch of the repository currently points to,
+    /// returning `None` if the repository is empty.
+    pub async fn head_id(self: Arc<Self>) -> Result<Option<ObjectId>> {
+        tokio::task::spawn_blocking(move || {
+            let repo = self.repo.to_thread_local();
+
+            Ok(find_default_reference(&repo)
+                .ok()
+                .and_then(|mut reference| reference.peel_to_id_in_place().ok())
+                .map(gix::Id::detach))
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// A digest of HEAD and every branch and tag of the repository, changing whenever any of
+    /// them move. Returns `None` for repositories without any refs.
+    pub async fn refs_digest(self: Arc<Self>) -> Result<Option<u64>> {
+        tokio::task::spawn_blocking(move || {
+            let repo = self.repo.to_thread_local();
+
+            let head = repo.head_id().ok().map(gix::Id::detach);
+            let refs = branches_and_tags(&repo)?;
+
+            Ok((head.is_some() || !refs.is_empty()).then(|| refs_digest(head, &refs)))
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Renders HEAD as it'd appear on disk, a symbolic ref to the default branch or, for
+    /// repositories with a detached HEAD, the object id it points to.
+    pub async fn head_ref(self: Arc<Self>) -> Result<String> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+                let head = repo.head().context("Couldn't find HEAD of repository")?;
+
+                Ok(match head.kind {
+                    gix::head::Kind::Symbolic(reference) => format!("ref: {}", reference.name),
+                    gix::head::Kind::Unborn(name) => format!("ref: {name}"),
+                    gix::head::Kind::Detached { target, .. } => target.to_string(),
+                })
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Short name of the default branch, `None` if the repository's HEAD is detached.
+    pub async fn default_branch(self: Arc<Self>) -> Result<Option<String>> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+
+                Ok(find_default_branch(&repo)?
+                    .strip_prefix("refs/heads/")
+                    .map(ToString::to_string))
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// The commit the repository was opened at, if it was opened at the oid of a commit rather
+    /// than at a branch.
+    pub fn branch_commit(&self) -> Option<ObjectId> {
+        self.branch
+            .as_deref()
+            .and_then(|branch| ObjectId::from_hex(branch.as_bytes()).ok())
+    }
+
+    /// Checks the branch the repository was opened with exists, or is the oid of a commit,
+    /// returning the branches that do exist if it doesn't so they can be offered in its place.
+    pub async fn missing_branch(self: Arc<Self>) -> Result<Option<Vec<String>>> {
+        let Some(branch) = self.branch.clone() else {
+            return Ok(None);
+        };
+
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+
+                // a name that isn't even valid as a ref can't exist either
+                if let Ok(Some(_)) = repo.try_find_reference(branch.as_ref()) {
+                    return Ok(None);
+                }
+
+                // pages can be asked for at a commit rather than a branch, such as by
+                // `/repo/log/<oid>/<path>`
+                if let Ok(oid) = ObjectId::from_hex(branch.as_bytes()) {
+                    if repo
+                        .try_find_header(oid)?
+                        .is_some_and(|header| header.kind() == Kind::Commit)
+                    {
+                        return Ok(None);
+                    }
+                }
+
+                let mut branches = Vec::new();
+                for reference in repo.references()?.local_branches()? {
+                    let reference = reference.map_err(|e| anyhow!(e))?;
+                    branches.push(reference.name().shorten().to_string());
+                }
+                branches.sort_unstable();
+
+                Ok(Some(branches))
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Fetches the commit at the tip of the branch, or HEAD. The tip is resolved on every
+    /// call but the commit itself goes through the commit cache, so concurrent requests for
+    /// the same tip share a single diff.
+    #[instrument(skip(self))]
+    pub async fn latest_commit(
+        self: Arc<Self>,
+        format: DiffFormat,
+    ) -> Result<Arc<Commit>, Arc<anyhow::Error>> {
+        let open_repo = self.clone();
+        let oid = tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = open_repo.repo.to_thread_local();
+
+                let commit = if let Some(reference) = &open_repo.branch {
+                    find_reference_commit(&repo, reference)?
+                } else {
+                    find_default_reference(&repo)?
+                        .peel_to_commit()
+                        .context("Couldn't find commit HEAD of repository refers to")?
+                };
+
+                Ok::<_, anyhow::Error>(commit.id)
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")
+        .and_then(|v| v)
+        .map_err(Arc::new)?;
+
+        self.commit_by_id(oid, format).await
+    }
+
+    /// Walks the first-parent history of the branch, returning the commits which changed the
+    /// file at `path`, skipping the first `offset` of them.
+    ///
+    /// If `follow` is set and a commit turns out to have created `path` by renaming another
+    /// file, the walk continues with the file's previous path. The walk stops early at the
+    /// boundary of shallow mirrors.
+    #[instrument(skip(self))]
+    pub async fn file_history(
+        self: Arc<Self>,
+        path: PathBuf,
+        follow: bool,
+        offset: usize,
+        amount: usize,
+        count_changes: bool,
+    ) -> Result<FileHistory> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+
+                let tip = match self.branch.as_deref() {
+                    Some(reference) => find_reference_commit(&repo, reference)?,
+                    None => find_default_reference(&repo)?
+                        .peel_to_commit()
+                        .context("Couldn't find commit HEAD of repository refers to")?,
+                };
+
+                let mut path = path.clone();
+                let mut entries = Vec::new();
+                let mut seen = 0;
+                let mut truncated = false;
+
+                for info in tip
+                    .ancestors()
+                    .first_parent_only()
+                    .all()?
+                    .take(FILE_HISTORY_WALK_LIMIT)
+                {
+                    let commit = info?.object()?;
+                    let tree = commit.tree()?;
+                    // there's no telling what the boundary of a shallow mirror changed
+                    if is_shallow_boundary(&repo, &commit) {
+                        truncated = true;
+                        break;
+                    }
+
+                    let parent_tree = match commit.parent_ids().next() {
+                        Some(parent) => parent.object()?.into_commit().tree()?,
+                        None => repo.empty_tree(),
+                    };
+
+                    let current = tree.lookup_entry_by_path(&path)?.map(|v| v.object_id());
+                    let previous = parent_tree
+                        .lookup_entry_by_path(&path)?
+                        .map(|v| v.object_id());
+
+                    if current == previous {
+                        continue;
+                    }
+
+                    let renamed_from = if follow && previous.is_none() {
+                        find_rename_source(&parent_tree, &tree, &path)?
+                    } else {
+                        None
+                    };
+
+                    seen += 1;
+
+                    if seen > offset {
+                        let changes = if count_changes {
+                            let previous = match &renamed_from {
+                                Some(source) => parent_tree
+                                    .lookup_entry_by_path(source)?
+                                    .map(|v| v.object_id()),
+                                None => previous,
+                            };
+
+                            count_line_changes(&repo, previous, current)?
+                        } else {
+                            None
+                        };
+
+                        let oid = take_oid(commit.id);
+                        let commit = Yoke::try_attach_to_cart(commit.detach().data, |commit| {
+                            CommitInner::new(CommitRef::from_bytes(commit)?, oid)
+                        })?;
+
+                        entries.push(FileHistoryEntry {
+                            commit,
+                            renamed_from: renamed_from
+                                .as_ref()
+                                .map(|v| v.to_string_lossy().into_owned()),
+                            changes,
+                        });
+
+                        if entries.len() == amount {
+                            break;
+                        }
+                    }
+
+                    if let Some(renamed_from) = renamed_from {
+                        path = renamed_from;
+                    }
+                }
+
+                Ok(FileHistory { entries, truncated })
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Compares `head` against `base`, both refs or commit oids, like `git diff base...head`
+    /// would. Lists the commits made on `head` since it diverged from `base`, along with the
+    /// diff of `head` against the point they diverged at.
+    ///
+    /// Only the latest [`COMPARE_COMMIT_LIMIT`] commits are kept, the rest are only counted
+    /// up to [`COMPARE_WALK_LIMIT`]. Returns `None` if either doesn't name a commit.
+    #[instrument(skip(self))]
+    pub async fn compare(
+        self: Arc<Self>,
+        base: String,
+        head: String,
+        repository: Arc<str>,
+    ) -> Result<Option<Comparison>> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+
+                let (Some(base), Some(head)) =
+                    (find_commit_id(&repo, &base)?, find_commit_id(&repo, &head)?)
+                else {
+                    return Ok(None);
+                };
+
+                let merge_base = match repo.merge_base(base, head) {
+                    Ok(id) => Some(id.detach()),
+                    Err(gix::repository::merge_base::Error::NotFound { .. }) => None,
+                    Err(e) => return Err(e.into()),
+                };
+
+                let mut comparison = Comparison {
+                    merge_base,
+                    commits: Vec::new(),
+                    more_commits: 0,
+                    more_commits_capped: false,
+                    diff_stats: String::new(),
+                    diff: String::new(),
+                };
+
+                // unrelated histories have nothing to compare, and neither does a head
+                // that's already part of base
+                let Some(merge_base) = merge_base.filter(|v| *v != head) else {
+                    return Ok(Some(comparison));
+                };
+
+                for (i, info) in repo
+                    .rev_walk([head])
+                    .with_pruned([merge_base])
+                    .all()?
+                    .enumerate()
+                {
+                    if i == COMPARE_WALK_LIMIT {
+                        comparison.more_commits_capped = true;
+                        break;
+                    }
+
+                    let info = info?;
+
+                    if comparison.commits.len() == COMPARE_COMMIT_LIMIT {
+                        comparison.more_commits += 1;
+                        continue;
+                    }
+
+                    let commit = info.object()?;
+                    let oid = take_oid(commit.id);
+                    comparison
+                        .commits
+                        .push(Yoke::try_attach_to_cart(commit.detach().data, |commit| {
+                            CommitInner::new(CommitRef::from_bytes(commit)?, oid)
+                        })?);
+                }
+
+                let (diff_stats, diff) = diff_trees(
+                    &repo,
+                    &repo.find_commit(merge_base)?.tree()?,
+                    &repo.find_commit(head)?.tree()?,
+                    Some(DiffLinks {
+                        repository: &repository,
+                        old: Some(merge_base),
+                        new: head,
+                    }),
+                )?;
+                comparison.diff_stats = diff_stats;
+                comparison.diff = diff;
+
+                Ok(Some(comparison))
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Whether the history of the repository ends at `commit` because the repository is a
+    /// shallow mirror, rather than because `commit` is a root commit.
+    pub async fn is_history_truncated(self: Arc<Self>, commit: [u8; 20]) -> Result<bool> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+                let commit = repo.find_commit(ObjectId::Sha1(commit))?;
+
+                Ok(is_shallow_boundary(&repo, &commit))
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Resolves the tree a snapshot of `commit`, or of the branch if there's no commit, is
+    /// built from. Snapshots only depend on the tree, so this identifies their content.
+    ///
+    /// Returns `None` if the commit or branch doesn't exist.
+    #[instrument(skip(self))]
+    pub async fn snapshot_tree(
+        self: Arc<Self>,
+        commit: Option<ObjectId>,
+    ) -> Result<Option<ObjectId>> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+
+                Ok(Some(if let Some(commit) = commit {
+                    let Some(object) = repo.try_find_object(commit)? else {
+                        return Ok(None);
+                    };
+
+                    object.try_into_commit()?.tree_id()?.detach()
+                } else if let Some(reference) = &self.branch {
+                    if let Ok(oid) = ObjectId::from_hex(reference.as_bytes()) {
+                        let Some(object) = repo.try_find_object(oid)? else {
+                            return Ok(None);
+                        };
+
+                        object.try_into_commit()?.tree_id()?.detach()
+                    } else {
+                        let Some(mut reference) = repo.try_find_reference(reference.as_ref())?
+                        else {
+                            return Ok(None);
+                        };
+
+                        reference.peel_to_tree()?.id
+                    }
+                } else {
+                    find_default_reference(&repo)?.peel_to_tree()?.id
+                }))
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Streams an archive of `tree` in the given `format`, with every file placed within the
+    /// directory `prefix` as `git archive --prefix` would.
+    #[instrument(skip_all)]
+    pub async fn archive(
+        self: Arc<Self>,
+        res: tokio::sync::mpsc::Sender<Result<Bytes, anyhow::Error>>,
+        cont: tokio::sync::oneshot::Sender<()>,
+        tree: ObjectId,
+        prefix: String,
+        format: ArchiveFormat,
+        limits: ArchiveLimits,
+    ) -> Result<(), anyhow::Error> {
+        tokio::task::spawn_blocking(move || {
+            let repo = self.repo.to_thread_local();
+            let tree = repo.find_tree(tree)?;
+
+            // tell the web server it can send response headers to the requester
+            if cont.send(()).is_err() {
+                return Err(anyhow!("requester gone"));
+            }
+
+            let buffer = BytesMut::with_capacity(BUFFER_CAP + 1024);
+            let mut visitor = ArchivalVisitor {
+                repository: &repo,
+                res,
+                archive: ArchiveBuilder::new(format, buffer),
+                prefix,
+                path_deque: VecDeque::new(),
+                path: BString::default(),
+                limits,
+                entries: 0,
+                bytes: 0,
+                exceeded: None,
+            };
+
+            let traversal = tree.traverse().breadthfirst(&mut visitor);
+
+            // the client is left with a truncated download rather than an archive that looks
+            // complete but isn't
+            if let Some(limit) = visitor.exceeded {
+                return Err(anyhow!(
+                    "archive of tree {tree} exceeds {limit}",
+                    tree = tree.id
+                ));
+            }
+            traversal?;
+
+            visitor.res.blocking_send(Ok(visitor.archive.finish()?))?;
+
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    /// Streams a listing of every file within `tree` in the format of `git ls-tree -r --long`,
+    /// giving the mode, type, oid, size and path of each. Only object headers are read to get
+    /// the sizes, so this is far cheaper than building an archive of the tree.
+    #[instrument(skip_all)]
+    pub async fn manifest(
+        self: Arc<Self>,
+        res: tokio::sync::mpsc::Sender<Result<Bytes, anyhow::Error>>,
+        tree: ObjectId,
+    ) -> Result<(), anyhow::Error> {
+        tokio::task::spawn_blocking(move || {
+            let repo = self.repo.to_thread_local();
+            let tree = repo.find_tree(tree)?;
+
+            let mut visitor = ManifestVisitor {
+                repository: &repo,
+                res,
+                buffer: BytesMut::with_capacity(BUFFER_CAP + 1024),
+                files: 0,
+                truncated: false,
+                path_deque: VecDeque::new(),
+                path: BString::default(),
+            };
+
+            // the visitor cancels the traversal itself once it's seen enough paths or the
+            // client has gone away
+            if let Err(error) = tree.traverse().breadthfirst(&mut visitor) {
+                if !visitor.truncated {
+                    return Err(error.into());
+                }
+
+                if visitor.res.is_closed() {
+                    return Ok(());
+                }
+            }
+
+            if visitor.truncated {
+                writeln!(visitor.buffer, "# truncated after {PATH_LIST_LIMIT} files")?;
+            }
+
+            visitor.res.blocking_send(Ok(visitor.buffer.freeze()))?;
+
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn commit(
+        self: Arc<Self>,
+        commit: &str,
+        format: DiffFormat,
+    ) -> Result<Arc<Commit>, Arc<anyhow::Error>> {
+        let Some(commit) = self
+            .clone()
+            .resolve_commit_id(commit.to_string())
+            .await
+            .map_err(Arc::new)?
+        else {
+            return Err(Arc::new(anyhow!(CommitNotFound)));
+        };
+
+        self.commit_by_id(commit, format).await
+    }
+
+    /// Resolves `id`, either a full commit oid or an abbreviation of one, to the commit it
+    /// names. Returns `None` if there's no such commit, and fails with [`AmbiguousCommitId`] if
+    /// the abbreviation matches more than one commit.
+    pub async fn resolve_commit_id(self: Arc<Self>, id: String) -> Result<Option<ObjectId>> {
+        tokio::task::spawn_blocking(move || {
+            retry_transient(|| {
+                let repo = self.repo.to_thread_local();
+                resolve_commit_id(&repo, &id)
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    async fn commit_by_id(
+        self: Arc<Self>,
+        commit: ObjectId,
+        format: DiffFormat,
+    ) -> Result<Arc<Commit>, Arc<anyhow::Error>> {
+        let git = self.git.clone();
+
+        git.commits
+            .try_get_with((commit, format.clone()), async move {
+                tokio::task::spawn_blocking(move || {
+                    retry_transient(|| {
+                        let repo = self.repo.to_thread_local();
+
+                        let commit = repo.find_commit(commit)?;
+
+                        let (diff_output, diff_stats) =
+                            fetch_diff_and_stats(&repo, &commit, &format)?;
+
+                        let oid = take_oid(commit.id);
+
+                        let inner = Yoke::try_attach_to_cart(commit.detach().data, |commit| {
+                            CommitInner::new(CommitRef::from_bytes(commit)?, oid)
+                        })?;
+
+                        Ok(Arc::new(Commit {
+                            inner,
+                            diff_stats,
+                            diff: diff_output,
+                        }))
+                    })
+                })
+                .await
+                .context("Failed to join Tokio task")?
+            })
+            .await
+    }
+}
+
+/// Reads the branch configured via `rgit.default-branch` to be presented as the default
+/// branch of the repository in place of whatever HEAD points at.
+pub fn configured_default_branch(repo: &gix::Repository) -> Option<String> {
+    repo.config_snapshot()
+        .string("rgit.default-branch")
+        .map(|v| v.to_string())
+}
+
+/// Finds the full name of the default branch of the repository, this is the configured
+/// default branch if there is one and it exists, otherwise the reference HEAD points to, or
+/// `HEAD` itself if it's detached.
+///
+/// HEAD is left pointing at a branch that no longer exists when the default branch of a
+/// mirror is renamed, in which case `main`, `master` and then the first branch by name are
+/// tried in its place.
+pub fn find_default_branch(repo: &gix::Repository) -> Result<String> {
+    if let Some(branch) = configured_default_branch(repo) {
+        let name = format!("refs/heads/{branch}");
+
+        if repo.try_find_reference(name.as_str())?.is_some() {
+            return Ok(name);
+        }
+    }
+
+    let head = repo.head()?;
+    // `name` is always `HEAD` itself, the branch it points at is its referent
+    let head_name = head.referent_name().map_or_else(
+        || head.name().as_bstr().to_string(),
+        |name| name.as_bstr().to_string(),
+    );
+    if !head.is_unborn() {
+        return Ok(head_name);
+    }
+
+    for name in ["refs/heads/main", "refs/heads/master"] {
+        if repo.try_find_reference(name)?.is_some() {
+            return Ok(name.to_string());
+        }
+    }
+
+    let first_branch = repo
+        .references()?
+        .local_branches()?
+        .filter_map(Result::ok)
+        .map(|reference| reference.name().as_bstr().to_string())
+        .min();
+
+    // an empty repository has no branches to fall back to, leave it to callers to notice
+    Ok(first_branch.unwrap_or(head_name))
+}
+
+/// Finds the commit at the tip of `reference`, which can also be the oid of a commit in place
+/// of a branch or tag.
+fn find_reference_commit<'a>(
+    repo: &'a gix::Repository,
+    reference: &str,
+) -> Result<gix::Commit<'a>> {
+    match ObjectId::from_hex(reference.as_bytes()) {
+        Ok(oid) => Ok(repo.find_commit(oid)?),
+        Err(_) => Ok(repo.find_reference(reference)?.peel_to_commit()?),
+    }
+}
+
+/// Finds the reference pages fall back to when no branch was asked for. Fails with
+/// [`EmptyRepository`] if nothing has been pushed to the repository yet.
+fn find_default_reference(repo: &gix::Repository) -> Result<gix::Reference<'_>> {
+    let name = find_default_branch(repo)?;
+
+    match repo.try_find_reference(name.as_str())? {
+        Some(reference) => Ok(reference),
+        None if is_empty_repository(repo)? => Err(anyhow!(EmptyRepository)),
+        None => Err(anyhow!("Couldn't find default branch {name} of repository")),
+    }
+}
+
+/// Whether the repository at `repository_path` has opted in to being served with a
+/// `git-daemon-export-ok` file, as git-daemon and cgit expect.
+pub fn is_export_ok(repository_path: &Path) -> bool {
+    repository_path.join("git-daemon-export-ok").exists()
+}
+
+/// Whether nothing has been pushed to the repository yet, leaving it without a single branch
+/// or tag, as is the case for a freshly initialised repository.
+pub fn is_empty_repository(repo: &gix::Repository) -> Result<bool> {
+    Ok(repo.references()?.all()?.next().is_none())
+}
+
+/// Finds the path `path` was renamed from between `parent` and `tree`, reusing the same
+/// rename detection as commit diffs, albeit with rewrite tracking turned on.
+fn find_rename_source(
+    parent: &gix::Tree<'_>,
+    tree: &gix::Tree<'_>,
+    path: &Path,
+) -> Result<Option<PathBuf>> {
+    let location = gix::path::into_bstr(path);
+    let mut source = None;
+
+    let mut changes = parent.changes()?;
+    changes.options(|opts| {
+        opts.track_path()
+            .track_rewrites(Some(gix::diff::Rewrites::default()));
+    });
+    changes.for_each_to_obtain_tree(tree, |change| {
+        if let gix::object::tree::diff::Change::Rewrite {
+            source_location,
+            location: destination,
+            copy: false,
+            ..
+        } = change
+        {
+            if destination == location.as_ref() {
+                source = Some(gix::path::from_bstr(source_location).into_owned());
+            }
+        }
+
+        Ok::<_, std::convert::Infallible>(gix::object::tree::diff::Action::Continue)
+    })?;
+
+    Ok(source)
+}
+
+/// Reads the object format of the repository at `git_dir` straight from its config, for
+/// repositories that can't be opened because the format isn't one we support. Returns `None`
+/// for SHA-1 repositories.
+pub fn unsupported_object_format(git_dir: &Path) -> Option<String> {
+    let config = gix::config::File::from_path_no_includes(
+        git_dir.join("config"),
+        gix::config::Source::Local,
+    )
+    .ok()?;
+
+    config
+        .string("extensions.objectFormat")
+        .filter(|format| !format.eq_ignore_ascii_case(b"sha1"))
+        .map(|format| format.to_string())
+}
+
+/// Abbreviated object ids are never shorter than this, the same as git's default.
+pub const MIN_ABBREV_LEN: usize = 7;
+
+/// Shown in place of objects that a partial clone never fetched.
+pub const OBJECT_UNAVAILABLE: &str = "object not available in this partial mirror";
+
+/// Whether `error` was caused by an object missing from the object database, as happens in
+/// partial clones where objects are only fetched on demand.
+pub fn is_missing_object(error: &anyhow::Error) -> bool {
+    use gix::objs::find::{existing, existing_iter, existing_object};
+
+    error.chain().any(|e| {
+        matches!(e.downcast_ref(), Some(existing::Error::NotFound { .. }))
+            || matches!(
+                e.downcast_ref(),
+                Some(existing_object::Error::NotFound { .. })
+            )
+            || matches!(
+                e.downcast_ref(),
+                Some(existing_iter::Error::NotFound { .. })
+            )
+    })
+}
+
+/// How long to wait before retrying an operation that ran into the repository being
+/// modified underneath it.
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Attached to errors that persisted after retrying an operation on a repository that looked
+/// to be in the middle of being modified, such as by `git gc` repacking it, which will
+/// likely succeed if tried again shortly.
+#[derive(Debug)]
+pub struct RepositoryBusy;
+
+impl fmt::Display for RepositoryBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("repository is being modified, try again shortly")
+    }
+}
+
+impl std::error::Error for RepositoryBusy {}
+
+/// The repository has nothing in it to show, see [`is_empty_repository`].
+#[derive(Debug)]
+pub struct EmptyRepository;
+
+impl fmt::Display for EmptyRepository {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("repository is empty")
+    }
+}
+
+impl std::error::Error for EmptyRepository {}
+
+/// A commit that was asked for doesn't exist, or names an object that isn't a commit.
+#[derive(Debug)]
+pub struct CommitNotFound;
+
+impl fmt::Display for CommitNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("commit not found")
+    }
+}
+
+impl std::error::Error for CommitNotFound {}
+
+/// An abbreviated commit oid that was asked for matches more than one commit, holding every
+/// commit it matches.
+#[derive(Debug)]
+pub struct AmbiguousCommitId(pub Vec<ObjectId>);
+
+impl fmt::Display for AmbiguousCommitId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "abbreviated commit id matches {} commits", self.0.len())
+    }
+}
+
+impl std::error::Error for AmbiguousCommitId {}
+
+/// Whether `error` looks to have been caused by the repository being modified whilst it was
+/// being read. Whilst `git gc` runs, packfiles and `packed-refs` are briefly missing as
+/// they're swapped out for their replacements, and writers hold lock files over refs.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.is::<RepositoryBusy>()
+        || error.chain().any(|e| {
+            e.is::<gix::lock::acquire::Error>()
+                || e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+                    matches!(e.kind(), ErrorKind::NotFound | ErrorKind::WouldBlock)
+                })
+        })
+}
+
+/// Runs `f`, running it a second time after a short delay if it failed with a transient
+/// error. Errors that are still transient on the second attempt are marked with
+/// [`RepositoryBusy`]. Blocks the thread whilst waiting, so must only be called from
+/// blocking tasks.
+pub fn retry_transient<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    match f() {
+        Err(error) if is_transient(&error) => {
+            debug!(%error, "Repository looks to be busy, retrying");
+            std::thread::sleep(TRANSIENT_RETRY_DELAY);
+
+            f().map_err(|error| {
+                if is_transient(&error) {
+                    error.context(RepositoryBusy)
+                } else {
+                    error
+                }
+            })
+        }
+        res => res,
+    }
+}
+
+/// Takes a copy of an error shared between everyone waiting on a cache entry, keeping it
+/// marked as [`RepositoryBusy`], [`CommitNotFound`] or [`EmptyRepository`] if it was.
+pub fn clone_shared_error(error: &anyhow::Error) -> anyhow::Error {
+    let cloned = anyhow!("{error:?}");
+
+    if error.is::<RepositoryBusy>() {
+        cloned.context(RepositoryBusy)
+    } else if error.is::<CommitNotFound>() {
+        cloned.context(CommitNotFound)
+    } else if error.is::<EmptyRepository>() {
+        cloned.context(EmptyRepository)
+    } else {
+        cloned
+    }
+}
+
+/// Whether `commit` is the oldest commit available in a shallow mirror. Commits on the
+/// boundary of a shallow clone still name their parents, they just aren't in the object
+/// database.
+pub fn is_shallow_boundary(repo: &gix::Repository, commit: &gix::Commit<'_>) -> bool {
+    commit.parent_ids().any(|parent| !repo.has_object(parent))
+}
+
+/// Whether `data` looks like the content of a binary file, going by the same check git uses
+/// for diffs: a NUL byte somewhere in the first 8000 bytes.
+fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|&b| b == 0)
+}
+
+/// Converts the time of a signature to a date, clamping it into the range of dates we can
+/// represent and falling back to UTC for offsets that aren't valid. Repositories imported from
+/// other systems can carry all sorts of nonsense here, such as commits made in year 0.
+pub fn signature_time(seconds: i64, offset: i32) -> OffsetDateTime {
+    let min = PrimitiveDateTime::MIN.assume_utc().unix_timestamp();
+    let max = PrimitiveDateTime::MAX.assume_utc().unix_timestamp();
+
+    let time = OffsetDateTime::from_unix_timestamp(seconds.clamp(min, max))
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    let offset = UtcOffset::from_whole_seconds(offset).unwrap_or(UtcOffset::UTC);
+
+    time.checked_to_offset(offset).unwrap_or(time)
+}
+
+/// Length of the shortest prefix of `oid` that's unique within the repository, picked the same
+/// way git does. Repositories with more objects start from longer prefixes, but never shorter
+/// than [`MIN_ABBREV_LEN`].
+fn abbreviation_len(repo: &gix::Repository, oid: ObjectId) -> usize {
+    oid.attach(repo)
+        .shorten()
+        .map_or(MIN_ABBREV_LEN, |prefix| prefix.hex_len())
+}
+
+/// Every branch and tag of the repository, along with what they point to.
+fn branches_and_tags(repo: &gix::Repository) -> Result<BTreeMap<BString, ObjectId>> {
+    let mut refs = BTreeMap::new();
+
+    for reference in repo.references()?.all()? {
+        let reference = reference.map_err(|e| anyhow!(e))?;
+
+        if !matches!(
+            reference.name().category(),
+            Some(Category::LocalBranch | Category::Tag)
+        ) {
+            continue;
+        }
+
+        if let Some(id) = reference.try_id() {
+            refs.insert(reference.name().as_bstr().to_owned(), id.detach());
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Digest of HEAD and the refs a bundle holds, changing whenever any of them move.
+fn refs_digest(head: Option<ObjectId>, refs: &BTreeMap<BString, ObjectId>) -> u64 {
+    let mut identity = Vec::new();
+
+    if let Some(head) = head {
+        identity.extend_from_slice(b"HEAD\0");
+        identity.extend_from_slice(head.as_slice());
+    }
+
+    for (name, oid) in refs {
+        identity.extend_from_slice(name);
+        identity.push(b'\0');
+        identity.extend_from_slice(oid.as_slice());
+    }
+
+    xxhash_rust::const_xxh3::xxh3_64(&identity)
+}
+
+/// Resolves `spec`, either a full commit oid or a ref, to the commit it points to. Tags are
+/// peeled to the commit they're of.
+fn find_commit_id(repo: &gix::Repository, spec: &str) -> Result<Option<ObjectId>> {
+    if let Ok(oid) = ObjectId::from_hex(spec.as_bytes()) {
+        return Ok(repo
+            .try_find_object(oid)?
+            .and_then(|object| object.peel_to_commit().ok())
+            .map(|commit| commit.id));
+    }
+
+    // names git wouldn't accept as a ref are treated the same as refs that don't exist
+    let Some(mut reference) = repo.try_find_reference(spec).ok().flatten() else {
+        return Ok(None);
+    };
+
+    Ok(reference.peel_to_commit().ok().map(|commit| commit.id))
+}
+
+/// See [`OpenRepository::resolve_commit_id`].
+fn resolve_commit_id(repo: &gix::Repository, id: &str) -> Result<Option<ObjectId>> {
+    let oid = if let Ok(oid) = ObjectId::from_hex(id.as_bytes()) {
+        oid
+    } else {
+        // anything that isn't at least a few hex digits can't be an abbreviation
+        let Ok(prefix) = gix::hash::Prefix::from_hex(id) else {
+            return Ok(None);
+        };
+
+        match repo.objects.lookup_prefix(prefix, None)? {
+            Some(Ok(oid)) => oid,
+            Some(Err(())) => return disambiguate_commit_id(repo, prefix),
+            None => return Ok(None),
+        }
+    };
+
+    Ok(repo
+        .try_find_header(oid)?
+        .filter(|header| header.kind() == Kind::Commit)
+        .map(|_| oid))
+}
+
+/// Picks the commit out of the objects an ambiguous abbreviation matches, as git does when it's
+/// only looking for a commit. Fails with [`AmbiguousCommitId`] if more than one commit matches.
+fn disambiguate_commit_id(
+    repo: &gix::Repository,
+    prefix: gix::hash::Prefix,
+) -> Result<Option<ObjectId>> {
+    let mut candidates = std::collections::HashSet::new();
+    repo.objects.lookup_prefix(prefix, Some(&mut candidates))?;
+
+    let mut commits = Vec::new();
+    for oid in candidates {
+        if repo
+            .try_find_header(oid)?
+            .is_some_and(|header| header.kind() == Kind::Commit)
+        {
+            commits.push(oid);
+        }
+    }
+
+    match commits.as_slice() {
+        [] => Ok(None),
+        [oid] => Ok(Some(*oid)),
+        _ => {
+            commits.sort_unstable();
+            Err(anyhow!(AmbiguousCommitId(commits)))
+        }
+    }
+}
+
+/// Whether `ancestor` is `descendant` or in its history.
+fn is_ancestor(repo: &gix::Repository, ancestor: ObjectId, descendant: ObjectId) -> Result<bool> {
+    if ancestor == descendant {
+        return Ok(true);
+    }
+
+    match repo.merge_base(ancestor, descendant) {
+        Ok(id) => Ok(id.detach() == ancestor),
+        Err(gix::repository::merge_base::Error::NotFound { .. }) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// See [`OpenRepository::is_reachable`].
+fn is_reachable(repo: &gix::Repository, oid: ObjectId) -> Result<bool> {
+    let Some(header) = repo.try_find_header(oid)? else {
+        return Ok(false);
+    };
+
+    let mut tips = Vec::new();
+
+    if let Ok(id) = repo.head_id() {
+        tips.push(id.detach());
+    }
+
+    for reference in repo.references()?.all()? {
+        let mut reference = reference.map_err(|e| anyhow!(e))?;
+
+        match reference.name().category() {
+            Some(Category::LocalBranch) => {
+                tips.push(reference.peel_to_id_in_place()?.detach());
+            }
+            Some(Category::Tag) => {
+                let target = match reference.peel_to_tag() {
+                    Ok(tag) => peel_nested_tag(&tag)?.0,
+                    Err(_) => reference.id().object()?,
+                };
+
+                if target.id == oid {
+                    return Ok(true);
+                }
+
+                if target.kind == Kind::Commit {
+                    tips.push(target.id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if tips.contains(&oid) {
+        return Ok(true);
+    }
+
+    // blobs are only ever requested by oid when they're the target of a tag
+    let find_tree = match header.kind() {
+        Kind::Commit => false,
+        Kind::Tree => true,
+        Kind::Blob | Kind::Tag => return Ok(false),
+    };
+
+    for info in repo.rev_walk(tips).all()? {
+        let info = info?;
+
+        if find_tree {
+            if info.object()?.tree_id()? == oid {
+                return Ok(true);
+            }
+        } else if info.id == oid {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Deepest chain of tags pointing at other tags that'll be followed before giving up.
+const MAX_TAG_DEPTH: usize = 10;
+
+/// Follows `tag` through any tags it points at to the first object that isn't a tag,
+/// returning that object along with the names of the tags passed through on the way.
+pub fn peel_nested_tag<'repo>(tag: &gix::Tag<'repo>) -> Result<(gix::Object<'repo>, Vec<String>)> {
+    let mut object = tag.target_id()?.object()?;
+    let mut via = Vec::new();
+
+    while object.kind == Kind::Tag {
+        anyhow::ensure!(
+            via.len() < MAX_TAG_DEPTH,
+            "Tag is nested more than {MAX_TAG_DEPTH} levels deep"
+        );
+
+        let tag = object.into_tag();
+        via.push(tag.decode()?.name.to_string());
+        object = tag.target_id()?.object()?;
+    }
+
+    Ok((object, via))
+}
+
+fn take_oid(v: ObjectId) -> [u8; 20] {
+    match v {
+        ObjectId::Sha1(v) => v,
+    }
+}
+
+const BUFFER_CAP: usize = 512 * 1024;
+
+/// Default size above which raw blobs are streamed back to the client in chunks rather than
+/// being read into memory in their entirety.
+pub const DEFAULT_STREAMING_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+/// Size of each chunk read from a streamed blob.
+const STREAMING_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The formats snapshots can be downloaded in.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Extension given to archives of this format, including the leading dot.
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::TarGz => ".tar.gz",
+            Self::Zip => ".zip",
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::TarGz => "application/gzip",
+            Self::Zip => "application/zip",
+        }
+    }
+
+    /// Splits the extension of a supported format off of `file_name`.
+    pub fn from_file_name(file_name: &str) -> Option<(&str, Self)> {
+        [Self::TarGz, Self::Zip].into_iter().find_map(|format| {
+            file_name
+                .strip_suffix(format.extension())
+                .map(|name| (name, format))
+        })
+    }
+}
+
+/// Bounds on the archives built for snapshots, so a tree crafted to expand enormously can't be
+/// used to tie up the server. Archives exceeding any of these are abandoned part way through.
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveLimits {
+    /// Most files within a single archive.
+    pub entries: u64,
+    /// Most bytes of file content, before compression, within a single archive.
+    pub bytes: u64,
+    /// Most directories deep a file may be nested.
+    pub path_depth: usize,
+    /// Longest path, in bytes, a file may have.
+    pub path_length: usize,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            entries: 250_000,
+            bytes: 4 * 1024 * 1024 * 1024,
+            path_depth: 128,
+            path_length: 4096,
+        }
+    }
+}
+
+enum ArchiveBuilder {
+    TarGz(Builder<GzEncoder<Writer<BytesMut>>>),
+    Zip(ZipBuilder),
+}
+
+impl ArchiveBuilder {
+    fn new(format: ArchiveFormat, buffer: BytesMut) -> Self {
+        match format {
+            ArchiveFormat::TarGz => Self::TarGz(Builder::new(GzEncoder::new(
+                buffer.writer(),
+                flate2::Compression::fast(),
+            ))),
+            ArchiveFormat::Zip => Self::Zip(ZipBuilder::new(buffer)),
+        }
+    }
+
+    fn append(&mut self, path: &BStr, mode: EntryMode, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Self::TarGz(archive) => {
+                let mut header = tar::Header::new_gnu();
+                if let Err(error) = header.set_path(path.to_path_lossy()) {
+                    warn!(%error, "Attempted to write invalid path to archive");
+                    return Ok(());
+                }
+                header.set_size(data.len() as u64);
+                #[allow(clippy::cast_sign_loss)]
+                header.set_mode(mode.0.into());
+                header.set_cksum();
+
+                archive.append(&header, data)?;
+            }
+            Self::Zip(archive) => {
+                // zip has no notion of file modes of its own, so they're given in the upper
+                // half of the external attributes as unix tools expect
+                let mode = match mode.kind() {
+                    EntryKind::Link => 0o120_777,
+                    EntryKind::BlobExecutable => 0o100_755,
+                    _ => 0o100_644,
+                };
+
+                archive.append(path, mode, data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The archive written so far that hasn't been sent to the client yet.
+    fn buffer(&mut self) -> &mut BytesMut {
+        match self {
+            Self::TarGz(archive) => archive.get_mut().get_mut().get_mut(),
+            Self::Zip(archive) => archive.buffer(),
+        }
+    }
+
+    /// Writes out the end of the archive, returning everything that hasn't been sent yet.
+    fn finish(self) -> anyhow::Result<Bytes> {
+        match self {
+            Self::TarGz(archive) => Ok(archive.into_inner()?.finish()?.into_inner().freeze()),
+            Self::Zip(archive) => archive.finish(),
+        }
+    }
+}
+
+pub struct ArchivalVisitor<'a> {
+    repository: &'a gix::Repository,
+    res: tokio::sync::mpsc::Sender<Result<Bytes, anyhow::Error>>,
+    archive: ArchiveBuilder,
+    /// Directory every file is placed within.
+    prefix: String,
+    path_deque: VecDeque<BString>,
+    path: BString,
+    limits: ArchiveLimits,
+    entries: u64,
+    bytes: u64,
+    /// The limit the archive went over, cancelling the traversal.
+    exceeded: Option<&'static str>,
+}
+
+impl<'a> ArchivalVisitor<'a> {
+    /// Whether the current path could place a file outside of the directory the archive is
+    /// extracted into. Git itself never writes trees like this, but they can be crafted.
+    fn is_unsafe_path(&self) -> bool {
+        self.path.is_empty()
+            || self.path.split_str("/").any(|component| {
+                component.is_empty()
+                    || component == b"."
+                    || component == b".."
+                    || component.contains(&b'\\')
+                    || component.contains(&b'\0')
+            })
+    }
+
+    /// Records the file about to be appended, cancelling the traversal if it takes the
+    /// archive over any of its limits.
+    fn check_limits(&mut self, size: u64) -> Action {
+        self.entries += 1;
+        self.bytes = self.bytes.saturating_add(size);
+
+        let exceeded = if self.entries > self.limits.entries {
+            Some("the maximum number of entries")
+        } else if self.bytes > self.limits.bytes {
+            Some("the maximum uncompressed size")
+        } else if self.path.len() > self.limits.path_length {
+            Some("the maximum path length")
+        } else if self.path.find_iter("/").count() >= self.limits.path_depth {
+            Some("the maximum path depth")
+        } else {
+            None
+        };
+
+        if let Some(limit) = exceeded {
+            warn!(
+                entries = self.entries,
+                bytes = self.bytes,
+                path = %self.path,
+                "Abandoning archive which exceeds {limit}"
+            );
+            self.exceeded = Some(limit);
+            return Action::Cancel;
+        }
+
+        Action::Continue
+    }
+
+    fn pop_element(&mut self) {
+        if let Some(pos) = self.path.rfind_byte(b'/') {
+            self.path.resize(pos, 0);
+        } else {
+            self.path.clear();
+        }
+    }
+
+    fn push_element(&mut self, name: &BStr) {
+        if !self.path.is_empty() {
+            self.path.push(b'/');
+        }
+        self.path.push_str(name);
+    }
 }
 
-fn take_oid(v: ObjectId) -> [u8; 20] {
-    match v {
-        ObjectId::Sha1(v) => v,
+impl<'a> gix::traverse::tree::Visit for ArchivalVisitor<'a> {
+    fn pop_front_tracked_path_and_set_current(&mut self) {
+        self.path = self
+            .path_deque
+            .pop_front()
+            .expect("every call is matched with push_tracked_path_component");
     }
-}
 
-const BUFFER_CAP: usize = 512 * 1024;
+    fn push_back_tracked_path_component(&mut self, component: &BStr) {
+        self.push_element(component);
+        self.path_deque.push_back(self.path.clone());
+    }
 
-pub struct ArchivalVisitor<'a> {
+    fn push_path_component(&mut self, component: &BStr) {
+        self.push_element(component);
+    }
+
+    fn pop_path_component(&mut self) {
+        self.pop_element();
+    }
+
+    fn visit_tree(&mut self, _entry: &EntryRef<'_>) -> Action {
+        Action::Continue
+    }
+
+    fn visit_nontree(&mut self, entry: &EntryRef<'_>) -> Action {
+        let entry = entry.attach(self.repository);
+
+        // only the header is read to begin with, so a blob too large for the archive is
+        // never loaded into memory
+        let Ok(header) = self.repository.find_header(entry.oid()) else {
+            return Action::Continue;
+        };
+
+        if header.kind() != Kind::Blob {
+            return Action::Continue;
+        }
+
+        if self.is_unsafe_path() {
+            warn!(path = %self.path, "Skipping file with unsafe path in archive");
+            return Action::Continue;
+        }
+
+        if let Action::Cancel = self.check_limits(header.size()) {
+            return Action::Cancel;
+        }
+
+        let Ok(blob) = self.repository.find_blob(entry.oid()) else {
+            return Action::Continue;
+        };
+
+        let mut path = BString::from(self.prefix.as_str());
+        path.push_str(&self.path);
+
+        if let Err(error) = self
+            .archive
+            .append(path.as_ref(), entry.mode(), blob.data.as_slice())
+        {
+            warn!(%error, "Failed to append to archive");
+            return Action::Cancel;
+        }
+
+        if self.archive.buffer().len() >= BUFFER_CAP {
+            let b = self.archive.buffer().split().freeze();
+
+            if self.res.blocking_send(Ok(b)).is_err() {
+                return Action::Cancel;
+            }
+        }
+
+        Action::Continue
+    }
+}
+
+struct ManifestVisitor<'a> {
     repository: &'a gix::Repository,
     res: tokio::sync::mpsc::Sender<Result<Bytes, anyhow::Error>>,
-    archive: Builder<GzEncoder<Writer<BytesMut>>>,
+    buffer: BytesMut,
+    files: usize,
+    /// Whether the traversal was cancelled early, either because the tree held more than
+    /// [`PATH_LIST_LIMIT`] files or the client went away.
+    truncated: bool,
     path_deque: VecDeque<BString>,
     path: BString,
 }
 
-impl<'a> ArchivalVisitor<'a> {
+impl ManifestVisitor<'_> {
     fn pop_element(&mut self) {
         if let Some(pos) = self.path.rfind_byte(b'/') {
             self.path.resize(pos, 0);
@@ -540,7 +2649,7 @@ impl<'a> ArchivalVisitor<'a> {
     }
 }
 
-impl<'a> gix::traverse::tree::Visit for ArchivalVisitor<'a> {
+impl gix::traverse::tree::Visit for ManifestVisitor<'_> {
     fn pop_front_tracked_path_and_set_current(&mut self) {
         self.path = self
             .path_deque
@@ -566,46 +2675,139 @@ impl<'a> gix::traverse::tree::Visit for ArchivalVisitor<'a> {
     }
 
     fn visit_nontree(&mut self, entry: &EntryRef<'_>) -> Action {
-        let entry = entry.attach(self.repository);
+        if self.files >= PATH_LIST_LIMIT {
+            self.truncated = true;
+            return Action::Cancel;
+        }
 
-        let Ok(object) = entry.object() else {
-            return Action::Continue;
+        self.files += 1;
+
+        // submodules point to commits in another repository, so they've no size to show
+        let (kind, size) = if entry.mode.is_commit() {
+            ("commit", None)
+        } else {
+            let size = match self.repository.find_header(entry.oid) {
+                Ok(header) => Some(header.size()),
+                Err(error) => {
+                    warn!(%error, "Failed to read header of {}", entry.oid);
+                    None
+                }
+            };
+
+            ("blob", size)
         };
 
-        if object.kind != Kind::Blob {
-            return Action::Continue;
+        let size = size.map_or_else(|| "-".to_string(), |v| v.to_string());
+
+        let _res = write!(
+            self.buffer,
+            "{:06o} {kind} {} {size:>7}\t",
+            entry.mode.0, entry.oid
+        );
+        self.buffer.extend_from_slice(&self.path);
+        self.buffer.put_u8(b'\n');
+
+        if self.buffer.len() >= BUFFER_CAP {
+            let b = self.buffer.split().freeze();
+
+            if self.res.blocking_send(Ok(b)).is_err() {
+                self.truncated = true;
+                return Action::Cancel;
+            }
         }
 
-        let blob = object.into_blob();
+        Action::Continue
+    }
+}
+
+/// Maximum number of paths held in a [`PathList`], anything past this is dropped so
+/// pathological trees can't exhaust memory.
+const PATH_LIST_LIMIT: usize = 100_000;
 
-        let mut header = tar::Header::new_gnu();
-        if let Err(error) = header.set_path(self.path.to_path_lossy()) {
-            warn!(%error, "Attempted to write invalid path to archive");
-            return Action::Continue;
+/// Every file within a tree, see [`OpenRepository::path_list`].
+pub struct PathList {
+    pub paths: Vec<String>,
+    /// Whether the tree held more than [`PATH_LIST_LIMIT`] files and `paths` is incomplete.
+    pub truncated: bool,
+}
+
+struct PathListVisitor {
+    paths: Vec<String>,
+    truncated: bool,
+    path_deque: VecDeque<BString>,
+    path: BString,
+}
+
+impl PathListVisitor {
+    fn pop_element(&mut self) {
+        if let Some(pos) = self.path.rfind_byte(b'/') {
+            self.path.resize(pos, 0);
+        } else {
+            self.path.clear();
         }
-        header.set_size(blob.data.len() as u64);
-        #[allow(clippy::cast_sign_loss)]
-        header.set_mode(entry.mode().0.into());
-        header.set_cksum();
+    }
 
-        if let Err(error) = self.archive.append(&header, blob.data.as_slice()) {
-            warn!(%error, "Failed to append to archive");
-            return Action::Cancel;
+    fn push_element(&mut self, name: &BStr) {
+        if !self.path.is_empty() {
+            self.path.push(b'/');
         }
+        self.path.push_str(name);
+    }
+}
+
+impl gix::traverse::tree::Visit for PathListVisitor {
+    fn pop_front_tracked_path_and_set_current(&mut self) {
+        self.path = self
+            .path_deque
+            .pop_front()
+            .expect("every call is matched with push_tracked_path_component");
+    }
 
-        if self.archive.get_ref().get_ref().get_ref().len() >= BUFFER_CAP {
-            let b = self.archive.get_mut().get_mut().get_mut().split().freeze();
+    fn push_back_tracked_path_component(&mut self, component: &BStr) {
+        self.push_element(component);
+        self.path_deque.push_back(self.path.clone());
+    }
 
-            if self.res.blocking_send(Ok(b)).is_err() {
-                return Action::Cancel;
-            }
+    fn push_path_component(&mut self, component: &BStr) {
+        self.push_element(component);
+    }
+
+    fn pop_path_component(&mut self) {
+        self.pop_element();
+    }
+
+    fn visit_tree(&mut self, _entry: &EntryRef<'_>) -> Action {
+        Action::Continue
+    }
+
+    fn visit_nontree(&mut self, entry: &EntryRef<'_>) -> Action {
+        // submodules don't have a blob to link to
+        if entry.mode.is_commit() {
+            return Action::Continue;
+        }
+
+        if self.paths.len() >= PATH_LIST_LIMIT {
+            self.truncated = true;
+            return Action::Cancel;
         }
 
+        self.paths.push(self.path.to_str_lossy().into_owned());
+
         Action::Continue
     }
 }
 
-fn parse_and_transform_markdown(s: &str) -> String {
+pub fn parse_and_transform_markdown(s: &str) -> String {
+    render_markdown(s, None)
+}
+
+/// Renders a readme, pointing links and images relative to it at the directory it was found
+/// in rather than at whatever page it happens to be shown on.
+fn render_readme(s: &str, links: &ReadmeLinks) -> String {
+    render_markdown(s, Some(links))
+}
+
+fn render_markdown(s: &str, links: Option<&ReadmeLinks>) -> String {
     let mut plugins = ComrakPlugins::default();
 
     plugins.render.codefence_syntax_highlighter = Some(&ComrakHighlightAdapter);
@@ -620,17 +2822,117 @@ fn parse_and_transform_markdown(s: &str) -> String {
     options.extension.tagfilter = true;
     options.extension.tasklist = true;
 
+    if let Some(links) = links {
+        let (page_links, raw_links) = (links.clone(), links.clone());
+        options.extension.link_url_rewriter =
+            Some(Arc::new(move |url: &str| page_links.resolve(url, false)));
+        options.extension.image_url_rewriter =
+            Some(Arc::new(move |url: &str| raw_links.resolve(url, true)));
+    }
+
     comrak::markdown_to_html_with_plugins(s, &options, &plugins)
 }
 
+/// The directory a readme was found in, which relative links within it are resolved against.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct ReadmeLinks {
+    pub repository: Arc<str>,
+    pub directory: PathBuf,
+    pub branch: Option<Arc<str>>,
+}
+
+impl ReadmeLinks {
+    /// Points `url` at the page for the file it names, or at its raw content if `raw`, leaving
+    /// anything that isn't relative to the readme alone. Links climbing out of the repository
+    /// are left alone too, there's nothing sensible for them to point at.
+    pub fn resolve(&self, url: &str, raw: bool) -> String {
+        if url.is_empty() || url.starts_with(['/', '#', '?']) || has_url_scheme(url) {
+            return url.to_string();
+        }
+
+        let (path, fragment) = url
+            .split_once('#')
+            .map_or((url, None), |(path, fragment)| (path, Some(fragment)));
+        // queries would only be meaningful to whatever served the file originally
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let path = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+
+        let resolved = self.directory.join(&*path).clean();
+        if resolved.starts_with("..") {
+            return url.to_string();
+        }
+
+        let mut out = format!(
+            "/{}/tree/{}",
+            utf8_percent_encode(&self.repository, LINK_ENCODE_SET),
+            utf8_percent_encode(&resolved.to_string_lossy(), LINK_ENCODE_SET),
+        );
+
+        let mut separator = '?';
+        if raw {
+            out.push_str("?raw=true");
+            separator = '&';
+        }
+        if let Some(branch) = &self.branch {
+            out.push(separator);
+            out.push_str("h=");
+            out.extend(utf8_percent_encode(branch, LINK_ENCODE_SET));
+        }
+        if let Some(fragment) = fragment {
+            out.push('#');
+            out.push_str(fragment);
+        }
+
+        out
+    }
+}
+
+/// Whether `url` starts with a scheme, such as `https:` or `mailto:`, and so isn't relative.
+fn has_url_scheme(url: &str) -> bool {
+    let Some((scheme, _)) = url.split_once(':') else {
+        return false;
+    };
+
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+}
+
+/// Names a readme can be given in a tree, matched case-insensitively, the first one found is
+/// shown.
+const README_FILES: &[(&str, ReadmeFormat)] = &[
+    ("README.md", ReadmeFormat::Markdown),
+    ("README.markdown", ReadmeFormat::Markdown),
+    ("README", ReadmeFormat::Plaintext),
+    ("README.txt", ReadmeFormat::Plaintext),
+    ("README.rst", ReadmeFormat::Plaintext),
+];
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ReadmeFormat {
     Markdown,
     Plaintext,
 }
 
+/// A readme found in a tree, ready to be shown.
+#[derive(Clone, Debug)]
+pub struct Readme {
+    /// The name of the readme, as it's cased in the tree.
+    pub name: Arc<str>,
+    pub format: ReadmeFormat,
+    /// The readme rendered to HTML if it's markdown, otherwise its raw content.
+    pub content: Arc<str>,
+    /// Whether the readme wasn't valid UTF-8, and had invalid bytes replaced to be shown.
+    pub lossy: bool,
+}
+
 pub enum PathDestination {
-    Tree(Vec<TreeItem>),
+    Tree {
+        items: Vec<TreeItem>,
+        /// The readme within the tree, if one was asked for.
+        readme: Option<Readme>,
+    },
     File(FileWithContent),
 }
 
@@ -648,33 +2950,315 @@ pub struct Submodule {
     pub oid: ObjectId,
 }
 
-#[derive(Debug)]
-pub struct Tree {
-    pub mode: u16,
-    pub name: String,
-    pub children: PathBuf,
-    pub path: PathBuf,
-}
+#[derive(Debug)]
+pub struct Tree {
+    pub mode: u16,
+    pub name: String,
+    pub children: PathBuf,
+    pub path: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct File {
+    pub mode: u16,
+    pub size: usize,
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug)]
+#[allow(unused)]
+pub struct FileWithContent {
+    pub metadata: File,
+    pub content: Content,
+}
+
+/// Splits `spec`, a ref or commit oid followed by a path, returning the two along with the
+/// tree the ref points to. Refs can contain slashes of their own, so the shortest prefix of
+/// `spec` naming a ref is taken, git refusing to create refs that would be ambiguous within
+/// the same namespace.
+fn split_reference_path<'a, 'b>(
+    repo: &'a gix::Repository,
+    spec: &'b str,
+) -> Result<Option<(&'b str, &'b str, gix::Tree<'a>)>> {
+    for (idx, _) in spec.match_indices('/') {
+        let (reference, path) = (&spec[..idx], &spec[idx + 1..]);
+
+        let tree = if let Ok(oid) = ObjectId::from_hex(reference.as_bytes()) {
+            match repo.try_find_object(oid)? {
+                Some(object) => object.peel_to_tree()?,
+                None => continue,
+            }
+        } else {
+            // names that aren't valid refs can't name one either
+            match repo.try_find_reference(reference).ok().flatten() {
+                Some(mut reference) => reference.peel_to_tree()?,
+                None => continue,
+            }
+        };
+
+        return Ok(Some((reference, path, tree)));
+    }
+
+    Ok(None)
+}
+
+/// A file requested by its ref and path, see [`OpenRepository::raw`].
+pub struct RawFile {
+    /// The ref or commit oid the file was found within.
+    pub reference: String,
+    pub oid: ObjectId,
+    pub path: PathBuf,
+    pub content: Content,
+}
+
+/// Unique abbreviations of object ids within a repository, see
+/// [`OpenRepository::abbreviations`].
+#[derive(Default)]
+pub struct Abbreviations(HashMap<ObjectId, usize>);
+
+impl Abbreviations {
+    /// Abbreviates the hex object id `hex`, falling back to [`MIN_ABBREV_LEN`] characters for
+    /// ids that weren't looked up.
+    pub fn abbreviate<'a>(&self, hex: &'a str) -> &'a str {
+        let len = ObjectId::from_hex(hex.as_bytes())
+            .ok()
+            .and_then(|oid| self.0.get(&oid).copied())
+            .unwrap_or(MIN_ABBREV_LEN);
+
+        hex.get(..len).unwrap_or(hex)
+    }
+}
+
+/// Two refs compared against each other, see [`OpenRepository::compare`].
+pub struct Comparison {
+    /// The commit the two diverged at, `None` if they have no history in common.
+    pub merge_base: Option<ObjectId>,
+    /// Commits made on the head since it diverged, newest first.
+    pub commits: Vec<Yoke<CommitInner<'static>, Vec<u8>>>,
+    /// Number of commits made on the head beyond those in [`Self::commits`].
+    pub more_commits: usize,
+    /// Whether the head's history was too long to count in full, making
+    /// [`Self::more_commits`] a lower bound.
+    pub more_commits_capped: bool,
+    pub diff_stats: String,
+    pub diff: String,
+}
+
+impl Comparison {
+    /// Whether there's anything to show, which isn't the case if the head has nothing that
+    /// isn't already part of the base.
+    pub fn is_empty(&self) -> bool {
+        self.commits.is_empty() && self.diff.is_empty()
+    }
+}
+
+/// What a bundle of the repository is built from, see [`OpenRepository::bundle_basis`].
+pub struct BundleBasis {
+    /// Digest of the refs the bundle holds, changing whenever any of them move.
+    pub refs_digest: u64,
+    /// Whether HEAD points to a commit, rather than being unborn. Only included in bundles of
+    /// every branch and tag.
+    pub head: bool,
+    /// Full names of the refs the bundle holds, or empty for every branch and tag.
+    pub refs: Vec<BString>,
+    /// The commit the bundle builds on top of, objects reachable from it are left out.
+    pub since: Option<ObjectId>,
+}
+
+/// A format blobs can be rendered in, in place of being shown as highlighted text.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RichFormat {
+    Notebook,
+    Csv,
+    Tsv,
+}
+
+impl RichFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("ipynb") => Some(Self::Notebook),
+            Some("csv") => Some(Self::Csv),
+            Some("tsv") => Some(Self::Tsv),
+            _ => None,
+        }
+    }
+
+    fn render(self, content: &str) -> Result<RenderedBlob> {
+        match self {
+            Self::Notebook => Ok(RenderedBlob {
+                html: crate::notebook::render(content)?,
+                truncated: false,
+            }),
+            Self::Csv => crate::csv_table::render(content, b','),
+            Self::Tsv => crate::csv_table::render(content, b'\t'),
+        }
+    }
+}
+
+/// A blob rendered in a [`RichFormat`].
+#[derive(Debug)]
+pub struct RenderedBlob {
+    pub html: String,
+    /// Whether only part of the blob was rendered.
+    pub truncated: bool,
+}
+
+#[derive(Debug)]
+pub enum Content {
+    Text(Cow<'static, str>),
+    /// Text that wasn't valid UTF-8, with the invalid bytes replaced so it can be shown.
+    Lossy(Cow<'static, str>),
+    /// A blob rendered in a [`RichFormat`].
+    Rendered(Arc<RenderedBlob>),
+    Binary(Vec<u8>),
+    Stream(BlobStream),
+    /// The blob is missing from the object database, as happens in partial clones.
+    Unavailable,
+}
+
+/// A handle to a blob that is too large to buffer in memory.
+#[derive(Debug)]
+pub struct BlobStream {
+    repository_path: PathBuf,
+    oid: ObjectId,
+    size: u64,
+}
+
+impl BlobStream {
+    /// Reads the bytes of the blob within `range` in chunks on a blocking thread, returning a
+    /// channel that yields each chunk as it's read.
+    ///
+    /// gitoxide can only decode objects in their entirety, so we lean on `git cat-file` to
+    /// do the decompression for us. Its output can't be seeked, so anything before the range
+    /// is read and discarded. The subprocess is killed as soon as the receiving end of the
+    /// channel goes away (ie. the client disconnected) or the range has been read.
+    fn spawn(
+        self,
+        range: std::ops::Range<u64>,
+    ) -> tokio::sync::mpsc::Receiver<Result<Bytes, std::io::Error>> {
+        let (send, recv) = tokio::sync::mpsc::channel(4);
+
+        tokio::task::spawn_blocking(move || {
+            let mut child = match std::process::Command::new("git")
+                .arg("cat-file")
+                .arg("blob")
+                .arg(self.oid.to_string())
+                .current_dir(&self.repository_path)
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::null())
+                .spawn()
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(error = %e, "Failed to spawn git cat-file");
+                    let _res = send.blocking_send(Err(e));
+                    return;
+                }
+            };
+
+            let Some(stdout) = child.stdout.take() else {
+                let _res = child.kill();
+                return;
+            };
+
+            let mut stdout = stdout.take(range.end);
+            let skipped = std::io::copy(&mut (&mut stdout).take(range.start), &mut std::io::sink());
+            if let Err(e) = skipped.and_then(|n| {
+                if n < range.start {
+                    Err(truncated_blob())
+                } else {
+                    Ok(())
+                }
+            }) {
+                let _res = send.blocking_send(Err(e));
+                let _res = child.kill();
+                let _res = child.wait();
+                return;
+            }
+
+            let mut remaining = range.end - range.start;
+
+            loop {
+                let mut buf = BytesMut::zeroed(STREAMING_CHUNK_SIZE);
+
+                let (out, end) = match stdout.read(&mut buf) {
+                    // the Content-Length has already been sent, so ending cleanly here would
+                    // leave the client with a truncated blob it can't tell apart from the
+                    // real thing
+                    Ok(0) if remaining == 0 => break,
+                    Ok(0) => (Err(truncated_blob()), true),
+                    Ok(n) => {
+                        buf.truncate(n);
+                        remaining -= n as u64;
+                        (Ok(buf.freeze()), false)
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => (Err(e), true),
+                };
+
+                if send.blocking_send(out).is_err() {
+                    warn!("Receiver went away whilst streaming blob");
+                    let _res = child.kill();
+                    break;
+                }
+
+                if end {
+                    let _res = child.kill();
+                    break;
+                }
+            }
+
+            // the rest of the blob isn't wanted if only part of it was asked for
+            let _res = child.kill();
+            if let Err(error) = child.wait() {
+                error!(%error, "Failed to wait on git cat-file shutdown");
+            }
+        });
 
-#[derive(Debug)]
-pub struct File {
-    pub mode: u16,
-    pub size: usize,
-    pub name: String,
-    pub path: PathBuf,
+        recv
+    }
 }
 
-#[derive(Debug)]
-#[allow(unused)]
-pub struct FileWithContent {
-    pub metadata: File,
-    pub content: Content,
+/// `git cat-file` ended before the whole blob was read, such as when it's killed partway.
+fn truncated_blob() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "git cat-file ended before the whole blob was read",
+    )
 }
 
-#[derive(Debug)]
-pub enum Content {
-    Text(Cow<'static, str>),
-    Binary(Vec<u8>),
+impl Content {
+    /// The length in bytes of the content as it's served raw, `None` for content that's
+    /// served as something else entirely.
+    pub fn raw_len(&self) -> Option<u64> {
+        match self {
+            Self::Text(t) | Self::Lossy(t) => Some(t.len() as u64),
+            Self::Binary(b) => Some(b.len() as u64),
+            Self::Stream(stream) => Some(stream.size),
+            Self::Rendered(_) | Self::Unavailable => None,
+        }
+    }
+
+    /// The bytes of the content within `range`, for answering range requests. `range` must
+    /// lie within [`Self::raw_len`].
+    pub fn into_range_body(self, range: std::ops::Range<u64>) -> Option<axum::body::Body> {
+        let slice = |len: usize| {
+            usize::try_from(range.start).unwrap_or(len)..usize::try_from(range.end).unwrap_or(len)
+        };
+
+        match self {
+            Self::Text(t) | Self::Lossy(t) => {
+                Some(Bytes::copy_from_slice(t.as_bytes().get(slice(t.len()))?).into())
+            }
+            Self::Binary(b) => Some(Bytes::copy_from_slice(b.get(slice(b.len()))?).into()),
+            Self::Stream(stream) => Some(axum::body::Body::from_stream(ReceiverStream::new(
+                stream.spawn(range),
+            ))),
+            Self::Rendered(_) | Self::Unavailable => None,
+        }
+    }
 }
 
 impl IntoResponse for Content {
@@ -682,7 +3266,7 @@ impl IntoResponse for Content {
         use axum::http;
 
         match self {
-            Self::Text(t) => {
+            Self::Text(t) | Self::Lossy(t) => {
                 let headers = [(
                     http::header::CONTENT_TYPE,
                     http::HeaderValue::from_static("text/plain; charset=UTF-8"),
@@ -690,6 +3274,14 @@ impl IntoResponse for Content {
 
                 (headers, t).into_response()
             }
+            Self::Rendered(t) => {
+                let headers = [(
+                    http::header::CONTENT_TYPE,
+                    http::HeaderValue::from_static("text/html; charset=UTF-8"),
+                )];
+
+                (headers, t.html.clone()).into_response()
+            }
             Self::Binary(b) => {
                 let headers = [(
                     http::header::CONTENT_TYPE,
@@ -698,14 +3290,31 @@ impl IntoResponse for Content {
 
                 (headers, b).into_response()
             }
+            Self::Stream(stream) => {
+                let headers = [
+                    (
+                        http::header::CONTENT_TYPE,
+                        http::HeaderValue::from_static("application/octet-stream"),
+                    ),
+                    (http::header::CONTENT_LENGTH, stream.size.into()),
+                ];
+
+                let size = stream.size;
+                let body =
+                    axum::body::Body::from_stream(ReceiverStream::new(stream.spawn(0..size)));
+
+                (headers, body).into_response()
+            }
+            Self::Unavailable => (http::StatusCode::NOT_FOUND, OBJECT_UNAVAILABLE).into_response(),
         }
     }
 }
 
 #[derive(Debug)]
-pub enum TaggedObject<'a> {
-    Commit(&'a BStr),
-    Tree(&'a BStr),
+pub enum TaggedObject {
+    Commit(ObjectId),
+    Tree(ObjectId),
+    Blob(ObjectId),
 }
 
 #[derive(Debug, Yokeable)]
@@ -713,7 +3322,17 @@ pub struct DetailedTag<'a> {
     pub name: Arc<str>,
     pub tagger: Option<CommitUser<'a>>,
     pub message: &'a BStr,
-    pub tagged_object: Option<TaggedObject<'a>>,
+    pub tagged_object: TaggedObject,
+    /// Names of the tags this tag points at on the way to [`Self::tagged_object`], for tags
+    /// of tags.
+    pub via: Vec<String>,
+}
+
+impl DetailedTag<'_> {
+    /// Whether the tag can be downloaded as a snapshot, which tags of blobs can't.
+    pub fn has_snapshot(&self) -> bool {
+        !matches!(self.tagged_object, TaggedObject::Blob(_))
+    }
 }
 
 #[derive(Debug)]
@@ -731,8 +3350,6 @@ impl<'a> TryFrom<SignatureRef<'a>> for CommitUser<'a> {
             name: v.name,
             email: v.email,
             time: (v.time.seconds, v.time.offset),
-            // time: OffsetDateTime::from_unix_timestamp(v.time.seconds)?
-            //     .to_offset(UtcOffset::from_whole_seconds(v.time.offset)?),
         })
     }
 }
@@ -747,9 +3364,86 @@ impl CommitUser<'_> {
     }
 
     pub fn time(&self) -> OffsetDateTime {
-        OffsetDateTime::from_unix_timestamp(self.time.0)
-            .unwrap()
-            .to_offset(UtcOffset::from_whole_seconds(self.time.1).unwrap())
+        signature_time(self.time.0, self.time.1)
+    }
+}
+
+/// Largest number of commits walked when building the history of a single file.
+const FILE_HISTORY_WALK_LIMIT: usize = 50_000;
+
+/// Most commits listed when comparing two refs, see [`OpenRepository::compare`].
+const COMPARE_COMMIT_LIMIT: usize = 250;
+
+/// Most commits walked when comparing two refs, commits past [`COMPARE_COMMIT_LIMIT`] are only
+/// counted, and only up to this many.
+const COMPARE_WALK_LIMIT: usize = 10_000;
+
+pub struct FileHistory {
+    pub entries: Vec<FileHistoryEntry>,
+    /// Whether the walk hit the boundary of a shallow mirror before running out of history.
+    pub truncated: bool,
+}
+
+/// A commit which changed a file, as part of that file's history.
+pub struct FileHistoryEntry {
+    commit: Yoke<CommitInner<'static>, Vec<u8>>,
+    /// Path the file was renamed from in this commit, if it was.
+    pub renamed_from: Option<String>,
+    /// Lines the commit changed in the file, if they were asked to be counted.
+    pub changes: Option<LineChanges>,
+}
+
+/// Lines added to and removed from a single file by a commit.
+#[derive(Debug, Clone, Copy)]
+pub struct LineChanges {
+    pub insertions: u32,
+    pub deletions: u32,
+}
+
+/// Counts the lines changed between two versions of a file, either of which may not exist.
+/// Returns `None` if either is binary, or missing from a partial clone.
+fn count_line_changes(
+    repo: &gix::Repository,
+    old: Option<ObjectId>,
+    new: Option<ObjectId>,
+) -> Result<Option<LineChanges>> {
+    let read = |id: Option<ObjectId>| -> Result<Option<Vec<u8>>> {
+        let Some(id) = id else {
+            return Ok(Some(Vec::new()));
+        };
+
+        Ok(repo
+            .try_find_object(id)?
+            .and_then(|v| v.try_into_blob().ok())
+            .map(|v| v.detach().data)
+            .filter(|data| !is_binary(data)))
+    };
+
+    let (Some(old), Some(new)) = (read(old)?, read(new)?) else {
+        return Ok(None);
+    };
+
+    let old = String::from_utf8_lossy(&old);
+    let new = String::from_utf8_lossy(&new);
+    let input = gix::diff::blob::intern::InternedInput::new(
+        gix::diff::blob::sources::lines_with_terminator(&old),
+        gix::diff::blob::sources::lines_with_terminator(&new),
+    );
+    let counter = gix::diff::blob::diff(
+        gix::diff::blob::Algorithm::Histogram,
+        &input,
+        gix::diff::blob::sink::Counter::default(),
+    );
+
+    Ok(Some(LineChanges {
+        insertions: counter.insertions,
+        deletions: counter.removals,
+    }))
+}
+
+impl FileHistoryEntry {
+    pub fn commit(&self) -> &CommitInner<'_> {
+        self.commit.get()
     }
 }
 
@@ -775,6 +3469,8 @@ pub struct CommitInner<'a> {
     parents: SmallVec<&'a BStr>,
     summary: Cow<'a, BStr>,
     body: &'a BStr,
+    /// Length in bytes of the message as it was written, before any trimming.
+    message_len: usize,
 }
 
 #[derive(Debug)]
@@ -819,6 +3515,7 @@ impl<'a> CommitInner<'a> {
 
             summary: message.summary(),
             body: message.body.unwrap_or_else(|| BStr::new("")),
+            message_len: commit.message.len(),
         })
     }
 }
@@ -838,6 +3535,10 @@ impl CommitInner<'_> {
         DisplayHexBuffer(buf)
     }
 
+    pub fn id(&self) -> ObjectId {
+        ObjectId::from(self.oid)
+    }
+
     pub fn tree(&self) -> &BStr {
         self.tree
     }
@@ -853,29 +3554,96 @@ impl CommitInner<'_> {
     pub fn body(&self) -> &BStr {
         self.body
     }
+
+    pub fn message_len(&self) -> usize {
+        self.message_len
+    }
 }
 
 #[instrument(skip(repo, commit))]
 fn fetch_diff_and_stats(
     repo: &gix::Repository,
     commit: &gix::Commit<'_>,
-    highlight: bool,
+    format: &DiffFormat,
 ) -> Result<(String, String)> {
-    const WIDTH: usize = 80;
-
     let current_tree = commit.tree().context("Couldn't get tree for the commit")?;
-    let parent_tree = commit
+    let parent = commit
         .ancestors()
         .first_parent_only()
         .all()?
         .nth(1)
         .transpose()?
         .map(|v| v.object())
-        .transpose()?
-        .map(|v| v.tree())
+        .transpose()?;
+    let parent_tree = parent
+        .as_ref()
+        .map(gix::Commit::tree)
         .transpose()?
         .unwrap_or_else(|| repo.empty_tree());
 
+    let links = match format {
+        DiffFormat::Plain => None,
+        DiffFormat::Highlighted { repository } => Some(DiffLinks {
+            repository,
+            old: parent.as_ref().map(|v| v.id),
+            new: commit.id,
+        }),
+    };
+
+    diff_trees(repo, &parent_tree, &current_tree, links)
+}
+
+/// How the diff of a commit is rendered.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DiffFormat {
+    /// Plain text, as `git diff` would write it.
+    Plain,
+    /// Highlighted HTML, with the line numbers of each line linking to the file on either side
+    /// of the diff within the repository served at `repository`.
+    Highlighted { repository: Arc<str> },
+}
+
+impl DiffFormat {
+    pub fn highlighted(repository: &Path) -> Self {
+        Self::Highlighted {
+            repository: Arc::from(repository.to_string_lossy()),
+        }
+    }
+}
+
+/// Where the line numbers of a highlighted diff link to, the commits on the `old` and `new`
+/// side of the diff in the repository served at `repository`.
+#[derive(Debug, Clone, Copy)]
+struct DiffLinks<'a> {
+    repository: &'a str,
+    /// `None` for root commits, which have nothing on the old side to link to.
+    old: Option<ObjectId>,
+    new: ObjectId,
+}
+
+/// Characters escaped in the paths of links within diffs, enough to keep them within both
+/// the path of the URL and the attribute they're written to.
+pub const LINK_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?');
+
+/// Diffs `current_tree` against `parent_tree`, returning the diffstat and the diff itself. The
+/// diff is highlighted if given somewhere for its line numbers to link to.
+fn diff_trees(
+    repo: &gix::Repository,
+    parent_tree: &gix::Tree<'_>,
+    current_tree: &gix::Tree<'_>,
+    links: Option<DiffLinks<'_>>,
+) -> Result<(String, String)> {
+    const WIDTH: usize = 80;
+
     let mut diffs = Vec::new();
     let mut diff_output = String::new();
 
@@ -886,21 +3654,24 @@ fn fetch_diff_and_stats(
         opts.track_path().track_rewrites(None);
     });
     changes.for_each_to_obtain_tree_with_cache(
-        &current_tree,
+        current_tree,
         &mut repo.diff_resource_cache_for_tree_diff()?,
         |change| {
-            if highlight {
+            if let Some(links) = links {
                 DiffBuilder {
+                    repo,
                     output: &mut diff_output,
                     resource_cache: &mut resource_cache,
                     diffs: &mut diffs,
                     formatter: SyntaxHighlightedDiffFormatter::new(
                         change.location().to_path().unwrap(),
+                        links,
                     ),
                 }
                 .handle(change)
             } else {
                 DiffBuilder {
+                    repo,
                     output: &mut diff_output,
                     resource_cache: &mut resource_cache,
                     diffs: &mut diffs,
@@ -993,6 +3764,9 @@ struct FileDiff {
 trait DiffFormatter {
     fn file_header(&self, output: &mut String, data: fmt::Arguments<'_>);
 
+    /// Called with the full content of both sides of a text file before its diff is written.
+    fn sources(&mut self, _old: &str, _new: &str) {}
+
     fn binary(
         &self,
         output: &mut String,
@@ -1004,6 +3778,7 @@ trait DiffFormatter {
 }
 
 struct DiffBuilder<'a, F> {
+    repo: &'a gix::Repository,
     output: &'a mut String,
     resource_cache: &'a mut gix::diff::blob::Platform,
     diffs: &'a mut Vec<FileDiff>,
@@ -1027,7 +3802,27 @@ impl<'a, F: DiffFormatter + Callback> DiffBuilder<'a, F> {
         };
         let change = change.diff(self.resource_cache)?;
 
-        let prep = change.resource_cache.prepare_diff()?;
+        let prep = match change.resource_cache.prepare_diff() {
+            Ok(prep) => prep,
+            Err(error) => {
+                let error = anyhow::Error::from(error);
+
+                if !is_missing_object(&error) {
+                    return Err(error);
+                }
+
+                self.formatter.file_header(
+                    self.output,
+                    format_args!("diff --git a/{0} b/{0}", diff.path),
+                );
+                self.formatter
+                    .file_header(self.output, format_args!("{OBJECT_UNAVAILABLE}"));
+                self.diffs.push(diff);
+
+                self.resource_cache.clear_resource_cache_keep_allocation();
+                return Ok(gix::object::tree::diff::Action::Continue);
+            }
+        };
 
         self.formatter.file_header(
             self.output,
@@ -1091,8 +3886,12 @@ impl<'a, F: DiffFormatter + Callback> DiffBuilder<'a, F> {
                     self.output,
                     format_args!(
                         "index {}..{}{index_suffix_sep}{index_suffix}",
-                        prep.old.id.to_hex_with_len(7),
-                        prep.new.id.to_hex_with_len(7)
+                        prep.old
+                            .id
+                            .to_hex_with_len(abbreviation_len(self.repo, prep.old.id.to_owned())),
+                        prep.new
+                            .id
+                            .to_hex_with_len(abbreviation_len(self.repo, prep.new.id.to_owned()))
                     ),
                 );
                 self.formatter
@@ -1100,12 +3899,12 @@ impl<'a, F: DiffFormatter + Callback> DiffBuilder<'a, F> {
                 self.formatter
                     .file_header(self.output, format_args!("+++ {new_path}"));
 
-                let old_source = gix::diff::blob::sources::lines_with_terminator(
-                    simdutf8::basic::from_utf8(prep.old.data.as_slice().unwrap_or_default())?,
-                );
-                let new_source = gix::diff::blob::sources::lines_with_terminator(
-                    simdutf8::basic::from_utf8(prep.new.data.as_slice().unwrap_or_default())?,
-                );
+                let old = simdutf8::basic::from_utf8(prep.old.data.as_slice().unwrap_or_default())?;
+                let new = simdutf8::basic::from_utf8(prep.new.data.as_slice().unwrap_or_default())?;
+                self.formatter.sources(old, new);
+
+                let old_source = gix::diff::blob::sources::lines_with_terminator(old);
+                let new_source = gix::diff::blob::sources::lines_with_terminator(new);
                 let input = gix::diff::blob::intern::InternedInput::new(old_source, new_source);
 
                 let output = gix::diff::blob::diff(
@@ -1166,33 +3965,98 @@ impl DiffFormatter for PlainDiffFormatter {
 }
 
 impl Callback for PlainDiffFormatter {
-    fn addition(&mut self, data: &str, dst: &mut String) {
+    fn addition(&mut self, _line: u32, data: &str, dst: &mut String) {
         write!(dst, "+{data}").unwrap();
     }
 
-    fn remove(&mut self, data: &str, dst: &mut String) {
+    fn remove(&mut self, _line: u32, data: &str, dst: &mut String) {
         write!(dst, "-{data}").unwrap();
     }
 
-    fn context(&mut self, data: &str, dst: &mut String) {
+    fn context(&mut self, _old_line: u32, _new_line: u32, data: &str, dst: &mut String) {
         write!(dst, " {data}").unwrap();
     }
 }
 
+/// Files larger than this have each line of their diff highlighted on its own, rather than
+/// highlighting both sides in their entirety to get the lines that were changed.
+const WHOLE_FILE_HIGHLIGHT_LIMIT: usize = 1024 * 1024;
+
 struct SyntaxHighlightedDiffFormatter<'a> {
     path: &'a Path,
+    links: DiffLinks<'a>,
+    /// Every line of the old and new side of the file. Highlighting each side as a whole keeps
+    /// constructs spanning multiple lines, such as block comments and multi-line strings,
+    /// highlighted correctly when only part of them is in the diff.
+    old_lines: Vec<String>,
+    new_lines: Vec<String>,
 }
 
 impl<'a> SyntaxHighlightedDiffFormatter<'a> {
-    fn new(path: &'a Path) -> Self {
-        Self { path }
+    fn new(path: &'a Path, links: DiffLinks<'a>) -> Self {
+        Self {
+            path,
+            links,
+            old_lines: Vec::new(),
+            new_lines: Vec::new(),
+        }
+    }
+
+    fn highlight_side(&self, content: &str) -> Vec<String> {
+        if content.len() > WHOLE_FILE_HIGHLIGHT_LIMIT {
+            return Vec::new();
+        }
+
+        highlight_lines(content, FileIdentifier::Path(self.path)).unwrap_or_else(|error| {
+            warn!(%error, "Failed to highlight {}", self.path.display());
+            Vec::new()
+        })
     }
 
-    fn write(&self, output: &mut String, class: &str, data: &str) {
-        write!(output, r#"<span class="diff-{class}">"#).unwrap();
-        format_file_inner(output, data, FileIdentifier::Path(self.path), false).unwrap();
+    fn write(
+        &self,
+        output: &mut String,
+        class: &str,
+        lines: (Option<u32>, Option<u32>),
+        highlighted: Option<&String>,
+        data: &str,
+    ) {
+        write!(
+            output,
+            r#"<span class="diff-{class}"><span class="diff-line-numbers">"#
+        )
+        .unwrap();
+        self.line_number(output, self.links.old, lines.0);
+        self.line_number(output, Some(self.links.new), lines.1);
+        output.push_str("</span>");
+
+        if let Some(highlighted) = highlighted {
+            output.push_str(highlighted);
+            output.push('\n');
+        } else {
+            format_file_inner(output, data, FileIdentifier::Path(self.path), false).unwrap();
+        }
+
         write!(output, r#"</span>"#).unwrap();
     }
+
+    /// Writes the number of the `line` on one side of the diff, linking to that line of the
+    /// file as of `commit`. Lines only on the other side get an empty cell.
+    fn line_number(&self, output: &mut String, commit: Option<ObjectId>, line: Option<u32>) {
+        let (Some(commit), Some(line)) = (commit, line) else {
+            output.push_str("<span></span>");
+            return;
+        };
+
+        let line = line + 1;
+        write!(
+            output,
+            r#"<a href="/{}/tree/{}?id={commit}#L{line}">{line}</a>"#,
+            utf8_percent_encode(self.links.repository, LINK_ENCODE_SET),
+            utf8_percent_encode(&self.path.to_string_lossy(), LINK_ENCODE_SET),
+        )
+        .unwrap();
+    }
 }
 
 impl<'a> DiffFormatter for SyntaxHighlightedDiffFormatter<'a> {
@@ -1202,6 +4066,11 @@ impl<'a> DiffFormatter for SyntaxHighlightedDiffFormatter<'a> {
         writeln!(output, r#"</span>"#).unwrap();
     }
 
+    fn sources(&mut self, old: &str, new: &str) {
+        self.old_lines = self.highlight_side(old);
+        self.new_lines = self.highlight_side(new);
+    }
+
     fn binary(
         &self,
         output: &mut String,
@@ -1215,15 +4084,624 @@ impl<'a> DiffFormatter for SyntaxHighlightedDiffFormatter<'a> {
 }
 
 impl<'a> Callback for SyntaxHighlightedDiffFormatter<'a> {
-    fn addition(&mut self, data: &str, dst: &mut String) {
-        self.write(dst, "add-line", data);
+    fn addition(&mut self, line: u32, data: &str, dst: &mut String) {
+        let highlighted = self.new_lines.get(line as usize);
+        self.write(dst, "add-line", (None, Some(line)), highlighted, data);
+    }
+
+    fn remove(&mut self, line: u32, data: &str, dst: &mut String) {
+        let highlighted = self.old_lines.get(line as usize);
+        self.write(dst, "remove-line", (Some(line), None), highlighted, data);
+    }
+
+    fn context(&mut self, old_line: u32, new_line: u32, data: &str, dst: &mut String) {
+        let highlighted = self.old_lines.get(old_line as usize);
+        self.write(
+            dst,
+            "context",
+            (Some(old_line), Some(new_line)),
+            highlighted,
+            data,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures_util::future::join_all;
+    use gix::ObjectId;
+
+    use anyhow::Context as _;
+
+    use super::{
+        clone_shared_error, find_default_branch, peel_nested_tag, retry_transient, take_oid,
+        ArchiveFormat, ArchiveLimits, BlobStream, CacheConfig, CommitNotFound, Content,
+        FileWithContent, Git, PathDestination, RepositoryBusy, MISSING_REPOSITORY_TTL,
+        STREAMING_CHUNK_SIZE,
+    };
+    use crate::testing::{TestRepo, COLLIDING_BLOBS};
+
+    #[test]
+    fn default_branch() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        repo.git(&["branch", "stable"]);
+
+        // unset, HEAD's branch is the default
+        assert_eq!(
+            find_default_branch(&repo.open()).unwrap(),
+            "refs/heads/main"
+        );
+
+        repo.git(&["config", "rgit.default-branch", "stable"]);
+        assert_eq!(
+            find_default_branch(&repo.open()).unwrap(),
+            "refs/heads/stable"
+        );
+
+        repo.git(&["config", "rgit.default-branch", "missing"]);
+        assert_eq!(
+            find_default_branch(&repo.open()).unwrap(),
+            "refs/heads/main"
+        );
+    }
+
+    #[test]
+    fn default_branch_detached() {
+        let repo = TestRepo::new();
+        let oid = repo.commit("README", "hello");
+        repo.git(&["update-ref", "--no-deref", "HEAD", &oid]);
+
+        assert_eq!(find_default_branch(&repo.open()).unwrap(), "HEAD");
+    }
+
+    #[test]
+    fn default_branch_renamed() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        repo.git(&["branch", "--move", "main", "trunk"]);
+        repo.git(&["symbolic-ref", "HEAD", "refs/heads/gone"]);
+
+        assert_eq!(
+            find_default_branch(&repo.open()).unwrap(),
+            "refs/heads/trunk"
+        );
+
+        repo.git(&["branch", "master", "trunk"]);
+        assert_eq!(
+            find_default_branch(&repo.open()).unwrap(),
+            "refs/heads/master"
+        );
+    }
+
+    #[test]
+    fn default_branch_empty() {
+        let repo = TestRepo::new();
+
+        assert_eq!(
+            find_default_branch(&repo.open()).unwrap(),
+            "refs/heads/main"
+        );
+    }
+
+    /// Builds an archive of `tree` within `repo`, returning its bytes or why it was abandoned.
+    async fn archive(
+        repo: &TestRepo,
+        tree: &str,
+        limits: ArchiveLimits,
+    ) -> Result<Vec<u8>, String> {
+        let git = Arc::new(Git::new(CacheConfig::default(), u64::MAX));
+        let open_repo = git.repo(repo.path().to_path_buf(), None).await.unwrap();
+
+        let (send, mut recv) = tokio::sync::mpsc::channel(1);
+        let (cont, _cont) = tokio::sync::oneshot::channel();
+        let tree = ObjectId::from_hex(tree.as_bytes()).unwrap();
+        let build = tokio::spawn(open_repo.archive(
+            send,
+            cont,
+            tree,
+            "repo/".to_string(),
+            ArchiveFormat::TarGz,
+            limits,
+        ));
+
+        let mut archive = Vec::new();
+        while let Some(chunk) = recv.recv().await {
+            archive.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+        }
+        build.await.unwrap().map_err(|e| e.to_string())?;
+
+        Ok(archive)
+    }
+
+    #[tokio::test]
+    async fn archive_limits() {
+        let repo = TestRepo::new();
+
+        // a single blob repeated throughout a tree expands to far more than it takes up in
+        // the repository
+        let blob = repo.blob(vec![b'a'; 256 * 1024]);
+        let names: Vec<_> = (0..64).map(|i| format!("file-{i}")).collect();
+        let entries: Vec<_> = names.iter().map(|v| (v.as_str(), blob.as_str())).collect();
+        let tree = repo.tree(&entries);
+
+        let cases = [
+            (
+                ArchiveLimits {
+                    bytes: 4 * 1024 * 1024,
+                    ..ArchiveLimits::default()
+                },
+                "the maximum uncompressed size",
+            ),
+            // turned away by its header alone, before the blob's been read
+            (
+                ArchiveLimits {
+                    bytes: 1024,
+                    ..ArchiveLimits::default()
+                },
+                "the maximum uncompressed size",
+            ),
+            (
+                ArchiveLimits {
+                    entries: 10,
+                    ..ArchiveLimits::default()
+                },
+                "the maximum number of entries",
+            ),
+        ];
+
+        for (limits, exceeded) in cases {
+            let error = archive(&repo, &tree, limits).await.unwrap_err();
+            assert!(error.ends_with(&format!("exceeds {exceeded}")), "{error}");
+        }
+
+        let small = repo.tree(&entries[..4]);
+        assert!(archive(&repo, &small, ArchiveLimits::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn commit_pages_coalesced() {
+        let git = Git::default();
+        let reads = AtomicUsize::new(0);
+
+        let page = |offset| {
+            git.commit_page(PathBuf::from("repo.git"), None, 50, offset, async {
+                reads.fetch_add(1, Ordering::SeqCst);
+                // held open so every request below is waiting on the same read
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Arc::from([]))
+            })
+        };
+
+        let pages = join_all((0..16).map(|_| page(100))).await;
+        assert!(pages.iter().all(Result::is_ok));
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        page(100).await.unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        page(150).await.unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+
+        git.invalidate_indexed();
+        page(100).await.unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+    }
+
+    /// Collects every chunk `stream` yields for `range`, stopping at the first error.
+    async fn read_stream(
+        stream: BlobStream,
+        range: std::ops::Range<u64>,
+    ) -> (Vec<usize>, Vec<u8>, Option<std::io::Error>) {
+        let mut recv = stream.spawn(range);
+        let (mut chunks, mut data) = (Vec::new(), Vec::new());
+
+        while let Some(chunk) = recv.recv().await {
+            match chunk {
+                Ok(chunk) => {
+                    chunks.push(chunk.len());
+                    data.extend_from_slice(&chunk);
+                }
+                Err(e) => return (chunks, data, Some(e)),
+            }
+        }
+
+        (chunks, data, None)
+    }
+
+    #[tokio::test]
+    async fn large_blobs_streamed() {
+        let repo = TestRepo::new();
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i: u32| (i % 251) as u8).collect();
+        let commit = repo.commit("large.bin", &data);
+        let head = repo.commit("small.txt", "hello");
+
+        let git = Arc::new(Git::new(CacheConfig::default(), 1024 * 1024));
+        let open_repo = git.repo(repo.path().to_path_buf(), None).await.unwrap();
+        let raw = |path: &str| open_repo.clone().raw(format!("{commit}/{path}"));
+
+        let Content::Stream(stream) = raw("large.bin").await.unwrap().unwrap().content else {
+            panic!("large blob wasn't streamed");
+        };
+        assert_eq!(stream.size, data.len() as u64);
+
+        // read a chunk at a time rather than all at once
+        let (chunks, read, error) = read_stream(stream, 0..data.len() as u64).await;
+        assert!(error.is_none(), "{error:?}");
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|&len| len <= STREAMING_CHUNK_SIZE));
+        assert_eq!(read, data);
+
+        let Content::Stream(stream) = raw("large.bin").await.unwrap().unwrap().content else {
+            panic!("large blob wasn't streamed");
+        };
+        let range = 3 * 1024 * 1024 + 7..3 * 1024 * 1024 + 100_000;
+        let (_, read, error) = read_stream(stream, range.start as u64..range.end as u64).await;
+        assert!(error.is_none(), "{error:?}");
+        assert_eq!(read, data[range]);
+
+        // blobs under the threshold, and anything being shown rather than downloaded, are
+        // read in full
+        let small = open_repo.clone().raw(format!("{head}/small.txt"));
+        assert!(matches!(
+            small.await.unwrap().unwrap().content,
+            Content::Text(_)
+        ));
+        let file = open_repo
+            .path(Some("large.bin".into()), None, true, false, None)
+            .await
+            .unwrap();
+        assert!(!matches!(
+            file,
+            PathDestination::File(FileWithContent {
+                content: Content::Stream(_),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_streams_fail() {
+        let repo = TestRepo::new();
+        let blob = repo.blob(vec![b'a'; 1024]);
+
+        // claims to be larger than the blob git cat-file will give back
+        let stream = BlobStream {
+            repository_path: repo.path().to_path_buf(),
+            oid: blob.parse().unwrap(),
+            size: 2048,
+        };
+
+        let (_, read, error) = read_stream(stream, 0..2048).await;
+        assert_eq!(read.len(), 1024);
+        assert_eq!(
+            error.map(|e| e.kind()),
+            Some(std::io::ErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[tokio::test]
+    async fn file_history_follows_renames() {
+        let repo = TestRepo::new();
+        let contents = "fn main() {\n    println!(\"hello\");\n}\n";
+        repo.commit("a.rs", contents);
+        repo.commit("a.rs", format!("{contents}// a\n"));
+        for (from, to, edit) in [("a.rs", "b.rs", "// b\n"), ("b.rs", "c.rs", "// c\n")] {
+            let current = repo.git(&["show", &format!("HEAD:{from}")]);
+            repo.remove(from);
+            repo.commit(to, format!("{current}\n"));
+            repo.commit(to, format!("{current}\n{edit}"));
+        }
+        // unrelated changes don't show up in the history
+        repo.commit("README", "hello");
+
+        let git = Arc::new(Git::default());
+        let open_repo = git.repo(repo.path().to_path_buf(), None).await.unwrap();
+        let history = |follow| {
+            open_repo
+                .clone()
+                .file_history("c.rs".into(), follow, 0, 100, false)
+        };
+
+        let followed = history(true).await.unwrap();
+        let renames: Vec<_> = followed
+            .entries
+            .iter()
+            .map(|v| v.renamed_from.as_deref())
+            .collect();
+        assert_eq!(
+            renames,
+            [None, Some("b.rs"), None, Some("a.rs"), None, None]
+        );
+
+        let unfollowed = history(false).await.unwrap();
+        assert_eq!(unfollowed.entries.len(), 2);
+        assert!(unfollowed.entries.iter().all(|v| v.renamed_from.is_none()));
+    }
+
+    #[tokio::test]
+    async fn shallow_mirrors() {
+        let repo = TestRepo::new();
+        repo.commit("a.rs", "1");
+        let boundary = repo.commit("a.rs", "2");
+        let tip = repo.commit("a.rs", "3");
+        let mirror = repo.clone("shallow.git", &["--depth", "2"]);
+
+        let git = Arc::new(Git::default());
+        let oid = |v: &str| take_oid(v.parse().unwrap());
+
+        let open_repo = git.clone().repo(mirror, None).await.unwrap();
+        assert!(open_repo
+            .clone()
+            .is_history_truncated(oid(&boundary))
+            .await
+            .unwrap());
+        assert!(!open_repo
+            .clone()
+            .is_history_truncated(oid(&tip))
+            .await
+            .unwrap());
+
+        // the boundary's own change is unknown, since its parent is missing
+        let history = open_repo
+            .file_history("a.rs".into(), true, 0, 100, true)
+            .await
+            .unwrap();
+        assert!(history.truncated);
+        assert_eq!(history.entries.len(), 1);
+
+        let open_repo = git.repo(repo.path().to_path_buf(), None).await.unwrap();
+        let history = open_repo
+            .file_history("a.rs".into(), true, 0, 100, true)
+            .await
+            .unwrap();
+        assert!(!history.truncated);
+        assert_eq!(history.entries.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn partial_mirrors() {
+        let repo = TestRepo::new();
+        let commit = repo.commit("src/main.rs", "fn main() {}");
+        repo.git(&["config", "uploadpack.allowFilter", "true"]);
+        let mirror = repo.clone("partial.git", &["--filter=blob:none"]);
+
+        let git = Arc::new(Git::default());
+        let open_repo = git.repo(mirror, None).await.unwrap();
+
+        let raw = open_repo
+            .clone()
+            .raw(format!("{commit}/src/main.rs"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(raw.content, Content::Unavailable));
+
+        for formatted in [true, false] {
+            let file = open_repo
+                .clone()
+                .path(Some("src/main.rs".into()), None, formatted, false, None)
+                .await
+                .unwrap();
+            assert!(matches!(
+                file,
+                PathDestination::File(FileWithContent {
+                    content: Content::Unavailable,
+                    ..
+                })
+            ));
+        }
+
+        // listings only need the trees, which were fetched
+        let tree = open_repo
+            .path(Some("src".into()), None, true, false, None)
+            .await
+            .unwrap();
+        assert!(matches!(tree, PathDestination::Tree { items, .. } if items.len() == 1));
     }
 
-    fn remove(&mut self, data: &str, dst: &mut String) {
-        self.write(dst, "remove-line", data);
+    #[test]
+    fn nested_tags_peeled() {
+        let repo = TestRepo::new();
+        let commit = repo.commit("README", "hello");
+        repo.git(&["tag", "-a", "-m", "v1.0", "v1.0", &commit]);
+        let mut inner = "v1.0".to_string();
+        for i in 0..=super::MAX_TAG_DEPTH {
+            let name = format!("nested-{i}");
+            repo.git(&["tag", "-a", "-m", &name, &name, &inner]);
+            inner = name;
+        }
+
+        let repo = repo.open();
+        let peel = |name: &str| {
+            let tag = repo
+                .find_reference(&format!("refs/tags/{name}"))
+                .unwrap()
+                .id()
+                .object()
+                .unwrap()
+                .into_tag();
+            peel_nested_tag(&tag).map(|(object, via)| (object.id.to_string(), via))
+        };
+
+        assert_eq!(peel("v1.0").unwrap(), (commit.clone(), vec![]));
+        assert_eq!(
+            peel("nested-1").unwrap(),
+            (commit, vec!["nested-0".to_string(), "v1.0".to_string()])
+        );
+
+        // as deep as tags are followed, then one past it
+        let deepest = format!("nested-{}", super::MAX_TAG_DEPTH - 1);
+        assert_eq!(peel(&deepest).unwrap().1.len(), super::MAX_TAG_DEPTH);
+        let error = peel(&inner).unwrap_err();
+        assert!(error.to_string().contains("nested more than"), "{error}");
+    }
+
+    #[test]
+    fn transient_errors_retried() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        repo.git(&["pack-refs", "--all"]);
+        let packed = repo.path().join("packed-refs");
+        let aside = repo.path().join("packed-refs.new");
+        let read = || std::fs::read(&packed).context("Couldn't read packed-refs");
+
+        // missing for the first read, as it is whilst git gc swaps in its replacement, and
+        // back in place by the time the read is retried
+        std::fs::rename(&packed, &aside).unwrap();
+        let mut attempts = 0;
+        let refs = retry_transient(|| {
+            attempts += 1;
+            let refs = read();
+            if attempts == 1 {
+                std::fs::rename(&aside, &packed).unwrap();
+            }
+            refs
+        });
+        assert!(refs.unwrap().ends_with(b" refs/heads/main\n"));
+        assert_eq!(attempts, 2);
+
+        // still missing when it's retried
+        std::fs::remove_file(&packed).unwrap();
+        let mut attempts = 0;
+        let error = retry_transient(|| {
+            attempts += 1;
+            read()
+        })
+        .unwrap_err();
+        assert!(error.is::<RepositoryBusy>());
+        assert!(clone_shared_error(&error).is::<RepositoryBusy>());
+        assert_eq!(attempts, 2);
+
+        // anything else fails straight away
+        let mut attempts = 0;
+        let error = retry_transient(|| -> anyhow::Result<()> {
+            attempts += 1;
+            Err(CommitNotFound.into())
+        })
+        .unwrap_err();
+        assert!(!error.is::<RepositoryBusy>());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn abbreviations_extended_on_collision() {
+        let repo = TestRepo::new();
+        let commit = repo.commit("README", "hello");
+        let colliding = COLLIDING_BLOBS.map(|v| repo.blob(v));
+        assert_eq!(colliding[0][..7], colliding[1][..7]);
+
+        let git = Arc::new(Git::default());
+        let open_repo = git.repo(repo.path().to_path_buf(), None).await.unwrap();
+        let oids: Vec<ObjectId> = [&commit, &colliding[0], &colliding[1]]
+            .iter()
+            .map(|v| v.parse().unwrap())
+            .collect();
+        let abbreviations = open_repo.abbreviations(oids).await.unwrap();
+
+        assert_eq!(abbreviations.abbreviate(&commit), &commit[..7]);
+        for oid in &colliding {
+            assert_eq!(abbreviations.abbreviate(oid), &oid[..8]);
+        }
+    }
+
+    #[tokio::test]
+    async fn tree_sizes_cached() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        let writer = repo.open();
+
+        // written loose, one file per object, so the blobs can be removed again below
+        let mut entries = Vec::new();
+        for i in 0..5_000 {
+            let content = format!("file {i}\n").repeat(i % 7 + 1);
+            let oid = writer.write_blob(&content).unwrap().detach();
+            entries.push(gix::objs::tree::Entry {
+                mode: gix::objs::tree::EntryKind::Blob.into(),
+                filename: format!("file-{i:04}").into(),
+                oid,
+            });
+        }
+        let expected: Vec<_> = (0..5_000)
+            .map(|i| format!("file {i}\n").len() * (i % 7 + 1))
+            .collect();
+        let blobs: Vec<_> = entries.iter().map(|entry| entry.oid).collect();
+        let tree = writer
+            .write_object(&gix::objs::Tree { entries })
+            .unwrap()
+            .to_string();
+
+        let sizes = |git: Arc<Git>| {
+            let path = repo.path().to_path_buf();
+            let tree = tree.clone();
+            async move {
+                let open_repo = git.repo(path, None).await.unwrap();
+                let listing = open_repo.path(None, Some(&tree), false, false, None);
+                let PathDestination::Tree { items, .. } = listing.await.unwrap() else {
+                    panic!("tree wasn't listed as one");
+                };
+
+                items
+                    .into_iter()
+                    .map(|item| match item {
+                        super::TreeItem::File(file) => file.size,
+                        _ => panic!("unexpected item in tree"),
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let git = Arc::new(Git::default());
+        let start = std::time::Instant::now();
+        assert_eq!(sizes(git.clone()).await, expected);
+        let first = start.elapsed();
+
+        // with the blobs gone, the sizes can only have come from the cache
+        for oid in blobs {
+            let hex = oid.to_string();
+            std::fs::remove_file(repo.path().join("objects").join(&hex[..2]).join(&hex[2..]))
+                .unwrap();
+        }
+
+        let start = std::time::Instant::now();
+        assert_eq!(sizes(git).await, expected);
+        let second = start.elapsed();
+        eprintln!("first view: {first:?}, second view: {second:?}");
+
+        // whereas anything looking them up finds them missing
+        assert!(sizes(Arc::new(Git::default()))
+            .await
+            .iter()
+            .all(|&size| size == 0));
     }
 
-    fn context(&mut self, data: &str, dst: &mut String) {
-        self.write(dst, "context", data);
+    #[tokio::test]
+    async fn missing_repositories_expire() {
+        let git = Git::new(CacheConfig::default(), u64::MAX);
+        let path = PathBuf::from("wp-admin");
+
+        assert!(!git.is_known_missing(&path));
+        git.remember_missing(path.clone()).await;
+        assert!(git.is_known_missing(&path));
+        assert!(!git.is_known_missing(&PathBuf::from("phpmyadmin")));
+
+        tokio::time::sleep(MISSING_REPOSITORY_TTL + Duration::from_millis(50)).await;
+        assert!(!git.is_known_missing(&path));
+
+        // and as soon as an index run might have found it
+        git.remember_missing(path.clone()).await;
+        git.invalidate_indexed();
+        assert!(!git.is_known_missing(&path));
     }
 }
@@ -0,0 +1,143 @@
+//! Credentials of the users allowed to push, read from an htpasswd file given by
+//! `--push-auth-file`.
+//!
+//! Only bcrypt hashes are accepted, as generated by `htpasswd -B`. The other schemes htpasswd
+//! supports are either unsalted or long since broken.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+    /// Checked against in place of users that don't exist, so how long a check takes doesn't
+    /// give away which users do.
+    dummy: String,
+}
+
+impl Htpasswd {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read push auth file {}", path.display()))?;
+
+        let mut users = HashMap::new();
+        let mut max_cost = 0;
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (user, hash) = line
+                .split_once(':')
+                .with_context(|| format!("{}:{}: expected `user:hash`", path.display(), idx + 1))?;
+
+            anyhow::ensure!(
+                ["$2a$", "$2b$", "$2y$"].iter().any(|v| hash.starts_with(v)),
+                "{}:{}: the password of {user} isn't hashed with bcrypt, generate it with \
+                 `htpasswd -B`",
+                path.display(),
+                idx + 1,
+            );
+
+            let parts = hash.parse::<bcrypt::HashParts>().with_context(|| {
+                format!(
+                    "{}:{}: the hash of {user} is malformed",
+                    path.display(),
+                    idx + 1
+                )
+            })?;
+            max_cost = max_cost.max(parts.get_cost());
+
+            users.insert(user.to_string(), hash.to_string());
+        }
+
+        anyhow::ensure!(
+            !users.is_empty(),
+            "Push auth file {} doesn't contain any users",
+            path.display()
+        );
+
+        // the dummy has to take as long to check as the slowest of the real hashes, `htpasswd
+        // -B` defaults to a far lower cost than the bcrypt crate does
+        Ok(Self {
+            users,
+            dummy: bcrypt::hash("", max_cost)?,
+        })
+    }
+
+    /// Whether `password` is the password of `user`. bcrypt compares the hashes in constant
+    /// time, and is slow on purpose, so this should be called from a blocking task.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let (hash, exists) = match self.users.get(user) {
+            Some(hash) => (hash.as_str(), true),
+            None => (self.dummy.as_str(), false),
+        };
+
+        bcrypt::verify(password, hash).unwrap_or(false) && exists
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::Htpasswd;
+
+    fn write(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn hash(password: &str, cost: u32) -> String {
+        bcrypt::hash(password, cost).unwrap()
+    }
+
+    #[test]
+    fn verify() {
+        let file = write(&format!(
+            "# pushers\n\nalice:{}\n  bob:{}  \n",
+            hash("hunter2", 4),
+            hash("correct horse", 5),
+        ));
+        let htpasswd = Htpasswd::load(file.path()).unwrap();
+
+        assert!(htpasswd.verify("alice", "hunter2"));
+        assert!(htpasswd.verify("bob", "correct horse"));
+        assert!(!htpasswd.verify("alice", "correct horse"));
+        assert!(!htpasswd.verify("alice", ""));
+        assert!(!htpasswd.verify("Alice", "hunter2"));
+        assert!(!htpasswd.verify("carol", "hunter2"));
+        assert!(!htpasswd.verify("carol", ""));
+    }
+
+    #[test]
+    fn dummy_matches_slowest_hash() {
+        let file = write(&format!("alice:{}\nbob:{}\n", hash("a", 4), hash("b", 6)));
+        let htpasswd = Htpasswd::load(file.path()).unwrap();
+
+        let dummy = htpasswd.dummy.parse::<bcrypt::HashParts>().unwrap();
+        assert_eq!(dummy.get_cost(), 6);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        for content in [
+            "",
+            "# nobody\n",
+            "alice\n",
+            "alice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n",
+            "alice:$apr1$salt$hash\n",
+            "alice:plaintext\n",
+            "alice:$2y$\n",
+            "alice:$2y$99$abcdefghijklmnopqrstuuabcdefghijklmnopqrstuvwxyz01234\n",
+        ] {
+            let file = write(content);
+            assert!(Htpasswd::load(file.path()).is_err(), "{content:?}");
+        }
+    }
+}
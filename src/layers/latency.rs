@@ -0,0 +1,291 @@
+//! Keeps an in-memory histogram of request latencies for each group of routes, so slow pages
+//! can be spotted without any external tooling.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::info;
+
+/// Upper bounds, in microseconds, of each bucket in a histogram. Anything slower than the
+/// last bound falls into an overflow bucket.
+const BUCKET_BOUNDS_MICROS: [u64; 14] = [
+    500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000,
+    2_500_000, 5_000_000, 10_000_000,
+];
+
+/// How often the latencies of each route group are written out to the log.
+const LOG_INTERVAL: Duration = Duration::from_hours(1);
+
+pub static LATENCY: Latency = Latency::new();
+
+/// A group of routes with similar performance characteristics, collapsing repository names,
+/// paths and oids so there's only ever a handful of them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RouteGroup {
+    Index,
+    Summary,
+    Log,
+    Refs,
+    Tree,
+    Blob,
+    Commit,
+    Diff,
+    Snapshot,
+    Feed,
+    Git,
+    Api,
+    Static,
+    Other,
+}
+
+impl RouteGroup {
+    const ALL: [Self; 14] = [
+        Self::Index,
+        Self::Summary,
+        Self::Log,
+        Self::Refs,
+        Self::Tree,
+        Self::Blob,
+        Self::Commit,
+        Self::Diff,
+        Self::Snapshot,
+        Self::Feed,
+        Self::Git,
+        Self::Api,
+        Self::Static,
+        Self::Other,
+    ];
+
+    /// Groups a request path by the handler it's routed to, see
+    /// [`crate::methods::repo::route_group`] for those within repositories.
+    pub fn from_path(path: &str) -> Self {
+        let path = path.trim_matches('/');
+
+        if path.is_empty() {
+            return Self::Index;
+        }
+
+        if path == "favicon.ico" || path.starts_with("style-") || path.starts_with("highlight-") {
+            return Self::Static;
+        }
+
+        // the instance's own endpoints, such as `/-/ready` and `/-/status.json`
+        if path.starts_with("-/") {
+            return Self::Api;
+        }
+
+        crate::methods::repo::route_group(path)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Index => "index",
+            Self::Summary => "summary",
+            Self::Log => "log",
+            Self::Refs => "refs",
+            Self::Tree => "tree",
+            Self::Blob => "blob",
+            Self::Commit => "commit",
+            Self::Diff => "diff",
+            Self::Snapshot => "snapshot",
+            Self::Feed => "feed",
+            Self::Git => "git",
+            Self::Api => "api",
+            Self::Static => "static",
+            Self::Other => "other",
+        }
+    }
+}
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKET_BOUNDS_MICROS.len() + 1],
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKET_BOUNDS_MICROS.len() + 1],
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = BUCKET_BOUNDS_MICROS.partition_point(|bound| *bound < micros);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> [u64; BUCKET_BOUNDS_MICROS.len() + 1] {
+        std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+}
+
+pub struct Latency {
+    groups: [Histogram; RouteGroup::ALL.len()],
+}
+
+impl Latency {
+    const fn new() -> Self {
+        Self {
+            groups: [const { Histogram::new() }; RouteGroup::ALL.len()],
+        }
+    }
+
+    pub fn record(&self, group: RouteGroup, duration: Duration) {
+        self.groups[group as usize].record(duration);
+    }
+
+    /// Summarises the latencies of every route group that has seen at least one request.
+    pub fn summarise(&self) -> Vec<LatencySummary> {
+        RouteGroup::ALL
+            .into_iter()
+            .filter_map(|group| LatencySummary::new(group, &self.groups[group as usize]))
+            .collect()
+    }
+}
+
+/// Request count and latency percentiles of a route group. Percentiles are the upper bound of
+/// the bucket they fall into, so they overestimate slightly, and anything past the last bucket
+/// is reported as the last bucket's bound.
+#[derive(Serialize)]
+pub struct LatencySummary {
+    pub group: &'static str,
+    pub count: u64,
+    #[serde(rename = "p50_ms")]
+    pub p50: Millis,
+    #[serde(rename = "p95_ms")]
+    pub p95: Millis,
+    #[serde(rename = "p99_ms")]
+    pub p99: Millis,
+}
+
+impl LatencySummary {
+    fn new(group: RouteGroup, histogram: &Histogram) -> Option<Self> {
+        let buckets = histogram.snapshot();
+        let count = buckets.iter().sum();
+
+        if count == 0 {
+            return None;
+        }
+
+        Some(Self {
+            group: group.name(),
+            count,
+            p50: percentile(&buckets, count, 50),
+            p95: percentile(&buckets, count, 95),
+            p99: percentile(&buckets, count, 99),
+        })
+    }
+}
+
+fn percentile(buckets: &[u64], count: u64, percentile: u64) -> Millis {
+    // the rank of the request at the given percentile, rounding up
+    let rank = (count * percentile).div_ceil(100);
+    let mut seen = 0;
+
+    for (bucket, bound) in buckets.iter().zip(BUCKET_BOUNDS_MICROS) {
+        seen += bucket;
+
+        if seen >= rank {
+            return Millis(bound);
+        }
+    }
+
+    Millis(BUCKET_BOUNDS_MICROS[BUCKET_BOUNDS_MICROS.len() - 1])
+}
+
+/// A duration held in microseconds, displayed and serialised as fractional milliseconds.
+#[derive(Copy, Clone)]
+pub struct Millis(u64);
+
+impl Millis {
+    #[allow(clippy::cast_precision_loss)]
+    fn as_f64(self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+}
+
+impl std::fmt::Display for Millis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}ms", self.as_f64())
+    }
+}
+
+impl Serialize for Millis {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.as_f64())
+    }
+}
+
+/// Writes the latencies of every route group out to the log every [`LOG_INTERVAL`].
+pub async fn log_periodically() {
+    loop {
+        tokio::time::sleep(LOG_INTERVAL).await;
+
+        for summary in LATENCY.summarise() {
+            info!(
+                group = summary.group,
+                count = summary.count,
+                p50 = %summary.p50,
+                p95 = %summary.p95,
+                p99 = %summary.p99,
+                "Request latency"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RouteGroup;
+
+    #[test]
+    fn route_groups() {
+        let oid = "0123456789abcdef0123456789abcdef01234567";
+        let cases = [
+            ("/", RouteGroup::Index),
+            ("/favicon.ico", RouteGroup::Static),
+            ("/style-0123abcd.css", RouteGroup::Static),
+            ("/highlight-dark-0123abcd.css", RouteGroup::Static),
+            ("/-/ready", RouteGroup::Api),
+            ("/-/version", RouteGroup::Api),
+            ("/-/status.json", RouteGroup::Api),
+            ("/group/repo.git", RouteGroup::Summary),
+            ("/group/repo.git/", RouteGroup::Summary),
+            ("/repo.git/about", RouteGroup::Other),
+            ("/repo.git/releases", RouteGroup::Other),
+            ("/repo.git/tag", RouteGroup::Other),
+            ("/repo.git/log", RouteGroup::Log),
+            ("/repo.git/log/main/src/main.rs", RouteGroup::Log),
+            ("/repo.git/refs", RouteGroup::Refs),
+            ("/repo.git/tree", RouteGroup::Tree),
+            ("/repo.git/tree/src/log/main.rs", RouteGroup::Tree),
+            ("/repo.git/files", RouteGroup::Tree),
+            ("/repo.git/raw/main/README.md", RouteGroup::Blob),
+            ("/repo.git/commit", RouteGroup::Commit),
+            ("/repo.git/diff", RouteGroup::Diff),
+            ("/repo.git/patch", RouteGroup::Diff),
+            (&format!("/repo.git/patch/{oid}.patch"), RouteGroup::Diff),
+            ("/repo.git/compare/v1.0...v2.0", RouteGroup::Diff),
+            ("/repo.git/snapshot", RouteGroup::Snapshot),
+            ("/repo.git/snapshot/v1.0.tar.gz", RouteGroup::Snapshot),
+            ("/repo.git/bundle", RouteGroup::Snapshot),
+            ("/repo.git/bundle/main", RouteGroup::Snapshot),
+            ("/repo.git/atom", RouteGroup::Feed),
+            ("/repo.git/tags.atom", RouteGroup::Feed),
+            ("/repo.git/info/refs", RouteGroup::Git),
+            ("/repo.git/git-upload-pack", RouteGroup::Git),
+            ("/repo.git/git-receive-pack", RouteGroup::Git),
+            ("/repo.git/HEAD", RouteGroup::Git),
+            ("/repo.git/-/heads.txt", RouteGroup::Api),
+            ("/repo.git/manifest", RouteGroup::Api),
+            ("/repo.git/%ff", RouteGroup::Other),
+        ];
+
+        for (path, group) in cases {
+            assert_eq!(RouteGroup::from_path(path), group, "{path}");
+        }
+    }
+}
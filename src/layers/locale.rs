@@ -0,0 +1,51 @@
+//! Picks the locale each request is rendered in, see [`crate::locale`].
+
+use axum::{
+    extract::Request,
+    http::{self, HeaderValue},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+
+use crate::locale::LocaleRef;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LocaleSettings {
+    /// Locale used when the client's isn't negotiated or none of its languages are supported.
+    pub default: LocaleRef,
+    /// Whether to pick the locale from the client's `Accept-Language` header.
+    pub negotiate: bool,
+}
+
+/// Resolves the locale of the request, handing it to handlers as an extension.
+pub async fn resolve(
+    Extension(settings): Extension<LocaleSettings>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let locale = if settings.negotiate {
+        request
+            .headers()
+            .get(http::header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(LocaleRef::negotiate)
+            .unwrap_or(settings.default)
+    } else {
+        settings.default
+    };
+
+    request.extensions_mut().insert(locale);
+
+    let mut response = next.run(request).await;
+
+    // pages can differ between clients, so shared caches mustn't serve one language to all
+    if settings.negotiate {
+        response.headers_mut().append(
+            http::header::VARY,
+            HeaderValue::from_static("Accept-Language"),
+        );
+    }
+
+    response
+}
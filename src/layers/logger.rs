@@ -18,7 +18,10 @@ use tower_service::Service;
 use tracing::{error, info, instrument::Instrumented, Instrument, Span};
 use uuid::Uuid;
 
-use super::UnwrapInfallible;
+use super::{
+    latency::{RouteGroup, LATENCY},
+    UnwrapInfallible,
+};
 
 pub trait GenericError: std::error::Error + Debug + Send + Sync {}
 
@@ -98,6 +101,8 @@ impl PendingLogMessage {
     pub fn log<ResBody>(&self, response: &Response<ResBody>) {
         let _enter = self.span.enter();
 
+        LATENCY.record(RouteGroup::from_path(&self.uri), self.start.elapsed());
+
         if response.status().is_server_error() {
             error!(
                 "{ip} - \"{method} {uri}\" {status} {duration:?} \"{user_agent}\" \"{error:?}\"",
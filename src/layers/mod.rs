@@ -1,5 +1,7 @@
 use std::convert::Infallible;
 
+pub mod latency;
+pub mod locale;
 pub mod logger;
 
 pub trait UnwrapInfallible<T> {
@@ -0,0 +1,274 @@
+//! Formatting of dates, relative times and counts shown on pages, for instances serving an
+//! audience that doesn't read English.
+//!
+//! The locale is picked once per request by [`crate::layers::locale`], either the one given
+//! by `--locale` or, with `--negotiate-locale`, the best match for the client's
+//! `Accept-Language`. Feeds and JSON are always formatted the same way regardless, since
+//! they're read by machines.
+
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    str::FromStr,
+    time::Duration,
+};
+
+use time::OffsetDateTime;
+
+/// Every locale rgit ships with, the first being the default.
+pub const LOCALES: &[&dyn Locale] = &[&English, &German, &French];
+
+/// How a locale presents dates, times and numbers. Adding a locale is a matter of
+/// implementing this and adding it to [`LOCALES`].
+pub trait Locale: Send + Sync {
+    /// The primary language subtag the locale is selected by, such as `en`.
+    fn tag(&self) -> &'static str;
+
+    /// Formats how long ago something happened, such as "3 days ago".
+    fn relative_time(&self, duration: Duration) -> String;
+
+    /// Formats a date in the order the locale expects its day, month and year.
+    fn date(&self, date: time::Date) -> String;
+
+    /// The separator placed between each group of three digits in large numbers.
+    fn thousands_separator(&self) -> &'static str;
+
+    /// The separator placed between the whole and fractional parts of a number.
+    fn decimal_separator(&self) -> &'static str;
+
+    /// Formats a point in time to the second, along with its offset from UTC.
+    fn date_time(&self, date_time: OffsetDateTime) -> String {
+        let offset = date_time.offset();
+
+        format!(
+            "{} {:02}:{:02}:{:02} {}{:02}:{:02}",
+            self.date(date_time.date()),
+            date_time.hour(),
+            date_time.minute(),
+            date_time.second(),
+            if offset.is_negative() { '-' } else { '+' },
+            offset.whole_hours().unsigned_abs(),
+            offset.minutes_past_hour().unsigned_abs(),
+        )
+    }
+
+    /// Formats a count with its digits grouped into thousands.
+    fn count(&self, count: u64) -> String {
+        let digits = count.to_string();
+        let mut out = String::with_capacity(digits.len() * 2);
+
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                out.push_str(self.thousands_separator());
+            }
+
+            out.push(digit);
+        }
+
+        out
+    }
+
+    /// Formats a size in bytes using binary units, to a single decimal place, such as
+    /// "1.2 GiB".
+    fn size(&self, bytes: u64) -> String {
+        const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+        match bytes.checked_ilog(1024).unwrap_or(0) {
+            0 => format!("{bytes} B"),
+            exponent => format!(
+                "{} {}",
+                one_decimal(bytes, 1024_u64.pow(exponent), self.decimal_separator()),
+                UNITS[exponent as usize]
+            ),
+        }
+    }
+
+    /// Formats a count abbreviated to a single decimal place, such as "1.4M".
+    fn compact_count(&self, count: u64) -> String {
+        const SUFFIXES: &[&str] = &["", "k", "M", "G", "T", "P", "E"];
+
+        match count.checked_ilog(1000).unwrap_or(0) {
+            0 => count.to_string(),
+            exponent => format!(
+                "{}{}",
+                one_decimal(count, 1000_u64.pow(exponent), self.decimal_separator()),
+                SUFFIXES[exponent as usize]
+            ),
+        }
+    }
+}
+
+/// Divides `value` by `unit`, rounded to a single decimal place.
+fn one_decimal(value: u64, unit: u64, separator: &str) -> String {
+    let tenths = (u128::from(value) * 10 + u128::from(unit) / 2) / u128::from(unit);
+    format!("{}{separator}{}", tenths / 10, tenths % 10)
+}
+
+pub struct English;
+
+impl Locale for English {
+    fn tag(&self) -> &'static str {
+        "en"
+    }
+
+    fn relative_time(&self, duration: Duration) -> String {
+        timeago::Formatter::new().convert(duration)
+    }
+
+    fn date(&self, date: time::Date) -> String {
+        format!(
+            "{:04}-{:02}-{:02}",
+            date.year(),
+            u8::from(date.month()),
+            date.day()
+        )
+    }
+
+    fn thousands_separator(&self) -> &'static str {
+        ","
+    }
+
+    fn decimal_separator(&self) -> &'static str {
+        "."
+    }
+}
+
+pub struct German;
+
+impl Locale for German {
+    fn tag(&self) -> &'static str {
+        "de"
+    }
+
+    fn relative_time(&self, duration: Duration) -> String {
+        timeago::Formatter::with_language(timeago::languages::german::German).convert(duration)
+    }
+
+    fn date(&self, date: time::Date) -> String {
+        format!(
+            "{:02}.{:02}.{:04}",
+            date.day(),
+            u8::from(date.month()),
+            date.year()
+        )
+    }
+
+    fn thousands_separator(&self) -> &'static str {
+        "."
+    }
+
+    fn decimal_separator(&self) -> &'static str {
+        ","
+    }
+}
+
+pub struct French;
+
+impl Locale for French {
+    fn tag(&self) -> &'static str {
+        "fr"
+    }
+
+    fn relative_time(&self, duration: Duration) -> String {
+        timeago::Formatter::with_language(timeago::languages::french::French).convert(duration)
+    }
+
+    fn date(&self, date: time::Date) -> String {
+        format!(
+            "{:02}/{:02}/{:04}",
+            date.day(),
+            u8::from(date.month()),
+            date.year()
+        )
+    }
+
+    fn thousands_separator(&self) -> &'static str {
+        // narrow no-break space
+        "\u{202f}"
+    }
+
+    fn decimal_separator(&self) -> &'static str {
+        ","
+    }
+}
+
+/// The locale a request is rendered in, handed to templates through their context.
+#[derive(Clone, Copy)]
+pub struct LocaleRef(pub &'static dyn Locale);
+
+impl LocaleRef {
+    /// Finds the locale selected by `tag`, ignoring any region or script subtags.
+    pub fn find(tag: &str) -> Option<Self> {
+        let language = tag.split(['-', '_']).next()?;
+
+        LOCALES
+            .iter()
+            .find(|locale| locale.tag().eq_ignore_ascii_case(language))
+            .map(|locale| Self(*locale))
+    }
+
+    /// Picks the locale best matching an `Accept-Language` header, in the client's order of
+    /// preference. Returns `None` if none of the client's languages are supported.
+    pub fn negotiate(accept_language: &str) -> Option<Self> {
+        let mut ranges: Vec<(&str, f32)> = accept_language
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let tag = parts.next().filter(|v| !v.is_empty())?;
+                let quality = parts
+                    .find_map(|v| v.strip_prefix("q="))
+                    .map_or(Some(1.0), |v| v.parse().ok())?;
+
+                Some((tag, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+
+        // stable, so ranges of equal quality keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges.into_iter().find_map(|(tag, _)| Self::find(tag))
+    }
+}
+
+impl Default for LocaleRef {
+    fn default() -> Self {
+        Self(LOCALES[0])
+    }
+}
+
+impl std::ops::Deref for LocaleRef {
+    type Target = dyn Locale;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl Display for LocaleRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.tag())
+    }
+}
+
+impl Debug for LocaleRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LocaleRef").field(&self.0.tag()).finish()
+    }
+}
+
+impl FromStr for LocaleRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::find(s).ok_or_else(|| {
+            format!(
+                "unsupported locale, must be one of: {}",
+                LOCALES
+                    .iter()
+                    .map(|v| v.tag())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+    }
+}
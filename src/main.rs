@@ -1,60 +1,90 @@
 #![deny(clippy::pedantic)]
 
 use std::{
-    borrow::Cow,
+    cell::Cell,
     fmt::{Display, Formatter},
     future::IntoFuture,
     net::SocketAddr,
-    path::PathBuf,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::{Arc, OnceLock},
     time::Duration,
 };
 
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use askama::Template;
 use axum::{
     body::Body,
     http,
     http::{HeaderValue, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
     Extension, Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use const_format::formatcp;
-use database::schema::SCHEMA_VERSION;
-use rocksdb::{Options, SliceTransform};
+use futures_util::future::Either;
+use rocksdb::{BlockBasedOptions, Cache, ErrorKind, Options, SliceTransform};
+use time::OffsetDateTime;
 use tokio::{
     net::TcpListener,
     signal::unix::{signal, SignalKind},
-    sync::mpsc,
+    sync::{mpsc, oneshot},
 };
 use tower_http::{cors::CorsLayer, timeout::TimeoutLayer};
 use tower_layer::layer_fn;
 use tracing::{error, info, instrument, warn};
 use tracing_subscriber::{
-    fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+    fmt::{format::FmtSpan, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter,
 };
 use xxhash_rust::const_xxh3;
 
 use crate::{
-    database::schema::prefixes::{
-        COMMIT_COUNT_FAMILY, COMMIT_FAMILY, REFERENCE_FAMILY, REPOSITORY_FAMILY, TAG_FAMILY,
+    build_info::BUILD_INFO,
+    database::{
+        indexer::{self, IndexerStatus, RunSummary},
+        maintenance, migration,
+        schema::{
+            prefixes::{
+                COMMIT_COUNT_FAMILY, COMMIT_FAMILY, REFERENCE_FAMILY, REPOSITORY_FAMILY,
+                REPOSITORY_ID_FAMILY, TAG_FAMILY, TOMBSTONE_FAMILY,
+            },
+            repository::{FEED_ITEMS_RANGE, LOG_PAGE_SIZE_RANGE},
+        },
     },
-    git::Git,
-    layers::logger::LoggingMiddleware,
+    git::{ArchiveLimits, CacheConfig, Git},
+    layers::{locale::LocaleSettings, logger::LoggingMiddleware},
+    locale::LocaleRef,
+    snapshot_cache::SnapshotCache,
+    storage::Storage,
     syntax_highlight::prime_highlighters,
     theme::Theme,
 };
 
+mod build_info;
+mod csv_table;
 mod database;
+mod gemini;
 mod git;
+mod htpasswd;
 mod layers;
+mod locale;
 mod methods;
+mod notebook;
+mod snapshot_cache;
+mod storage;
 mod syntax_highlight;
+#[cfg(test)]
+mod testing;
 mod theme;
 mod unified_diff_builder;
+mod zip_archive;
 
 const CRATE_VERSION: &str = clap::crate_version!();
 
@@ -67,6 +97,31 @@ static HIGHLIGHT_CSS_HASH: OnceLock<Box<str>> = OnceLock::new();
 static DARK_HIGHLIGHT_CSS_HASH: OnceLock<Box<str>> = OnceLock::new();
 
 #[derive(Parser, Debug)]
+#[clap(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    arg_required_else_help = true
+)]
+pub struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Arguments to `serve`, which is run when no subcommand is given
+    #[clap(flatten)]
+    serve: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serves the web interface, this is the default when no subcommand is given
+    Serve(Box<Args>),
+    /// Inspects and maintains the index database
+    Db(maintenance::DbArgs),
+}
+
+#[derive(Parser, Debug)]
+#[allow(clippy::struct_excessive_bools)]
 #[clap(author, version, about)]
 pub struct Args {
     /// Path to a directory in which the `RocksDB` database should be stored, will be created if it doesn't already exist
@@ -84,6 +139,155 @@ pub struct Args {
     /// Configures the request timeout.
     #[clap(long, default_value_t = Duration::from_secs(10).into())]
     request_timeout: humantime::Duration,
+    /// Size in bytes of the `RocksDB` block cache, shared between all column families.
+    ///
+    /// Defaults to `RocksDB`'s own per-family block cache
+    #[clap(long)]
+    db_cache_bytes: Option<usize>,
+    /// Logs a warning after each index whenever the database exceeds this size in bytes
+    #[clap(long)]
+    db_size_soft_limit: Option<u64>,
+    /// Opens the database without ever writing to it, disabling indexing entirely.
+    ///
+    /// Useful for serving a snapshot of another instance's database
+    #[clap(long)]
+    read_only: bool,
+    /// Moves the database aside and starts over with a fresh one if it turns out to be
+    /// corrupted when opening it, rather than exiting
+    #[clap(long, conflicts_with = "read_only")]
+    recreate_db_on_corruption: bool,
+    /// Most commits indexed for each branch or tag in a single run of the indexer.
+    ///
+    /// The newest commits of a branch are indexed first, with older history backfilled on later
+    /// runs, so a repository with an enormous history doesn't hold up the rest
+    #[clap(long, default_value_t = indexer::DEFAULT_COMMIT_BUDGET, value_parser = clap::value_parser!(u64).range(1..))]
+    index_commit_budget: u64,
+    /// How long requests for a repository that has been deleted from disk are answered with
+    /// `410 Gone` rather than `404 Not Found` (eg. "30days", or "0s" to disable)
+    #[clap(long, default_value_t = indexer::DEFAULT_TOMBSTONE_RETENTION.into())]
+    tombstone_retention: humantime::Duration,
+    /// Flushes the database to disk on an interval, in addition to after every index (eg. "never" or "10m")
+    #[clap(long, default_value_t = RefreshInterval::Never)]
+    db_flush_interval: RefreshInterval,
+    /// Enables administrative endpoints, such as the indexer status page at `/-/status` and
+    /// triggering a reindex from the index page
+    #[clap(long)]
+    admin_endpoints: bool,
+    /// Falls back to matching repositories case-insensitively when a request doesn't name one
+    /// exactly, redirecting to the repository's actual path if there's a single match
+    #[clap(long)]
+    case_insensitive_paths: bool,
+    /// Serves every repository beneath the scan path. Set to false to only serve repositories
+    /// containing a `git-daemon-export-ok` file, as git-daemon and cgit do
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    export_all: bool,
+    /// Serves repositories reached through symlinks that lead outside of the scan path, which
+    /// are otherwise treated as not found
+    #[clap(long)]
+    allow_symlinks: bool,
+    /// Accepts pushes over smart HTTP from the users in this htpasswd file, whose passwords
+    /// must be hashed with bcrypt (`htpasswd -B`). Pushing is disabled without it
+    #[clap(long)]
+    push_auth_file: Option<PathBuf>,
+    /// Only shows commits, trees and blobs requested by their oid if they can be reached from
+    /// a branch or tag, hiding objects only referenced by refs that aren't listed
+    #[clap(long)]
+    strict_reachability: bool,
+    /// Number of commits included in feeds, repositories can set their own with
+    /// `rgit.feed-items`
+    #[clap(long, default_value_t = methods::repo::EntryLimits::default().feed_items, value_parser = clap::value_parser!(u64).range(FEED_ITEMS_RANGE))]
+    feed_items: u64,
+    /// Number of commits shown on each page of the log, repositories can set their own with
+    /// `rgit.log-page-size`
+    #[clap(long, default_value_t = methods::repo::EntryLimits::default().log_page_size, value_parser = clap::value_parser!(u64).range(LOG_PAGE_SIZE_RANGE))]
+    log_page_size: u64,
+    /// Socket address to serve a read-only Gemini mirror of the repository pages on (eg.
+    /// 0.0.0.0:1965), requires `--gemini-cert` and `--gemini-key`
+    #[clap(long, requires = "gemini_cert", requires = "gemini_key")]
+    gemini_bind: Option<SocketAddr>,
+    /// PEM encoded certificate chain presented by the Gemini mirror, Gemini clients trust
+    /// self-signed certificates on first use
+    #[clap(long, requires = "gemini_bind")]
+    gemini_cert: Option<PathBuf>,
+    /// PEM encoded private key of `--gemini-cert`
+    #[clap(long, requires = "gemini_bind")]
+    gemini_key: Option<PathBuf>,
+    /// Base URL of the web interface the Gemini mirror links to for anything it can't show,
+    /// such as diffs and binary files (eg. <https://git.example.com>).
+    ///
+    /// Defaults to https:// on the host the Gemini request was made to
+    #[clap(long, requires = "gemini_bind")]
+    gemini_http_url: Option<String>,
+    /// Clone URL shown for each repository, with `{repo}` replaced by the path of the repository
+    /// (eg. <https://git.example.com/{repo}>).
+    ///
+    /// Defaults to a URL on the host and scheme each request was made with
+    #[clap(long)]
+    clone_url: Option<String>,
+    /// SSH clone URL shown for each repository alongside `--clone-url`, with `{repo}` replaced
+    /// by the path of the repository (eg. "git@example.com:{repo}")
+    #[clap(long)]
+    clone_url_ssh: Option<String>,
+    /// Hides the version and commit rgit was built from in page footers and disables the
+    /// `/-/version` endpoint
+    #[clap(long)]
+    hide_build_info: bool,
+    /// Locale dates, relative times and counts are shown in on pages (eg. "en", "de" or "fr")
+    #[clap(long, default_value_t = LocaleRef::default())]
+    locale: LocaleRef,
+    /// Picks the locale of each request from its `Accept-Language` header, falling back to
+    /// `--locale` if none of the client's languages are supported
+    #[clap(long)]
+    negotiate_locale: bool,
+    /// Directory to keep generated snapshots in, so downloading the same snapshot again doesn't
+    /// rebuild it
+    #[clap(long)]
+    snapshot_cache_dir: Option<PathBuf>,
+    /// Size in bytes the snapshot cache is kept under, the least recently downloaded snapshots
+    /// are evicted first
+    #[clap(long, default_value_t = 1024 * 1024 * 1024)]
+    snapshot_cache_size: u64,
+    /// Most files a snapshot may contain, downloads of larger snapshots are abandoned
+    #[clap(long, default_value_t = ArchiveLimits::default().entries)]
+    snapshot_max_entries: u64,
+    /// Most bytes of file content, before compression, a snapshot may contain
+    #[clap(long, default_value_t = ArchiveLimits::default().bytes)]
+    snapshot_max_bytes: u64,
+    /// Most directories deep a file within a snapshot may be nested
+    #[clap(long, default_value_t = ArchiveLimits::default().path_depth)]
+    snapshot_max_path_depth: usize,
+    /// Longest path, in bytes, of a file within a snapshot
+    #[clap(long, default_value_t = ArchiveLimits::default().path_length)]
+    snapshot_max_path_length: usize,
+    /// How long a repository summary (branches, tags and latest commits) is cached for
+    #[clap(long, default_value_t = CacheConfig::default().refs_ttl.into())]
+    refs_cache_ttl: humantime::Duration,
+    /// Maximum number of repository summaries to cache
+    #[clap(long, default_value_t = CacheConfig::default().refs_capacity)]
+    refs_cache_capacity: u64,
+    /// How long parsed commits are cached for
+    #[clap(long, default_value_t = CacheConfig::default().commit_ttl.into())]
+    commit_cache_ttl: humantime::Duration,
+    /// Maximum number of parsed commits to cache
+    #[clap(long, default_value_t = CacheConfig::default().commit_capacity)]
+    commit_cache_capacity: u64,
+    /// How long rendered content, such as readmes, is cached for
+    #[clap(long, default_value_t = CacheConfig::default().content_ttl.into())]
+    content_cache_ttl: humantime::Duration,
+    /// Maximum number of rendered documents to cache
+    #[clap(long, default_value_t = CacheConfig::default().content_capacity)]
+    content_cache_capacity: u64,
+    /// Size in bytes above which raw files are streamed out of the repository rather than read
+    /// into memory first
+    #[clap(long, default_value_t = git::DEFAULT_STREAMING_THRESHOLD)]
+    raw_streaming_threshold: u64,
+    /// Once the first index completes, fills the caches of this many of the most recently
+    /// modified repositories, so the first visitors after a restart don't wait on them
+    #[clap(long)]
+    warm_cache: Option<usize>,
+    /// Has `/-/ready` report the instance as unready until `--warm-cache` has finished
+    #[clap(long, requires = "warm_cache")]
+    ready_after_warm: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -115,16 +319,47 @@ impl FromStr for RefreshInterval {
     }
 }
 
+/// The reason an index run was started.
+#[derive(Debug, Clone, Copy)]
+enum IndexTrigger {
+    Startup,
+    Interval,
+    Sighup,
+    Admin,
+    Push,
+}
+
+impl Display for IndexTrigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Startup => write!(f, "startup"),
+            Self::Interval => write!(f, "refresh interval"),
+            Self::Sighup => write!(f, "SIGHUP"),
+            Self::Admin => write!(f, "admin request"),
+            Self::Push => write!(f, "push"),
+        }
+    }
+}
+
 #[tokio::main]
 #[allow(clippy::too_many_lines)]
 async fn main() -> Result<(), anyhow::Error> {
-    let args: Args = Args::parse();
+    let cli = Cli::parse();
 
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
     }
 
-    let logger_layer = tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE);
+    // the db subcommands print their results to stdout, so logs are kept out of their way
+    let writer = if matches!(cli.command, Some(Command::Db(_))) {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+
+    let logger_layer = tracing_subscriber::fmt::layer()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(writer);
     let env_filter = EnvFilter::from_default_env();
 
     tracing_subscriber::registry()
@@ -132,20 +367,133 @@ async fn main() -> Result<(), anyhow::Error> {
         .with(logger_layer)
         .init();
 
-    let db = open_db(&args)?;
+    let args = match cli.command {
+        Some(Command::Db(db_args)) => {
+            let read_only = db_args.command.is_read_only();
+            let db = open_db(&db_args.db_store, None, read_only, false)?;
+            return maintenance::run(&db, db_args.command);
+        }
+        Some(Command::Serve(args)) => *args,
+        None => cli
+            .serve
+            .context("clap requires the serve arguments without a subcommand")?,
+    };
+
+    build_info::set_public(!args.hide_build_info);
+
+    let scan_root = methods::repo::ScanRoot::new(&args.scan_path, args.allow_symlinks)?;
+    let db = open_db(
+        &args.db_store,
+        args.db_cache_bytes,
+        args.read_only,
+        args.recreate_db_on_corruption,
+    )?;
+    let git = Arc::new(Git::new(
+        CacheConfig {
+            refs_ttl: args.refs_cache_ttl.into(),
+            refs_capacity: args.refs_cache_capacity,
+            commit_ttl: args.commit_cache_ttl.into(),
+            commit_capacity: args.commit_cache_capacity,
+            content_ttl: args.content_cache_ttl.into(),
+            content_capacity: args.content_cache_capacity,
+        },
+        args.raw_streaming_threshold,
+    ));
+    let snapshot_cache = args
+        .snapshot_cache_dir
+        .clone()
+        .map(|dir| SnapshotCache::new(dir, args.snapshot_cache_size).map(Arc::new))
+        .transpose()?;
+
+    let database_size = match database::size_on_disk(&args.db_store) {
+        Ok(size) => {
+            info!("Database is {size} bytes on disk");
+            Some(size)
+        }
+        Err(error) => {
+            warn!(%error, "Failed to determine size of database on disk");
+            None
+        }
+    };
+
+    if !args.read_only {
+        flush_db_on_panic(db.clone());
+        run_periodic_flush(db.clone(), args.db_flush_interval);
+    }
+
+    tokio::spawn(layers::latency::log_periodically());
+
+    let indexer_status = Arc::new(ArcSwap::from_pointee(IndexerStatus::new(
+        &db,
+        database_size,
+    )));
+    let storage = Storage::new(&args.scan_path);
+
+    let (indexer_wakeup_send, indexer_wakeup_recv) = mpsc::channel(10);
+    let (first_index_send, first_index_recv) = oneshot::channel();
+
+    let push_access = args
+        .push_auth_file
+        .as_deref()
+        .map(htpasswd::Htpasswd::load)
+        .transpose()?
+        .map(|credentials| methods::repo::PushAccess {
+            credentials: Arc::new(credentials),
+            indexer: (!args.read_only).then(|| indexer_wakeup_send.clone()),
+        });
+
+    // only handed to the index page and refresh endpoint if there's an indexer to wake up
+    let refresh_requests =
+        (args.admin_endpoints && !args.read_only).then(|| indexer_wakeup_send.clone());
+
+    let indexer_wakeup_task = if args.read_only {
+        info!("Running in read-only mode, indexing is disabled");
+        drop(first_index_send);
+        Either::Left(ignore_reindex_requests())
+    } else {
+        Either::Right(run_indexer(
+            db.clone(),
+            git.clone(),
+            storage.clone(),
+            args.refresh_interval,
+            args.index_commit_budget,
+            args.export_all,
+            scan_root.clone(),
+            *args.tombstone_retention,
+            args.db_store.clone(),
+            args.db_size_soft_limit,
+            indexer_status.clone(),
+            indexer_wakeup_send,
+            indexer_wakeup_recv,
+            first_index_send,
+        ))
+    };
+
+    let readiness = methods::status::Readiness::new(!args.ready_after_warm);
 
-    let indexer_wakeup_task =
-        run_indexer(db.clone(), args.scan_path.clone(), args.refresh_interval);
+    if let Some(count) = args.warm_cache {
+        let (git, db, scan_path, readiness) = (
+            git.clone(),
+            db.clone(),
+            args.scan_path.clone(),
+            readiness.clone(),
+        );
+
+        tokio::spawn(async move {
+            // in read-only mode there's no indexer, and the sender is dropped straight away
+            let _res = first_index_recv.await;
+            methods::repo::warm_caches(git, db, &scan_path, count).await;
+            readiness.mark_ready();
+        });
+    }
 
     let css = {
         let theme = basic_toml::from_str::<Theme>(include_str!("../themes/github_light.toml"))
             .unwrap()
             .build_css();
-        let css = Box::leak(
-            format!(r#"@media (prefers-color-scheme: light){{{theme}}}"#)
-                .into_boxed_str()
-                .into_boxed_bytes(),
-        );
+        // served without a media query so user agents without a colour scheme preference,
+        // and printers, still get highlighting. the dark theme overrides it where preferred
+        let css = Box::leak(theme.into_boxed_str().into_boxed_bytes());
         HIGHLIGHT_CSS_HASH.set(build_asset_hash(css)).unwrap();
         css
     };
@@ -187,9 +535,9 @@ async fn main() -> Result<(), anyhow::Error> {
 
     info!("Priming highlighters...");
     prime_highlighters();
-    info!("Server starting up...");
+    info!(build = %BUILD_INFO, "Server starting up...");
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", get(methods::index::handle))
         .route(
             formatcp!("/style-{}.css", GLOBAL_CSS_HASH),
@@ -210,96 +558,342 @@ async fn main() -> Result<(), anyhow::Error> {
             "/favicon.ico",
             get(static_favicon(include_bytes!("../statics/favicon.ico"))),
         )
-        .fallback(methods::repo::service)
+        .fallback(methods::repo::service);
+
+    app = app.route("/-/ready", get(methods::status::handle_ready));
+
+    if !args.hide_build_info {
+        app = app.route("/-/version", get(methods::version::handle));
+    }
+
+    if args.admin_endpoints {
+        app = app
+            .route("/-/status", get(methods::status::handle))
+            .route("/-/status.json", get(methods::status::handle_json));
+
+        if refresh_requests.is_some() {
+            app = app.route("/-/refresh-metadata", post(methods::status::handle_refresh));
+        }
+    }
+
+    let entry_limits = methods::repo::EntryLimits {
+        feed_items: args.feed_items,
+        log_page_size: args.log_page_size,
+    };
+
+    let clone_urls = methods::repo::CloneUrls(
+        args.clone_url
+            .iter()
+            .chain(&args.clone_url_ssh)
+            .cloned()
+            .collect(),
+    );
+
+    let gemini_task = match args.gemini_bind {
+        Some(bind_address) => {
+            let (Some(cert), Some(key)) = (&args.gemini_cert, &args.gemini_key) else {
+                unreachable!("clap requires the certificate and key alongside the bind address");
+            };
+
+            let acceptor = gemini::tls_acceptor(cert, key)?;
+            let listener = TcpListener::bind(&bind_address).await?;
+            info!("Serving Gemini mirror on {bind_address}");
+
+            Either::Left(gemini::serve(
+                listener,
+                acceptor,
+                Arc::new(gemini::State {
+                    db: db.clone(),
+                    git: git.clone(),
+                    scan_path: Arc::new(args.scan_path.clone()),
+                    scan_root: scan_root.clone(),
+                    export_all: args.export_all,
+                    limits: entry_limits,
+                    http_url: args.gemini_http_url.clone(),
+                    clone_urls: clone_urls.clone(),
+                    storage: storage.clone(),
+                    timeout: args.request_timeout.into(),
+                }),
+            ))
+        }
+        None => Either::Right(futures_util::future::pending()),
+    };
+
+    let app = app
+        .layer(middleware::from_fn(layers::locale::resolve))
         .layer(TimeoutLayer::new(args.request_timeout.into()))
         .layer(layer_fn(LoggingMiddleware))
-        .layer(Extension(Arc::new(Git::new())))
-        .layer(Extension(db))
+        .layer(Extension(git))
+        .layer(Extension(snapshot_cache))
+        .layer(Extension(db.clone()))
+        .layer(Extension(indexer_status))
+        .layer(Extension(readiness))
+        .layer(Extension(storage.clone()))
+        .layer(Extension(refresh_requests))
+        .layer(Extension(scan_root))
+        .layer(Extension(push_access))
         .layer(Extension(Arc::new(args.scan_path)))
+        .layer(Extension(methods::repo::CaseInsensitivePaths(
+            args.case_insensitive_paths,
+        )))
+        .layer(Extension(methods::repo::ExportAll(args.export_all)))
+        .layer(Extension(clone_urls))
+        .layer(Extension(methods::repo::StrictReachability(
+            args.strict_reachability,
+        )))
+        .layer(Extension(ArchiveLimits {
+            entries: args.snapshot_max_entries,
+            bytes: args.snapshot_max_bytes,
+            path_depth: args.snapshot_max_path_depth,
+            path_length: args.snapshot_max_path_length,
+        }))
+        .layer(Extension(entry_limits))
+        .layer(Extension(LocaleSettings {
+            default: args.locale,
+            negotiate: args.negotiate_locale,
+        }))
         .layer(CorsLayer::new());
 
     let listener = TcpListener::bind(&args.bind_address).await?;
     let app = app.into_make_service_with_connect_info::<SocketAddr>();
     let server = axum::serve(listener, app).into_future();
 
-    tokio::select! {
+    let res = tokio::select! {
         res = server => res.context("failed to run server"),
         res = indexer_wakeup_task => res.context("failed to run indexer"),
+        res = gemini_task => res.context("failed to run gemini server"),
         _ = tokio::signal::ctrl_c() => {
             info!("Received ctrl-c, shutting down");
             Ok(())
         }
+    };
+
+    if !args.read_only {
+        if let Err(error) = database::flush(&db) {
+            error!(?error, "Failed to flush database on shutdown");
+        }
     }
+
+    res
+}
+
+thread_local! {
+    /// Whether a panic on this thread flushes the database, see [`flush_db_on_panic`].
+    static FLUSH_ON_PANIC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Makes a best-effort attempt at flushing the database before a panic takes the process
+/// down. Only panics on the calling thread, which should be the main thread, and the
+/// indexer's thread flush. Panics in request handlers are caught and recovered from, so
+/// flushing on them would only turn every hit of a panicking page into a full flush.
+fn flush_db_on_panic(db: Arc<rocksdb::DB>) {
+    let previous_hook = std::panic::take_hook();
+
+    FLUSH_ON_PANIC.set(true);
+
+    std::panic::set_hook(Box::new(move |info| {
+        if FLUSH_ON_PANIC.get() {
+            if let Err(error) = database::flush(&db) {
+                error!(?error, "Failed to flush database on panic");
+            }
+        }
+
+        previous_hook(info);
+    }));
 }
 
-fn open_db(args: &Args) -> Result<Arc<rocksdb::DB>, anyhow::Error> {
+fn run_periodic_flush(db: Arc<rocksdb::DB>, interval: RefreshInterval) {
+    let RefreshInterval::Duration(interval) = interval else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let db = db.clone();
+            let res = tokio::task::spawn_blocking(move || database::flush(&db))
+                .await
+                .context("Failed to join Tokio task")
+                .and_then(|res| res);
+
+            if let Err(error) = res {
+                error!(?error, "Failed to flush database");
+            }
+        }
+    });
+}
+
+fn open_db(
+    db_store: &Path,
+    cache_bytes: Option<usize>,
+    read_only: bool,
+    recreate_on_corruption: bool,
+) -> Result<Arc<rocksdb::DB>, anyhow::Error> {
+    let block_cache = cache_bytes.map(Cache::new_lru_cache);
+    let family_options = || {
+        let mut options = Options::default();
+
+        if let Some(cache) = &block_cache {
+            let mut block_options = BlockBasedOptions::default();
+            block_options.set_block_cache(cache);
+            options.set_block_based_table_factory(&block_options);
+        }
+
+        options
+    };
+
     loop {
-        let mut db_options = Options::default();
+        let mut db_options = family_options();
         db_options.create_missing_column_families(true);
         db_options.create_if_missing(true);
 
-        let mut commit_family_options = Options::default();
+        let mut commit_family_options = family_options();
         commit_family_options.set_prefix_extractor(SliceTransform::create(
             "commit_prefix",
             |input| memchr::memchr(b'\0', input).map_or(input, |idx| &input[..idx]),
             None,
         ));
 
-        let mut tag_family_options = Options::default();
+        let mut tag_family_options = family_options();
         tag_family_options.set_prefix_extractor(SliceTransform::create_fixed_prefix(
             std::mem::size_of::<u64>(),
         )); // repository id prefix
 
-        let db = rocksdb::DB::open_cf_with_opts(
-            &db_options,
-            &args.db_store,
-            vec![
-                (COMMIT_FAMILY, commit_family_options),
-                (REPOSITORY_FAMILY, Options::default()),
-                (TAG_FAMILY, tag_family_options),
-                (REFERENCE_FAMILY, Options::default()),
-                (COMMIT_COUNT_FAMILY, Options::default()),
-            ],
-        )?;
-
-        let needs_schema_regen = match db.get("schema_version")? {
-            Some(v) if v.as_slice() != SCHEMA_VERSION.as_bytes() => Some(Some(v)),
-            Some(_) => None,
-            None => {
-                db.put("schema_version", SCHEMA_VERSION)?;
-                None
-            }
-        };
+        let families = vec![
+            (COMMIT_FAMILY, commit_family_options),
+            (REPOSITORY_FAMILY, family_options()),
+            (TAG_FAMILY, tag_family_options),
+            (REFERENCE_FAMILY, family_options()),
+            (REPOSITORY_ID_FAMILY, family_options()),
+            (COMMIT_COUNT_FAMILY, family_options()),
+            (TOMBSTONE_FAMILY, family_options()),
+        ];
 
-        if let Some(version) = needs_schema_regen {
-            let old_version = version
-                .as_deref()
-                .map_or(Cow::Borrowed("unknown"), String::from_utf8_lossy);
+        if read_only {
+            let db = rocksdb::DB::open_cf_with_opts_for_read_only(
+                &db_options,
+                db_store,
+                families,
+                false,
+            )
+            .map_err(|e| database::explain_open_error(db_store, e))
+            .context("Failed to open database in read-only mode")?;
 
-            warn!("Clearing outdated database ({old_version} != {SCHEMA_VERSION})");
+            anyhow::ensure!(
+                migration::is_up_to_date(&db)?,
+                "Database at {} is outdated and can't be migrated in read-only mode",
+                db_store.display(),
+            );
 
-            drop(db);
-            rocksdb::DB::destroy(&Options::default(), &args.db_store)?;
-        } else {
             break Ok(Arc::new(db));
         }
+
+        let db = match rocksdb::DB::open_cf_with_opts(&db_options, db_store, families) {
+            Ok(db) => db,
+            Err(e) if recreate_on_corruption && e.kind() == ErrorKind::Corruption => {
+                let moved_to = database::set_aside(db_store)?;
+                error!(
+                    error = %e,
+                    "Database at {} is corrupted! It has been moved to {} and a fresh database \
+                     will be built in its place, repositories won't be listed until the first \
+                     index completes",
+                    db_store.display(),
+                    moved_to.display(),
+                );
+                continue;
+            }
+            Err(e) => return Err(database::explain_open_error(db_store, e)),
+        };
+
+        match migration::migrate(&db)? {
+            migration::Outcome::UpToDate => break Ok(Arc::new(db)),
+            migration::Outcome::Unmigratable => {
+                warn!("Clearing outdated database");
+
+                drop(db);
+                rocksdb::DB::destroy(&Options::default(), db_store)?;
+            }
+        }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_indexer(
     db: Arc<rocksdb::DB>,
-    scan_path: PathBuf,
+    git: Arc<Git>,
+    storage: Storage,
     refresh_interval: RefreshInterval,
+    commit_budget: u64,
+    export_all: bool,
+    scan_root: methods::repo::ScanRoot,
+    tombstone_retention: Duration,
+    db_store: PathBuf,
+    db_size_soft_limit: Option<u64>,
+    status: Arc<ArcSwap<IndexerStatus>>,
+    indexer_wakeup_send: mpsc::Sender<IndexTrigger>,
+    mut indexer_wakeup_recv: mpsc::Receiver<IndexTrigger>,
+    first_index: oneshot::Sender<()>,
 ) -> Result<(), tokio::task::JoinError> {
-    let (indexer_wakeup_send, mut indexer_wakeup_recv) = mpsc::channel(10);
+    let runtime = tokio::runtime::Handle::current();
+
+    std::thread::spawn(move || {
+        // the index is left however far it got if a run panics, which is worth keeping
+        FLUSH_ON_PANIC.set(true);
+
+        let mut trigger = IndexTrigger::Startup;
+        let mut first_index = Some(first_index);
 
-    std::thread::spawn(move || loop {
-        info!("Running periodic index");
-        crate::database::indexer::run(&scan_path, &db);
-        info!("Finished periodic index");
+        loop {
+            wait_for_storage(&storage, &status);
+
+            info!("Running periodic index (triggered by {trigger})");
+            status.rcu(|current| IndexerStatus {
+                trigger: Some(trigger.to_string()),
+                last_run_start: Some(OffsetDateTime::now_utc()),
+                ..(**current).clone()
+            });
+
+            match std::panic::catch_unwind(AssertUnwindSafe(|| {
+                indexer::run(
+                    storage.path(),
+                    &db,
+                    commit_budget,
+                    export_all,
+                    &scan_root,
+                    tombstone_retention,
+                )
+            })) {
+                Ok(summary) => {
+                    git.invalidate_indexed();
+                    runtime.block_on(methods::repo::prime_recent_commits(&git, &db));
+                    finish_index_run(&db, &status, &db_store, db_size_soft_limit, &summary);
+                }
+                Err(panic) => {
+                    let message = panic
+                        .downcast_ref::<&str>()
+                        .map(ToString::to_string)
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "indexer panicked".to_string());
+
+                    error!("Index run failed: {message}");
+
+                    status.rcu(|current| IndexerStatus {
+                        last_run_end: Some(OffsetDateTime::now_utc()),
+                        last_error: Some(message.clone()),
+                        ..(**current).clone()
+                    });
+                }
+            }
+
+            if let Some(first_index) = first_index.take() {
+                let _res = first_index.send(());
+            }
 
-        if indexer_wakeup_recv.blocking_recv().is_none() {
-            break;
+            match indexer_wakeup_recv.blocking_recv() {
+                Some(next) => trigger = next,
+                None => break,
+            }
         }
     });
 
@@ -314,12 +908,12 @@ async fn run_indexer(
 
         async move {
             loop {
-                tokio::select! {
-                    _ = sighup.recv() => {},
-                    () = build_sleeper() => {},
-                }
+                let trigger = tokio::select! {
+                    _ = sighup.recv() => IndexTrigger::Sighup,
+                    () = build_sleeper() => IndexTrigger::Interval,
+                };
 
-                if indexer_wakeup_send.send(()).await.is_err() {
+                if indexer_wakeup_send.send(trigger).await.is_err() {
                     error!("Indexing thread has died and is no longer accepting wakeup messages");
                 }
             }
@@ -328,6 +922,93 @@ async fn run_indexer(
     .await
 }
 
+/// Holds off indexing whilst the scan path can't be read, checking back with an increasing
+/// delay. The index is left as it was, since every repository would otherwise look deleted.
+fn wait_for_storage(storage: &Storage, status: &ArcSwap<IndexerStatus>) {
+    const MIN_BACKOFF: Duration = Duration::from_secs(5);
+    const MAX_BACKOFF: Duration = Duration::from_mins(5);
+
+    let mut backoff = MIN_BACKOFF;
+
+    while !storage.check() {
+        let message = format!("scan path {} is unavailable", storage.path().display());
+        status.rcu(|current| IndexerStatus {
+            last_error: Some(message.clone()),
+            ..(**current).clone()
+        });
+
+        warn!(
+            "Pausing indexing for {}",
+            humantime::format_duration(backoff)
+        );
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn finish_index_run(
+    db: &rocksdb::DB,
+    status: &ArcSwap<IndexerStatus>,
+    db_store: &Path,
+    db_size_soft_limit: Option<u64>,
+    summary: &RunSummary,
+) {
+    let finished_at = OffsetDateTime::now_utc();
+    if let Err(error) = indexer::store_last_success(db, finished_at) {
+        error!(?error, "Failed to persist time of last successful index");
+    }
+
+    if let Err(error) = database::flush(db) {
+        error!(?error, "Failed to flush database after index");
+    }
+
+    info!(
+        repositories_scanned = summary.repositories_scanned,
+        repositories_updated = summary.repositories_updated,
+        repositories_unchanged = summary.repositories_unchanged,
+        repositories_failed = summary.repositories_failed,
+        commits_indexed = summary.commits_indexed,
+        repositories_backfilling = summary.backfilling.len(),
+        duration = ?summary.duration,
+        "Finished periodic index"
+    );
+
+    let database_size = match database::size_on_disk(db_store) {
+        Ok(size) => Some(size),
+        Err(error) => {
+            warn!(%error, "Failed to determine size of database on disk");
+            None
+        }
+    };
+
+    if let (Some(size), Some(limit)) = (database_size, db_size_soft_limit) {
+        if size > limit {
+            warn!("Database is {size} bytes on disk, exceeding the soft limit of {limit} bytes");
+        }
+    }
+
+    status.rcu(|current| IndexerStatus {
+        last_run_end: Some(finished_at),
+        last_success: Some(finished_at),
+        last_summary: Some(summary.clone()),
+        last_error: None,
+        database_size: database_size.or(current.database_size),
+        ..(**current).clone()
+    });
+}
+
+/// Stands in for the indexer in read-only mode, letting operators know that SIGHUPs won't
+/// trigger a reindex.
+async fn ignore_reindex_requests() -> Result<(), tokio::task::JoinError> {
+    let mut sighup = signal(SignalKind::hangup()).expect("could not subscribe to sighup");
+
+    while sighup.recv().await.is_some() {
+        warn!("Received SIGHUP, but reindexing is disabled in read-only mode");
+    }
+
+    Ok(())
+}
+
 #[must_use]
 pub fn build_asset_hash(v: &[u8]) -> Box<str> {
     let hasher = const_xxh3::xxh3_128(v);
@@ -337,6 +1018,7 @@ pub fn build_asset_hash(v: &[u8]) -> Box<str> {
 
 pub struct TemplateResponse<T> {
     template: T,
+    status: StatusCode,
 }
 
 impl<T: Template> IntoResponse for TemplateResponse<T> {
@@ -349,15 +1031,49 @@ impl<T: Template> IntoResponse for TemplateResponse<T> {
                     HeaderValue::from_static(T::MIME_TYPE),
                 )];
 
-                (headers, body).into_response()
+                let mut response = (self.status, headers, body).into_response();
+                apply_standard_headers(response.headers_mut());
+                response
             }
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
 }
 
+/// Headers carried by every rendered page. The request ID is left to [`LoggingMiddleware`],
+/// which sets it on every response whether it was rendered or not.
+const STANDARD_HEADERS: [(http::HeaderName, &str); 3] = [
+    (http::header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+    (http::header::X_FRAME_OPTIONS, "DENY"),
+    (http::header::REFERRER_POLICY, "same-origin"),
+];
+
+/// `Cache-Control` of pages that don't set their own, they can change with every push so
+/// have to be revalidated each time they're used.
+const DEFAULT_CACHE_CONTROL: &str = "no-cache";
+
+/// Adds the headers every rendered page should carry, leaving any `Cache-Control` the page
+/// set for itself alone.
+pub fn apply_standard_headers(headers: &mut http::HeaderMap) {
+    for (name, value) in STANDARD_HEADERS {
+        headers.insert(name, HeaderValue::from_static(value));
+    }
+
+    headers
+        .entry(http::header::CACHE_CONTROL)
+        .or_insert(HeaderValue::from_static(DEFAULT_CACHE_CONTROL));
+}
+
 pub fn into_response<T: Template>(template: T) -> impl IntoResponse {
-    TemplateResponse { template }
+    into_response_with_status(StatusCode::OK, template)
+}
+
+/// Renders `template` as the body of a response with the given `status`, such as a 404 page.
+pub fn into_response_with_status<T: Template>(
+    status: StatusCode,
+    template: T,
+) -> impl IntoResponse {
+    TemplateResponse { template, status }
 }
 
 pub enum ResponseEither<A, B> {
@@ -0,0 +1,276 @@
+//! Support for answering conditional GET requests, allowing clients that poll a resource
+//! (such as feed readers) to do so without us having to render it every time.
+
+use std::{fmt::Display, time::SystemTime};
+
+use axum::{
+    http::{self, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use clap::crate_version;
+use time::OffsetDateTime;
+
+use crate::locale::LocaleRef;
+
+/// `Cache-Control` of resources addressed by an object id whose bytes can never change, such
+/// as raw blobs and patches.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// `Cache-Control` of pages addressed by an object id. The object can't change but the page
+/// around it can, with relative times going stale and the header following the repository's
+/// configuration, so they're only cached for a short while.
+const PAGE_CACHE_CONTROL: &str = "public, max-age=300";
+
+/// The validators of a resource, used to determine whether a client's cached copy is still
+/// current.
+pub struct Validators {
+    etag: String,
+    last_modified: Option<OffsetDateTime>,
+    cache_control: Option<&'static str>,
+}
+
+impl Validators {
+    /// `etag` must change whenever the content of the resource does, the last modified time
+    /// is only used by clients that don't send `If-None-Match`.
+    pub fn new(etag: impl Display, last_modified: Option<OffsetDateTime>) -> Self {
+        Self {
+            etag: format!("\"{etag}\""),
+            last_modified,
+            cache_control: None,
+        }
+    }
+
+    /// Validators of a resource requested by the object id `id` whose body is taken straight
+    /// from the object, such as a raw blob, a patch or a snapshot, which can be cached forever
+    /// since the object can never change. Returns `None` if `id` isn't a full object id, such
+    /// as a branch name, since what it refers to can move.
+    pub fn for_object_id(id: &str) -> Option<Self> {
+        let oid = gix::ObjectId::from_hex(id.as_bytes()).ok()?;
+
+        Some(Self {
+            cache_control: Some(IMMUTABLE_CACHE_CONTROL),
+            ..Self::new(oid, None)
+        })
+    }
+
+    /// Validators of a page rendering the object `id`. Unlike [`Self::for_object_id`] the page
+    /// also depends on the locale it's rendered in and on the templates of the running
+    /// version, both of which are folded into the `ETag`, and it's only cached for a short
+    /// while.
+    pub fn for_page(id: &str, locale: LocaleRef) -> Option<Self> {
+        let oid = gix::ObjectId::from_hex(id.as_bytes()).ok()?;
+
+        Some(Self {
+            cache_control: Some(PAGE_CACHE_CONTROL),
+            ..Self::new(
+                format_args!("{oid}-{}-{}", locale.tag(), crate_version!()),
+                None,
+            )
+        })
+    }
+
+    /// Checks whether the client already holds the current version of the resource. As per
+    /// RFC 9110, `If-Modified-Since` is ignored whenever `If-None-Match` is present.
+    pub fn is_fresh(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.get(http::header::IF_NONE_MATCH) {
+            let Ok(if_none_match) = if_none_match.to_str() else {
+                return false;
+            };
+
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag);
+        }
+
+        let (Some(last_modified), Some(if_modified_since)) = (
+            self.last_modified,
+            headers
+                .get(http::header::IF_MODIFIED_SINCE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| httpdate::parse_http_date(v).ok()),
+        ) else {
+            return false;
+        };
+
+        SystemTime::from(last_modified) <= if_modified_since
+    }
+
+    /// Whether a range the client asked for can be served, which with `If-Range` is only the
+    /// case if it still holds the version of the resource it's asking for the rest of. As per
+    /// RFC 9110, weak entity tags never match, and dates have to match exactly.
+    pub fn range_applies(&self, headers: &HeaderMap) -> bool {
+        let Some(if_range) = headers.get(http::header::IF_RANGE) else {
+            return true;
+        };
+        let Ok(if_range) = if_range.to_str() else {
+            return false;
+        };
+
+        if if_range.starts_with('"') {
+            return if_range == self.etag;
+        }
+
+        match (self.last_modified, httpdate::parse_http_date(if_range)) {
+            (Some(last_modified), Ok(date)) => date
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .is_ok_and(|v| i64::try_from(v.as_secs()) == Ok(last_modified.unix_timestamp())),
+            _ => false,
+        }
+    }
+
+    /// Builds the response for a client that already has the current version of the
+    /// resource.
+    pub fn not_modified(&self) -> Response {
+        let mut response = StatusCode::NOT_MODIFIED.into_response();
+        self.apply(&mut response);
+        response
+    }
+
+    /// Attaches the validators to a response so the client can make conditional requests for
+    /// the resource in the future.
+    pub fn apply(&self, response: &mut Response) {
+        let headers = response.headers_mut();
+
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            headers.insert(http::header::ETAG, etag);
+        }
+
+        if let Some(cache_control) = self.cache_control {
+            headers.insert(
+                http::header::CACHE_CONTROL,
+                HeaderValue::from_static(cache_control),
+            );
+        }
+
+        if let Some(last_modified) = self.last_modified {
+            let last_modified = httpdate::fmt_http_date(last_modified.into());
+
+            if let Ok(last_modified) = HeaderValue::from_str(&last_modified) {
+                headers.insert(http::header::LAST_MODIFIED, last_modified);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        http::{self, HeaderMap, HeaderValue, StatusCode},
+        response::IntoResponse,
+    };
+    use clap::crate_version;
+    use time::OffsetDateTime;
+
+    use super::Validators;
+    use crate::locale::LocaleRef;
+
+    const OID: &str = "4b825dc642cb6eb9a060e54bf8d69288fbc4904a";
+
+    /// The headers a page ends up with once the validators its handler picked, if any, and
+    /// the headers added to every page have been applied.
+    fn headers(validators: Option<Validators>) -> HeaderMap {
+        let mut response = StatusCode::OK.into_response();
+
+        if let Some(validators) = validators {
+            validators.apply(&mut response);
+        }
+
+        crate::apply_standard_headers(response.headers_mut());
+        response.headers().clone()
+    }
+
+    #[test]
+    fn pages_by_object_id_are_cached_briefly() {
+        let locale = LocaleRef::default();
+
+        // `/commit?id=<oid>`
+        let commit = headers(Validators::for_page(OID, locale));
+        // `/tree?h=main`, the branch can move so the page mustn't be cached at all
+        let tree = headers(None::<&str>.and_then(|id| Validators::for_page(id, locale)));
+
+        assert_eq!(commit[http::header::CACHE_CONTROL], "public, max-age=300",);
+        assert_eq!(
+            commit[http::header::ETAG],
+            format!("\"{OID}-{}-{}\"", locale.tag(), crate_version!()).as_str(),
+        );
+
+        assert_eq!(tree[http::header::CACHE_CONTROL], "no-cache");
+        assert!(!tree.contains_key(http::header::ETAG));
+    }
+
+    #[test]
+    fn raw_objects_are_immutable() {
+        let raw = headers(Validators::for_object_id(OID));
+
+        assert_eq!(
+            raw[http::header::CACHE_CONTROL],
+            "public, max-age=31536000, immutable",
+        );
+        assert_eq!(raw[http::header::ETAG], format!("\"{OID}\"").as_str());
+    }
+
+    #[test]
+    fn only_full_object_ids_are_cached() {
+        for id in ["main", "4b825dc", "", &OID.to_uppercase()[..39]] {
+            assert!(Validators::for_object_id(id).is_none(), "{id}");
+            assert!(
+                Validators::for_page(id, LocaleRef::default()).is_none(),
+                "{id}"
+            );
+        }
+    }
+
+    #[test]
+    fn page_etag_depends_on_locale() {
+        let english = Validators::for_page(OID, LocaleRef::find("en").unwrap()).unwrap();
+        let german = Validators::for_page(OID, LocaleRef::find("de").unwrap()).unwrap();
+
+        let mut request = HeaderMap::new();
+        request.insert(
+            http::header::IF_NONE_MATCH,
+            HeaderValue::from_str(&english.etag).unwrap(),
+        );
+
+        assert!(english.is_fresh(&request));
+        assert!(!german.is_fresh(&request));
+    }
+
+    #[test]
+    fn if_range() {
+        // 2024-01-01 12:00:00.5, which is only ever sent to the second
+        let modified =
+            OffsetDateTime::from_unix_timestamp_nanos(1_704_110_400_500_000_000).unwrap();
+        let validators = Validators::new(OID, Some(modified));
+        let cases = [
+            (None, true),
+            (Some(format!("\"{OID}\"")), true),
+            (Some(format!("W/\"{OID}\"")), false),
+            (Some("\"0000\"".to_string()), false),
+            (Some("Mon, 01 Jan 2024 12:00:00 GMT".to_string()), true),
+            (Some("Mon, 01 Jan 2024 11:59:59 GMT".to_string()), false),
+            (Some("Tue, 02 Jan 2024 12:00:00 GMT".to_string()), false),
+            (Some("garbage".to_string()), false),
+        ];
+
+        for (if_range, applies) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(if_range) = &if_range {
+                headers.insert(
+                    http::header::IF_RANGE,
+                    HeaderValue::from_str(if_range).unwrap(),
+                );
+            }
+
+            assert_eq!(validators.range_applies(&headers), applies, "{if_range:?}");
+        }
+
+        // without a date to go by, only entity tags can match
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::IF_RANGE,
+            HeaderValue::from_static("Mon, 01 Jan 2024 12:00:00 GMT"),
+        );
+        assert!(!Validators::new(OID, None).range_applies(&headers));
+    }
+}
@@ -0,0 +1,43 @@
+use std::borrow::Cow;
+
+use askama::Template;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+use super::filters;
+use crate::into_response_with_status;
+
+/// A page explaining why a request failed, served with `status` so clients that don't read the
+/// body can still tell a missing page from a broken one.
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct ErrorPage {
+    pub status: StatusCode,
+    pub message: Cow<'static, str>,
+    /// Further lines shown beneath the message, such as the branches that do exist.
+    pub detail: Vec<String>,
+}
+
+impl ErrorPage {
+    pub fn new(status: StatusCode, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            detail: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_detail(mut self, detail: Vec<String>) -> Self {
+        self.detail = detail;
+        self
+    }
+}
+
+impl IntoResponse for ErrorPage {
+    fn into_response(self) -> Response {
+        into_response_with_status(self.status, self).into_response()
+    }
+}
@@ -5,14 +5,21 @@ use std::{
     borrow::Borrow,
     fmt::Display,
     sync::{Arc, LazyLock},
+    time::Duration,
 };
 
 use arc_swap::ArcSwap;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use rkyv::{
     rend::{i32_le, i64_le},
     tuple::ArchivedTuple2,
 };
-use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    git::{signature_time, Abbreviations, LINK_ENCODE_SET},
+    locale::LocaleRef,
+};
 
 // pub fn format_time(s: impl Borrow<time::OffsetDateTime>) -> Result<String, askama::Error> {
 pub fn format_time(s: impl Into<Timestamp>) -> Result<String, askama::Error> {
@@ -24,17 +31,102 @@ pub fn format_time(s: impl Into<Timestamp>) -> Result<String, askama::Error> {
         .map_err(askama::Error::Custom)
 }
 
+/// Formats the date portion of a timestamp as `YYYY-MM-DD`.
+pub fn format_date(s: impl Into<Timestamp>) -> Result<String, askama::Error> {
+    let s = s.into().0;
+
+    Ok(format!(
+        "{:04}-{:02}-{:02}",
+        s.year(),
+        u8::from(s.month()),
+        s.day()
+    ))
+}
+
+/// Characters encoded within query string values, being everything but those RFC 3986 leaves
+/// unreserved.
+pub const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
 pub fn branch_query(branch: Option<&str>) -> String {
     if let Some(b) = branch {
-        format!("?h={b}")
+        format!("?h={}", utf8_percent_encode(b, QUERY_ENCODE_SET))
     } else {
         String::new()
     }
 }
 
-pub fn timeago(s: impl Into<Timestamp>) -> Result<String, askama::Error> {
-    Ok(timeago::Formatter::new()
-        .convert((OffsetDateTime::now_utc() - s.into().0).try_into().unwrap()))
+/// Percent-encodes `s` so it can be used as a query string value.
+pub fn encode_query(s: impl Display) -> Result<String, askama::Error> {
+    Ok(utf8_percent_encode(&s.to_string(), QUERY_ENCODE_SET).to_string())
+}
+
+/// Percent-encodes each segment of the path `s` so it can be used within the path of a link,
+/// leaving the slashes between them as they are.
+pub fn encode_path(s: impl Display) -> Result<String, askama::Error> {
+    Ok(utf8_percent_encode(&s.to_string(), LINK_ENCODE_SET).to_string())
+}
+
+/// Formats how long ago a timestamp was, times in the future (which clock skew and clamped
+/// signature times give us) are treated as having just happened.
+pub fn timeago(s: impl Into<Timestamp>, locale: &LocaleRef) -> Result<String, askama::Error> {
+    let elapsed = (OffsetDateTime::now_utc() - s.into().0)
+        .try_into()
+        .unwrap_or(Duration::ZERO);
+
+    Ok(locale.relative_time(elapsed))
+}
+
+/// Formats a timestamp to the second as the request's locale would write it.
+pub fn format_datetime(
+    s: impl Into<Timestamp>,
+    locale: &LocaleRef,
+) -> Result<String, askama::Error> {
+    Ok(locale.date_time(s.into().0))
+}
+
+/// Formats a count with its digits grouped as the request's locale would group them.
+pub fn format_count<T: Copy + TryInto<u64>>(
+    s: &T,
+    locale: &LocaleRef,
+) -> Result<String, askama::Error> {
+    let count = (*s)
+        .try_into()
+        .map_err(|_| askama::Error::Custom("count out of range".into()))?;
+
+    Ok(locale.count(count))
+}
+
+/// Formats a size in bytes as the request's locale would, such as "1.2 GiB".
+pub fn format_size(s: &u64, locale: &LocaleRef) -> Result<String, askama::Error> {
+    Ok(locale.size(*s))
+}
+
+/// Formats a count abbreviated to a single decimal place, such as "1.4M".
+pub fn format_compact_count(s: &u64, locale: &LocaleRef) -> Result<String, askama::Error> {
+    Ok(locale.compact_count(*s))
+}
+
+/// Shows a placeholder in place of the summary of a commit without a message, so there's still
+/// something to click on in lists of commits.
+pub fn commit_summary(s: impl Display) -> Result<String, askama::Error> {
+    let summary = s.to_string();
+
+    if summary.trim().is_empty() {
+        Ok("(no commit message)".to_string())
+    } else {
+        Ok(summary)
+    }
+}
+
+/// Abbreviates an object id to the length that's unique within its repository, see
+/// [`Abbreviations`].
+pub fn abbrev(s: impl Display, abbreviations: &Abbreviations) -> Result<String, askama::Error> {
+    let hex = s.to_string();
+    Ok(abbreviations.abbreviate(&hex).to_string())
 }
 
 pub fn file_perms(s: &u16) -> Result<String, askama::Error> {
@@ -83,31 +175,19 @@ pub struct Timestamp(OffsetDateTime);
 
 impl From<&ArchivedTuple2<i64_le, i32_le>> for Timestamp {
     fn from(value: &ArchivedTuple2<i64_le, i32_le>) -> Self {
-        Self(
-            OffsetDateTime::from_unix_timestamp(value.0.to_native())
-                .unwrap()
-                .to_offset(UtcOffset::from_whole_seconds(value.1.to_native()).unwrap()),
-        )
+        Self(signature_time(value.0.to_native(), value.1.to_native()))
     }
 }
 
 impl From<(i64, i32)> for Timestamp {
     fn from(value: (i64, i32)) -> Self {
-        Self(
-            OffsetDateTime::from_unix_timestamp(value.0)
-                .unwrap()
-                .to_offset(UtcOffset::from_whole_seconds(value.1).unwrap()),
-        )
+        Self(signature_time(value.0, value.1))
     }
 }
 
 impl From<&(i64, i32)> for Timestamp {
     fn from(value: &(i64, i32)) -> Self {
-        Self(
-            OffsetDateTime::from_unix_timestamp(value.0)
-                .unwrap()
-                .to_offset(UtcOffset::from_whole_seconds(value.1).unwrap()),
-        )
+        Self(signature_time(value.0, value.1))
     }
 }
 
@@ -116,3 +196,43 @@ impl From<OffsetDateTime> for Timestamp {
         Self(value)
     }
 }
+
+impl From<&OffsetDateTime> for Timestamp {
+    fn from(value: &OffsetDateTime) -> Self {
+        Self(*value)
+    }
+}
+
+impl From<&&OffsetDateTime> for Timestamp {
+    fn from(value: &&OffsetDateTime) -> Self {
+        Self(**value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Duration, OffsetDateTime};
+
+    use super::timeago;
+    use crate::locale::LocaleRef;
+
+    #[test]
+    fn timeago_future() {
+        let locale = LocaleRef::default();
+
+        let future = OffsetDateTime::now_utc() + Duration::days(1);
+        assert_eq!(timeago(future, &locale).unwrap(), "now");
+
+        let past = OffsetDateTime::now_utc() - Duration::days(3);
+        assert_eq!(timeago(past, &locale).unwrap(), "3 days ago");
+    }
+
+    #[test]
+    fn timeago_clamped() {
+        let locale = LocaleRef::default();
+
+        // signature times past the last representable date are clamped to it
+        assert_eq!(timeago((i64::MAX, 0), &locale).unwrap(), "now");
+        assert!(timeago((i64::MIN, 0), &locale).unwrap().ends_with("ago"));
+    }
+}
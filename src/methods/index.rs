@@ -1,17 +1,29 @@
-use std::{cell::RefCell, sync::Arc};
+use std::{cell::RefCell, sync::Arc, time::Duration};
 
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use askama::Template;
 use axum::{
+    extract::Query,
+    http::StatusCode,
     response::{IntoResponse, Response},
     Extension,
 };
 use itertools::{Either, Itertools};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
 
-use super::filters;
+use super::{error::ErrorPage, filters, repo::ExportAll};
 use crate::{
-    database::schema::repository::{Repository, YokedRepository},
+    database::{
+        indexer::IndexerStatus,
+        schema::repository::{Repository, YokedRepository},
+    },
     into_response,
+    locale::LocaleRef,
+    storage::Storage,
+    IndexTrigger,
 };
 
 #[derive(Template)]
@@ -24,6 +36,65 @@ pub struct View<
     // this type sig is a necessary evil unfortunately, because askama takes a reference
     // to the data for rendering.
     pub repositories: RefCell<Either<GroupIter, std::iter::Empty<(&'a str, Group)>>>,
+    /// When the indexer last finished a run, which is as fresh as the listing gets.
+    pub data_as_of: Option<OffsetDateTime>,
+    /// Whether admin endpoints are enabled, showing a button to reindex straight away.
+    pub can_refresh: bool,
+    /// Whether an activity filter was given, and how many repositories it hid.
+    pub filtered: bool,
+    pub filtered_out: usize,
+    pub locale: LocaleRef,
+    /// Whether the scan path can't be read, leaving the listing as of the last index.
+    pub storage_unavailable: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UriQuery {
+    /// Only lists repositories committed to within this long, eg. `30d`.
+    active_within: Option<String>,
+    /// Only lists repositories that haven't been committed to for this long, eg. `180d`.
+    idle_for: Option<String>,
+}
+
+/// Narrows the listing down by when each repository was last committed to.
+#[derive(Debug, Clone, Copy)]
+pub enum ActivityFilter {
+    ActiveWithin(Duration),
+    IdleFor(Duration),
+}
+
+impl ActivityFilter {
+    /// Parses the filter from the query, returning a message to show the user if the
+    /// durations aren't valid or both filters were given at once.
+    pub fn from_query(query: &UriQuery) -> Result<Option<Self>, String> {
+        let parse = |name: &str, value: &str| {
+            humantime::parse_duration(value).map_err(|e| format!("Invalid {name} \"{value}\": {e}"))
+        };
+
+        match (query.active_within.as_deref(), query.idle_for.as_deref()) {
+            (Some(_), Some(_)) => {
+                Err("active-within and idle-for can't be used together".to_string())
+            }
+            (Some(v), None) => parse("active-within", v).map(|v| Some(Self::ActiveWithin(v))),
+            (None, Some(v)) => parse("idle-for", v).map(|v| Some(Self::IdleFor(v))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Whether a repository last committed to at `last_modified`, as a unix timestamp, passes
+    /// the filter at `now`.
+    pub fn matches(self, last_modified: i64, now: OffsetDateTime) -> bool {
+        let since = |duration: Duration| {
+            now.unix_timestamp()
+                .saturating_sub(i64::try_from(duration.as_secs()).unwrap_or(i64::MAX))
+        };
+
+        match self {
+            Self::ActiveWithin(duration) => last_modified >= since(duration),
+            Self::IdleFor(duration) => last_modified < since(duration),
+        }
+    }
 }
 
 impl<'a, Group, GroupIter> View<'a, Group, GroupIter>
@@ -38,11 +109,33 @@ where
 
 pub async fn handle(
     Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(status): Extension<Arc<ArcSwap<IndexerStatus>>>,
+    Extension(refresh_requests): Extension<Option<mpsc::Sender<IndexTrigger>>>,
+    Extension(locale): Extension<LocaleRef>,
+    Extension(ExportAll(export_all)): Extension<ExportAll>,
+    Extension(storage): Extension<Storage>,
+    Query(query): Query<UriQuery>,
 ) -> Result<Response, super::repo::Error> {
-    let fetched = tokio::task::spawn_blocking(move || Repository::fetch_all(&db))
+    let filter = match ActivityFilter::from_query(&query) {
+        Ok(v) => v,
+        Err(message) => return Ok(ErrorPage::new(StatusCode::BAD_REQUEST, message).into_response()),
+    };
+
+    let storage_unavailable = !storage.check_async().await?;
+
+    let mut fetched = tokio::task::spawn_blocking(move || Repository::fetch_all(&db))
         .await
         .context("Failed to join Tokio task")??;
 
+    // only left in the index until the indexer next runs after `--export-all` is turned off
+    fetched.retain(|_, repository| repository.get().is_visible(export_all));
+
+    let now = OffsetDateTime::now_utc();
+    let matching = |repository: &YokedRepository| {
+        filter.is_none_or(|f| f.matches(repository.get().last_modified.0.to_native(), now))
+    };
+    let filtered_out = fetched.values().filter(|v| !matching(v)).count();
+
     // rocksdb returned the keys already ordered for us so group_by is a nice
     // operation we can use here to avoid writing into a map to group. though,
     // now that i think about it it might act a little bit strangely when mixing
@@ -51,10 +144,94 @@ pub async fn handle(
     // as a TODO.
     let repositories = fetched
         .iter()
+        .filter(|(_, repository)| matching(repository))
         .group_by(|(k, _)| memchr::memrchr(b'/', k.as_bytes()).map_or("", |idx| &k[..idx]));
 
     Ok(into_response(View {
         repositories: Either::Left(repositories.into_iter()).into(),
+        data_as_of: status.load().last_success,
+        can_refresh: refresh_requests.is_some(),
+        filtered: filter.is_some(),
+        filtered_out,
+        locale,
+        storage_unavailable,
     })
     .into_response())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use time::OffsetDateTime;
+
+    use super::{ActivityFilter, UriQuery};
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn days(days: i64) -> Duration {
+        Duration::from_secs(u64::try_from(days * DAY).unwrap())
+    }
+
+    fn query(active_within: Option<&str>, idle_for: Option<&str>) -> UriQuery {
+        UriQuery {
+            active_within: active_within.map(ToString::to_string),
+            idle_for: idle_for.map(ToString::to_string),
+        }
+    }
+
+    #[test]
+    fn from_query() {
+        let thirty_days = days(30);
+
+        assert!(matches!(
+            ActivityFilter::from_query(&query(None, None)),
+            Ok(None)
+        ));
+        assert!(matches!(
+            ActivityFilter::from_query(&query(Some("30d"), None)),
+            Ok(Some(ActivityFilter::ActiveWithin(v))) if v == thirty_days
+        ));
+        assert!(matches!(
+            ActivityFilter::from_query(&query(None, Some("30days"))),
+            Ok(Some(ActivityFilter::IdleFor(v))) if v == thirty_days
+        ));
+
+        for (active_within, idle_for) in [
+            (Some("30d"), Some("30d")),
+            (Some("soon"), None),
+            (None, Some("")),
+            (None, Some("-1d")),
+        ] {
+            assert!(
+                ActivityFilter::from_query(&query(active_within, idle_for)).is_err(),
+                "{active_within:?} {idle_for:?}",
+            );
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let now = OffsetDateTime::from_unix_timestamp(1_000 * DAY).unwrap();
+        let week = days(7);
+        let ago = |days: i64| now.unix_timestamp() - days * DAY;
+
+        let active = ActivityFilter::ActiveWithin(week);
+        assert!(active.matches(ago(0), now));
+        assert!(active.matches(ago(7), now));
+        assert!(!active.matches(ago(7) - 1, now));
+        // clocks disagreeing can put the last change ahead of us
+        assert!(active.matches(ago(-1), now));
+
+        let idle = ActivityFilter::IdleFor(week);
+        assert!(!idle.matches(ago(0), now));
+        assert!(!idle.matches(ago(7), now));
+        assert!(idle.matches(ago(7) - 1, now));
+        assert!(idle.matches(0, now));
+
+        // durations longer than there's been time for don't overflow
+        let forever = Duration::from_secs(u64::MAX);
+        assert!(ActivityFilter::ActiveWithin(forever).matches(0, now));
+        assert!(!ActivityFilter::IdleFor(forever).matches(0, now));
+    }
+}
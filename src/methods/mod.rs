@@ -1,3 +1,7 @@
+pub mod conditional;
+pub mod error;
 pub mod filters;
 pub mod index;
 pub mod repo;
+pub mod status;
+pub mod version;
@@ -5,11 +5,11 @@ use axum::{extract::Query, response::IntoResponse, Extension};
 use serde::Deserialize;
 
 use crate::{
-    git::ReadmeFormat,
+    git::Readme,
     into_response,
     methods::{
         filters,
-        repo::{Repository, RepositoryPath, Result},
+        repo::{RepoContext, RepositoryPath, Result},
     },
     Git,
 };
@@ -23,13 +23,13 @@ pub struct UriQuery {
 #[derive(Template)]
 #[template(path = "repo/about.html")]
 pub struct View {
-    repo: Repository,
-    readme: Option<(ReadmeFormat, Arc<str>)>,
+    ctx: RepoContext,
+    readme: Option<Readme>,
     branch: Option<Arc<str>>,
 }
 
 pub async fn handle(
-    Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(git): Extension<Arc<Git>>,
     Query(query): Query<UriQuery>,
@@ -38,10 +38,12 @@ pub async fn handle(
         .clone()
         .repo(repository_path, query.branch.clone())
         .await?;
-    let readme = open_repo.readme().await?;
+    let readme = open_repo
+        .readme(Arc::from(ctx.repo.to_string_lossy()))
+        .await?;
 
     Ok(into_response(View {
-        repo,
+        ctx,
         readme,
         branch: query.branch,
     }))
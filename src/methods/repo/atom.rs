@@ -0,0 +1,336 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use askama::Template;
+use axum::{
+    extract::Query,
+    http::{self, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_extra::extract::Host;
+use rkyv::string::ArchivedString;
+use serde::Deserialize;
+use time::OffsetDateTime;
+use xxhash_rust::const_xxh3;
+use yoke::Yoke;
+
+use crate::{
+    database::schema::{commit::YokedCommit, repository::YokedRepository},
+    methods::{
+        conditional::Validators,
+        filters,
+        repo::{
+            log::fetch_commits,
+            releases::{build_tag_entries, fill_tag_messages, TagEntry},
+            summary::fetch_summary,
+            CommitDate, ReferenceNotFound, RepoContext, Repository, RepositoryPath, Result,
+        },
+    },
+    Git,
+};
+
+#[derive(Deserialize)]
+pub struct UriQuery {
+    #[serde(rename = "h")]
+    branch: Option<String>,
+    /// Which of each commit's dates entries are updated at.
+    #[serde(default)]
+    date: CommitDate,
+}
+
+#[derive(Template)]
+#[template(path = "repo/atom.xml")]
+pub struct View<'a> {
+    repo: Repository,
+    commits: &'a [YokedCommit],
+    branch: Option<String>,
+    host: String,
+    updated: OffsetDateTime,
+    date: CommitDate,
+}
+
+#[derive(Template)]
+#[template(path = "repo/tags_atom.xml")]
+pub struct TagsView {
+    repo: Repository,
+    tags: Vec<TagEntry>,
+    host: String,
+    updated: OffsetDateTime,
+    allow_snapshots: bool,
+}
+
+pub async fn handle(
+    Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
+    Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(git): Extension<Arc<Git>>,
+    Query(query): Query<UriQuery>,
+    Host(host): Host,
+    headers: HeaderMap,
+) -> Result<Response> {
+    if let Some(branch) = query.branch.clone() {
+        let (repo, db) = (repo.clone(), db.clone());
+
+        let exists = tokio::task::spawn_blocking(move || {
+            let repository = crate::database::schema::repository::Repository::open(&db, &*repo)?
+                .context("Repository does not exist")?;
+
+            has_reference(&repository, &db, &branch)
+        })
+        .await
+        .context("Failed to attach to tokio task")??;
+
+        if !exists {
+            return Ok(ReferenceNotFound.into_response());
+        }
+    }
+
+    let commits = fetch_commits(
+        repo.clone(),
+        db,
+        git,
+        query.branch.clone(),
+        ctx.feed_items,
+        0,
+    )
+    .await?;
+    let feed_len = usize::try_from(ctx.feed_items).unwrap_or(usize::MAX);
+    let commits = &commits[..commits.len().min(feed_len)];
+
+    // commits below the tip can't change without the tip changing too, so its oid is
+    // enough to identify the content of the feed, along with the dates it's showing
+    let mut etag = commits.first().map_or_else(
+        || "empty".to_string(),
+        |commit| const_hex::encode(commit.get().hash),
+    );
+    if query.date.is_author() {
+        etag.push_str("-author");
+    }
+    let validators = Validators::new(
+        etag,
+        commits.first().map(|commit| commit.get().committer.time()),
+    );
+
+    if validators.is_fresh(&headers) {
+        return Ok(validators.not_modified());
+    }
+
+    // author dates aren't ordered, a commit further down may well have been authored last
+    let updated = commits
+        .iter()
+        .map(|commit| query.date.of(commit.get()).time())
+        .max()
+        .unwrap_or_else(OffsetDateTime::now_utc);
+
+    let body = View {
+        repo,
+        commits,
+        branch: query.branch,
+        host,
+        updated,
+        date: query.date,
+    }
+    .render()
+    .context("Failed to render feed")?;
+
+    Ok(feed_response(body, &validators))
+}
+
+/// Checks the indexed heads of the repository for a branch or tag called `name`.
+fn has_reference(
+    repository: &YokedRepository,
+    db: &rocksdb::DB,
+    name: &str,
+) -> anyhow::Result<bool> {
+    let heads = repository.get().heads(db)?;
+
+    Ok(heads
+        .as_ref()
+        .map(Yoke::get)
+        .into_iter()
+        .flat_map(|heads| heads.0.as_slice())
+        .map(ArchivedString::as_str)
+        .any(|head| {
+            head.strip_prefix("refs/heads/")
+                .or_else(|| head.strip_prefix("refs/tags/"))
+                == Some(name)
+        }))
+}
+
+pub async fn handle_tags(
+    Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(git): Extension<Arc<Git>>,
+    Host(host): Host,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let summary = fetch_summary(
+        repo.clone(),
+        repository_path.clone(),
+        db.clone(),
+        git.clone(),
+    )
+    .await?;
+
+    let mut tags = tokio::task::spawn_blocking({
+        let repo = repo.clone();
+        move || build_tag_entries(&repo, &db, &summary, true)
+    })
+    .await
+    .context("Failed to attach to tokio task")??;
+    tags.truncate(usize::try_from(ctx.feed_items).unwrap_or(usize::MAX));
+
+    let validators = tags_validators(&tags);
+    if validators.is_fresh(&headers) {
+        return Ok(validators.not_modified());
+    }
+
+    let open_repo = git.repo(repository_path, None).await?;
+    fill_tag_messages(&open_repo, &mut tags).await?;
+
+    let updated = tags
+        .first()
+        .map_or_else(OffsetDateTime::now_utc, |tag| tag.updated);
+
+    let body = TagsView {
+        repo,
+        tags,
+        host,
+        updated,
+        allow_snapshots: ctx.allow_snapshots,
+    }
+    .render()
+    .context("Failed to render feed")?;
+
+    Ok(feed_response(body, &validators))
+}
+
+/// Tags are identified by their name and date rather than an oid, since the index doesn't
+/// hold the oids of tags. Recreating a tag moves its date along with it.
+fn tags_validators(tags: &[TagEntry]) -> Validators {
+    let mut identity = Vec::new();
+
+    for tag in tags {
+        identity.extend_from_slice(tag.name.as_bytes());
+        identity.push(b'\0');
+        identity.extend_from_slice(&tag.updated.unix_timestamp().to_be_bytes());
+    }
+
+    Validators::new(
+        const_hex::encode(const_xxh3::xxh3_64(&identity).to_be_bytes()),
+        tags.first().map(|tag| tag.updated),
+    )
+}
+
+pub(super) fn feed_response(body: String, validators: &Validators) -> Response {
+    let mut response = (
+        [(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("application/atom+xml"),
+        )],
+        body,
+    )
+        .into_response();
+    validators.apply(&mut response);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Response, StatusCode},
+        Router,
+    };
+    use tempfile::TempDir;
+
+    use crate::testing::{self, TestRepo};
+
+    #[tokio::test]
+    async fn branch_links_are_encoded() {
+        let repo = TestRepo::new();
+        let commit = repo.commit("README", "hello");
+        repo.git(&["update-ref", "refs/heads/feature/a&b#c", &commit]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        for uri in [
+            "/repo.git/atom?h=feature%2Fa%26b%23c",
+            "/repo.git/log?h=feature%2Fa%26b%23c",
+            "/repo.git/tree?h=feature%2Fa%26b%23c",
+        ] {
+            let response = testing::get(&mut app, uri, &[("host", "example.com")]).await;
+            assert!(response.status().is_success(), "{uri}");
+
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains("?h=feature%2Fa%26b%23c"), "{uri}: {body}");
+            assert!(!body.contains("h=feature/a"), "{uri}: {body}");
+        }
+    }
+
+    /// Fetches the repository's feed with `headers`.
+    async fn poll(app: &mut Router, headers: &[(&str, &str)]) -> Response<Body> {
+        let headers = [headers, &[("host", "example.com")]].concat();
+        testing::get(app, "/repo.git/atom", &headers).await
+    }
+
+    #[tokio::test]
+    async fn polled_conditionally() {
+        let repo = TestRepo::new();
+        let first = repo.commit("README", "hello");
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db.clone());
+
+        let response = poll(&mut app, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let last_modified = response.headers()["last-modified"]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // nothing's changed between polls
+        for header in [
+            ("if-none-match", etag.as_str()),
+            ("if-modified-since", last_modified.as_str()),
+        ] {
+            let response = poll(&mut app, &[header]).await;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{header:?}");
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert!(body.is_empty(), "{header:?}");
+        }
+
+        // a new commit with the same timestamp still changes the feed
+        repo.commit("README", "hello again");
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db.clone());
+
+        let response = poll(&mut app, &[("if-none-match", &etag)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let new_etag = response.headers()["etag"].to_str().unwrap().to_string();
+        assert_ne!(new_etag, etag);
+
+        // as does force-pushing back to an older commit
+        repo.git(&["update-ref", "HEAD", &first]);
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let headers = [
+            ("if-none-match", new_etag.as_str()),
+            ("if-modified-since", last_modified.as_str()),
+        ];
+        let response = poll(&mut app, &headers).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()["etag"], new_etag.as_str());
+    }
+}
@@ -0,0 +1,270 @@
+use std::{borrow::Cow, path::Path, process::Stdio, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use axum::{
+    body::Body,
+    extract::Query,
+    http::{self, HeaderMap, Response, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+use bytes::{Bytes, BytesMut};
+use gix::bstr::{BString, ByteSlice};
+use serde::Deserialize;
+use tokio::{
+    io::AsyncReadExt,
+    process::{Child, ChildStderr, ChildStdout, Command},
+    sync::mpsc,
+    time::Instant,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, info_span, warn, Instrument};
+
+use super::{
+    snapshot::{content_disposition, serve_cached, tee_to_cache},
+    ChildPath, DownloadDisabled, ObjectNotFound, ReferenceNotFound, RepoContext, Repository,
+    RepositoryPath, Result, StrictReachability,
+};
+use crate::{
+    git::{BundleBasis, Git},
+    methods::conditional::Validators,
+    snapshot_cache::{bundle_key, SnapshotCache},
+};
+
+/// Bundles are cut short once they grow past this, so a single download can't tie up the
+/// server indefinitely.
+const MAX_BUNDLE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// How long git is given to write out a bundle before it's killed, half an hour.
+const MAX_BUNDLE_DURATION: Duration = Duration::from_mins(30);
+
+const BUNDLE_CONTENT_TYPE: &str = "application/octet-stream";
+
+#[derive(Deserialize)]
+pub struct UriQuery {
+    since: Option<String>,
+}
+
+/// Serves a bundle of every branch and tag, for mirroring a repository offline, requested as
+/// `/repo/bundle`, or of a single branch or tag as `/repo/bundle/<ref>`. With `?since=<ref>`,
+/// objects reachable from the ref are left out, so an existing mirror can be brought up to
+/// date with `git fetch` from the bundle.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle(
+    Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(ChildPath(child_path)): Extension<ChildPath>,
+    Extension(git): Extension<Arc<Git>>,
+    Extension(snapshot_cache): Extension<Option<Arc<SnapshotCache>>>,
+    Extension(strict): Extension<StrictReachability>,
+    Query(query): Query<UriQuery>,
+    headers: HeaderMap,
+) -> Result<Response<Body>> {
+    if !ctx.allow_clone {
+        return Ok(DownloadDisabled::Clone.into_response());
+    }
+
+    let open_repo = git.repo(repository_path.clone(), None).await?;
+    if !strict.permits(&open_repo, query.since.as_deref()).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
+    let reference = child_path.map(|v| v.to_string_lossy().into_owned());
+    let Some(basis) = open_repo.bundle_basis(reference, query.since).await? else {
+        return Ok(ReferenceNotFound.into_response());
+    };
+
+    // the bundle only changes when a ref moves, which the key already accounts for
+    let key = bundle_key(&repository_path, basis.refs_digest, basis.since);
+    let validators = Validators::new(&key, None);
+    if validators.is_fresh(&headers) {
+        return Ok(validators.not_modified());
+    }
+
+    let repo_name = repo
+        .file_name()
+        .map(|v| v.to_string_lossy())
+        .unwrap_or_default();
+    let repo_name = repo_name.strip_suffix(".git").unwrap_or(&repo_name);
+    let file_name = match basis.refs.first() {
+        Some(reference) => format!(
+            "{repo_name}-{}.bundle",
+            short_ref_name(reference).replace('/', "-")
+        ),
+        None => format!("{repo_name}.bundle"),
+    };
+
+    let writer = if let Some(cache) = snapshot_cache {
+        if let Some(cached) = cache.open(&key).await {
+            return serve_cached(
+                cached,
+                &headers,
+                BUNDLE_CONTENT_TYPE,
+                &file_name,
+                &validators,
+            )
+            .await;
+        }
+
+        match cache.writer(key).await {
+            Ok(writer) => Some(writer),
+            Err(error) => {
+                warn!(%error, "Failed to start caching bundle");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut child = spawn_bundle(&repository_path, &basis)?;
+    let mut stdout = child.stdout.take().context("Stdout already taken")?;
+    let mut stderr = child.stderr.take().context("Stderr already taken")?;
+    let deadline = Instant::now() + MAX_BUNDLE_DURATION;
+
+    // wait for the start of the bundle before sending any headers, git refuses to write
+    // bundles without any objects in them
+    let mut out_buf = BytesMut::with_capacity(64 * 1024);
+    let n = stdout
+        .read_buf(&mut out_buf)
+        .await
+        .context("Failed to read bundle from git")?;
+
+    if n == 0 {
+        let status = child.wait().await.context("Failed to wait on git")?;
+        let mut err = String::new();
+        let _res = stderr.read_to_string(&mut err).await;
+
+        if err.contains("empty bundle") {
+            return Ok((StatusCode::NOT_FOUND, "Nothing to bundle").into_response());
+        }
+
+        return Err(anyhow!("git bundle exited with {status}: {}", err.trim()).into());
+    }
+
+    let (send, recv) = mpsc::channel(1);
+    tokio::spawn(
+        forward_bundle(out_buf, send, stdout, stderr, child, deadline)
+            .instrument(info_span!("git bundle reader")),
+    );
+
+    let recv = if let Some(writer) = writer {
+        tee_to_cache(recv, writer)
+    } else {
+        recv
+    };
+
+    let mut response = Response::builder()
+        .header(http::header::CONTENT_TYPE, BUNDLE_CONTENT_TYPE)
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            content_disposition(&file_name),
+        )
+        .body(Body::from_stream(ReceiverStream::new(recv)))
+        .context("failed to build response")?;
+    validators.apply(&mut response);
+
+    Ok(response)
+}
+
+/// Starts git writing the bundle described by `basis` to its stdout.
+fn spawn_bundle(repository_path: &Path, basis: &BundleBasis) -> anyhow::Result<Child> {
+    let mut command = Command::new("git");
+    command
+        .arg("-C")
+        .arg(repository_path)
+        .args(["bundle", "create", "--quiet", "-"]);
+
+    if basis.refs.is_empty() {
+        command.args(["--branches", "--tags"]);
+    } else {
+        // full names of refs we looked up ourselves, so always start with `refs/`
+        for reference in &basis.refs {
+            command.arg(&*reference.to_os_str_lossy());
+        }
+    }
+
+    if basis.head {
+        command.arg("HEAD");
+    }
+
+    // passed as an oid we resolved ourselves, so nothing from the client reaches git's
+    // arguments
+    if let Some(since) = basis.since {
+        command.arg(format!("^{since}"));
+    }
+
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to spawn git bundle")
+}
+
+/// The name of a branch or tag without its `refs/heads/` or `refs/tags/` prefix.
+fn short_ref_name(reference: &BString) -> Cow<'_, str> {
+    let name = reference
+        .strip_prefix(b"refs/heads/")
+        .or_else(|| reference.strip_prefix(b"refs/tags/"))
+        .unwrap_or(reference);
+    String::from_utf8_lossy(name)
+}
+
+/// Forwards the bundle git writes to `stdout` on to `send`, killing git and ending the
+/// stream with an error if the bundle grows too large or takes too long to write.
+async fn forward_bundle(
+    mut out_buf: BytesMut,
+    send: mpsc::Sender<Result<Bytes, anyhow::Error>>,
+    mut stdout: ChildStdout,
+    mut stderr: ChildStderr,
+    mut child: Child,
+    deadline: Instant,
+) {
+    let mut written = 0_u64;
+
+    let result = loop {
+        if !out_buf.is_empty() {
+            written += out_buf.len() as u64;
+            if written > MAX_BUNDLE_SIZE {
+                break Err(anyhow!("Bundle exceeded {MAX_BUNDLE_SIZE} bytes"));
+            }
+
+            if send.send(Ok(out_buf.split().freeze())).await.is_err() {
+                // client went away, git is killed as it's dropped
+                return;
+            }
+        }
+
+        out_buf.reserve(64 * 1024);
+
+        match tokio::time::timeout_at(deadline, stdout.read_buf(&mut out_buf)).await {
+            Ok(Ok(0)) => break Ok(()),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => break Err(anyhow!(e).context("Failed to read bundle from git")),
+            Err(_) => break Err(anyhow!("Bundle took longer than {MAX_BUNDLE_DURATION:?}")),
+        }
+    };
+
+    let result = match result {
+        Ok(()) => match child.wait().await {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => {
+                let mut err = String::new();
+                let _res = stderr.read_to_string(&mut err).await;
+                Err(anyhow!("git bundle exited with {status}: {}", err.trim()))
+            }
+            Err(e) => Err(anyhow!(e).context("Failed to wait on git")),
+        },
+        Err(e) => {
+            let _res = child.kill().await;
+            Err(e)
+        }
+    };
+
+    if let Err(error) = result {
+        error!(%error, "Failed to write bundle");
+        let _res = send.send(Err(anyhow!("git bundle failed"))).await;
+    }
+}
@@ -1,15 +1,23 @@
 use std::sync::Arc;
 
 use askama::Template;
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::Query,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Extension,
+};
 use serde::Deserialize;
 
 use crate::{
-    git::{Commit, OpenRepository},
+    git::{Commit, DiffFormat, OpenRepository},
     into_response,
     methods::{
+        conditional::Validators,
         filters,
-        repo::{Repository, RepositoryPath, Result},
+        repo::{
+            BranchNotFound, ObjectNotFound, RepoContext, RepositoryPath, Result, StrictReachability,
+        },
     },
     Git,
 };
@@ -17,7 +25,7 @@ use crate::{
 #[derive(Template)]
 #[template(path = "repo/commit.html")]
 pub struct View {
-    pub repo: Repository,
+    pub ctx: RepoContext,
     pub commit: Arc<Commit>,
     pub branch: Option<Arc<str>>,
     pub dl_branch: Arc<str>,
@@ -32,51 +40,157 @@ pub struct UriQuery {
 }
 
 pub async fn handle(
-    Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(git): Extension<Arc<Git>>,
+    Extension(strict): Extension<StrictReachability>,
     Query(query): Query<UriQuery>,
-) -> Result<impl IntoResponse> {
+    headers: HeaderMap,
+) -> Result<Response> {
+    let validators = query
+        .id
+        .as_deref()
+        .and_then(|id| Validators::for_page(id, ctx.locale));
+    if let Some(validators) = validators.as_ref().filter(|v| v.is_fresh(&headers)) {
+        return Ok(validators.not_modified());
+    }
+
     let open_repo = git.repo(repository_path, query.branch.clone()).await?;
+    if let Some(branches) = open_repo.clone().missing_branch().await? {
+        return Ok(BranchNotFound(branches).into_response());
+    }
+    if !strict.permits(&open_repo, query.id.as_deref()).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
 
     let (dl_branch, commit) = tokio::try_join!(
         fetch_dl_branch(query.branch.clone(), open_repo.clone()),
-        fetch_commit(query.id.as_deref(), open_repo),
+        fetch_commit(
+            query.id.as_deref(),
+            open_repo,
+            DiffFormat::highlighted(&ctx.repo)
+        ),
     )?;
 
-    Ok(into_response(View {
-        repo,
+    // a detached HEAD isn't on any branch, so the snapshot is taken of the commit itself
+    let id = query
+        .id
+        .or_else(|| dl_branch.is_none().then(|| commit.get().oid().to_string()));
+
+    let mut response = into_response(View {
+        ctx,
         commit,
         branch: query.branch,
-        id: query.id,
-        dl_branch,
-    }))
+        id,
+        dl_branch: dl_branch.unwrap_or_else(|| Arc::from("HEAD")),
+    })
+    .into_response();
+
+    if let Some(validators) = validators {
+        validators.apply(&mut response);
+    }
+
+    Ok(response)
 }
 
 async fn fetch_commit(
     commit_id: Option<&str>,
     open_repo: Arc<OpenRepository>,
+    format: DiffFormat,
 ) -> Result<Arc<Commit>> {
     Ok(if let Some(commit) = commit_id {
-        open_repo.commit(commit, true).await?
+        open_repo.commit(commit, format).await?
     } else {
-        Arc::new(open_repo.latest_commit(true).await?)
+        open_repo.latest_commit(format).await?
     })
 }
 
+/// Finds the branch snapshots of the commit are taken from, returning `None` if the
+/// repository's HEAD is detached.
 async fn fetch_dl_branch(
     branch: Option<Arc<str>>,
     open_repo: Arc<OpenRepository>,
-) -> Result<Arc<str>> {
+) -> Result<Option<Arc<str>>> {
     if let Some(branch) = branch.clone() {
-        Ok(branch)
+        Ok(Some(branch))
     } else {
-        Ok(Arc::from(
-            open_repo
-                .clone()
-                .default_branch()
-                .await?
-                .unwrap_or_else(|| "master".to_string()),
-        ))
+        Ok(open_repo.default_branch().await?.map(Arc::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use tempfile::TempDir;
+
+    use crate::testing::{self, TestRepo};
+
+    /// Parses the link in a line number cell of a diff, returning the commit and line it
+    /// points at, if it has one, and what follows the cell.
+    fn line_number(cell: &str) -> (Option<(&str, usize)>, &str) {
+        if let Some(rest) = cell.strip_prefix("<span></span>") {
+            return (None, rest);
+        }
+
+        let (href, rest) = cell
+            .strip_prefix(r#"<a href="/repo.git/tree/notes.txt?id="#)
+            .and_then(|v| v.split_once(r#"">"#))
+            .unwrap_or_else(|| panic!("unexpected line number {cell}"));
+        let (commit, line) = href.split_once("#L").unwrap();
+        let rest = rest.split_once("</a>").unwrap().1;
+
+        (Some((commit, line.parse().unwrap())), rest)
+    }
+
+    #[tokio::test]
+    async fn line_numbers_linked() {
+        let repo = TestRepo::new();
+        let old: Vec<_> = (1..=40).map(|i| format!("line {i}")).collect();
+        let parent = repo.commit("notes.txt", old.join("\n") + "\n");
+
+        // one line swapped for three near the top and two removed near the bottom, so the
+        // second hunk is offset on the new side
+        let mut new = old.clone();
+        new.splice(1..2, ["added a", "added b", "added c"].map(String::from));
+        new.drain(31..33);
+        let commit = repo.commit("notes.txt", new.join("\n") + "\n");
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let uri = format!("/repo.git/commit?id={commit}");
+        let response = testing::get(&mut app, &uri, &[("host", "example.com")]).await;
+        assert!(response.status().is_success());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        let (mut added, mut removed, mut context) = (0, 0, 0);
+
+        for line in body.split(r#"<span class="diff-line-numbers">"#).skip(1) {
+            let (old_link, rest) = line_number(line);
+            let (new_link, rest) = line_number(rest);
+            let text = rest.strip_prefix("</span>").unwrap();
+            let text = text.split_once('\n').unwrap().0;
+
+            match (old_link, new_link) {
+                (Some(_), Some(_)) => context += 1,
+                (Some(_), None) => removed += 1,
+                (None, Some(_)) => added += 1,
+                (None, None) => panic!("line without any numbers: {line}"),
+            }
+
+            // every link points at the same line in the file at that side of the diff
+            for (link, side, lines) in [(old_link, &parent, &old), (new_link, &commit, &new)] {
+                if let Some((id, n)) = link {
+                    assert_eq!(id, side, "{line}");
+                    assert_eq!(text, lines[n - 1], "{line}");
+                }
+            }
+        }
+
+        assert_eq!((added, removed), (3, 3));
+        assert!(context > 0);
     }
 }
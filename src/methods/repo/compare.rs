@@ -0,0 +1,76 @@
+use std::{path::Path, sync::Arc};
+
+use askama::Template;
+use axum::{
+    response::{IntoResponse, Response},
+    Extension,
+};
+use gix::bstr::ByteSlice;
+
+use crate::{
+    git::{Comparison, Git},
+    into_response,
+    methods::{
+        filters,
+        repo::{
+            ChildPath, ObjectNotFound, ReferenceNotFound, RepoContext, RepositoryPath, Result,
+            StrictReachability,
+        },
+    },
+};
+
+#[derive(Template)]
+#[template(path = "repo/compare.html")]
+pub struct View {
+    ctx: RepoContext,
+    base: String,
+    head: String,
+    comparison: Comparison,
+    branch: Option<Arc<str>>,
+}
+
+/// Compares two refs or commits against each other, requested as
+/// `/repo/compare/<base>...<head>`.
+pub async fn handle(
+    Extension(ctx): Extension<RepoContext>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(ChildPath(child_path)): Extension<ChildPath>,
+    Extension(git): Extension<Arc<Git>>,
+    Extension(strict): Extension<StrictReachability>,
+) -> Result<Response> {
+    let Some((base, head)) = child_path
+        .as_deref()
+        .and_then(Path::to_str)
+        .and_then(|v| v.split_once("..."))
+        .filter(|(base, head)| !base.is_empty() && !head.is_empty())
+    else {
+        return Ok(ReferenceNotFound.into_response());
+    };
+
+    let open_repo = git.repo(repository_path, None).await?;
+    for spec in [base, head] {
+        if !strict.permits(&open_repo, Some(spec)).await? {
+            return Ok(ObjectNotFound.into_response());
+        }
+    }
+
+    let Some(comparison) = open_repo
+        .compare(
+            base.to_string(),
+            head.to_string(),
+            Arc::from(ctx.repo.to_string_lossy()),
+        )
+        .await?
+    else {
+        return Ok(ReferenceNotFound.into_response());
+    };
+
+    Ok(into_response(View {
+        ctx,
+        base: base.to_string(),
+        head: head.to_string(),
+        comparison,
+        branch: None,
+    })
+    .into_response())
+}
@@ -1,22 +1,27 @@
 use std::{fmt::Write, sync::Arc};
 
+use anyhow::Context;
 use askama::Template;
 use axum::{
     extract::Query,
-    http::HeaderValue,
+    http::{HeaderMap, HeaderValue},
     response::{IntoResponse, Response},
     Extension,
 };
-use bytes::{BufMut, BytesMut};
+use bytes::BytesMut;
 use clap::crate_version;
 use time::format_description::well_known::Rfc2822;
 
 use crate::{
-    git::Commit,
+    git::{Commit, DiffFormat},
     http, into_response,
     methods::{
+        conditional::Validators,
         filters,
-        repo::{commit::UriQuery, Repository, RepositoryPath, Result},
+        repo::{
+            commit::UriQuery, BranchNotFound, ChildPath, ObjectNotFound, RepoContext,
+            RepositoryPath, Result, StrictReachability,
+        },
     },
     Git,
 };
@@ -24,41 +29,87 @@ use crate::{
 #[derive(Template)]
 #[template(path = "repo/diff.html")]
 pub struct View {
-    pub repo: Repository,
+    pub ctx: RepoContext,
     pub commit: Arc<Commit>,
     pub branch: Option<Arc<str>>,
 }
 
 pub async fn handle(
-    Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(git): Extension<Arc<Git>>,
+    Extension(strict): Extension<StrictReachability>,
     Query(query): Query<UriQuery>,
-) -> Result<impl IntoResponse> {
+    headers: HeaderMap,
+) -> Result<Response> {
+    let validators = query
+        .id
+        .as_deref()
+        .and_then(|id| Validators::for_page(id, ctx.locale));
+    if let Some(validators) = validators.as_ref().filter(|v| v.is_fresh(&headers)) {
+        return Ok(validators.not_modified());
+    }
+
     let open_repo = git.repo(repository_path, query.branch.clone()).await?;
+    if let Some(branches) = open_repo.clone().missing_branch().await? {
+        return Ok(BranchNotFound(branches).into_response());
+    }
+    if !strict.permits(&open_repo, query.id.as_deref()).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
     let commit = if let Some(commit) = query.id {
-        open_repo.commit(&commit, true).await?
+        open_repo
+            .commit(&commit, DiffFormat::highlighted(&ctx.repo))
+            .await?
     } else {
-        Arc::new(open_repo.latest_commit(true).await?)
+        open_repo
+            .latest_commit(DiffFormat::highlighted(&ctx.repo))
+            .await?
     };
 
-    Ok(into_response(View {
-        repo,
+    let mut response = into_response(View {
+        ctx,
         commit,
         branch: query.branch,
-    }))
+    })
+    .into_response();
+
+    if let Some(validators) = validators {
+        validators.apply(&mut response);
+    }
+
+    Ok(response)
 }
 
+/// Serves a commit as a patch `git am` can apply, the same as `git format-patch -1` would
+/// write it, requested as `/repo/patch?id=<oid>` or `/repo/patch/<oid>.patch`. Merge commits
+/// are diffed against their first parent.
 pub async fn handle_plain(
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(ChildPath(child_path)): Extension<ChildPath>,
     Extension(git): Extension<Arc<Git>>,
-    Query(query): Query<UriQuery>,
+    Extension(strict): Extension<StrictReachability>,
+    Query(mut query): Query<UriQuery>,
+    request_headers: HeaderMap,
 ) -> Result<Response> {
+    // the router only hands over oids, optionally with a `.patch` extension
+    if let Some(name) = child_path.as_deref().and_then(|v| v.to_str()) {
+        query.id = Some(name.strip_suffix(".patch").unwrap_or(name).to_string());
+    }
+
+    let validators = query.id.as_deref().and_then(Validators::for_object_id);
+    if let Some(validators) = validators.as_ref().filter(|v| v.is_fresh(&request_headers)) {
+        return Ok(validators.not_modified());
+    }
+
     let open_repo = git.repo(repository_path, query.branch).await?;
+    if !strict.permits(&open_repo, query.id.as_deref()).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
     let commit = if let Some(commit) = query.id {
-        open_repo.commit(&commit, false).await?
+        open_repo.commit(&commit, DiffFormat::Plain).await?
     } else {
-        Arc::new(open_repo.latest_commit(false).await?)
+        open_repo.latest_commit(DiffFormat::Plain).await?
     };
 
     let headers = [(
@@ -77,16 +128,14 @@ pub async fn handle_plain(
     )
     .unwrap();
 
-    write!(data, "Date: ").unwrap();
-    let mut writer = data.writer();
-    commit
+    // rfc 2822 can't represent years before 1900, which a commit is free to claim
+    let authored = commit
         .get()
         .author()
         .time()
-        .format_into(&mut writer, &Rfc2822)
-        .unwrap();
-    let mut data = writer.into_inner();
-    writeln!(data).unwrap();
+        .format(&Rfc2822)
+        .context("Commit date can't be represented in a patch")?;
+    writeln!(data, "Date: {authored}").unwrap();
 
     writeln!(data, "Subject: [PATCH] {}\n", commit.get().summary()).unwrap();
 
@@ -100,5 +149,11 @@ pub async fn handle_plain(
 
     writeln!(data, "--\nrgit {}", crate_version!()).unwrap();
 
-    Ok((headers, data.freeze()).into_response())
+    let mut response = (headers, data.freeze()).into_response();
+
+    if let Some(validators) = validators {
+        validators.apply(&mut response);
+    }
+
+    Ok(response)
 }
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use askama::Template;
+use axum::{
+    extract::Query,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    into_response,
+    methods::{
+        filters,
+        repo::{
+            BranchNotFound, ObjectNotFound, RepoContext, RepositoryPath, Result, StrictReachability,
+        },
+    },
+    Git,
+};
+
+/// Number of paths shown on each page.
+const PAGE_LENGTH: usize = 200;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct UriQuery {
+    #[serde(rename = "h", skip_serializing_if = "Option::is_none")]
+    branch: Option<Arc<str>>,
+    /// Only paths containing this string, ignoring case, are listed.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    q: String,
+    /// Only paths starting with this string are listed.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    prefix: String,
+    #[serde(rename = "ofs", skip_serializing_if = "Option::is_none")]
+    offset: Option<usize>,
+}
+
+#[derive(Template)]
+#[template(path = "repo/files.html")]
+pub struct View {
+    ctx: RepoContext,
+    paths: Vec<String>,
+    truncated: bool,
+    query: UriQuery,
+    next_query: Option<String>,
+    branch: Option<Arc<str>>,
+}
+
+pub async fn handle(
+    Extension(ctx): Extension<RepoContext>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(git): Extension<Arc<Git>>,
+    Extension(strict): Extension<StrictReachability>,
+    Query(query): Query<UriQuery>,
+) -> Result<Response> {
+    let open_repo = git.repo(repository_path, query.branch.clone()).await?;
+    if let Some(branches) = open_repo.clone().missing_branch().await? {
+        return Ok(BranchNotFound(branches).into_response());
+    }
+    if !strict.permits(&open_repo, None).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
+    let path_list = open_repo.path_list().await?;
+
+    let needle = query.q.to_lowercase();
+    let offset = query.offset.unwrap_or(0);
+
+    let mut paths: Vec<_> = path_list
+        .paths
+        .iter()
+        .filter(|path| path.starts_with(&query.prefix))
+        .filter(|path| needle.is_empty() || path.to_lowercase().contains(&needle))
+        .skip(offset)
+        .take(PAGE_LENGTH + 1)
+        .cloned()
+        .collect();
+
+    let next_query = if paths.len() > PAGE_LENGTH {
+        paths.pop();
+
+        let next = UriQuery {
+            offset: Some(offset + PAGE_LENGTH),
+            ..query.clone()
+        };
+
+        Some(serde_urlencoded::to_string(next).context("Failed to build next page link")?)
+    } else {
+        None
+    };
+
+    Ok(into_response(View {
+        ctx,
+        paths,
+        truncated: path_list.truncated,
+        branch: query.branch.clone(),
+        query,
+        next_query,
+    })
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::testing::{self, TestRepo};
+
+    #[tokio::test]
+    async fn links_are_encoded() {
+        let repo = TestRepo::new();
+        repo.commit("dir?x/a b#c%.txt", "hello");
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        for (uri, link) in [
+            (
+                "/repo.git/files",
+                "/repo.git/tree/dir%3Fx/a%20b%23c%25.txt\"",
+            ),
+            ("/repo.git/tree", "/repo.git/tree/dir%3Fx\""),
+            (
+                "/repo.git/tree/dir%3Fx",
+                "/repo.git/tree/dir%3Fx/a%20b%23c%25.txt\"",
+            ),
+        ] {
+            let response = testing::get(&mut app, uri, &[]).await;
+            assert!(response.status().is_success(), "{uri}");
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains(link), "{uri}: {body}");
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::sync::Arc;
+
+use axum::{
+    http::{self, HeaderValue},
+    response::IntoResponse,
+    Extension,
+};
+
+use crate::{
+    methods::repo::{RepositoryPath, Result},
+    Git,
+};
+
+/// Serves the repository's HEAD file, as expected by tooling and the dumb HTTP protocol.
+pub async fn handle(
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(git): Extension<Arc<Git>>,
+) -> Result<impl IntoResponse> {
+    let open_repo = git.repo(repository_path, None).await?;
+    let head = open_repo.head_ref().await?;
+
+    Ok((
+        [(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain"),
+        )],
+        format!("{head}\n"),
+    ))
+}
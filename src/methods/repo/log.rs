@@ -1,17 +1,40 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::Context;
 use askama::Template;
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::Query,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_extra::extract::Host;
+use gix::bstr::ByteSlice;
 use serde::Deserialize;
+use time::OffsetDateTime;
+use tracing::warn;
 
 use crate::{
-    database::schema::{commit::YokedCommit, repository::YokedRepository},
-    into_response,
+    database::schema::{
+        commit::{CommitTree, YokedCommit},
+        repository::YokedRepository,
+    },
+    git::{Abbreviations, FileHistoryEntry},
+    into_response, into_response_with_status,
     methods::{
+        conditional::Validators,
         filters,
-        repo::{Repository, Result, DEFAULT_BRANCHES},
+        repo::{
+            atom::feed_response, summary::fetch_summary, BranchNotFound, ChildPath, CommitDate,
+            Decoration, ObjectNotFound, ReferenceNotFound, RepoContext, Repository, RepositoryPath,
+            Result, StrictReachability, DEFAULT_BRANCHES,
+        },
     },
+    Git,
 };
 
 #[derive(Deserialize)]
@@ -20,46 +43,426 @@ pub struct UriQuery {
     offset: Option<u64>,
     #[serde(rename = "h")]
     branch: Option<String>,
+    /// Restricts the log to commits which changed the file at this path.
+    path: Option<PathBuf>,
+    /// Set to `0` to stop following the file across renames.
+    follow: Option<u8>,
+    /// Set to `atom` for a feed of the history of `path`.
+    #[serde(default)]
+    format: LogFormat,
+    /// Which of each commit's dates is shown.
+    #[serde(default)]
+    date: CommitDate,
+}
+
+#[derive(Deserialize, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Html,
+    Atom,
 }
 
+/// Number of commits kept in the recent commits cache for each branch, a page of the log along
+/// with the one telling whether there's a next page.
+const RECENT_COMMITS: u64 = 101;
+
 #[derive(Template)]
 #[template(path = "repo/log.html")]
-pub struct View {
-    repo: Repository,
-    commits: Vec<YokedCommit>,
+pub struct View<'a> {
+    ctx: RepoContext,
+    commits: &'a [YokedCommit],
     next_offset: Option<u64>,
     branch: Option<String>,
+    decorations: HashMap<[u8; 20], Vec<Decoration>>,
+    /// Whether the log ends early because the repository is a shallow mirror.
+    truncated: bool,
+    /// Whether the log ends early because older history is still being indexed.
+    backfilling: bool,
+    date: CommitDate,
+}
+
+#[derive(Template)]
+#[template(path = "repo/file_log.html")]
+pub struct FileView {
+    ctx: RepoContext,
+    entries: Vec<FileHistoryEntry>,
+    truncated: bool,
+    next_offset: Option<u64>,
+    branch: Option<String>,
+    path: PathBuf,
+    follow: bool,
+    abbreviations: Abbreviations,
+}
+
+#[derive(Template)]
+#[template(path = "repo/file_atom.xml")]
+pub struct FileFeedView {
+    repo: Repository,
+    entries: Vec<FileHistoryEntry>,
+    branch: Option<String>,
+    path: PathBuf,
+    follow: bool,
+    host: String,
+    updated: OffsetDateTime,
+}
+
+/// The history of a single path, as asked for by the request.
+struct FileHistoryRequest {
+    path: PathBuf,
+    follow: bool,
+    offset: u64,
+    branch: Option<String>,
+    format: LogFormat,
 }
 
+/// Serves the log of a branch, or the history of a single path when requested either as
+/// `/repo/log?path=<path>&h=<ref>` or as `/repo/log/<ref>/<path>`, either of which can be
+/// given `format=atom` for a feed.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle(
     Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(ChildPath(child_path)): Extension<ChildPath>,
     Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(git): Extension<Arc<Git>>,
+    Extension(strict): Extension<StrictReachability>,
     Query(query): Query<UriQuery>,
-) -> Result<impl IntoResponse> {
-    tokio::task::spawn_blocking(move || {
-        let offset = query.offset.unwrap_or(0);
-
-        let repository = crate::database::schema::repository::Repository::open(&db, &*repo)?
-            .context("Repository does not exist")?;
-        let mut commits =
-            get_branch_commits(&repository, &db, query.branch.as_deref(), 101, offset)?;
-
-        let next_offset = if commits.len() == 101 {
-            commits.pop();
-            Some(offset + 100)
-        } else {
-            None
+    Host(host): Host,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let history_request = |path, branch| FileHistoryRequest {
+        path,
+        follow: query.follow != Some(0),
+        offset: query.offset.unwrap_or(0),
+        branch,
+        format: query.format,
+    };
+
+    if let Some(spec) = child_path {
+        let Some((branch, path)) = git
+            .clone()
+            .repo(repository_path.clone(), None)
+            .await?
+            .split_reference_path(spec.to_string_lossy().into_owned())
+            .await?
+        else {
+            return Ok(ReferenceNotFound.into_response());
         };
 
-        Ok(into_response(View {
-            repo,
-            commits,
-            next_offset,
-            branch: query.branch,
-        }))
+        let request = history_request(path, Some(branch));
+        return file_history(ctx, repository_path, git, strict, request, host, &headers).await;
+    }
+
+    if let Some(path) = query.path.clone() {
+        let request = history_request(path, query.branch.clone());
+        return file_history(ctx, repository_path, git, strict, request, host, &headers).await;
+    }
+
+    let open_repo = git
+        .clone()
+        .repo(
+            repository_path.clone(),
+            query.branch.as_deref().map(Arc::from),
+        )
+        .await?;
+    if let Some(branches) = open_repo.missing_branch().await? {
+        return Ok(BranchNotFound(branches).into_response());
+    }
+
+    let summary = fetch_summary(
+        repo.clone(),
+        repository_path.clone(),
+        db.clone(),
+        git.clone(),
+    )
+    .await?;
+
+    let offset = query.offset.unwrap_or(0);
+    let branch = query.branch.clone();
+    let page_size = ctx.log_page_size;
+
+    // one more than a page is fetched to tell whether there's a next page
+    let commits = fetch_commits(
+        repo.clone(),
+        db.clone(),
+        git.clone(),
+        query.branch,
+        page_size + 1,
+        offset,
+    )
+    .await?;
+
+    let page_len = usize::try_from(page_size).unwrap_or(usize::MAX);
+    let (commits, next_offset) = if commits.len() > page_len {
+        (&commits[..page_len], Some(offset + page_size))
+    } else {
+        (&commits[..], None)
+    };
+
+    let backfilling = if next_offset.is_none() {
+        let branch = branch.clone();
+        tokio::task::spawn_blocking(move || is_backfilling(&db, &repo, branch.as_deref()))
+            .await
+            .context("Failed to attach to tokio task")??
+    } else {
+        false
+    };
+
+    // the index has no idea whether the oldest commit it has is a root commit or the boundary
+    // of a shallow mirror, so ask the repository once we're on the last page
+    let truncated = match (next_offset, commits.last()) {
+        (None, Some(oldest)) if !backfilling => {
+            git.repo(repository_path, None)
+                .await?
+                .is_history_truncated(oldest.get().hash)
+                .await?
+        }
+        _ => false,
+    };
+
+    Ok(into_response(View {
+        ctx,
+        commits,
+        next_offset,
+        branch,
+        decorations: summary.refs.decorations(),
+        truncated,
+        backfilling,
+        date: query.date,
     })
-    .await
-    .context("Failed to attach to tokio task")?
+    .into_response())
+}
+
+/// Fetches the latest commits of `branch` through the cache shared by the summary, the first
+/// page of the log and feeds, so bursts of requests for them only read the index once.
+pub(super) async fn fetch_recent_commits(
+    repo: Repository,
+    db: Arc<rocksdb::DB>,
+    git: Arc<Git>,
+    branch: Option<String>,
+) -> Result<Arc<[YokedCommit]>> {
+    let path = repo.to_path_buf();
+
+    Ok(git
+        .recent_commits(path, branch.clone(), async move {
+            tokio::task::spawn_blocking(move || load_recent_commits(&db, &repo, branch.as_deref()))
+                .await
+                .context("Failed to attach to tokio task")?
+        })
+        .await?)
+}
+
+/// Fetches `amount` commits of `branch` starting `offset` commits in, going through the recent
+/// commits cache when they're all within it and the cache of other pages otherwise.
+pub async fn fetch_commits(
+    repo: Repository,
+    db: Arc<rocksdb::DB>,
+    git: Arc<Git>,
+    branch: Option<String>,
+    amount: u64,
+    offset: u64,
+) -> Result<Arc<[YokedCommit]>> {
+    if offset == 0 && amount <= RECENT_COMMITS {
+        return fetch_recent_commits(repo, db, git, branch).await;
+    }
+
+    let path = repo.to_path_buf();
+
+    Ok(git
+        .commit_page(path, branch.clone(), amount, offset, async move {
+            tokio::task::spawn_blocking(move || {
+                let repository =
+                    crate::database::schema::repository::Repository::open(&db, &*repo)?
+                        .context("Repository does not exist")?;
+
+                get_branch_commits(&repository, &db, branch.as_deref(), amount, offset)
+                    .map(Arc::from)
+            })
+            .await
+            .context("Failed to attach to tokio task")?
+        })
+        .await?)
+}
+
+/// Reads the latest commits of the repository at `path` from the index and caches them, run
+/// after every index so the first visitors to the repository don't have to.
+pub async fn prime_recent_commits(git: &Git, db: &Arc<rocksdb::DB>) {
+    let repositories = match crate::database::schema::repository::Repository::fetch_all(db) {
+        Ok(v) => v,
+        Err(error) => {
+            warn!(%error, "Failed to read repository index to prime recent commits");
+            return;
+        }
+    };
+
+    for path in repositories.into_keys() {
+        match load_recent_commits(db, Path::new(&path), None) {
+            Ok(commits) => {
+                git.prime_recent_commits(PathBuf::from(path), None, commits)
+                    .await;
+            }
+            Err(error) => warn!(%error, "Failed to prime recent commits of {path}"),
+        }
+    }
+}
+
+fn load_recent_commits(
+    db: &Arc<rocksdb::DB>,
+    path: &Path,
+    branch: Option<&str>,
+) -> anyhow::Result<Arc<[YokedCommit]>> {
+    let repository = crate::database::schema::repository::Repository::open(db, path)?
+        .context("Repository does not exist")?;
+
+    get_branch_commits(&repository, db, branch, RECENT_COMMITS, 0).map(Arc::from)
+}
+
+/// Renders the history of a single file, which isn't indexed so is walked from the repository
+/// itself.
+async fn file_history(
+    ctx: RepoContext,
+    repository_path: PathBuf,
+    git: Arc<Git>,
+    strict: StrictReachability,
+    request: FileHistoryRequest,
+    host: String,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    if request.format == LogFormat::Atom {
+        return file_history_feed(ctx, repository_path, git, strict, request, host, headers).await;
+    }
+
+    let FileHistoryRequest {
+        path,
+        follow,
+        offset,
+        branch,
+        ..
+    } = request;
+    let page_size = ctx.log_page_size;
+    let page_len = usize::try_from(page_size).unwrap_or(usize::MAX);
+
+    let open_repo = git
+        .repo(repository_path, branch.as_deref().map(Arc::from))
+        .await?;
+    if let Some(branches) = open_repo.clone().missing_branch().await? {
+        return Ok(BranchNotFound(branches).into_response());
+    }
+    if !strict.permits(&open_repo, None).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
+    let history = open_repo
+        .clone()
+        .file_history(
+            path.clone(),
+            follow,
+            usize::try_from(offset).unwrap_or(usize::MAX),
+            page_len + 1,
+            false,
+        )
+        .await?;
+
+    let mut entries = history.entries;
+
+    let next_offset = if entries.len() > page_len {
+        entries.truncate(page_len);
+        Some(offset + page_size)
+    } else {
+        None
+    };
+
+    let abbreviations = open_repo
+        .abbreviations(
+            entries
+                .iter()
+                .filter(|entry| entry.renamed_from.is_some())
+                .map(|entry| entry.commit().id())
+                .collect::<Vec<_>>(),
+        )
+        .await?;
+
+    // a path that never existed has no history at all, rather than an empty page of it
+    let status = if entries.is_empty() && offset == 0 {
+        StatusCode::NOT_FOUND
+    } else {
+        StatusCode::OK
+    };
+
+    Ok(into_response_with_status(
+        status,
+        FileView {
+            ctx,
+            entries,
+            truncated: history.truncated,
+            next_offset,
+            branch,
+            path,
+            follow,
+            abbreviations,
+        },
+    )
+    .into_response())
+}
+
+/// Serves the latest commits to touch a single file as a feed, with the lines each of them
+/// changed in it.
+async fn file_history_feed(
+    ctx: RepoContext,
+    repository_path: PathBuf,
+    git: Arc<Git>,
+    strict: StrictReachability,
+    request: FileHistoryRequest,
+    host: String,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let open_repo = git
+        .repo(repository_path, request.branch.as_deref().map(Arc::from))
+        .await?;
+    if let Some(branches) = open_repo.clone().missing_branch().await? {
+        return Ok(BranchNotFound(branches).into_response());
+    }
+    if !strict.permits(&open_repo, None).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
+
+    let history = open_repo
+        .file_history(
+            request.path.clone(),
+            request.follow,
+            0,
+            usize::try_from(ctx.feed_items).unwrap_or(usize::MAX),
+            true,
+        )
+        .await?;
+
+    // older commits to the file can't change without the newest changing too
+    let newest = history.entries.first().map(FileHistoryEntry::commit);
+    let validators = Validators::new(
+        newest.map_or_else(|| "empty".to_string(), |commit| commit.oid().to_string()),
+        newest.map(|commit| commit.committer().time()),
+    );
+
+    if validators.is_fresh(headers) {
+        return Ok(validators.not_modified());
+    }
+
+    let updated = newest.map_or_else(OffsetDateTime::now_utc, |commit| commit.committer().time());
+
+    let body = FileFeedView {
+        repo: ctx.repo,
+        entries: history.entries,
+        branch: request.branch,
+        path: request.path,
+        follow: request.follow,
+        host,
+        updated,
+    }
+    .render()
+    .context("Failed to render feed")?;
+
+    Ok(feed_response(body, &validators))
 }
 
 pub fn get_branch_commits(
@@ -68,23 +471,34 @@ pub fn get_branch_commits(
     branch: Option<&str>,
     amount: u64,
     offset: u64,
-) -> Result<Vec<YokedCommit>> {
+) -> anyhow::Result<Vec<YokedCommit>> {
+    match find_commit_tree(repository, database, branch)? {
+        Some(commit_tree) => commit_tree.fetch_latest(amount, offset),
+        None => Ok(vec![]),
+    }
+}
+
+/// Picks the indexed history that the log of `branch` is read from, or of the default branch
+/// if `None`. Branches take precedence over tags of the same name.
+fn find_commit_tree(
+    repository: &YokedRepository,
+    database: &Arc<rocksdb::DB>,
+    branch: Option<&str>,
+) -> anyhow::Result<Option<CommitTree>> {
     if let Some(reference) = branch {
         let commit_tree = repository
             .get()
             .commit_tree(database.clone(), &format!("refs/heads/{reference}"));
-        let commit_tree = commit_tree.fetch_latest(amount, offset)?;
 
-        if !commit_tree.is_empty() {
-            return Ok(commit_tree);
+        if commit_tree.len()? > 0 {
+            return Ok(Some(commit_tree));
         }
 
-        let tag_tree = repository
-            .get()
-            .commit_tree(database.clone(), &format!("refs/tags/{reference}"));
-        let tag_tree = tag_tree.fetch_latest(amount, offset)?;
-
-        return Ok(tag_tree);
+        return Ok(Some(
+            repository
+                .get()
+                .commit_tree(database.clone(), &format!("refs/tags/{reference}")),
+        ));
     }
 
     for branch in repository
@@ -95,12 +509,128 @@ pub fn get_branch_commits(
         .chain(DEFAULT_BRANCHES.into_iter())
     {
         let commit_tree = repository.get().commit_tree(database.clone(), branch);
-        let commits = commit_tree.fetch_latest(amount, offset)?;
 
-        if !commits.is_empty() {
-            return Ok(commits);
+        if commit_tree.len()? > 0 {
+            return Ok(Some(commit_tree));
         }
     }
 
-    Ok(vec![])
+    Ok(None)
+}
+
+/// Whether the older history of `branch` is still being indexed, in which case the log ends
+/// early.
+fn is_backfilling(
+    db: &Arc<rocksdb::DB>,
+    repo: &Path,
+    branch: Option<&str>,
+) -> anyhow::Result<bool> {
+    let repository = crate::database::schema::repository::Repository::open(db, repo)?
+        .context("Repository does not exist")?;
+
+    Ok(match find_commit_tree(&repository, db, branch)? {
+        Some(commit_tree) => commit_tree.backfill()?.is_some(),
+        None => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use tempfile::TempDir;
+
+    use crate::testing::{self, TestRepo};
+
+    #[tokio::test]
+    async fn shallow_mirrors_truncated() {
+        let repo = TestRepo::new();
+        for i in 0..3 {
+            repo.commit("README", i.to_string());
+        }
+        repo.clone("shallow.git", &["--depth", "2"]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        for (uri, truncated) in [
+            ("/repo.git/log", false),
+            ("/shallow.git/log", true),
+            ("/repo.git/log?path=README", false),
+            ("/shallow.git/log?path=README", true),
+        ] {
+            let response = testing::get(&mut app, uri, &[("host", "example.com")]).await;
+            assert!(response.status().is_success(), "{uri}");
+
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert_eq!(
+                body.contains("history truncated (shallow mirror)"),
+                truncated,
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn file_feed() {
+        let repo = TestRepo::new();
+        repo.commit("a.rs", "fn main() {}\n");
+        repo.remove("a.rs");
+        repo.commit("b.rs", "fn main() {}\n");
+        let newest = repo.commit("b.rs", "fn main() {}\nfn other() {}\n");
+        repo.commit("README", "unrelated");
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        for (uri, renamed_from) in [
+            ("/repo.git/log?path=b.rs&format=atom", Some("a.rs")),
+            ("/repo.git/log/main/b.rs?format=atom", Some("a.rs")),
+            ("/repo.git/log?path=b.rs&format=atom&follow=0", None),
+        ] {
+            let response = testing::get(&mut app, uri, &[("host", "example.com")]).await;
+            assert!(response.status().is_success(), "{uri}");
+            assert_eq!(response.headers()["content-type"], "application/atom+xml");
+
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let feed = atom_syndication::Feed::read_from(&body[..]).unwrap();
+            let entries = feed.entries();
+
+            assert_eq!(
+                entries.len(),
+                if renamed_from.is_some() { 3 } else { 2 },
+                "{uri}"
+            );
+            assert_eq!(
+                entries[0].links()[0].href(),
+                format!("https://example.com/repo.git/commit/?id={newest}")
+            );
+            let content = entries[0].content().unwrap().value().unwrap();
+            assert!(
+                content.starts_with("1 insertions(+), 0 deletions(-)"),
+                "{content}"
+            );
+
+            let renamed = entries[1].title().as_str();
+            assert_eq!(
+                renamed.strip_suffix(" (renamed from a.rs)").is_some(),
+                renamed_from.is_some(),
+                "{uri}: {renamed}"
+            );
+        }
+
+        // validated by the newest commit to touch the file, which the unrelated commit isn't
+        let uri = "/repo.git/log?path=b.rs&format=atom";
+        let response = testing::get(&mut app, uri, &[("host", "example.com")]).await;
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        assert!(etag.contains(&newest), "{etag}");
+
+        let headers = [("host", "example.com"), ("if-none-match", etag.as_str())];
+        let response = testing::get(&mut app, uri, &headers).await;
+        assert_eq!(response.status(), axum::http::StatusCode::NOT_MODIFIED);
+    }
 }
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::Query,
+    http::{self, HeaderMap, Response},
+    response::IntoResponse,
+    Extension,
+};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, info_span, Instrument};
+
+use super::{ObjectNotFound, ReferenceNotFound, RepositoryPath, Result, StrictReachability};
+use crate::{git::Git, methods::conditional::Validators};
+
+#[derive(Deserialize)]
+pub struct UriQuery {
+    #[serde(rename = "h")]
+    branch: Option<Arc<str>>,
+    id: Option<Arc<str>>,
+}
+
+/// Lists every file of the tree a snapshot would be built from, for verifying the contents
+/// of a checkout or snapshot file by file.
+pub async fn handle(
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(git): Extension<Arc<Git>>,
+    Extension(strict): Extension<StrictReachability>,
+    Query(query): Query<UriQuery>,
+    headers: HeaderMap,
+) -> Result<Response<Body>> {
+    let validators = query.id.as_deref().and_then(Validators::for_object_id);
+    if let Some(validators) = validators.as_ref().filter(|v| v.is_fresh(&headers)) {
+        return Ok(validators.not_modified());
+    }
+
+    let open_repo = git.repo(repository_path, query.branch.clone()).await?;
+    if !strict.permits(&open_repo, query.id.as_deref()).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
+
+    let commit = match query.id {
+        Some(id) => match open_repo.clone().resolve_commit_id(id.to_string()).await? {
+            Some(oid) => Some(oid),
+            None => return Ok(ReferenceNotFound.into_response()),
+        },
+        None => None,
+    };
+
+    let Some(tree) = open_repo.clone().snapshot_tree(commit).await? else {
+        return Ok(ReferenceNotFound.into_response());
+    };
+
+    // a branch can move, but the listing only changes along with the tree
+    let validators = validators.unwrap_or_else(|| Validators::new(tree, None));
+    if validators.is_fresh(&headers) {
+        return Ok(validators.not_modified());
+    }
+
+    let (send, recv) = mpsc::channel(1);
+
+    tokio::spawn(
+        async move {
+            if let Err(error) = open_repo.manifest(send.clone(), tree).await {
+                error!(%error, "Failed to build manifest for client");
+                let _res = send.send(Err(error)).await;
+            }
+        }
+        .instrument(info_span!("sender")),
+    );
+
+    let mut response = Response::builder()
+        .header(http::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from_stream(ReceiverStream::new(recv)))
+        .context("failed to build response")?;
+    validators.apply(&mut response);
+
+    Ok(response)
+}
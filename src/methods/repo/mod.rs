@@ -1,92 +1,551 @@
 mod about;
+mod atom;
+mod bundle;
 mod commit;
+mod compare;
 mod diff;
+mod files;
+mod head;
 mod log;
+mod manifest;
+mod raw;
 mod refs;
+mod releases;
 mod smart_git;
 mod snapshot;
 mod summary;
 mod tag;
 mod tree;
+mod warm;
 
 use std::{
-    collections::BTreeMap,
+    borrow::Cow,
     ops::Deref,
     path::{Path, PathBuf},
     sync::{Arc, LazyLock},
 };
 
+use anyhow::Context;
 use axum::{
     body::Body,
     handler::Handler,
-    http::{Request, StatusCode},
+    http::{self, HeaderValue, Request, StatusCode},
     response::{IntoResponse, Response},
 };
 use path_clean::PathClean;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::{debug, error};
 
 use self::{
     about::handle as handle_about,
+    atom::{handle as handle_atom, handle_tags as handle_tags_atom},
+    bundle::handle as handle_bundle,
     commit::handle as handle_commit,
+    compare::handle as handle_compare,
     diff::{handle as handle_diff, handle_plain as handle_patch},
+    files::handle as handle_files,
+    head::handle as handle_head,
     log::handle as handle_log,
-    refs::handle as handle_refs,
+    manifest::handle as handle_manifest,
+    raw::handle as handle_raw,
+    refs::{handle as handle_refs, handle_heads as handle_heads_txt},
+    releases::handle as handle_releases,
     smart_git::handle as handle_smart_git,
     snapshot::handle as handle_snapshot,
     summary::handle as handle_summary,
     tag::handle as handle_tag,
     tree::handle as handle_tree,
 };
-use crate::database::schema::tag::YokedString;
-use crate::database::schema::{commit::YokedCommit, tag::YokedTag};
+
+pub use self::{
+    log::{fetch_commits, prime_recent_commits},
+    warm::warm_caches,
+};
+pub use crate::database::refs::{Decoration, Refs};
+use crate::database::schema::{
+    commit::{ArchivedAuthor, ArchivedCommit},
+    repository::{ArchivedRepository, Link, YokedRepository},
+    tombstone::Tombstone,
+};
+use crate::git::{
+    clone_shared_error, is_export_ok, AmbiguousCommitId, ArchiveFormat, CommitNotFound,
+    EmptyRepository, Git, OpenRepository, RepositoryBusy,
+};
+use crate::htpasswd::Htpasswd;
+use crate::layers::latency::RouteGroup;
+use crate::locale::LocaleRef;
+use crate::methods::error::ErrorPage;
+use crate::storage::Storage;
+use crate::IndexTrigger;
 
 pub const DEFAULT_BRANCHES: [&str; 2] = ["refs/heads/master", "refs/heads/main"];
 
 // this is some wicked, wicked abuse of axum right here...
-#[allow(clippy::trait_duplication_in_bounds)] // clippy seems a bit.. lost
+#[allow(clippy::trait_duplication_in_bounds, clippy::too_many_lines)] // clippy seems a bit.. lost
 pub async fn service(mut request: Request<Body>) -> Response {
     let scan_path = request
         .extensions()
         .get::<Arc<PathBuf>>()
         .expect("scan_path missing");
 
+    let decoded_path = match decode_path(request.uri().path()) {
+        Ok(v) => v,
+        Err(reason) => {
+            debug!(path = request.uri().path(), reason, "Rejected request path");
+            return InvalidRequest.into_response();
+        }
+    };
+
     let ParsedUri {
         uri,
         child_path,
         action,
-    } = parse_uri(request.uri().path().trim_matches('/'));
+    } = parse_uri(decoded_path.trim_matches('/'));
 
+    let raw_uri = uri;
     let uri = Path::new(uri).clean();
+
+    // cleaning resolves every `..` it can, so any left over would escape the scan path
+    if escapes_root(&uri) || child_path.as_deref().is_some_and(escapes_root) {
+        debug!(
+            path = request.uri().path(),
+            reason = "path traverses outside of root",
+            "Rejected request path"
+        );
+        return InvalidRequest.into_response();
+    }
+
     let path = scan_path.join(&uri);
 
-    let db = request
+    if path.as_os_str().is_empty() {
+        return RepositoryNotFound.into_response();
+    }
+
+    let storage = request
         .extensions()
-        .get::<Arc<rocksdb::DB>>()
-        .expect("db extension missing");
-    if path.as_os_str().is_empty()
-        || !crate::database::schema::repository::Repository::exists(db, &uri).unwrap_or_default()
-    {
+        .get::<Storage>()
+        .expect("storage missing");
+    if matches!(storage.check_async().await, Ok(false)) {
+        return StorageUnavailable.into_response();
+    }
+
+    let rest = &decoded_path.trim_matches('/')[raw_uri.len()..];
+    let repository =
+        match open_repository(request.extensions(), &uri, rest, request.uri().query()).await {
+            Ok(v) => v,
+            Err(response) => return response,
+        };
+
+    let scan_root = request
+        .extensions()
+        .get::<ScanRoot>()
+        .expect("scan root missing");
+    if !scan_root.contains(&path).await {
+        debug!(
+            path = request.uri().path(),
+            reason = "repository resolves outside of root",
+            "Rejected request path"
+        );
         return RepositoryNotFound.into_response();
     }
 
+    if let Some(format) = repository.get().unsupported_object_format.as_ref() {
+        return UnsupportedObjectFormat(format.to_string()).into_response();
+    }
+
+    let repo = Repository(uri);
+    let context = RepoContext::new(
+        repo.clone(),
+        repository.get(),
+        action.tab(),
+        request.extensions(),
+    );
+    let noindex = context.noindex;
+
     request.extensions_mut().insert(ChildPath(child_path));
-    request.extensions_mut().insert(Repository(uri));
+    request.extensions_mut().insert(repo);
+    request.extensions_mut().insert(context);
     request.extensions_mut().insert(RepositoryPath(path));
 
-    match action {
+    let mut response = match action {
         HandlerAction::About => handle_about.call(request, None::<()>).await,
+        HandlerAction::Atom => handle_atom.call(request, None::<()>).await,
+        HandlerAction::TagsAtom => handle_tags_atom.call(request, None::<()>).await,
         HandlerAction::SmartGit => handle_smart_git.call(request, None::<()>).await,
+        HandlerAction::Head => handle_head.call(request, None::<()>).await,
         HandlerAction::Refs => handle_refs.call(request, None::<()>).await,
+        HandlerAction::HeadsTxt => handle_heads_txt.call(request, None::<()>).await,
+        HandlerAction::Releases => handle_releases.call(request, None::<()>).await,
         HandlerAction::Log => handle_log.call(request, None::<()>).await,
         HandlerAction::Tree => handle_tree.call(request, None::<()>).await,
         HandlerAction::Commit => handle_commit.call(request, None::<()>).await,
+        HandlerAction::Compare => handle_compare.call(request, None::<()>).await,
         HandlerAction::Diff => handle_diff.call(request, None::<()>).await,
+        HandlerAction::Files => handle_files.call(request, None::<()>).await,
         HandlerAction::Patch => handle_patch.call(request, None::<()>).await,
         HandlerAction::Tag => handle_tag.call(request, None::<()>).await,
         HandlerAction::Snapshot => handle_snapshot.call(request, None::<()>).await,
+        HandlerAction::Bundle => handle_bundle.call(request, None::<()>).await,
+        HandlerAction::Manifest => handle_manifest.call(request, None::<()>).await,
+        HandlerAction::Raw => handle_raw.call(request, None::<()>).await,
         HandlerAction::Summary => handle_summary.call(request, None::<()>).await,
+    };
+
+    // covers everything served for the repository, not just the pages carrying the meta tag
+    if noindex {
+        response
+            .headers_mut()
+            .insert("x-robots-tag", HeaderValue::from_static("noindex"));
+    }
+
+    response
+}
+
+/// The scan path with every symlink along it resolved, which repositories must resolve to
+/// somewhere beneath unless `--allow-symlinks` is set.
+#[derive(Clone)]
+pub struct ScanRoot {
+    canonical: Arc<Path>,
+    allow_symlinks: bool,
+}
+
+impl ScanRoot {
+    pub fn new(scan_path: &Path, allow_symlinks: bool) -> anyhow::Result<Self> {
+        let canonical = std::fs::canonicalize(scan_path)
+            .with_context(|| format!("Failed to resolve scan path {}", scan_path.display()))?;
+
+        Ok(Self {
+            canonical: Arc::from(canonical),
+            allow_symlinks,
+        })
+    }
+
+    /// Whether the repository at `path` resolves to somewhere within the scan path. Paths have
+    /// already had any `..` rejected by this point, so only a symlink can take them elsewhere.
+    pub async fn contains(&self, path: &Path) -> bool {
+        self.allow_symlinks || self.resolves_within(path, tokio::fs::canonicalize(path).await)
+    }
+
+    /// Like [`ScanRoot::contains`], for the indexer which runs outside of the async runtime.
+    pub fn contains_blocking(&self, path: &Path) -> bool {
+        self.allow_symlinks || self.resolves_within(path, std::fs::canonicalize(path))
+    }
+
+    fn resolves_within(&self, path: &Path, resolved: std::io::Result<PathBuf>) -> bool {
+        match resolved {
+            Ok(resolved) => resolved.starts_with(&self.canonical),
+            Err(error) => {
+                debug!(%error, path = %path.display(), "Failed to resolve repository path");
+                false
+            }
+        }
+    }
+}
+
+/// Whether repositories can be requested with the wrong casing, set by
+/// `--case-insensitive-paths`.
+#[derive(Copy, Clone)]
+pub struct CaseInsensitivePaths(pub bool);
+
+/// Whether repositories are served without a `git-daemon-export-ok` file, set by
+/// `--export-all`.
+#[derive(Copy, Clone)]
+pub struct ExportAll(pub bool);
+
+/// How many commits feeds and pages of the log hold for repositories that don't configure
+/// their own, set by `--feed-items` and `--log-page-size`.
+#[derive(Copy, Clone)]
+pub struct EntryLimits {
+    pub feed_items: u64,
+    pub log_page_size: u64,
+}
+
+impl Default for EntryLimits {
+    fn default() -> Self {
+        Self {
+            feed_items: 20,
+            log_page_size: 100,
+        }
+    }
+}
+
+/// Who may push to repositories over smart HTTP, set by `--push-auth-file`. Pushes are
+/// refused as if the endpoint didn't exist without it.
+#[derive(Clone)]
+pub struct PushAccess {
+    pub credentials: Arc<Htpasswd>,
+    /// Wakes the indexer after each push so the new commits show up straight away, `None` in
+    /// read-only mode.
+    pub indexer: Option<mpsc::Sender<IndexTrigger>>,
+}
+
+/// Whether objects requested by their oid have to be reachable from a branch or tag, set by
+/// `--strict-reachability`.
+#[derive(Copy, Clone)]
+pub struct StrictReachability(pub bool);
+
+impl StrictReachability {
+    /// Whether the object `id` requested by the client, and the commit the repository was
+    /// opened at if it was given as an oid in place of a branch, may be shown. Without
+    /// `--strict-reachability` anything within the object database can be.
+    pub async fn permits(self, open_repo: &Arc<OpenRepository>, id: Option<&str>) -> Result<bool> {
+        if !self.0 {
+            return Ok(true);
+        }
+
+        if let Some(oid) = open_repo.branch_commit() {
+            if !open_repo.clone().is_reachable(oid).await? {
+                return Ok(false);
+            }
+        }
+
+        let Some(id) = id else {
+            return Ok(true);
+        };
+
+        // abbreviated commit ids are resolved the same way the handler would, anything else
+        // malformed is left for the handler to reject
+        let oid = match gix::ObjectId::from_hex(id.as_bytes()) {
+            Ok(oid) => oid,
+            Err(_) => match open_repo.clone().resolve_commit_id(id.to_string()).await? {
+                Some(oid) => oid,
+                None => return Ok(true),
+            },
+        };
+
+        Ok(open_repo.clone().is_reachable(oid).await?)
+    }
+}
+
+/// Templates of the clone URLs shown for repositories, set by `--clone-url` and
+/// `--clone-url-ssh`, with `{repo}` standing in for the path of the repository.
+#[derive(Clone, Default)]
+pub struct CloneUrls(pub Arc<[String]>);
+
+impl CloneUrls {
+    /// Renders the clone URLs of `repo`. Without any configured, a URL on the host the request
+    /// was made to is given instead, using the scheme a proxy passed on in `X-Forwarded-Proto`
+    /// or https if there wasn't one.
+    pub fn render(&self, repo: &Path, host: &str, headers: &http::HeaderMap) -> Vec<String> {
+        if !self.0.is_empty() {
+            return self.configured(repo);
+        }
+
+        let scheme = headers
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(str::trim)
+            .filter(|v| v.eq_ignore_ascii_case("http"))
+            .map_or("https", |_| "http");
+
+        vec![format!("{scheme}://{host}/{}", repo.display())]
+    }
+
+    /// Renders the configured clone URLs of `repo`, empty if none were configured.
+    pub fn configured(&self, repo: &Path) -> Vec<String> {
+        let repo = repo.display().to_string();
+        self.0.iter().map(|v| v.replace("{repo}", &repo)).collect()
+    }
+}
+
+/// Characters escaped when building a redirect to a repository, `/` is left alone since
+/// it separates path segments.
+const REDIRECT_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Looks up the repository at `uri` in the index, remembering misses so repeated requests for
+/// paths that don't exist are turned away without going back to the index.
+async fn open_repository(
+    extensions: &http::Extensions,
+    uri: &Path,
+    rest: &str,
+    query: Option<&str>,
+) -> Result<YokedRepository, Response> {
+    let db = extensions
+        .get::<Arc<rocksdb::DB>>()
+        .expect("db extension missing");
+    let git = extensions.get::<Arc<Git>>().expect("git extension missing");
+
+    if git.is_known_missing(uri) {
+        return Err(RepositoryNotFound.into_response());
+    }
+
+    match crate::database::schema::repository::Repository::open(db, uri) {
+        // treated the same as repositories that don't exist, but not remembered as missing
+        // since exporting them doesn't require a reindex to be noticed
+        Ok(Some(repository)) if !is_exported(extensions, uri, repository.get()) => {
+            Err(RepositoryNotFound.into_response())
+        }
+        Ok(Some(repository)) => Ok(repository),
+        Ok(None) => {
+            if let Some(response) = tombstone_response(db, uri, rest, query) {
+                return Err(response);
+            }
+
+            if extensions
+                .get::<CaseInsensitivePaths>()
+                .is_some_and(|v| v.0)
+            {
+                let response = redirect_to_canonical_path(extensions, db, uri, rest, query);
+                if response.status() == StatusCode::NOT_FOUND {
+                    git.remember_missing(uri.to_path_buf()).await;
+                }
+                return Err(response);
+            }
+
+            git.remember_missing(uri.to_path_buf()).await;
+            Err(RepositoryNotFound.into_response())
+        }
+        Err(_) => Err(RepositoryNotFound.into_response()),
     }
 }
 
+/// Whether the indexed `repository` at `uri` is served, see [`ExportAll`]. The marker is
+/// checked on disk too, so a repository stops being served as soon as it's removed rather than
+/// after the next index.
+fn is_exported(extensions: &http::Extensions, uri: &Path, repository: &ArchivedRepository) -> bool {
+    if extensions.get::<ExportAll>().is_none_or(|v| v.0) {
+        return true;
+    }
+
+    let scan_path = extensions.get::<Arc<PathBuf>>().expect("scan_path missing");
+
+    repository.is_visible(false) && is_export_ok(&scan_path.join(uri))
+}
+
+/// Looks for an indexed repository matching `uri` case-insensitively and redirects to it,
+/// keeping the rest of the request path and query intact. Only a unique match is redirected
+/// to, since there's no way of knowing which of several the user meant.
+fn redirect_to_canonical_path(
+    extensions: &http::Extensions,
+    db: &rocksdb::DB,
+    uri: &Path,
+    rest: &str,
+    query: Option<&str>,
+) -> Response {
+    let mut matches =
+        match crate::database::schema::repository::Repository::find_case_insensitive(db, uri) {
+            Ok(v) => v,
+            Err(error) => {
+                debug!(%error, "Failed to search for repository case-insensitively");
+                return RepositoryNotFound.into_response();
+            }
+        };
+
+    // redirecting to a hidden repository would give away that it exists
+    matches.retain(|path| {
+        crate::database::schema::repository::Repository::open(db, path)
+            .ok()
+            .flatten()
+            .is_some_and(|v| is_exported(extensions, Path::new(path), v.get()))
+    });
+
+    if matches.len() != 1 {
+        debug!(
+            path = %uri.display(),
+            matches = matches.len(),
+            "No unique case-insensitive match for repository"
+        );
+        return RepositoryNotFound.into_response();
+    }
+
+    let canonical = matches.remove(0);
+
+    moved_permanently(&repository_location(&canonical, rest, query))
+}
+
+/// Answers requests for a repository that has been deleted from `uri`, redirecting to where
+/// it's moved if that's known. `None` if there's no record of a repository ever being there.
+fn tombstone_response(
+    db: &rocksdb::DB,
+    uri: &Path,
+    rest: &str,
+    query: Option<&str>,
+) -> Option<Response> {
+    let tombstone = match Tombstone::open(db, uri) {
+        Ok(v) => v?,
+        Err(error) => {
+            debug!(%error, "Failed to read tombstone");
+            return None;
+        }
+    };
+
+    if tombstone.is_expired(time::OffsetDateTime::now_utc()) {
+        return None;
+    }
+
+    Some(match tombstone.redirect {
+        Some(url) if url.contains("://") => moved_permanently(&url),
+        Some(path) => moved_permanently(&repository_location(&path, rest, query)),
+        None => RepositoryGone(tombstone.deleted_at()).into_response(),
+    })
+}
+
+/// The path of `rest` within the repository at `repository`, keeping `query` intact.
+fn repository_location(repository: &str, rest: &str, query: Option<&str>) -> String {
+    let mut location = format!(
+        "/{}{}",
+        utf8_percent_encode(repository, REDIRECT_ENCODE_SET),
+        utf8_percent_encode(rest, REDIRECT_ENCODE_SET)
+    );
+
+    if let Some(query) = query {
+        location.push('?');
+        location.push_str(query);
+    }
+
+    location
+}
+
+fn moved_permanently(location: &str) -> Response {
+    match HeaderValue::from_str(location) {
+        Ok(location) => (
+            StatusCode::MOVED_PERMANENTLY,
+            [(http::header::LOCATION, location)],
+        )
+            .into_response(),
+        Err(_) => RepositoryNotFound.into_response(),
+    }
+}
+
+/// Longest request path, in bytes, accepted by [`service`].
+const MAX_PATH_LENGTH: usize = 4096;
+
+/// Percent-decodes the path of a request, rejecting anything that can't possibly name a
+/// repository before it gets anywhere near routing or the filesystem.
+fn decode_path(path: &str) -> Result<Cow<'_, str>, &'static str> {
+    if path.len() > MAX_PATH_LENGTH {
+        return Err("path too long");
+    }
+
+    // overlong encodings are caught here too, since they aren't valid utf-8
+    let decoded = percent_encoding::percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|_| "path isn't valid utf-8")?;
+
+    if decoded.contains('\0') {
+        return Err("path contains a nul byte");
+    }
+
+    Ok(decoded)
+}
+
+fn escapes_root(path: &Path) -> bool {
+    path.is_absolute() || path.starts_with("..")
+}
+
 #[derive(Debug, PartialEq, Eq)]
 struct ParsedUri<'a> {
     action: HandlerAction,
@@ -94,6 +553,7 @@ struct ParsedUri<'a> {
     child_path: Option<PathBuf>,
 }
 
+#[allow(clippy::too_many_lines)]
 fn parse_uri(uri: &str) -> ParsedUri<'_> {
     let mut uri_parts = memchr::memchr_iter(b'/', uri.as_bytes());
 
@@ -110,7 +570,22 @@ fn parse_uri(uri: &str) -> ParsedUri<'_> {
             uri,
             child_path: None,
         },
-        Some("git-upload-pack") => ParsedUri {
+        Some("atom") => ParsedUri {
+            action: HandlerAction::Atom,
+            uri,
+            child_path: None,
+        },
+        Some("tags.atom") => ParsedUri {
+            action: HandlerAction::TagsAtom,
+            uri,
+            child_path: None,
+        },
+        Some("HEAD") => ParsedUri {
+            action: HandlerAction::Head,
+            uri,
+            child_path: None,
+        },
+        Some("git-upload-pack" | "git-receive-pack") => ParsedUri {
             action: HandlerAction::SmartGit,
             uri,
             child_path: None,
@@ -138,6 +613,17 @@ fn parse_uri(uri: &str) -> ParsedUri<'_> {
                 }
             }
         }
+        // kept under `-` like the instance's own endpoints, so it can't be mistaken for a page
+        Some("heads.txt") if uri.ends_with("/-") => ParsedUri {
+            action: HandlerAction::HeadsTxt,
+            uri: &uri[..uri.len() - 2],
+            child_path: None,
+        },
+        Some("releases") => ParsedUri {
+            action: HandlerAction::Releases,
+            uri,
+            child_path: None,
+        },
         Some("log") => ParsedUri {
             action: HandlerAction::Log,
             uri,
@@ -158,6 +644,11 @@ fn parse_uri(uri: &str) -> ParsedUri<'_> {
             uri,
             child_path: None,
         },
+        Some("files") => ParsedUri {
+            action: HandlerAction::Files,
+            uri,
+            child_path: None,
+        },
         Some("patch") => ParsedUri {
             action: HandlerAction::Patch,
             uri,
@@ -173,14 +664,115 @@ fn parse_uri(uri: &str) -> ParsedUri<'_> {
             uri,
             child_path: None,
         },
+        Some("bundle") => ParsedUri {
+            action: HandlerAction::Bundle,
+            uri,
+            child_path: None,
+        },
+        Some("manifest") => ParsedUri {
+            action: HandlerAction::Manifest,
+            uri,
+            child_path: None,
+        },
         Some(_) => {
             static TREE_FINDER: LazyLock<memchr::memmem::Finder> =
                 LazyLock::new(|| memchr::memmem::Finder::new(b"/tree/"));
+            static SNAPSHOT_FINDER: LazyLock<memchr::memmem::Finder> =
+                LazyLock::new(|| memchr::memmem::Finder::new(b"/snapshot/"));
+            static RAW_FINDER: LazyLock<memchr::memmem::Finder> =
+                LazyLock::new(|| memchr::memmem::Finder::new(b"/raw/"));
+            static LOG_FINDER: LazyLock<memchr::memmem::Finder> =
+                LazyLock::new(|| memchr::memmem::Finder::new(b"/log/"));
+            static PATCH_FINDER: LazyLock<memchr::memmem::Finder> =
+                LazyLock::new(|| memchr::memmem::Finder::new(b"/patch/"));
+            static COMPARE_FINDER: LazyLock<memchr::memmem::Finder> =
+                LazyLock::new(|| memchr::memmem::Finder::new(b"/compare/"));
+            static BUNDLE_FINDER: LazyLock<memchr::memmem::Finder> =
+                LazyLock::new(|| memchr::memmem::Finder::new(b"/bundle/"));
 
             uri = original_uri;
 
-            // match tree children
-            if let Some(idx) = TREE_FINDER.find(uri.as_bytes()) {
+            let tree_idx = TREE_FINDER.find(uri.as_bytes());
+            let snapshot_idx = SNAPSHOT_FINDER
+                .find(uri.as_bytes())
+                .filter(|_| ArchiveFormat::from_file_name(uri).is_some());
+            let raw_idx = RAW_FINDER.find(uri.as_bytes());
+            let log_idx = LOG_FINDER.find(uri.as_bytes());
+            // only ever followed by a commit oid, so repositories within a directory named
+            // `patch` are left alone
+            let patch_idx = PATCH_FINDER
+                .find(uri.as_bytes())
+                .filter(|idx| is_patch_file_name(&uri[idx + 7..]));
+
+            // whichever comes first is the action, the rest is the child path, which may well
+            // contain the others (such as a file within a directory named `tree`)
+            // refs can't contain `...`, so it only ever separates the two being compared
+            let compare_idx = COMPARE_FINDER
+                .find(uri.as_bytes())
+                .filter(|idx| uri[idx + 9..].contains("..."));
+            let bundle_idx = BUNDLE_FINDER.find(uri.as_bytes());
+
+            let first = [
+                snapshot_idx,
+                raw_idx,
+                log_idx,
+                patch_idx,
+                compare_idx,
+                bundle_idx,
+                tree_idx,
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+
+            if let Some(idx) = snapshot_idx.filter(|idx| first == Some(*idx)) {
+                // match snapshots of refs, whose names can contain slashes of their own
+                ParsedUri {
+                    action: HandlerAction::Snapshot,
+                    uri: &uri[..idx],
+                    // 10 is the length of /snapshot/
+                    child_path: Some(Path::new(&uri[idx + 10..]).clean()),
+                }
+            } else if let Some(idx) = raw_idx.filter(|idx| first == Some(*idx)) {
+                // match raw files, given as a ref followed by their path
+                ParsedUri {
+                    action: HandlerAction::Raw,
+                    uri: &uri[..idx],
+                    // 5 is the length of /raw/
+                    child_path: Some(Path::new(&uri[idx + 5..]).clean()),
+                }
+            } else if let Some(idx) = log_idx.filter(|idx| first == Some(*idx)) {
+                // match the history of a path, given as a ref followed by the path
+                ParsedUri {
+                    action: HandlerAction::Log,
+                    uri: &uri[..idx],
+                    // 5 is the length of /log/
+                    child_path: Some(Path::new(&uri[idx + 5..]).clean()),
+                }
+            } else if let Some(idx) = patch_idx.filter(|idx| first == Some(*idx)) {
+                ParsedUri {
+                    action: HandlerAction::Patch,
+                    uri: &uri[..idx],
+                    // 7 is the length of /patch/
+                    child_path: Some(Path::new(&uri[idx + 7..]).clean()),
+                }
+            } else if let Some(idx) = compare_idx.filter(|idx| first == Some(*idx)) {
+                ParsedUri {
+                    action: HandlerAction::Compare,
+                    uri: &uri[..idx],
+                    // 9 is the length of /compare/
+                    child_path: Some(Path::new(&uri[idx + 9..]).clean()),
+                }
+            } else if let Some(idx) = bundle_idx.filter(|idx| first == Some(*idx)) {
+                // match bundles of a single ref, whose names can contain slashes of their own
+                ParsedUri {
+                    action: HandlerAction::Bundle,
+                    uri: &uri[..idx],
+                    // 8 is the length of /bundle/
+                    child_path: Some(Path::new(&uri[idx + 8..]).clean()),
+                }
+            } else if let Some(idx) = tree_idx {
+                // match tree children
                 ParsedUri {
                     action: HandlerAction::Tree,
                     uri: &uri[..idx],
@@ -203,21 +795,202 @@ fn parse_uri(uri: &str) -> ParsedUri<'_> {
     }
 }
 
+/// Whether `name` is a commit oid, optionally followed by `.patch`, as requested by
+/// `/repo/patch/<oid>`.
+fn is_patch_file_name(name: &str) -> bool {
+    let oid = name.strip_suffix(".patch").unwrap_or(name);
+    gix::ObjectId::from_hex(oid.as_bytes()).is_ok()
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum HandlerAction {
     About,
+    Atom,
+    TagsAtom,
     SmartGit,
+    Head,
     Refs,
+    HeadsTxt,
+    Releases,
     Log,
     Tree,
     Commit,
+    Compare,
     Diff,
+    Files,
     Patch,
     Tag,
     Snapshot,
+    Bundle,
+    Manifest,
+    Raw,
     Summary,
 }
 
+impl HandlerAction {
+    /// The tab of the repository navigation that's highlighted while this action's page is
+    /// being viewed, if any.
+    fn tab(self) -> Option<Tab> {
+        match self {
+            Self::About => Some(Tab::About),
+            Self::Summary => Some(Tab::Summary),
+            Self::Refs => Some(Tab::Refs),
+            Self::Releases => Some(Tab::Releases),
+            Self::Log | Self::Compare => Some(Tab::Log),
+            Self::Tree | Self::Files => Some(Tab::Tree),
+            Self::Commit => Some(Tab::Commit),
+            Self::Diff => Some(Tab::Diff),
+            Self::Atom
+            | Self::TagsAtom
+            | Self::SmartGit
+            | Self::Head
+            | Self::HeadsTxt
+            | Self::Patch
+            | Self::Tag
+            | Self::Snapshot
+            | Self::Bundle
+            | Self::Manifest
+            | Self::Raw => None,
+        }
+    }
+}
+
+impl HandlerAction {
+    fn route_group(self) -> RouteGroup {
+        match self {
+            Self::Summary => RouteGroup::Summary,
+            Self::Log => RouteGroup::Log,
+            Self::Refs => RouteGroup::Refs,
+            Self::Tree | Self::Files => RouteGroup::Tree,
+            Self::Raw => RouteGroup::Blob,
+            Self::Commit => RouteGroup::Commit,
+            Self::Diff | Self::Patch | Self::Compare => RouteGroup::Diff,
+            Self::Snapshot | Self::Bundle => RouteGroup::Snapshot,
+            Self::Atom | Self::TagsAtom => RouteGroup::Feed,
+            Self::SmartGit | Self::Head => RouteGroup::Git,
+            Self::HeadsTxt | Self::Manifest => RouteGroup::Api,
+            Self::About | Self::Releases | Self::Tag => RouteGroup::Other,
+        }
+    }
+}
+
+/// Groups the path of a request within a repository by the handler [`service`] routes it to,
+/// so latencies can be recorded without a group for every repository and ref.
+pub fn route_group(path: &str) -> RouteGroup {
+    match decode_path(path) {
+        Ok(decoded) => parse_uri(decoded.trim_matches('/')).action.route_group(),
+        Err(_) => RouteGroup::Other,
+    }
+}
+
+/// Which of a commit's two dates the log and feeds show, picked by `?date=`. Defaults to the
+/// committer date, which is what the log is ordered by.
+#[derive(Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitDate {
+    Author,
+    #[default]
+    Committer,
+}
+
+impl CommitDate {
+    /// The signature of `commit` carrying the date.
+    pub fn of(self, commit: &ArchivedCommit) -> &ArchivedAuthor {
+        match self {
+            Self::Author => &commit.author,
+            Self::Committer => &commit.committer,
+        }
+    }
+
+    /// Whether the date differs from the default, and so has to be kept in links.
+    pub fn is_author(self) -> bool {
+        self == Self::Author
+    }
+}
+
+/// A tab of the navigation shown on every repository page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tab {
+    About,
+    Summary,
+    Refs,
+    Releases,
+    Log,
+    Tree,
+    Commit,
+    Diff,
+}
+
+/// Everything the header and navigation shared by every repository page needs to render,
+/// built once per request by [`service`] and handed to handlers as an extension.
+#[derive(Clone)]
+pub struct RepoContext {
+    pub repo: Repository,
+    pub description: Option<String>,
+    pub tab: Option<Tab>,
+    /// Whether search engines should be asked not to index the page.
+    pub noindex: bool,
+    /// Locale dates, relative times and counts are formatted in.
+    pub locale: LocaleRef,
+    /// How many commits the repository's feeds hold.
+    pub feed_items: u64,
+    /// How many commits each page of the repository's log holds.
+    pub log_page_size: u64,
+    /// Whether snapshots of the repository can be downloaded, set by `rgit.allow-snapshots`.
+    pub allow_snapshots: bool,
+    /// Whether the repository can be cloned or bundled, set by `rgit.allow-clone`.
+    pub allow_clone: bool,
+    /// Links out of the repository shown in the header, set by `rgit.link`.
+    pub links: Vec<Link>,
+}
+
+impl RepoContext {
+    fn new(
+        repo: Repository,
+        repository: &ArchivedRepository,
+        tab: Option<Tab>,
+        extensions: &http::Extensions,
+    ) -> Self {
+        let locale = extensions.get::<LocaleRef>().copied().unwrap_or_default();
+        let limits = extensions.get::<EntryLimits>().copied().unwrap_or_default();
+
+        Self {
+            repo,
+            description: repository.description.as_deref().map(ToString::to_string),
+            tab,
+            noindex: repository.noindex,
+            locale,
+            feed_items: repository
+                .feed_items
+                .as_ref()
+                .map_or(limits.feed_items, |v| v.to_native()),
+            log_page_size: repository
+                .log_page_size
+                .as_ref()
+                .map_or(limits.log_page_size, |v| v.to_native()),
+            allow_snapshots: repository.allow_snapshots,
+            allow_clone: repository.allow_clone,
+            links: repository
+                .links
+                .iter()
+                .map(|v| Link {
+                    label: v.label.to_string(),
+                    url: v.url.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The class given to the link of `tab` in the navigation.
+    pub fn nav_class(&self, tab: Tab) -> &'static str {
+        if self.tab == Some(tab) {
+            "active"
+        } else {
+            ""
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Repository(pub PathBuf);
 
@@ -249,7 +1022,7 @@ pub struct InvalidRequest;
 
 impl IntoResponse for InvalidRequest {
     fn into_response(self) -> Response {
-        (StatusCode::NOT_FOUND, "Invalid request").into_response()
+        ErrorPage::new(StatusCode::BAD_REQUEST, "Invalid request").into_response()
     }
 }
 
@@ -257,7 +1030,112 @@ pub struct RepositoryNotFound;
 
 impl IntoResponse for RepositoryNotFound {
     fn into_response(self) -> Response {
-        (StatusCode::NOT_FOUND, "Repository not found").into_response()
+        ErrorPage::new(StatusCode::NOT_FOUND, "Repository not found").into_response()
+    }
+}
+
+/// The repository used to exist but has been deleted, see [`Tombstone`].
+pub struct RepositoryGone(time::OffsetDateTime);
+
+impl IntoResponse for RepositoryGone {
+    fn into_response(self) -> Response {
+        ErrorPage::new(StatusCode::GONE, "Repository has been deleted")
+            .with_detail(vec![format!("It was deleted on {}.", self.0.date())])
+            .into_response()
+    }
+}
+
+pub struct UnsupportedObjectFormat(String);
+
+impl IntoResponse for UnsupportedObjectFormat {
+    fn into_response(self) -> Response {
+        ErrorPage::new(
+            StatusCode::NOT_IMPLEMENTED,
+            format!(
+                "Repository uses the {} object format, which is unsupported",
+                self.0
+            ),
+        )
+        .into_response()
+    }
+}
+
+/// The scan path can't be read, such as when the network share it's on has gone away.
+pub struct StorageUnavailable;
+
+impl IntoResponse for StorageUnavailable {
+    fn into_response(self) -> Response {
+        (
+            [(http::header::RETRY_AFTER, "30")],
+            ErrorPage::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Repository storage is unavailable",
+            )
+            .with_detail(vec![
+                "Repositories can't be read right now, try again shortly.".to_string(),
+            ]),
+        )
+            .into_response()
+    }
+}
+
+pub struct ReferenceNotFound;
+
+impl IntoResponse for ReferenceNotFound {
+    fn into_response(self) -> Response {
+        ErrorPage::new(StatusCode::NOT_FOUND, "Reference not found").into_response()
+    }
+}
+
+/// A branch that was asked for doesn't exist, responded to with the branches that do.
+pub struct BranchNotFound(pub Vec<String>);
+
+impl IntoResponse for BranchNotFound {
+    fn into_response(self) -> Response {
+        if self.0.is_empty() {
+            return ErrorPage::new(
+                StatusCode::NOT_FOUND,
+                "Branch not found, the repository has no branches",
+            )
+            .into_response();
+        }
+
+        ErrorPage::new(
+            StatusCode::NOT_FOUND,
+            "Branch not found, available branches are:",
+        )
+        .with_detail(self.0)
+        .into_response()
+    }
+}
+
+/// A download the repository's configuration doesn't allow, such as a snapshot of a
+/// repository with `rgit.allow-snapshots` set to false.
+pub enum DownloadDisabled {
+    Snapshots,
+    Clone,
+}
+
+impl IntoResponse for DownloadDisabled {
+    fn into_response(self) -> Response {
+        let message = match self {
+            Self::Snapshots => "Snapshots of this repository have been disabled",
+            Self::Clone => "Cloning this repository has been disabled",
+        };
+
+        ErrorPage::new(StatusCode::FORBIDDEN, message)
+            .with_detail(vec![
+                "The repository can still be browsed, but not downloaded in bulk.".to_string(),
+            ])
+            .into_response()
+    }
+}
+
+pub struct ObjectNotFound;
+
+impl IntoResponse for ObjectNotFound {
+    fn into_response(self) -> Response {
+        ErrorPage::new(StatusCode::NOT_FOUND, "Object not found").into_response()
     }
 }
 
@@ -265,7 +1143,7 @@ pub struct Error(anyhow::Error);
 
 impl From<Arc<anyhow::Error>> for Error {
     fn from(e: Arc<anyhow::Error>) -> Self {
-        Self(anyhow::Error::msg(format!("{e:?}")))
+        Self(clone_shared_error(&e))
     }
 }
 
@@ -275,13 +1153,495 @@ impl From<anyhow::Error> for Error {
     }
 }
 
+impl From<Error> for anyhow::Error {
+    fn from(e: Error) -> Self {
+        e.0
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", self.0)).into_response()
+        // the repository was caught mid-gc, which should be over by the time the client
+        // tries again
+        if self.0.is::<RepositoryBusy>() {
+            return (
+                [(http::header::RETRY_AFTER, "5")],
+                ErrorPage::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Repository is being modified, try again shortly",
+                ),
+            )
+                .into_response();
+        }
+
+        if self.0.is::<CommitNotFound>() {
+            return ErrorPage::new(StatusCode::NOT_FOUND, "Commit not found").into_response();
+        }
+
+        if let Some(AmbiguousCommitId(candidates)) = self.0.downcast_ref() {
+            return ErrorPage::new(
+                StatusCode::NOT_FOUND,
+                "Abbreviated commit id is ambiguous, matching commits are:",
+            )
+            .with_detail(candidates.iter().map(ToString::to_string).collect())
+            .into_response();
+        }
+
+        if self.0.is::<EmptyRepository>() {
+            return ErrorPage::new(
+                StatusCode::NOT_FOUND,
+                "Repository is empty, push something to get started",
+            )
+            .into_response();
+        }
+
+        error!(error = ?self.0, "Failed to serve request");
+
+        ErrorPage::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Something went wrong reading the repository",
+        )
+        .with_detail(self.0.chain().map(ToString::to_string).collect())
+        .into_response()
     }
 }
 
-pub struct Refs {
-    heads: BTreeMap<String, YokedCommit>,
-    tags: Vec<(YokedString, YokedTag)>,
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc, time::Instant};
+
+    use askama::Template;
+    use axum::{body::to_bytes, http::StatusCode, response::IntoResponse};
+    use path_clean::PathClean;
+    use tempfile::TempDir;
+
+    use super::{
+        decode_path, escapes_root, parse_uri, Error, RepoContext, Repository, RepositoryBusy,
+        ScanRoot, MAX_PATH_LENGTH,
+    };
+    use crate::{
+        database::{
+            indexer,
+            schema::{self, repository::Link},
+        },
+        git::{CacheConfig, Git},
+        layers::logger::REQ_TIMESTAMP,
+        locale::LocaleRef,
+        methods::filters,
+        testing::{self, TestRepo},
+    };
+
+    /// Whether [`super::service`] turns `path` away before it's looked up in the index.
+    fn is_rejected(path: &str) -> bool {
+        let Ok(decoded) = decode_path(path) else {
+            return true;
+        };
+
+        let parsed = parse_uri(decoded.trim_matches('/'));
+
+        escapes_root(&Path::new(parsed.uri).clean())
+            || parsed.child_path.as_deref().is_some_and(escapes_root)
+    }
+
+    #[test]
+    fn request_paths() {
+        let too_long = format!("/{}", "a".repeat(MAX_PATH_LENGTH));
+        let longest = format!("/{}", "a".repeat(MAX_PATH_LENGTH - 1));
+
+        let cases: &[(&str, bool)] = &[
+            ("/foo.git", false),
+            ("/foo.git/tree/src/main.rs", false),
+            ("/group/foo.git/log", false),
+            ("/foo%20bar.git", false),
+            ("/foo.git/../bar.git", false),
+            // traversal
+            ("/../etc/passwd", true),
+            ("/foo.git/../../etc", true),
+            ("/%2e%2e/etc/passwd", true),
+            ("/%2E%2E/%2e%2e/etc/passwd", true),
+            ("/foo.git/%2e%2e/%2e%2e/etc", true),
+            ("/..%2fetc%2fpasswd", true),
+            // traversal hidden behind a directory within the child path
+            ("/foo.git/tree/a/../../x", true),
+            ("/foo.git/raw/a/../../x", true),
+            ("/foo.git/log/a/../../x", true),
+            ("/foo.git/snapshot/a/../../x.tar.gz", true),
+            ("/foo.git/bundle/a/../../x", true),
+            ("/foo.git/compare/a/../../x...main", true),
+            ("/foo.git/raw/a/../x", false),
+            ("/foo.git/compare/main...feature", false),
+            // nul bytes
+            ("/foo.git%00", true),
+            ("/foo.git/tree/a%00b", true),
+            // invalid and overlong utf-8
+            ("/%ff.git", true),
+            ("/%c0%ae%c0%ae/etc", true),
+            ("/foo%e2%82.git", true),
+            // length
+            (&longest, false),
+            (&too_long, true),
+        ];
+
+        for (path, rejected) in cases {
+            let path_display = &path[..path.len().min(40)];
+            assert_eq!(is_rejected(path), *rejected, "{path_display}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn scan_root_contains() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("outside.git");
+        std::fs::create_dir_all(root.join("inside.git")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("escape.git")).unwrap();
+        std::os::unix::fs::symlink(root.join("inside.git"), root.join("alias.git")).unwrap();
+
+        let scan_root = ScanRoot::new(&root, false).unwrap();
+        let cases = [
+            ("inside.git", true),
+            ("alias.git", true),
+            ("escape.git", false),
+            ("missing.git", false),
+        ];
+
+        for (path, contained) in cases {
+            assert_eq!(
+                scan_root.contains(&root.join(path)).await,
+                contained,
+                "{path}"
+            );
+            assert_eq!(
+                scan_root.contains_blocking(&root.join(path)),
+                contained,
+                "{path}"
+            );
+        }
+
+        let allow_symlinks = ScanRoot::new(&root, true).unwrap();
+        assert!(allow_symlinks.contains(&root.join("escape.git")).await);
+    }
+
+    /// The header and navigation shared by every repository page, without any page around
+    /// them.
+    #[derive(Template)]
+    #[template(path = "repo/base.html")]
+    struct Header {
+        ctx: RepoContext,
+        branch: Option<Arc<str>>,
+    }
+
+    #[test]
+    fn links_are_escaped() {
+        let header = Header {
+            ctx: RepoContext {
+                repo: Repository("foo.git".into()),
+                description: None,
+                tab: None,
+                noindex: false,
+                locale: LocaleRef::default(),
+                feed_items: 10,
+                log_page_size: 100,
+                allow_snapshots: true,
+                allow_clone: true,
+                links: vec![Link {
+                    label: "<script>alert(1)</script>".to_string(),
+                    url: "https://example.com/?a=1&b=\"><script>".to_string(),
+                }],
+            },
+            branch: None,
+        };
+
+        let html = REQ_TIMESTAMP
+            .sync_scope(Instant::now(), || header.render())
+            .unwrap();
+
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;</a>"));
+        assert!(html.contains(r#"href="https://example.com/?a=1&amp;b=&quot;&gt;&lt;script&gt;""#));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[tokio::test]
+    async fn downloads_disabled() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        repo.git(&["config", "rgit.allow-snapshots", "false"]);
+        repo.git(&["config", "rgit.allow-clone", "false"]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let cases = [
+            ("/repo.git", StatusCode::OK),
+            ("/repo.git/tree", StatusCode::OK),
+            ("/repo.git/snapshot?h=main", StatusCode::FORBIDDEN),
+            ("/repo.git/snapshot/main.tar.gz", StatusCode::FORBIDDEN),
+            ("/repo.git/bundle", StatusCode::FORBIDDEN),
+            ("/repo.git/bundle/main", StatusCode::FORBIDDEN),
+            (
+                "/repo.git/info/refs?service=git-upload-pack",
+                StatusCode::FORBIDDEN,
+            ),
+            ("/repo.git/git-upload-pack", StatusCode::FORBIDDEN),
+        ];
+
+        for (uri, status) in cases {
+            assert_eq!(
+                testing::get(&mut app, uri, &[("host", "example.com")])
+                    .await
+                    .status(),
+                status,
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn noindex() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        repo.clone("indexed.git", &[]);
+        repo.git(&["config", "rgit.noindex", "true"]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        for (name, noindex) in [("repo.git", true), ("indexed.git", false)] {
+            for page in ["", "/tree", "/log", "/raw/main/README", "/atom"] {
+                let uri = format!("/{name}{page}");
+                let response = testing::get(&mut app, &uri, &[("host", "example.com")]).await;
+                assert_eq!(response.status(), StatusCode::OK, "{uri}");
+
+                let header = response.headers().get("x-robots-tag");
+                assert_eq!(header.is_some_and(|v| v == "noindex"), noindex, "{uri}");
+
+                let is_html = response.headers()["content-type"]
+                    .to_str()
+                    .unwrap()
+                    .starts_with("text/html");
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let body = String::from_utf8_lossy(&body);
+                assert_eq!(
+                    body.contains(r#"<meta name="robots" content="noindex">"#),
+                    noindex && is_html,
+                    "{uri}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn busy_repositories_unavailable() {
+        let busy = || anyhow::anyhow!("packed-refs not found").context(RepositoryBusy);
+
+        // whether it came straight from the read, or was shared with everyone waiting on it
+        for error in [Error::from(busy()), Error::from(Arc::new(busy()))] {
+            let response = REQ_TIMESTAMP.sync_scope(Instant::now(), || error.into_response());
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.headers()["retry-after"], "5");
+        }
+
+        let error = Error::from(anyhow::anyhow!("something else"));
+        let response = REQ_TIMESTAMP.sync_scope(Instant::now(), || error.into_response());
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn strict_reachability() {
+        let repo = TestRepo::new();
+        let listed = repo.commit("README", "hello");
+        // only reachable from a ref that isn't listed, like a mirrored pull request
+        let hidden = repo.commit("README", "secret");
+        repo.git(&["update-ref", "refs/pull/1/head", &hidden]);
+        repo.git(&["update-ref", "refs/heads/main", &listed]);
+        let hidden_tree = repo.git(&["rev-parse", &format!("{hidden}^{{tree}}")]);
+        let hidden_blob = repo.git(&["rev-parse", &format!("{hidden}:README")]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+
+        let hidden_uris = [
+            format!("/repo.git/commit?id={hidden}"),
+            format!("/repo.git/patch?id={hidden}"),
+            format!("/repo.git/diff?id={hidden}"),
+            format!("/repo.git/tree?id={hidden_tree}"),
+            format!("/repo.git/tree?blob={hidden_blob}&raw=true"),
+        ];
+
+        for (mut app, strict) in [
+            (testing::app(repo.scan_path(), db.clone()), false),
+            (testing::strict_app(repo.scan_path(), db.clone()), true),
+        ] {
+            let uri = format!("/repo.git/commit?id={listed}");
+            let response = testing::get(&mut app, &uri, &[("host", "example.com")]).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+
+            for uri in &hidden_uris {
+                let response = testing::get(&mut app, uri, &[("host", "example.com")]).await;
+                let status = if strict {
+                    StatusCode::NOT_FOUND
+                } else {
+                    StatusCode::OK
+                };
+                assert_eq!(response.status(), status, "{uri}, strict: {strict}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn storage_unavailable() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db.clone());
+        // resolved once at startup, whilst the scan path could still be read
+        let scan_root = ScanRoot::new(repo.scan_path(), false).unwrap();
+
+        let body = |response: axum::response::Response| async {
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        // like a network share dropping out from under the running instance
+        let away = repo.scan_path().with_extension("away");
+        std::fs::rename(repo.scan_path(), &away).unwrap();
+
+        let response = testing::get(&mut app, "/repo.git", &[("host", "example.com")]).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "30");
+        assert!(body(response)
+            .await
+            .contains("Repository storage is unavailable"));
+
+        let response = testing::get(&mut app, "/", &[("host", "example.com")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let html = body(response).await;
+        assert!(html.contains("repo.git"));
+        assert!(html.contains("this listing may be stale"));
+
+        let response = testing::get(&mut app, "/-/ready", &[]).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let scan_path = repo.scan_path().display().to_string();
+        assert!(body(response).await.contains(&scan_path));
+
+        // an index run whilst it's gone mustn't take the repository for deleted
+        indexer::run(
+            repo.scan_path(),
+            &db,
+            u64::MAX,
+            true,
+            &scan_root,
+            indexer::DEFAULT_TOMBSTONE_RETENTION,
+        );
+
+        std::fs::rename(&away, repo.scan_path()).unwrap();
+
+        let response = testing::get(&mut app, "/repo.git", &[("host", "example.com")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = testing::get(&mut app, "/", &[("host", "example.com")]).await;
+        assert!(!body(response).await.contains("this listing may be stale"));
+
+        let response = testing::get(&mut app, "/-/ready", &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn missing_repositories_found_after_index() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let git = Arc::new(Git::new(CacheConfig::default(), u64::MAX));
+        let mut app = testing::app_with_git(repo.scan_path(), db.clone(), git.clone());
+
+        let response = testing::get(&mut app, "/other.git", &[("host", "example.com")]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        repo.clone("other.git", &[]);
+        testing::index(repo.scan_path(), &db);
+
+        // still remembered as missing, without going back to the index
+        let response = testing::get(&mut app, "/other.git", &[("host", "example.com")]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // which the server does after every index run
+        git.invalidate_indexed();
+
+        let response = testing::get(&mut app, "/other.git", &[("host", "example.com")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn export_ok_only() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        let hidden = repo.clone("hidden.git", &[]);
+        let marker = repo.path().join("git-daemon-export-ok");
+        std::fs::write(&marker, "").unwrap();
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        let scan_root = ScanRoot::new(repo.scan_path(), false).unwrap();
+        let index = || {
+            indexer::run(
+                repo.scan_path(),
+                &db,
+                u64::MAX,
+                false,
+                &scan_root,
+                indexer::DEFAULT_TOMBSTONE_RETENTION,
+            );
+        };
+        index();
+        let mut app = testing::export_ok_app(repo.scan_path(), db.clone());
+
+        assert!(schema::repository::Repository::open(&db, "repo.git")
+            .unwrap()
+            .is_some());
+        assert!(schema::repository::Repository::open(&db, "hidden.git")
+            .unwrap()
+            .is_none());
+        assert!(hidden.exists());
+
+        let response = testing::get(&mut app, "/", &[("host", "example.com")]).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains("repo.git"));
+        assert!(!html.contains("hidden.git"));
+
+        for uri in ["/repo.git", "/repo.git/atom", "/repo.git/tree"] {
+            let response = testing::get(&mut app, uri, &[("host", "example.com")]).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+
+        for uri in ["/hidden.git", "/hidden.git/atom", "/hidden.git/tree"] {
+            let response = testing::get(&mut app, uri, &[("host", "example.com")]).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+
+        // hidden as soon as it's no longer exported, and dropped from the index on the next run
+        std::fs::remove_file(&marker).unwrap();
+        for uri in ["/repo.git", "/repo.git/atom", "/repo.git/tree"] {
+            let response = testing::get(&mut app, uri, &[("host", "example.com")]).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+
+        index();
+        assert!(schema::repository::Repository::open(&db, "repo.git")
+            .unwrap()
+            .is_none());
+    }
 }
@@ -0,0 +1,217 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use axum::{
+    http::{self, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+
+use super::{
+    snapshot::{range_header, ByteRange},
+    ChildPath, ObjectNotFound, RepositoryPath, Result, StrictReachability,
+};
+use crate::{
+    git::{Content, Git},
+    methods::conditional::Validators,
+};
+
+/// Types browsers would run scripts within, which are served as text instead so files can't
+/// be used to inject content into our origin.
+const ACTIVE_CONTENT_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+    "text/javascript",
+    "application/javascript",
+    "application/wasm",
+];
+
+/// Serves the raw content of a file, requested as `/repo/raw/<ref>/<path>`. A single range of
+/// the file can be requested with a `Range` header, so large downloads can be resumed.
+pub async fn handle(
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(ChildPath(child_path)): Extension<ChildPath>,
+    Extension(git): Extension<Arc<Git>>,
+    Extension(strict): Extension<StrictReachability>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let Some(spec) = child_path.as_deref().and_then(Path::to_str) else {
+        return Ok(ObjectNotFound.into_response());
+    };
+
+    let open_repo = git.repo(repository_path, None).await?;
+    let Some(file) = open_repo.clone().raw(spec.to_string()).await? else {
+        return Ok(ObjectNotFound.into_response());
+    };
+
+    if !strict.permits(&open_repo, Some(&file.reference)).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
+
+    // a file within a commit can never change, whereas a branch's only changes along with
+    // the blob
+    let validators = Validators::for_object_id(&file.reference)
+        .unwrap_or_else(|| Validators::new(file.oid, None));
+    if validators.is_fresh(&headers) {
+        return Ok(validators.not_modified());
+    }
+
+    let content_type = content_type(&file.path, &file.content);
+    let len = file.content.raw_len();
+    let range = ByteRange::parse(range_header(&headers, &validators), len.unwrap_or_default());
+
+    let mut response = match (len, range) {
+        (Some(len), ByteRange::Partial(start, end)) => {
+            let body = file
+                .content
+                .into_range_body(start..end + 1)
+                .context("Range lies outside of the file")?;
+
+            Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    http::header::CONTENT_RANGE,
+                    format!("bytes {start}-{end}/{len}"),
+                )
+                .header(http::header::CONTENT_LENGTH, end - start + 1)
+                .body(body)
+                .context("failed to build response")?
+        }
+        (Some(len), ByteRange::Unsatisfiable) => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(http::header::CONTENT_RANGE, format!("bytes */{len}"))],
+            )
+                .into_response());
+        }
+        _ => file.content.into_response(),
+    };
+
+    if response.status().is_success() {
+        let headers = response.headers_mut();
+        headers.insert(http::header::CONTENT_TYPE, content_type);
+        if len.is_some() {
+            headers.insert(
+                http::header::ACCEPT_RANGES,
+                HeaderValue::from_static("bytes"),
+            );
+        }
+        headers.insert(
+            http::header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        headers.insert(
+            http::header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("default-src 'none'; sandbox"),
+        );
+        validators.apply(&mut response);
+    }
+
+    Ok(response)
+}
+
+/// Guesses the type of a file from its extension, falling back to plain text for files that
+/// are valid UTF-8 and to `application/octet-stream` otherwise.
+fn content_type(path: &Path, content: &Content) -> HeaderValue {
+    let is_text = matches!(content, Content::Text(_));
+
+    let guessed = mime_guess::from_path(path)
+        .first()
+        .filter(|mime| !ACTIVE_CONTENT_TYPES.contains(&mime.essence_str()))
+        // text that isn't UTF-8 is in an encoding we can't name
+        .filter(|mime| is_text || mime.type_() != mime_guess::mime::TEXT);
+
+    match guessed {
+        Some(mime) if is_text && mime.get_param(mime_guess::mime::CHARSET).is_none() => {
+            HeaderValue::from_str(&format!("{mime}; charset=utf-8")).ok()
+        }
+        Some(mime) => HeaderValue::from_str(mime.as_ref()).ok(),
+        None => None,
+    }
+    .unwrap_or_else(|| {
+        HeaderValue::from_static(if is_text {
+            "text/plain; charset=utf-8"
+        } else {
+            "application/octet-stream"
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::StatusCode};
+    use tempfile::TempDir;
+
+    use crate::testing::{self, TestRepo};
+
+    #[tokio::test]
+    async fn ranges() {
+        let repo = TestRepo::new();
+        let commit = repo.commit("data.txt", "0123456789");
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let uri = format!("/repo.git/raw/{commit}/data.txt");
+        let etag = format!("\"{commit}\"");
+        let cases = [
+            (&[][..], StatusCode::OK, None, "0123456789"),
+            (
+                &[("range", "bytes=2-4")][..],
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 2-4/10"),
+                "234",
+            ),
+            (
+                &[("range", "bytes=-3")],
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 7-9/10"),
+                "789",
+            ),
+            (
+                &[("range", "bytes=10-")],
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                Some("bytes */10"),
+                "",
+            ),
+            (
+                &[("range", "bytes=2-4"), ("if-range", &etag)],
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 2-4/10"),
+                "234",
+            ),
+            // the client's copy is out of date, so it gets all of the current one
+            (
+                &[("range", "bytes=2-4"), ("if-range", "\"stale\"")],
+                StatusCode::OK,
+                None,
+                "0123456789",
+            ),
+        ];
+
+        for (headers, status, content_range, body) in cases {
+            let response = testing::get(&mut app, &uri, headers).await;
+
+            assert_eq!(response.status(), status, "{headers:?}");
+            assert_eq!(
+                response
+                    .headers()
+                    .get("content-range")
+                    .map(|v| v.to_str().unwrap()),
+                content_range,
+                "{headers:?}"
+            );
+            if status.is_success() {
+                assert_eq!(response.headers()["accept-ranges"], "bytes");
+            }
+
+            let response = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(response, body.as_bytes(), "{headers:?}");
+        }
+    }
+}
@@ -1,63 +1,215 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{fmt::Write, sync::Arc};
 
 use crate::{
     into_response,
     methods::{
+        conditional::Validators,
         filters,
-        repo::{Refs, Repository, Result},
+        repo::{CloneUrls, Refs, RepoContext, Repository, RepositoryPath, Result},
     },
+    Git,
 };
 use anyhow::Context;
 use askama::Template;
-use axum::{response::IntoResponse, Extension};
-use rkyv::string::ArchivedString;
-use yoke::Yoke;
+use axum::{
+    extract::Query,
+    http::{self, HeaderMap},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_extra::extract::Host;
+use gix::ObjectId;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct UriQuery {
+    merged: Option<MergedFilter>,
+}
+
+/// Narrows the branches listed down by whether they've been merged into the default branch.
+#[derive(Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MergedFilter {
+    /// Only lists branches yet to be merged.
+    Hide,
+    /// Only lists branches that have been merged.
+    Only,
+}
 
 #[derive(Template)]
 #[template(path = "repo/refs.html")]
 pub struct View {
-    repo: Repository,
+    ctx: RepoContext,
     refs: Refs,
     branch: Option<Arc<str>>,
+    merged_filter: Option<MergedFilter>,
 }
 
 pub async fn handle(
     Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(db): Extension<Arc<rocksdb::DB>>,
-) -> Result<impl IntoResponse> {
-    tokio::task::spawn_blocking(move || {
+    Extension(git): Extension<Arc<Git>>,
+    Query(query): Query<UriQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let (mut refs, default_branch) = tokio::task::spawn_blocking(move || {
         let repository = crate::database::schema::repository::Repository::open(&db, &*repo)?
             .context("Repository does not exist")?;
-        let repository = repository.get();
+        let default_branch = repository
+            .get()
+            .default_branch
+            .as_deref()
+            .and_then(|v| v.strip_prefix("refs/heads/"))
+            .map(ToString::to_string);
 
-        let heads_db = repository.heads(&db)?;
-        let heads_db = heads_db.as_ref().map(Yoke::get);
+        Ok::<_, anyhow::Error>((Refs::fetch(repository.get(), &db)?, default_branch))
+    })
+    .await
+    .context("Failed to attach to tokio task")??;
 
-        let mut heads = BTreeMap::new();
-        if let Some(archived_heads) = heads_db {
-            for head in archived_heads
-                .0
-                .as_slice()
+    let validators = Validators::new(format_args!("{:016x}", refs.digest()), None);
+    if validators.is_fresh(&headers) {
+        return Ok(validators.not_modified());
+    }
+
+    // the default branch is always merged into itself, so it's left unmarked
+    let merged = git
+        .repo(repository_path, None)
+        .await?
+        .merged(
+            refs.heads
                 .iter()
-                .map(ArchivedString::as_str)
-            {
-                let commit_tree = repository.commit_tree(db.clone(), head);
-                let name = head.strip_prefix("refs/heads/");
-
-                if let (Some(name), Some(commit)) = (name, commit_tree.fetch_latest_one()?) {
-                    heads.insert(name.to_string(), commit);
-                }
-            }
-        }
+                .filter(|(name, _)| Some(name.as_str()) != default_branch.as_deref())
+                .map(|(_, commit)| ObjectId::from(commit.get().hash))
+                .collect(),
+        )
+        .await?;
+
+    refs.merged = refs
+        .heads
+        .iter()
+        .filter(|(name, _)| Some(name.as_str()) != default_branch.as_deref())
+        .filter(|(_, commit)| merged.contains(&ObjectId::from(commit.get().hash)))
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    match query.merged {
+        Some(MergedFilter::Hide) => refs.heads.retain(|name, _| !refs.merged.contains(name)),
+        Some(MergedFilter::Only) => refs.heads.retain(|name, _| refs.merged.contains(name)),
+        None => {}
+    }
+
+    // rendered back on the request's task rather than the blocking pool, so anything the
+    // templates read from the request's task is available to them
+    let mut response = into_response(View {
+        ctx,
+        refs,
+        branch: None,
+        merged_filter: query.merged,
+    })
+    .into_response();
+    validators.apply(&mut response);
+
+    Ok(response)
+}
+
+/// Lists the tip of every branch as `git ls-remote --heads` would, requested as
+/// `/repo/-/heads.txt`, for scripts mirroring repositories. The clone URLs are given on comment
+/// lines above them if the repository can be cloned.
+pub async fn handle_heads(
+    Extension(repo): Extension<Repository>,
+    Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(clone_urls): Extension<CloneUrls>,
+    Host(host): Host,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let (refs, exported) = tokio::task::spawn_blocking({
+        let repo = repo.clone();
 
-        let tags = repository.tag_tree(db).fetch_all()?;
+        move || {
+            let repository = crate::database::schema::repository::Repository::open(&db, &*repo)?
+                .context("Repository does not exist")?;
 
-        Ok(into_response(View {
-            repo,
-            refs: Refs { heads, tags },
-            branch: None,
-        }))
+            Ok::<_, anyhow::Error>((
+                Refs::fetch(repository.get(), &db)?,
+                repository.get().exported && repository.get().allow_clone,
+            ))
+        }
     })
     .await
-    .context("Failed to attach to tokio task")?
+    .context("Failed to attach to tokio task")??;
+
+    let validators = Validators::new(format_args!("{:016x}", refs.digest()), None);
+    if validators.is_fresh(&headers) {
+        return Ok(validators.not_modified());
+    }
+
+    let mut body = String::new();
+
+    if exported {
+        for url in clone_urls.render(&repo, &host, &headers) {
+            let _res = writeln!(body, "# clone: {url}");
+        }
+    }
+
+    // sorted by their short name, which sorts the same as with the `refs/heads/` prefix
+    for (name, commit) in &refs.heads {
+        let _res = writeln!(
+            body,
+            "{}\trefs/heads/{name}",
+            const_hex::encode(commit.get().hash)
+        );
+    }
+
+    let mut response = (
+        [(http::header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        body,
+    )
+        .into_response();
+    validators.apply(&mut response);
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::StatusCode};
+    use tempfile::TempDir;
+
+    use crate::testing::{self, TestRepo};
+
+    #[tokio::test]
+    async fn heads_match_ls_remote() {
+        let repo = TestRepo::new();
+        let first = repo.commit("README", "hello");
+        repo.commit("README", "hello again");
+        repo.git(&["branch", "feature/a", &first]);
+        repo.git(&["branch", "zeta"]);
+        repo.git(&["tag", "v1.0"]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let headers = [("host", "example.com")];
+        let response = testing::get(&mut app, "/repo.git/-/heads.txt", &headers).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()["etag"].clone();
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let heads = String::from_utf8(body.to_vec()).unwrap();
+        let heads: Vec<_> = heads.lines().filter(|v| !v.starts_with('#')).collect();
+        let ls_remote = repo.git(&["ls-remote", "--heads", "."]);
+        assert_eq!(heads, ls_remote.lines().collect::<Vec<_>>());
+
+        let headers = [
+            ("host", "example.com"),
+            ("if-none-match", etag.to_str().unwrap()),
+        ];
+        let response = testing::get(&mut app, "/repo.git/-/heads.txt", &headers).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
 }
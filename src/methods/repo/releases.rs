@@ -0,0 +1,313 @@
+use std::{cmp::Reverse, collections::HashSet, iter, sync::Arc};
+
+use anyhow::Context;
+use askama::Template;
+use axum::{extract::Query, response::IntoResponse, Extension};
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::{
+    database::schema::{repository::YokedRepository, tag::TagTarget},
+    git::{parse_and_transform_markdown, OpenRepository, Summary},
+    into_response,
+    methods::{
+        filters,
+        repo::{summary::fetch_summary, RepoContext, Repository, RepositoryPath, Result},
+    },
+    Git,
+};
+
+/// Number of releases shown on each page.
+const PAGE_LENGTH: usize = 20;
+
+#[derive(Deserialize)]
+pub struct UriQuery {
+    #[serde(rename = "ofs")]
+    offset: Option<usize>,
+    #[serde(default)]
+    lightweight: bool,
+}
+
+#[derive(Template)]
+#[template(path = "repo/releases.html")]
+pub struct View {
+    ctx: RepoContext,
+    releases: Vec<Release>,
+    next_offset: Option<usize>,
+    lightweight: bool,
+    branch: Option<Arc<str>>,
+}
+
+pub struct Release {
+    tag: TagEntry,
+    /// The tag made before this one, which its changes are compared against.
+    previous: Option<String>,
+    /// Release notes rendered from the message of an annotated tag.
+    notes: Option<String>,
+}
+
+pub async fn handle(
+    Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(git): Extension<Arc<Git>>,
+    Query(query): Query<UriQuery>,
+) -> Result<impl IntoResponse> {
+    let summary = fetch_summary(
+        repo.clone(),
+        repository_path.clone(),
+        db.clone(),
+        git.clone(),
+    )
+    .await?;
+
+    let tags = tokio::task::spawn_blocking({
+        let repo = repo.clone();
+        move || build_tag_entries(&repo, &db, &summary, query.lightweight)
+    })
+    .await
+    .context("Failed to attach to tokio task")??;
+
+    let offset = query.offset.unwrap_or(0);
+    let previous: Vec<_> = tags
+        .iter()
+        .skip(offset + 1)
+        .take(PAGE_LENGTH)
+        .map(|tag| tag.name.clone())
+        .collect();
+    let mut tags: Vec<_> = tags
+        .into_iter()
+        .skip(offset)
+        .take(PAGE_LENGTH + 1)
+        .collect();
+
+    let next_offset = if tags.len() > PAGE_LENGTH {
+        tags.pop();
+        Some(offset + PAGE_LENGTH)
+    } else {
+        None
+    };
+
+    let open_repo = git.repo(repository_path, None).await?;
+    fill_tag_messages(&open_repo, &mut tags).await?;
+
+    let releases = tokio::task::spawn_blocking(move || {
+        tags.into_iter()
+            .zip(previous.into_iter().map(Some).chain(iter::repeat(None)))
+            .map(|(tag, previous)| Release {
+                notes: tag
+                    .annotated
+                    .then(|| parse_and_transform_markdown(&tag.message)),
+                tag,
+                previous,
+            })
+            .collect()
+    })
+    .await
+    .context("Failed to attach to tokio task")?;
+
+    Ok(into_response(View {
+        ctx,
+        releases,
+        next_offset,
+        lightweight: query.lightweight,
+        branch: None,
+    }))
+}
+
+/// A tag within the releases page or tags feed, annotated tags are described by their tagger and message
+/// while lightweight tags borrow the details of the commit they point to.
+pub struct TagEntry {
+    pub name: String,
+    pub author_name: String,
+    pub author_email: String,
+    pub updated: OffsetDateTime,
+    pub message: String,
+    pub annotated: bool,
+    pub target: TagTarget,
+}
+
+/// Builds every tag of the repository, newest first. Messages of annotated
+/// tags aren't stored in the index and are left for the caller to fill in from the
+/// repository.
+pub fn build_tag_entries(
+    repo: &Repository,
+    db: &Arc<rocksdb::DB>,
+    summary: &Summary,
+    include_lightweight: bool,
+) -> anyhow::Result<Vec<TagEntry>> {
+    let repository = crate::database::schema::repository::Repository::open(db, &**repo)?
+        .context("Repository does not exist")?;
+
+    let mut entries = Vec::new();
+    let mut annotated = HashSet::new();
+
+    for (name, tag) in &summary.refs.tags {
+        let name = *name.get();
+        annotated.insert(name);
+
+        if let Some(tagger) = tag.get().tagger.as_ref() {
+            entries.push(TagEntry {
+                name: name.to_string(),
+                author_name: tagger.name.to_string(),
+                author_email: tagger.email.to_string(),
+                updated: tagger.time(),
+                message: String::new(),
+                annotated: true,
+                target: tag.get().target(),
+            });
+        } else if let Some(mut entry) = tag_entry_from_commit(&repository, db, name)? {
+            entry.annotated = true;
+            entries.push(entry);
+        }
+    }
+
+    if let Some(heads) = repository.get().heads(db)?.filter(|_| include_lightweight) {
+        for name in heads
+            .get()
+            .0
+            .as_slice()
+            .iter()
+            .filter_map(|head| head.as_str().strip_prefix("refs/tags/"))
+            .filter(|name| !annotated.contains(name))
+        {
+            if let Some(entry) = tag_entry_from_commit(&repository, db, name)? {
+                entries.push(entry);
+            }
+        }
+    }
+
+    entries.sort_unstable_by_key(|entry| Reverse(entry.updated));
+
+    Ok(entries)
+}
+
+/// Finds the tag made before `name` within `tags`, which are ordered newest first as
+/// [`build_tag_entries`] returns them.
+pub fn previous_tag(tags: &[TagEntry], name: &str) -> Option<String> {
+    tags.iter()
+        .skip_while(|tag| tag.name != name)
+        .nth(1)
+        .map(|tag| tag.name.clone())
+}
+
+fn tag_entry_from_commit(
+    repository: &YokedRepository,
+    db: &Arc<rocksdb::DB>,
+    name: &str,
+) -> anyhow::Result<Option<TagEntry>> {
+    let commit_tree = repository
+        .get()
+        .commit_tree(db.clone(), &format!("refs/tags/{name}"));
+
+    let Some(commit) = commit_tree.fetch_latest_one()? else {
+        return Ok(None);
+    };
+    let commit = commit.get();
+
+    let mut message = commit.summary.to_string();
+    if !commit.message.is_empty() {
+        message.push_str("\n\n");
+        message.push_str(&commit.message);
+    }
+
+    Ok(Some(TagEntry {
+        name: name.to_string(),
+        author_name: commit.author.name.to_string(),
+        author_email: commit.author.email.to_string(),
+        updated: commit.committer.time(),
+        message,
+        annotated: false,
+        target: TagTarget::Commit,
+    }))
+}
+
+/// Reads the messages of any annotated tags in `tags` from the repository.
+pub async fn fill_tag_messages(
+    open_repo: &Arc<OpenRepository>,
+    tags: &mut [TagEntry],
+) -> anyhow::Result<()> {
+    let annotated = tags
+        .iter()
+        .filter(|tag| tag.annotated)
+        .map(|tag| tag.name.clone())
+        .collect();
+    let mut messages = open_repo.clone().tag_messages(annotated).await?;
+
+    for tag in tags {
+        if let Some(message) = messages.remove(&tag.name) {
+            tag.message = message;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::testing::{self, TestRepo};
+
+    /// Tags `commit` as `name`, tagged at `time` seconds since the epoch.
+    fn tag(repo: &TestRepo, commit: &str, name: &str, time: u64) {
+        let tag = repo.git_with_input(
+            &["mktag"],
+            format!(
+                "object {commit}\ntype commit\ntag {name}\n\
+                 tagger rgit <rgit@example.com> {time} +0000\n\nRelease {name}\n"
+            )
+            .as_bytes(),
+        );
+        repo.git(&["update-ref", &format!("refs/tags/{name}"), &tag]);
+    }
+
+    async fn body(app: &mut axum::Router, uri: &str) -> String {
+        let response = testing::get(app, uri, &[]).await;
+        assert!(response.status().is_success(), "{uri}");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn compared_against_previous_tag() {
+        let repo = TestRepo::new();
+        let commit = repo.commit("README", "hello");
+        tag(&repo, &commit, "v1", 1_000);
+        tag(&repo, &commit, "v2", 2_000);
+        tag(&repo, &commit, "release/v3", 3_000);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let releases = body(&mut app, "/repo.git/releases").await;
+        assert!(releases.contains("/repo.git/compare/v2...release/v3\""));
+        assert!(releases.contains("/repo.git/compare/v1...v2\""));
+        assert!(!releases.contains("...v1\""));
+
+        let cases = [
+            ("release%2Fv3", Some("v2...release/v3")),
+            ("v2", Some("v1...v2")),
+            ("v1", None),
+        ];
+
+        for (name, compare) in cases {
+            let tag = body(&mut app, &format!("/repo.git/tag?h={name}")).await;
+            match compare {
+                Some(compare) => {
+                    assert!(
+                        tag.contains(&format!("/repo.git/compare/{compare}\"")),
+                        "{name}"
+                    );
+                }
+                None => assert!(!tag.contains("/repo.git/compare/"), "{name}"),
+            }
+        }
+    }
+}
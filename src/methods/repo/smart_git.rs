@@ -4,41 +4,85 @@ use anyhow::{anyhow, Context};
 use axum::{
     body::Body,
     http::{
+        self,
         header::{HeaderMap, HeaderName, HeaderValue},
         Method, Uri,
     },
     response::{IntoResponse, Response},
     Extension,
 };
+use axum_extra::headers::{authorization::Basic, Authorization, HeaderMapExt};
 use bytes::{Buf, Bytes, BytesMut};
 use futures_util::TryStreamExt;
 use httparse::Status;
 use tokio::{
     io::AsyncReadExt,
     process::{Child, ChildStderr, ChildStdout, Command},
-    sync::mpsc,
+    sync::{mpsc, mpsc::error::TrySendError},
 };
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::io::StreamReader;
 use tracing::{debug, error, info_span, warn, Instrument};
 
 use crate::{
-    methods::repo::{Repository, RepositoryPath, Result},
-    StatusCode,
+    methods::{
+        error::ErrorPage,
+        repo::{DownloadDisabled, PushAccess, RepoContext, RepositoryPath, Result},
+    },
+    IndexTrigger, StatusCode,
 };
 
-#[allow(clippy::unused_async)]
+/// Realm given to clients asked to authenticate before pushing.
+const PUSH_REALM: &str = r#"Basic realm="rgit", charset="UTF-8""#;
+
 pub async fn handle(
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
-    Extension(Repository(repository)): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
+    Extension(push_access): Extension<Option<PushAccess>>,
     method: Method,
     uri: Uri,
     headers: HeaderMap,
     body: Body,
-) -> Result<impl IntoResponse> {
-    let path = extract_path(&uri, &repository)?;
+) -> Result<Response> {
+    let path = extract_path(&uri, &ctx.repo)?;
+
+    // upload-pack is allowed by http-backend by default, receive-pack only with
+    // `http.receivepack` or once a user has authenticated
+    let mut config = vec![("uploadpack.allowFilter", "true")];
+    let mut remote_user = None;
+    let mut indexer = None;
+
+    if is_push(path, uri.query()) {
+        let Some(push_access) = push_access else {
+            return Ok(ErrorPage::new(StatusCode::NOT_FOUND, "Not found").into_response());
+        };
+
+        let Some(user) = authenticate(&push_access, &headers).await? else {
+            return Ok((
+                StatusCode::UNAUTHORIZED,
+                [(http::header::WWW_AUTHENTICATE, PUSH_REALM)],
+                "Authentication required to push",
+            )
+                .into_response());
+        };
+
+        config.push(("http.receivepack", "true"));
+        remote_user = Some(user);
+
+        // the ref advertisement that starts off a push doesn't change anything
+        if path.ends_with("/git-receive-pack") {
+            indexer = push_access.indexer;
+        }
+    } else if !ctx.allow_clone {
+        return Ok(DownloadDisabled::Clone.into_response());
+    }
 
     let mut command = Command::new("git");
+    set_config(&mut command, &config);
+
+    if let Some(user) = remote_user {
+        command.env("REMOTE_USER", user);
+    }
 
     for (header, env) in [
         ("Content-Type", "CONTENT_TYPE"),
@@ -49,6 +93,10 @@ pub async fn handle(
         extract_header(&headers, &mut command, header, env)?;
     }
 
+    // protocol v2 is negotiated by the client's Git-Protocol header above, but partial clones
+    // (`--filter`) are only honoured because upload-pack is told to allow them. shallow
+    // fetches (`--depth`, `--deepen`, `--unshallow`) need nothing extra, each round of their
+    // negotiation is a POST of its own with the client resending its shallow state every time
     let mut child = command
         .arg("http-backend")
         .env("REQUEST_METHOD", method.as_str())
@@ -66,14 +114,22 @@ pub async fn handle(
     let mut stderr = child.stderr.take().context("Stderr already taken")?;
     let mut stdin = child.stdin.take().context("Stdin already taken")?;
 
-    // read request body and forward to stdin
+    // forward the request body to stdin as it arrives rather than all up front, upload-pack
+    // can start responding before it's read the whole request and would otherwise block on
+    // a full stdout. stdin is closed once the body ends, which is the only way git can tell
+    // a chunked request is over
     let mut body = StreamReader::new(
         body.into_data_stream()
             .map_err(|e| std::io::Error::new(ErrorKind::Other, e)),
     );
-    tokio::io::copy_buf(&mut body, &mut stdin)
-        .await
-        .context("Failed to copy bytes from request to command stdin")?;
+    tokio::spawn(
+        async move {
+            if let Err(error) = tokio::io::copy_buf(&mut body, &mut stdin).await {
+                warn!(%error, "Failed to copy bytes from request to command stdin");
+            }
+        }
+        .instrument(info_span!("git http-backend writer")),
+    );
 
     // wait for the headers back from git http-backend
     let mut out_buf = BytesMut::with_capacity(1024);
@@ -103,22 +159,70 @@ pub async fn handle(
     // stream the response back to the client
     let (body_send, body_recv) = mpsc::channel(8);
     tokio::spawn(
-        forward_response_to_client(out_buf, body_send, stdout, stderr, child)
-            .instrument(info_span!("git http-backend reader")),
+        async move {
+            let succeeded =
+                forward_response_to_client(out_buf, body_send, stdout, stderr, child).await;
+
+            if let Some(indexer) = indexer.filter(|_| succeeded) {
+                // a full queue already has runs waiting that'll pick up the push
+                if let Err(TrySendError::Closed(_)) = indexer.try_send(IndexTrigger::Push) {
+                    error!("Indexing thread has died and is no longer accepting wakeup messages");
+                }
+            }
+        }
+        .instrument(info_span!("git http-backend reader")),
     );
 
-    Ok((headers, Body::from_stream(ReceiverStream::new(body_recv))))
+    Ok((headers, Body::from_stream(ReceiverStream::new(body_recv))).into_response())
+}
+
+/// Whether the request is part of a push, either the ref advertisement asked for by
+/// `info/refs?service=git-receive-pack` or sending the pack itself. The query is decoded the
+/// same way http-backend decodes it, so an encoded service can't slip past.
+fn is_push(path: &str, query: Option<&str>) -> bool {
+    path.ends_with("/git-receive-pack")
+        || (path.ends_with("/info/refs")
+            && query.is_some_and(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .any(|(key, value)| key == "service" && value == "git-receive-pack")
+            }))
+}
+
+/// Checks the Basic credentials given by the client, returning the name of the user if
+/// they're allowed to push.
+async fn authenticate(push_access: &PushAccess, headers: &HeaderMap) -> Result<Option<String>> {
+    let Some(Authorization(credentials)) = headers.typed_get::<Authorization<Basic>>() else {
+        return Ok(None);
+    };
+
+    let user = credentials.username().to_string();
+    let password = credentials.password().to_string();
+    let htpasswd = push_access.credentials.clone();
+
+    let verified = tokio::task::spawn_blocking({
+        let user = user.clone();
+        move || htpasswd.verify(&user, &password)
+    })
+    .await
+    .context("Failed to join Tokio task")?;
+
+    if verified {
+        Ok(Some(user))
+    } else {
+        debug!(user, "Rejected push credentials");
+        Ok(None)
+    }
 }
 
 /// Forwards the entirety of `stdout` to `body_send`, printing subprocess stderr and status on
-/// completion.
+/// completion. Returns whether the subprocess exited successfully.
 async fn forward_response_to_client(
     mut out_buf: BytesMut,
     body_send: mpsc::Sender<Result<Bytes, io::Error>>,
     mut stdout: ChildStdout,
     mut stderr: ChildStderr,
     mut child: Child,
-) {
+) -> bool {
     loop {
         let (out, mut end) = match stdout.read_buf(&mut out_buf).await {
             Ok(0) => (Ok(out_buf.split().freeze()), true),
@@ -136,17 +240,24 @@ async fn forward_response_to_client(
         }
     }
 
-    print_status(&mut child, &mut stderr).await;
+    print_status(&mut child, &mut stderr).await
 }
 
-/// Prints the exit status of the `git` subprocess.
-async fn print_status(child: &mut Child, stderr: &mut ChildStderr) {
+/// Prints the exit status of the `git` subprocess, returning whether it succeeded.
+async fn print_status(child: &mut Child, stderr: &mut ChildStderr) -> bool {
     match tokio::try_join!(child.wait(), read_stderr(stderr)) {
         Ok((status, stderr)) if status.success() => {
             debug!(stderr, "git http-backend successfully shutdown");
+            true
+        }
+        Ok((status, stderr)) => {
+            error!(stderr, "git http-backend exited with status code {status}");
+            false
+        }
+        Err(e) => {
+            error!("Failed to wait on git http-backend shutdown: {e}");
+            false
         }
-        Ok((status, stderr)) => error!(stderr, "git http-backend exited with status code {status}"),
-        Err(e) => error!("Failed to wait on git http-backend shutdown: {e}"),
     }
 }
 
@@ -157,6 +268,18 @@ async fn read_stderr(stderr: &mut ChildStderr) -> io::Result<String> {
     Ok(String::from_utf8_lossy(&stderr_out).into_owned())
 }
 
+/// Passes `config` to the `git` subprocess through the environment, where it takes precedence
+/// over the repository's own configuration.
+fn set_config(command: &mut Command, config: &[(&str, &str)]) {
+    command.env("GIT_CONFIG_COUNT", config.len().to_string());
+
+    for (idx, (key, value)) in config.iter().enumerate() {
+        command
+            .env(format!("GIT_CONFIG_KEY_{idx}"), key)
+            .env(format!("GIT_CONFIG_VALUE_{idx}"), value);
+    }
+}
+
 /// Extracts a single header (`header`) from the `input` and passes it as `env` to
 /// `output`.
 fn extract_header(input: &HeaderMap, output: &mut Command, header: &str, env: &str) -> Result<()> {
@@ -213,3 +336,170 @@ pub fn parse_cgi_headers(buffer: &[u8]) -> Result<Option<(usize, Response<()>)>,
 
     Ok(Some((body_offset, response)))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, process::Command};
+
+    use tempfile::TempDir;
+
+    use super::is_push;
+    use crate::testing::{self, TestRepo};
+
+    /// Clones `url` into `dest` over protocol `version` with the extra `args`, returning git's
+    /// packet trace.
+    async fn clone(url: String, dest: &Path, version: &str, args: &[&str]) -> String {
+        let mut command = Command::new("git");
+        command
+            .args(["-c", &format!("protocol.version={version}")])
+            .args(["clone", "--quiet", "--bare"])
+            .args(args)
+            .arg(url)
+            .arg(dest)
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_TRACE_PACKET", "1");
+
+        let output = tokio::task::spawn_blocking(move || command.output().unwrap())
+            .await
+            .unwrap();
+        let trace = String::from_utf8(output.stderr).unwrap();
+        assert!(output.status.success(), "{trace}");
+
+        trace
+    }
+
+    #[test]
+    fn detects_pushes() {
+        let cases = [
+            ("/info/refs", Some("service=git-receive-pack"), true),
+            ("/info/refs", Some("service=git-upload-pack"), false),
+            ("/info/refs", None, false),
+            ("/info/refs", Some(""), false),
+            ("/git-receive-pack", None, true),
+            ("/git-receive-pack", Some("service=git-upload-pack"), true),
+            ("/git-upload-pack", None, false),
+            ("/git-upload-pack", Some("service=git-receive-pack"), false),
+            // encoded the same as git http-backend would decode it
+            ("/info/refs", Some("service=git%2dreceive%2dpack"), true),
+            ("/info/refs", Some("service=git%2Dreceive-pack"), true),
+            ("/info/refs", Some("%73ervice=git-receive-pack"), true),
+            ("/info/refs", Some("foo=bar&service=git-receive-pack"), true),
+            ("/info/refs", Some("service=git-receive-pack+"), false),
+            ("/info/refs", Some("services=git-receive-pack"), false),
+            ("/info/refs", Some("service=git-receive-packs"), false),
+        ];
+
+        for (path, query, expected) in cases {
+            assert_eq!(is_push(path, query), expected, "{path} {query:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn protocol_v2() {
+        let repo = TestRepo::new();
+        let commit = repo.commit("README", "hello");
+        std::fs::write(repo.path().join("git-daemon-export-ok"), "").unwrap();
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let address = testing::serve(testing::app(repo.scan_path(), db)).await;
+        let url = format!("http://{address}/repo.git");
+
+        for version in ["0", "2"] {
+            let dest = TempDir::new().unwrap();
+            let dest = dest.path().join("clone.git");
+            let trace = clone(url.clone(), &dest, version, &[]).await;
+
+            // v2 servers advertise their capabilities rather than every ref up front
+            assert_eq!(
+                trace.contains("clone< version 2"),
+                version == "2",
+                "{trace}"
+            );
+            assert_eq!(
+                trace.contains("clone> command=ls-refs"),
+                version == "2",
+                "{trace}"
+            );
+
+            let cloned = Command::new("git")
+                .args(["rev-parse", "refs/heads/main"])
+                .current_dir(&dest)
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(cloned.stdout).unwrap().trim(), commit);
+        }
+    }
+
+    #[tokio::test]
+    async fn shallow_and_partial_clones() {
+        let repo = TestRepo::new();
+        let first = repo.commit("README", "hello");
+        repo.commit("README", "hello again");
+        let tip = repo.commit("src/main.rs", "fn main() {}");
+        repo.git(&["config", "uploadpack.allowFilter", "false"]);
+        std::fs::write(repo.path().join("git-daemon-export-ok"), "").unwrap();
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let address = testing::serve(testing::app(repo.scan_path(), db)).await;
+        let url = format!("http://{address}/repo.git");
+
+        let git = |dir: &Path, args: &[&str]| {
+            let output = Command::new("git")
+                .args(args)
+                .current_dir(dir)
+                .env("GIT_CONFIG_GLOBAL", "/dev/null")
+                .env("GIT_CONFIG_NOSYSTEM", "1")
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "git {args:?}: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+
+        for version in ["0", "2"] {
+            let config = format!("protocol.version={version}");
+            let dir = TempDir::new().unwrap();
+
+            let shallow = dir.path().join("shallow.git");
+            clone(url.clone(), &shallow, version, &["--depth", "1"]).await;
+            assert_eq!(git(&shallow, &["rev-list", "--count", "HEAD"]), "1");
+
+            // each round of deepening is a request of its own
+            let fetch = tokio::task::spawn_blocking({
+                let (shallow, config) = (shallow.clone(), config.clone());
+                move || {
+                    git(
+                        &shallow,
+                        &["-c", &config, "fetch", "--quiet", "--unshallow"],
+                    )
+                }
+            });
+            fetch.await.unwrap();
+            assert_eq!(git(&shallow, &["rev-list", "--count", "HEAD"]), "3");
+            assert!(!shallow.join("shallow").exists());
+            git(&shallow, &["cat-file", "-e", &first]);
+
+            // filters are allowed regardless of the repository's own configuration
+            let partial = dir.path().join("partial.git");
+            clone(url.clone(), &partial, version, &["--filter=blob:none"]).await;
+            assert_eq!(git(&partial, &["rev-parse", "HEAD"]), tip);
+            let blob = git(&partial, &["rev-parse", "HEAD:README"]);
+            let missing = git(
+                &partial,
+                &["rev-list", "--objects", "--missing=print", "HEAD"],
+            );
+            assert!(
+                missing.lines().any(|v| v == format!("?{blob}")),
+                "{missing}"
+            );
+        }
+    }
+}
@@ -1,41 +1,276 @@
-use std::sync::Arc;
+use std::{io::SeekFrom, sync::Arc};
 
 use anyhow::{anyhow, Context};
-use axum::{body::Body, extract::Query, http::Response, Extension};
+use axum::{
+    body::Body,
+    extract::Query,
+    http::{self, HeaderMap, Response, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+use bytes::Bytes;
 use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc,
+};
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::{error, info_span, Instrument};
+use tokio_util::io::ReaderStream;
+use tracing::{error, info_span, warn, Instrument};
 
-use super::{RepositoryPath, Result};
-use crate::git::Git;
+use super::{
+    ChildPath, DownloadDisabled, ObjectNotFound, ReferenceNotFound, RepoContext, Repository,
+    RepositoryPath, Result, StrictReachability,
+};
+use crate::{
+    git::{ArchiveFormat, ArchiveLimits, Git, OpenRepository},
+    methods::conditional::Validators,
+    snapshot_cache::{snapshot_key, CachedSnapshot, SnapshotCache, SnapshotWriter},
+};
 
 #[derive(Deserialize)]
 pub struct UriQuery {
     #[serde(rename = "h")]
     branch: Option<Arc<str>>,
     id: Option<Arc<str>>,
+    #[serde(default)]
+    format: ArchiveFormat,
 }
 
+/// Serves a snapshot of a commit or branch, requested either as
+/// `/repo/snapshot?h=branch`/`?id=commit` (with `&format=zip` for a zip archive) or as
+/// `/repo/snapshot/<ref>.tar.gz`/`<ref>.zip`. Commits may be given by full or abbreviated oid,
+/// in which case the archive is named after the abbreviation.
+#[allow(clippy::too_many_arguments, clippy::too_many_lines)]
 pub async fn handle(
+    Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(ChildPath(child_path)): Extension<ChildPath>,
     Extension(git): Extension<Arc<Git>>,
+    Extension(snapshot_cache): Extension<Option<Arc<SnapshotCache>>>,
+    Extension(strict): Extension<StrictReachability>,
+    Extension(limits): Extension<ArchiveLimits>,
     Query(query): Query<UriQuery>,
+    headers: HeaderMap,
 ) -> Result<Response<Body>> {
-    let open_repo = git.repo(repository_path, query.branch.clone()).await?;
+    if !ctx.allow_snapshots {
+        return Ok(DownloadDisabled::Snapshots.into_response());
+    }
+
+    let (branch, id, format) = if let Some(reference) = child_path {
+        let Some((reference, format)) = reference
+            .to_str()
+            .and_then(ArchiveFormat::from_file_name)
+            .filter(|(v, _)| !v.is_empty())
+        else {
+            return Ok(ReferenceNotFound.into_response());
+        };
+
+        if gix::ObjectId::from_hex(reference.as_bytes()).is_ok() {
+            (None, Some(Arc::from(reference)), format)
+        } else {
+            (Some(Arc::from(reference)), None, format)
+        }
+    } else {
+        (query.branch, query.id, query.format)
+    };
+
+    let validators = id.as_deref().and_then(Validators::for_object_id);
+    if let Some(validators) = validators.as_ref().filter(|v| v.is_fresh(&headers)) {
+        return Ok(validators.not_modified());
+    }
+
+    let mut open_repo = git
+        .clone()
+        .repo(repository_path.clone(), branch.clone())
+        .await?;
+
+    // a name in the path that isn't a ref may instead be an abbreviated commit id, refs take
+    // precedence should both exist
+    let (branch, id) = match branch {
+        Some(reference)
+            if gix::hash::Prefix::from_hex(&reference).is_ok()
+                && open_repo.clone().missing_branch().await?.is_some() =>
+        {
+            open_repo = git.repo(repository_path.clone(), None).await?;
+            (None, Some(reference))
+        }
+        branch => (branch, id),
+    };
+
+    if !strict.permits(&open_repo, id.as_deref()).await? {
+        return Ok(ObjectNotFound.into_response());
+    }
+
+    // resolved the same way as the commit view, so abbreviations are accepted
+    let commit = match id {
+        Some(id) => match open_repo.clone().resolve_commit_id(id.to_string()).await? {
+            Some(oid) => Some(oid),
+            None => return Ok(ReferenceNotFound.into_response()),
+        },
+        None => None,
+    };
+
+    let Some(tree) = open_repo.clone().snapshot_tree(commit).await? else {
+        return Ok(ReferenceNotFound.into_response());
+    };
+
+    // a branch can move, but the archive only changes along with the tree
+    let validators = validators.unwrap_or_else(|| Validators::new(tree, None));
+    if validators.is_fresh(&headers) {
+        return Ok(validators.not_modified());
+    }
+
+    let reference = if let Some(commit) = commit {
+        let hex = commit.to_hex().to_string();
+        open_repo
+            .clone()
+            .abbreviations([commit])
+            .await?
+            .abbreviate(&hex)
+            .to_string()
+    } else if let Some(branch) = branch {
+        branch.to_string()
+    } else {
+        open_repo
+            .clone()
+            .default_branch()
+            .await?
+            .unwrap_or_else(|| "HEAD".to_string())
+    };
+
+    // named like `git archive` would be given a prefix, since a tarball's contents should be
+    // within a directory of their own
+    let repo_name = repo
+        .file_name()
+        .map(|v| v.to_string_lossy())
+        .unwrap_or_default();
+    let name = format!(
+        "{}-{}",
+        repo_name.strip_suffix(".git").unwrap_or(&repo_name),
+        reference.replace('/', "-")
+    );
+    let prefix = format!("{name}/");
+
+    let file_name = format!("{name}{}", format.extension());
+
+    let mut writer = if let Some(cache) = snapshot_cache {
+        let key = snapshot_key(&repository_path, tree, &prefix, format);
+
+        if let Some(cached) = cache.open(&key).await {
+            return serve_cached(
+                cached,
+                &headers,
+                format.content_type(),
+                &file_name,
+                &validators,
+            )
+            .await;
+        }
+
+        match cache.writer(key).await {
+            Ok(writer) => Some(writer),
+            Err(error) => {
+                warn!(%error, "Failed to start caching snapshot");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let response = Response::builder()
+        .header(http::header::CONTENT_TYPE, format.content_type())
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            content_disposition(&file_name),
+        )
+        .header(http::header::ACCEPT_RANGES, "bytes");
+
+    // archives come out the same every time they're built, so a range of one that isn't
+    // cached is served by building it once to find out how long it is, then again to skip
+    // to the range
+    if let Some(range) = range_header(&headers, &validators) {
+        let mut recv =
+            start_archive(open_repo.clone(), tree, prefix.clone(), format, limits).await?;
+        if let Some(writer) = writer.take() {
+            recv = tee_to_cache(recv, writer);
+        }
 
+        let mut len = 0;
+        while let Some(chunk) = recv.recv().await {
+            len += chunk?.len() as u64;
+        }
+
+        match ByteRange::parse(Some(range), len) {
+            ByteRange::Whole => {}
+            ByteRange::Partial(start, end) => {
+                let recv = start_archive(open_repo, tree, prefix, format, limits).await?;
+
+                let mut response = response
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(
+                        http::header::CONTENT_RANGE,
+                        format!("bytes {start}-{end}/{len}"),
+                    )
+                    .header(http::header::CONTENT_LENGTH, end - start + 1)
+                    .body(Body::from_stream(ReceiverStream::new(slice_archive(
+                        recv,
+                        start,
+                        end + 1,
+                    ))))
+                    .context("failed to build response")?;
+                validators.apply(&mut response);
+
+                return Ok(response);
+            }
+            ByteRange::Unsatisfiable => {
+                return Ok(response
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(http::header::CONTENT_RANGE, format!("bytes */{len}"))
+                    .body(Body::empty())
+                    .context("failed to build response")?);
+            }
+        }
+    }
+
+    let recv = start_archive(open_repo, tree, prefix, format, limits).await?;
+    let recv = if let Some(writer) = writer {
+        tee_to_cache(recv, writer)
+    } else {
+        recv
+    };
+
+    let mut response = response
+        .body(Body::from_stream(ReceiverStream::new(recv)))
+        .context("failed to build response")?;
+    validators.apply(&mut response);
+
+    Ok(response)
+}
+
+/// Starts building an archive of `tree`, handing back its bytes as they're built once the
+/// archive builder is far enough along that it won't fail before sending any, so the error
+/// can still be returned to the client in place of the archive.
+async fn start_archive(
+    open_repo: Arc<OpenRepository>,
+    tree: gix::ObjectId,
+    prefix: String,
+    format: ArchiveFormat,
+    limits: ArchiveLimits,
+) -> Result<mpsc::Receiver<Result<Bytes, anyhow::Error>>> {
     // byte stream back to the client
-    let (send, recv) = tokio::sync::mpsc::channel(1);
+    let (send, recv) = mpsc::channel(1);
 
     // channel for `archive` to tell us we can send headers etc back to
     // the user so it has time to return an error
     let (send_cont, recv_cont) = tokio::sync::oneshot::channel();
 
-    let id = query.id.clone();
-
     let res = tokio::spawn(
         async move {
             if let Err(error) = open_repo
-                .archive(send.clone(), send_cont, id.as_deref())
+                .archive(send.clone(), send_cont, tree, prefix, format, limits)
                 .await
             {
                 error!(%error, "Failed to build archive for client");
@@ -64,18 +299,312 @@ pub async fn handle(
         return Err(anyhow!("Ran into inconsistent error state whilst building archive, please file an issue at https://github.com/w4/rgit/issues").into());
     }
 
-    let file_name = query
-        .id
-        .as_deref()
-        .or(query.branch.as_deref())
-        .unwrap_or("main");
+    Ok(recv)
+}
+
+/// Forwards only the bytes of an archive from `start` up to (but not including) `end` on to
+/// the client, leaving the archive builder to stop once the client's gone.
+fn slice_archive(
+    mut recv: mpsc::Receiver<Result<Bytes, anyhow::Error>>,
+    start: u64,
+    end: u64,
+) -> mpsc::Receiver<Result<Bytes, anyhow::Error>> {
+    let (send, client_recv) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        let mut offset = 0;
+
+        while offset < end {
+            let Some(chunk) = recv.recv().await else {
+                return;
+            };
+
+            let chunk = chunk.map(|bytes| {
+                let chunk_start = offset;
+                offset += bytes.len() as u64;
+
+                // both bounds lie within the chunk once clamped to it
+                let from = start.clamp(chunk_start, offset) - chunk_start;
+                let to = end.clamp(chunk_start, offset) - chunk_start;
+                #[allow(clippy::cast_possible_truncation)]
+                bytes.slice(from as usize..to as usize)
+            });
+
+            if matches!(&chunk, Ok(bytes) if bytes.is_empty()) {
+                continue;
+            }
 
-    Ok(Response::builder()
-        .header("Content-Type", "application/gzip")
+            let failed = chunk.is_err();
+            if send.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    client_recv
+}
+
+/// Forwards the archive on to the client whilst writing it to the cache. The archive keeps
+/// being written even if the client goes away, so the next download doesn't have to build it.
+pub(super) fn tee_to_cache(
+    mut recv: mpsc::Receiver<Result<Bytes, anyhow::Error>>,
+    mut writer: SnapshotWriter,
+) -> mpsc::Receiver<Result<Bytes, anyhow::Error>> {
+    let (send, client_recv) = mpsc::channel(1);
+
+    tokio::spawn(
+        async move {
+            while let Some(chunk) = recv.recv().await {
+                let written = match &chunk {
+                    Ok(bytes) => writer.write(bytes).await,
+                    Err(_) => Err(anyhow!("archive builder failed")),
+                };
+
+                if let Err(error) = written {
+                    warn!(%error, "Failed to cache snapshot");
+                    writer.abandon().await;
+
+                    // hand the rest of the archive to the client as it comes
+                    let _res = send.send(chunk).await;
+                    while let Some(chunk) = recv.recv().await {
+                        let _res = send.send(chunk).await;
+                    }
+
+                    return;
+                }
+
+                let _res = send.send(chunk).await;
+            }
+
+            if let Err(error) = writer.finish().await {
+                warn!(%error, "Failed to cache snapshot");
+            }
+        }
+        .instrument(info_span!("snapshot_cache")),
+    );
+
+    client_recv
+}
+
+/// Serves an archive from the cache, honouring any single range the client asked for.
+pub(super) async fn serve_cached(
+    mut cached: CachedSnapshot,
+    headers: &HeaderMap,
+    content_type: &'static str,
+    file_name: &str,
+    validators: &Validators,
+) -> Result<Response<Body>> {
+    let range = ByteRange::parse(range_header(headers, validators), cached.len);
+
+    let response = Response::builder()
+        .header(http::header::CONTENT_TYPE, content_type)
         .header(
-            "Content-Disposition",
-            format!("attachment; filename=\"{file_name}.tar.gz\""),
+            http::header::CONTENT_DISPOSITION,
+            content_disposition(file_name),
         )
-        .body(Body::from_stream(ReceiverStream::new(recv)))
-        .context("failed to build response")?)
+        .header(http::header::ACCEPT_RANGES, "bytes");
+
+    let (response, start, len) = match range {
+        ByteRange::Whole => (response.status(StatusCode::OK), 0, cached.len),
+        ByteRange::Partial(start, end) => (
+            response.status(StatusCode::PARTIAL_CONTENT).header(
+                http::header::CONTENT_RANGE,
+                format!("bytes {start}-{end}/{}", cached.len),
+            ),
+            start,
+            end - start + 1,
+        ),
+        ByteRange::Unsatisfiable => {
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(
+                    http::header::CONTENT_RANGE,
+                    format!("bytes */{}", cached.len),
+                )
+                .body(Body::empty())
+                .context("failed to build response")?);
+        }
+    };
+
+    cached
+        .file
+        .seek(SeekFrom::Start(start))
+        .await
+        .context("Failed to seek cached snapshot")?;
+
+    let mut response = response
+        .header(http::header::CONTENT_LENGTH, len)
+        .body(Body::from_stream(ReaderStream::new(cached.file.take(len))))
+        .context("failed to build response")?;
+    validators.apply(&mut response);
+
+    Ok(response)
+}
+
+/// The `Range` a client asked for, unless its `If-Range` shows it holds a different version of
+/// the resource than the one described by `validators`, in which case it gets the whole thing.
+pub(super) fn range_header<'a>(headers: &'a HeaderMap, validators: &Validators) -> Option<&'a str> {
+    headers
+        .get(http::header::RANGE)
+        .filter(|_| validators.range_applies(headers))
+        .and_then(|v| v.to_str().ok())
+}
+
+/// The part of a resource a client asked for in its `Range` header.
+pub(super) enum ByteRange {
+    /// The whole resource, either because no range was asked for or because it's one we
+    /// ignore, such as multiple ranges.
+    Whole,
+    /// The inclusive bounds of a single range.
+    Partial(u64, u64),
+    /// A range lying outside of the resource.
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parses a `Range` header for a resource of `len` bytes.
+    pub(super) fn parse(header: Option<&str>, len: u64) -> Self {
+        header
+            .and_then(|header| Self::parse_inner(header, len))
+            .unwrap_or(Self::Whole)
+    }
+
+    fn parse_inner(header: &str, len: u64) -> Option<Self> {
+        let (start, end) = header.strip_prefix("bytes=")?.trim().split_once('-')?;
+
+        if end.contains(',') {
+            return None;
+        }
+
+        let (start, end) = if start.is_empty() {
+            // a suffix range, asking for the last however many bytes
+            let suffix: u64 = end.parse().ok()?;
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        } else {
+            let start: u64 = start.parse().ok()?;
+            let end = if end.is_empty() {
+                len.saturating_sub(1)
+            } else {
+                let end: u64 = end.parse().ok()?;
+
+                if end < start {
+                    return None;
+                }
+
+                end.min(len.saturating_sub(1))
+            };
+
+            (start, end)
+        };
+
+        Some(if start < len && start <= end {
+            Self::Partial(start, end)
+        } else {
+            Self::Unsatisfiable
+        })
+    }
+}
+
+pub(super) fn content_disposition(file_name: &str) -> String {
+    format!("attachment; filename=\"{file_name}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::StatusCode};
+    use tempfile::TempDir;
+
+    use super::ByteRange;
+    use crate::testing::{self, TestRepo};
+
+    fn parse(header: &str, len: u64) -> Option<(u64, u64)> {
+        match ByteRange::parse(Some(header), len) {
+            ByteRange::Whole => None,
+            ByteRange::Partial(start, end) => Some((start, end)),
+            ByteRange::Unsatisfiable => Some((u64::MAX, u64::MAX)),
+        }
+    }
+
+    #[test]
+    fn byte_ranges() {
+        const UNSATISFIABLE: Option<(u64, u64)> = Some((u64::MAX, u64::MAX));
+
+        let cases = [
+            ("bytes=0-99", Some((0, 99))),
+            ("bytes=10-19", Some((10, 19))),
+            ("bytes= 10-19 ", Some((10, 19))),
+            ("bytes=10-", Some((10, 99))),
+            ("bytes=-10", Some((90, 99))),
+            // ranges running past the end are cut short
+            ("bytes=90-200", Some((90, 99))),
+            ("bytes=-200", Some((0, 99))),
+            ("bytes=99-99", Some((99, 99))),
+            ("bytes=100-", UNSATISFIABLE),
+            ("bytes=100-200", UNSATISFIABLE),
+            // anything we don't understand is ignored
+            ("bytes=20-10", None),
+            ("bytes=0-1,5-6", None),
+            ("bytes=a-b", None),
+            ("items=0-10", None),
+            ("bytes=10", None),
+        ];
+
+        for (header, range) in cases {
+            assert_eq!(parse(header, 100), range, "{header}");
+        }
+
+        assert_eq!(parse("bytes=0-", 0), UNSATISFIABLE);
+        assert_eq!(parse("bytes=-1", 0), UNSATISFIABLE);
+        assert!(matches!(ByteRange::parse(None, 100), ByteRange::Whole));
+    }
+
+    #[tokio::test]
+    async fn ranges_of_uncached_snapshots() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let uri = "/repo.git/snapshot/main.tar.gz";
+        let whole = testing::get(&mut app, uri, &[]).await;
+        assert_eq!(whole.status(), StatusCode::OK);
+        assert_eq!(whole.headers()["accept-ranges"], "bytes");
+        let etag = whole.headers()["etag"].to_str().unwrap().to_string();
+        let whole = to_bytes(whole.into_body(), usize::MAX).await.unwrap();
+        let len = whole.len();
+
+        let partial = testing::get(&mut app, uri, &[("range", "bytes=10-19")]).await;
+        assert_eq!(partial.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            partial.headers()["content-range"],
+            format!("bytes 10-19/{len}").as_str()
+        );
+        let partial = to_bytes(partial.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(partial, whole.slice(10..20));
+
+        let suffix = testing::get(&mut app, uri, &[("range", "bytes=-5")]).await;
+        let suffix = to_bytes(suffix.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(suffix, whole.slice(len - 5..));
+
+        let unsatisfiable =
+            testing::get(&mut app, uri, &[("range", &format!("bytes={len}-"))]).await;
+        assert_eq!(unsatisfiable.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            unsatisfiable.headers()["content-range"],
+            format!("bytes */{len}").as_str()
+        );
+
+        let current = [("range", "bytes=10-19"), ("if-range", etag.as_str())];
+        let current = testing::get(&mut app, uri, &current).await;
+        assert_eq!(current.status(), StatusCode::PARTIAL_CONTENT);
+
+        let stale = [("range", "bytes=10-19"), ("if-range", "\"stale\"")];
+        let stale = testing::get(&mut app, uri, &stale).await;
+        assert_eq!(stale.status(), StatusCode::OK);
+        let stale = to_bytes(stale.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(stale, whole);
+    }
 }
@@ -1,92 +1,193 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use anyhow::Context;
 use askama::Template;
-use axum::{response::IntoResponse, Extension};
+use axum::{http::HeaderMap, response::IntoResponse, Extension};
 use axum_extra::extract::Host;
-use rkyv::string::ArchivedString;
+use gix::ObjectId;
 
 use crate::{
-    database::schema::{commit::YokedCommit, repository::YokedRepository},
+    database::schema::{
+        commit::YokedCommit,
+        repository::{ObjectStats, Remote},
+    },
+    git::{Abbreviations, Summary},
     into_response,
     methods::{
         filters,
-        repo::{Refs, Repository, Result, DEFAULT_BRANCHES},
+        repo::{
+            log::fetch_recent_commits, CloneUrls, Decoration, Refs, RepoContext, Repository,
+            RepositoryPath, Result,
+        },
     },
+    Git,
 };
 
 #[derive(Template)]
 #[template(path = "repo/summary.html")]
 pub struct View {
-    repo: Repository,
-    refs: Refs,
-    commit_list: Vec<YokedCommit>,
+    ctx: RepoContext,
+    summary: Arc<Summary>,
     branch: Option<Arc<str>>,
-    exported: bool,
-    host: String,
+    /// Clone URLs of the repository, HTTP first.
+    clone_urls: Vec<String>,
+    decorations: HashMap<[u8; 20], Vec<Decoration>>,
+    abbreviations: Abbreviations,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle(
     Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(git): Extension<Arc<Git>>,
+    Extension(clone_urls): Extension<CloneUrls>,
     Host(host): Host,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
-    tokio::task::spawn_blocking(move || {
-        let repository = crate::database::schema::repository::Repository::open(&db, &*repo)?
-            .context("Repository does not exist")?;
-        let commits = get_default_branch_commits(&repository, &db)?;
-
-        let mut heads = BTreeMap::new();
-        if let Some(heads_db) = repository.get().heads(&db)? {
-            for head in heads_db
-                .get()
-                .0
-                .as_slice()
+    let summary = fetch_summary(repo.clone(), repository_path.clone(), db, git.clone()).await?;
+
+    let abbreviations = git
+        .repo(repository_path, None)
+        .await?
+        .abbreviations(
+            summary
+                .detached_head
                 .iter()
-                .map(ArchivedString::as_str)
-            {
-                let commit_tree = repository.get().commit_tree(db.clone(), head);
-                let name = head.strip_prefix("refs/heads/");
-
-                if let (Some(name), Some(commit)) = (name, commit_tree.fetch_latest_one()?) {
-                    heads.insert(name.to_string(), commit);
-                }
-            }
-        }
-
-        let tags = repository.get().tag_tree(db).fetch_all()?;
-
-        Ok(into_response(View {
-            repo,
-            refs: Refs { heads, tags },
-            commit_list: commits,
-            branch: None,
-            exported: repository.get().exported,
-            host,
-        }))
-    })
-    .await
-    .context("Failed to attach to tokio task")?
+                .map(|commit| ObjectId::from(commit.get().hash))
+                .collect::<Vec<_>>(),
+        )
+        .await?;
+
+    Ok(into_response(View {
+        ctx,
+        decorations: summary.refs.decorations(),
+        summary,
+        branch: None,
+        clone_urls: clone_urls.render(&repo, &host, &headers),
+        abbreviations,
+    }))
 }
 
-pub fn get_default_branch_commits(
-    repository: &YokedRepository,
-    database: &Arc<rocksdb::DB>,
-) -> Result<Vec<YokedCommit>> {
-    for branch in repository
-        .get()
-        .default_branch
-        .as_deref()
-        .into_iter()
-        .chain(DEFAULT_BRANCHES.into_iter())
-    {
-        let commit_tree = repository.get().commit_tree(database.clone(), branch);
-        let commits = commit_tree.fetch_latest(11, 0)?;
-
-        if !commits.is_empty() {
-            return Ok(commits);
-        }
+/// Fetches the summary of the repository from the cache, building it if any of the
+/// repository's refs have moved since it was last built.
+pub(super) async fn fetch_summary(
+    repo: Repository,
+    repository_path: PathBuf,
+    db: Arc<rocksdb::DB>,
+    git: Arc<Git>,
+) -> Result<Arc<Summary>> {
+    let open_repo = git.clone().repo(repository_path.clone(), None).await?;
+    let commits = fetch_recent_commits(repo.clone(), db.clone(), git.clone(), None).await?;
+
+    let build = async move {
+        tokio::task::spawn_blocking(move || build_summary(&repo, &db, commits))
+            .await
+            .context("Failed to attach to tokio task")?
+    };
+
+    // empty repositories have nothing worth caching
+    if let Some(refs_digest) = open_repo.refs_digest().await? {
+        Ok(git.summary(repository_path, refs_digest, build).await?)
+    } else {
+        Ok(build.await?)
     }
+}
+
+fn build_summary(
+    repo: &Repository,
+    db: &Arc<rocksdb::DB>,
+    commits: Arc<[YokedCommit]>,
+) -> anyhow::Result<Arc<Summary>> {
+    let repository = crate::database::schema::repository::Repository::open(db, &**repo)?
+        .context("Repository does not exist")?;
+
+    // the indexer keeps the history of a detached HEAD under HEAD itself
+    let detached_head = if repository.get().default_branch.as_deref() == Some("HEAD") {
+        repository
+            .get()
+            .commit_tree(db.clone(), "HEAD")
+            .fetch_latest_one()?
+    } else {
+        None
+    };
+
+    Ok(Arc::new(Summary {
+        refs: Refs::fetch(repository.get(), db)?,
+        commit_list: commits,
+        detached_head,
+        // the clone url is only worth showing if cloning is allowed
+        exported: repository.get().exported && repository.get().allow_clone,
+        objects: repository.get().objects.as_ref().map(|v| ObjectStats {
+            count: v.count.to_native(),
+            size: v.size.to_native(),
+        }),
+        remotes: repository
+            .get()
+            .remotes
+            .iter()
+            .map(|v| Remote {
+                name: v.name.to_string(),
+                url: v.url.to_string(),
+            })
+            .collect(),
+        default_branch: repository
+            .get()
+            .default_branch
+            .as_deref()
+            .and_then(|v| v.strip_prefix("refs/heads/"))
+            .map(ToString::to_string),
+    }))
+}
 
-    Ok(vec![])
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use tempfile::TempDir;
+
+    use crate::testing::{self, TestRepo};
+
+    #[tokio::test]
+    async fn detached_head() {
+        let repo = TestRepo::new();
+        let detached = repo.commit("README", "first revision");
+        let tip = repo.commit("README", "second revision");
+        repo.git(&["update-ref", "--no-deref", "HEAD", &detached]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let mut page = async |uri: &str| {
+            let response = testing::get(&mut app, uri, &[("host", "example.com")]).await;
+            assert!(response.status().is_success(), "{uri}");
+
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let body = page("/repo.git").await;
+        assert!(
+            body.contains(&format!("(detached at {})", &detached[..7])),
+            "{body}"
+        );
+        assert!(body.contains("/repo.git/log/?h=main"), "{body}");
+
+        // everything without a branch given follows HEAD
+        let body = page("/repo.git/log").await;
+        assert!(body.contains(&detached), "{body}");
+        assert!(!body.contains(&tip), "{body}");
+
+        let body = page("/repo.git/tree/README").await;
+        assert!(body.contains("first revision"), "{body}");
+
+        let body = page("/repo.git/atom").await;
+        assert!(body.contains(&detached), "{body}");
+
+        let body = page("/repo.git/refs").await;
+        assert!(body.contains("/repo.git/log/?h=main"), "{body}");
+        assert!(!body.contains("?h=HEAD"), "{body}");
+    }
 }
@@ -1,16 +1,21 @@
 use std::sync::Arc;
 
+use anyhow::Context;
 use askama::Template;
 use axum::{extract::Query, response::IntoResponse, Extension};
 use serde::Deserialize;
 use yoke::Yoke;
 
 use crate::{
-    git::DetailedTag,
+    git::{Abbreviations, DetailedTag, TaggedObject},
     into_response,
     methods::{
         filters,
-        repo::{Repository, RepositoryPath, Result},
+        repo::{
+            releases::{build_tag_entries, previous_tag},
+            summary::fetch_summary,
+            RepoContext, Repository, RepositoryPath, Result,
+        },
     },
     Git,
 };
@@ -24,23 +29,168 @@ pub struct UriQuery {
 #[derive(Template)]
 #[template(path = "repo/tag.html")]
 pub struct View {
-    repo: Repository,
+    ctx: RepoContext,
     tag: Yoke<DetailedTag<'static>, Vec<u8>>,
     branch: Option<Arc<str>>,
+    abbreviations: Abbreviations,
+    /// The tag made before this one, lightweight or not, which its changes are compared against.
+    previous: Option<String>,
 }
 
 pub async fn handle(
     Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(db): Extension<Arc<rocksdb::DB>>,
     Extension(git): Extension<Arc<Git>>,
     Query(query): Query<UriQuery>,
 ) -> Result<impl IntoResponse> {
-    let open_repo = git.repo(repository_path, Some(query.name.clone())).await?;
-    let tag = open_repo.tag_info().await?;
+    let open_repo = git
+        .clone()
+        .repo(repository_path.clone(), Some(query.name.clone()))
+        .await?;
+    let tag = open_repo.clone().tag_info().await?;
+
+    let (TaggedObject::Commit(oid) | TaggedObject::Tree(oid) | TaggedObject::Blob(oid)) =
+        tag.get().tagged_object;
+    let abbreviations = open_repo.abbreviations([oid]).await?;
+
+    let summary = fetch_summary(repo.clone(), repository_path, db.clone(), git).await?;
+    let previous = tokio::task::spawn_blocking({
+        let name = query.name.clone();
+        move || {
+            let tags = build_tag_entries(&repo, &db, &summary, true)?;
+            anyhow::Ok(previous_tag(&tags, &name))
+        }
+    })
+    .await
+    .context("Failed to attach to tokio task")??;
 
     Ok(into_response(View {
-        repo,
+        ctx,
         tag,
         branch: Some(query.name),
+        abbreviations,
+        previous,
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, Router};
+    use tempfile::TempDir;
+
+    use crate::testing::{self, TestRepo, COLLIDING_BLOBS};
+
+    async fn page(app: &mut Router, uri: &str) -> String {
+        let response = testing::get(app, uri, &[("host", "example.com")]).await;
+        assert!(response.status().is_success(), "{uri}");
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn trees_and_blobs_tagged() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        let blob = repo.blob("an artifact");
+        let tree = repo.tree(&[("artifact", &blob)]);
+        repo.git(&["tag", "-a", "-m", "a tree", "tree-tag", &tree]);
+        repo.git(&["tag", "-a", "-m", "a blob", "blob-tag", &blob]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let body = page(&mut app, "/repo.git/tag?h=tree-tag").await;
+        assert!(
+            body.contains(&format!("/repo.git/tree?id={tree}")),
+            "{body}"
+        );
+        assert!(body.contains("/repo.git/snapshot?h=tree-tag"), "{body}");
+
+        let body = page(&mut app, "/repo.git/tag?h=blob-tag").await;
+        assert!(
+            body.contains(&format!("/repo.git/tree?blob={blob}")),
+            "{body}"
+        );
+        assert!(!body.contains("/repo.git/snapshot"), "{body}");
+
+        let body = page(&mut app, &format!("/repo.git/tree?blob={blob}")).await;
+        assert!(body.contains("an artifact"), "{body}");
+
+        let body = page(&mut app, "/repo.git/refs").await;
+        assert!(
+            body.contains(r#"<span class="decoration tag">tree</span>"#),
+            "{body}"
+        );
+        assert!(
+            body.contains(r#"<span class="decoration tag">blob</span>"#),
+            "{body}"
+        );
+        assert!(body.contains("/repo.git/snapshot?h=tree-tag"), "{body}");
+        assert!(!body.contains("/repo.git/snapshot?h=blob-tag"), "{body}");
+
+        let body = page(&mut app, "/repo.git/tags.atom").await;
+        assert!(body.contains("<title>tree-tag (tree)</title>"), "{body}");
+        assert!(body.contains("<title>blob-tag (blob)</title>"), "{body}");
+        assert!(!body.contains("/repo.git/snapshot?h=blob-tag"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn nested_tags() {
+        let repo = TestRepo::new();
+        let commit = repo.commit("README", "hello");
+        repo.git(&["tag", "-a", "-m", "v1.0", "v1.0", &commit]);
+        repo.git(&["tag", "-a", "-m", "signed", "v1.0-signed", "v1.0"]);
+        repo.git(&["tag", "-a", "-m", "release", "v1.0-release", "v1.0-signed"]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let body = page(&mut app, "/repo.git/tag?h=v1.0-release").await;
+        assert!(
+            body.contains(&format!("/repo.git/commit?id={commit}")),
+            "{body}"
+        );
+        assert!(body.contains("via v1.0-signed → v1.0"), "{body}");
+
+        // listed as a tag of a commit, with the nested tags' own dates
+        let body = page(&mut app, "/repo.git/refs").await;
+        assert!(body.contains("/repo.git/tag/?h=v1.0-release"), "{body}");
+        assert!(!body.contains(r#"<span class="decoration tag">"#), "{body}");
+        assert!(body.contains("/repo.git/snapshot?h=v1.0-release"), "{body}");
+
+        let response = testing::get(&mut app, "/repo.git/snapshot?h=v1.0-release", &[]).await;
+        assert!(response.status().is_success());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        // gzip magic
+        assert_eq!(body[..2], [0x1f, 0x8b]);
+    }
+
+    #[tokio::test]
+    async fn abbreviations_unique() {
+        let repo = TestRepo::new();
+        repo.commit("README", "hello");
+        let blobs = COLLIDING_BLOBS.map(|v| repo.blob(v));
+        repo.git(&["tag", "-a", "-m", "a blob", "first", &blobs[0]]);
+        repo.git(&["tag", "-a", "-m", "another blob", "second", &blobs[1]]);
+
+        let db_dir = TempDir::new().unwrap();
+        let db = testing::database(db_dir.path());
+        testing::index(repo.scan_path(), &db);
+        let mut app = testing::app(repo.scan_path(), db);
+
+        let body = page(&mut app, "/repo.git/tag?h=first").await;
+        let link = format!(
+            r#"/repo.git/tree?blob={}">blob {}</a>"#,
+            blobs[0],
+            &blobs[0][..8]
+        );
+        assert!(body.contains(&link), "{body}");
+    }
+}
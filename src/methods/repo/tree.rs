@@ -1,6 +1,12 @@
 use askama::Template;
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::Query,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Extension,
+};
 use itertools::Itertools;
+use percent_encoding::utf8_percent_encode;
 use serde::Deserialize;
 use std::path::PathBuf;
 use std::{
@@ -9,11 +15,15 @@ use std::{
 };
 
 use crate::{
-    git::{FileWithContent, PathDestination, TreeItem},
+    git::{FileWithContent, OpenRepository, PathDestination, Readme, ReadmeLinks, TreeItem},
     into_response,
     methods::{
+        conditional::Validators,
         filters,
-        repo::{ChildPath, Repository, RepositoryPath, Result},
+        repo::{
+            BranchNotFound, ChildPath, ObjectNotFound, RepoContext, RepositoryPath, Result,
+            StrictReachability,
+        },
     },
     Git, ResponseEither,
 };
@@ -21,10 +31,17 @@ use crate::{
 #[derive(Deserialize)]
 pub struct UriQuery {
     id: Option<String>,
+    /// Shows a blob by its oid rather than by a path, for blobs such as the targets of tags.
+    blob: Option<String>,
     #[serde(default)]
     raw: bool,
+    /// Shows files that would otherwise be rendered, such as notebooks, as highlighted text.
+    plain: Option<String>,
     #[serde(rename = "h")]
     branch: Option<Arc<str>>,
+    /// Set to `1` to list a directory without rendering the readme within it.
+    #[serde(rename = "no-readme")]
+    no_readme: Option<u8>,
 }
 
 impl Display for UriQuery {
@@ -37,7 +54,11 @@ impl Display for UriQuery {
         }
 
         if let Some(branch) = self.branch.as_deref() {
-            write!(f, "{prefix}h={branch}")?;
+            write!(
+                f,
+                "{prefix}h={}",
+                utf8_percent_encode(branch, filters::QUERY_ENCODE_SET)
+            )?;
         }
 
         Ok(())
@@ -48,52 +69,130 @@ impl Display for UriQuery {
 #[template(path = "repo/tree.html")]
 #[allow(clippy::module_name_repetitions)]
 pub struct TreeView {
-    pub repo: Repository,
+    pub ctx: RepoContext,
     pub items: Vec<TreeItem>,
     pub query: UriQuery,
     pub repo_path: PathBuf,
     pub branch: Option<Arc<str>>,
+    pub readme: Option<Readme>,
 }
 
 #[derive(Template)]
 #[template(path = "repo/file.html")]
 pub struct FileView {
-    pub repo: Repository,
+    pub ctx: RepoContext,
     pub repo_path: PathBuf,
     pub file: FileWithContent,
     pub branch: Option<Arc<str>>,
+    /// The oid the file was requested by, if it wasn't requested by path.
+    pub blob: Option<String>,
 }
 
 pub async fn handle(
-    Extension(repo): Extension<Repository>,
+    Extension(ctx): Extension<RepoContext>,
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(ChildPath(child_path)): Extension<ChildPath>,
     Extension(git): Extension<Arc<Git>>,
+    Extension(strict): Extension<StrictReachability>,
     Query(query): Query<UriQuery>,
-) -> Result<impl IntoResponse> {
+    headers: HeaderMap,
+) -> Result<Response> {
+    let validators = query
+        .blob
+        .as_deref()
+        .or(query.id.as_deref())
+        .and_then(|id| {
+            if query.raw {
+                Validators::for_object_id(id)
+            } else {
+                Validators::for_page(id, ctx.locale)
+            }
+        });
+    if let Some(validators) = validators.as_ref().filter(|v| v.is_fresh(&headers)) {
+        return Ok(validators.not_modified());
+    }
+
     let open_repo = git.repo(repository_path, query.branch.clone()).await?;
+    if let Some(branches) = open_repo.clone().missing_branch().await? {
+        return Ok(BranchNotFound(branches).into_response());
+    }
+    if !strict
+        .permits(&open_repo, query.blob.as_deref().or(query.id.as_deref()))
+        .await?
+    {
+        return Ok(ObjectNotFound.into_response());
+    }
+
+    let mut response = render(ctx, open_repo, child_path, query)
+        .await?
+        .into_response();
+
+    if let Some(validators) = validators {
+        validators.apply(&mut response);
+    }
+
+    Ok(response)
+}
+
+async fn render(
+    ctx: RepoContext,
+    open_repo: Arc<OpenRepository>,
+    child_path: Option<PathBuf>,
+    query: UriQuery,
+) -> Result<impl IntoResponse> {
+    if let Some(blob) = query.blob.as_deref() {
+        let file = open_repo
+            .blob(blob, !query.raw, query.plain.is_none())
+            .await?;
+
+        return Ok(if query.raw {
+            ResponseEither::Right(file.content)
+        } else {
+            ResponseEither::Left(ResponseEither::Right(into_response(FileView {
+                ctx,
+                repo_path: file.metadata.path.clone(),
+                file,
+                branch: query.branch,
+                blob: query.blob,
+            })))
+        });
+    }
+
+    let readme = (query.no_readme != Some(1) && !query.raw).then(|| ReadmeLinks {
+        repository: Arc::from(ctx.repo.to_string_lossy()),
+        directory: child_path.clone().unwrap_or_default(),
+        branch: query.branch.clone(),
+    });
 
     Ok(
         match open_repo
-            .path(child_path.clone(), query.id.as_deref(), !query.raw)
+            .path(
+                child_path.clone(),
+                query.id.as_deref(),
+                !query.raw,
+                query.plain.is_none(),
+                readme,
+            )
             .await?
         {
-            PathDestination::Tree(items) => {
+            PathDestination::Tree { items, readme } => {
                 ResponseEither::Left(ResponseEither::Left(into_response(TreeView {
-                    repo,
+                    ctx,
                     items,
                     branch: query.branch.clone(),
                     query,
                     repo_path: child_path.unwrap_or_default(),
+                    readme,
                 })))
             }
             PathDestination::File(file) if query.raw => ResponseEither::Right(file.content),
             PathDestination::File(file) => {
                 ResponseEither::Left(ResponseEither::Right(into_response(FileView {
-                    repo,
+                    ctx,
                     file,
                     branch: query.branch,
                     repo_path: child_path.unwrap_or_default(),
+                    blob: None,
                 })))
             }
         },
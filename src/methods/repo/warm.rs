@@ -0,0 +1,111 @@
+use std::{
+    cmp::Reverse,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use super::{summary::fetch_summary, Repository};
+use crate::{
+    database::schema::repository::Repository as IndexedRepository,
+    git::{clone_shared_error, DiffFormat, Git},
+};
+
+/// How long warming waits between repositories, leaving the blocking pool free for real
+/// traffic in between.
+const WARM_PAUSE: Duration = Duration::from_millis(100);
+
+/// Fills the caches behind the summary, about and commit pages of the `count` most recently
+/// modified repositories, so the first visitors after a restart don't have to wait for them
+/// to be built. Repositories are warmed one at a time.
+pub async fn warm_caches(git: Arc<Git>, db: Arc<rocksdb::DB>, scan_path: &Path, count: usize) {
+    let start = Instant::now();
+
+    let repositories = match most_recently_modified(&db, count) {
+        Ok(v) => v,
+        Err(error) => {
+            warn!(%error, "Failed to read repository index to warm caches");
+            return;
+        }
+    };
+
+    let mut warmed = 0_usize;
+
+    for (idx, path) in repositories.into_iter().enumerate() {
+        if idx > 0 {
+            tokio::time::sleep(WARM_PAUSE).await;
+        }
+
+        let repository_path = scan_path.join(&path);
+
+        match warm_repository(git.clone(), db.clone(), path.clone(), repository_path).await {
+            Ok(()) => warmed += 1,
+            Err(error) => warn!(%error, "Failed to warm caches of {}", path.display()),
+        }
+    }
+
+    info!(
+        repositories = warmed,
+        duration = ?start.elapsed(),
+        "Finished warming caches"
+    );
+}
+
+/// Paths of the `count` indexed repositories modified most recently, newest first.
+fn most_recently_modified(db: &rocksdb::DB, count: usize) -> anyhow::Result<Vec<PathBuf>> {
+    let mut repositories = IndexedRepository::fetch_all(db)?
+        .into_iter()
+        .map(|(path, repository)| (repository.get().last_modified.0, PathBuf::from(path)))
+        .collect::<Vec<_>>();
+
+    repositories.sort_unstable_by_key(|(last_modified, _)| Reverse(*last_modified));
+
+    Ok(repositories
+        .into_iter()
+        .take(count)
+        .map(|(_, path)| path)
+        .collect())
+}
+
+async fn warm_repository(
+    git: Arc<Git>,
+    db: Arc<rocksdb::DB>,
+    path: PathBuf,
+    repository_path: PathBuf,
+) -> anyhow::Result<()> {
+    // covers the refs and latest commits as well
+    fetch_summary(
+        Repository(path.clone()),
+        repository_path.clone(),
+        db,
+        git.clone(),
+    )
+    .await
+    .map_err(anyhow::Error::from)
+    .context("Failed to build summary")?;
+
+    let open_repo = git.repo(repository_path, None).await?;
+
+    if open_repo.clone().head_id().await?.is_none() {
+        // nothing more to show for empty repositories
+        return Ok(());
+    }
+
+    open_repo
+        .clone()
+        .readme(Arc::from(path.to_string_lossy()))
+        .await
+        .context("Failed to render readme")?;
+
+    open_repo
+        .latest_commit(DiffFormat::highlighted(&path))
+        .await
+        .map_err(|e| clone_shared_error(&e))
+        .context("Failed to render latest commit")?;
+
+    Ok(())
+}
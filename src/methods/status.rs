@@ -0,0 +1,194 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use arc_swap::ArcSwap;
+use askama::Template;
+use axum::{
+    http::{self, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Extension,
+};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::error;
+
+use super::filters;
+use crate::{
+    build_info::{BuildInfo, BUILD_INFO},
+    database::indexer::IndexerStatus,
+    into_response,
+    layers::latency::{LatencySummary, LATENCY},
+    locale::LocaleRef,
+    storage::Storage,
+    IndexTrigger,
+};
+
+#[derive(Template)]
+#[template(path = "status.html")]
+pub struct View {
+    status: Arc<IndexerStatus>,
+    uptime: time::Duration,
+    latency: Vec<LatencySummary>,
+    locale: LocaleRef,
+}
+
+#[derive(Serialize)]
+struct JsonView<'a> {
+    version: &'static str,
+    build: BuildInfo,
+    uptime_seconds: i64,
+    running: bool,
+    latency: Vec<LatencySummary>,
+    #[serde(flatten)]
+    status: &'a IndexerStatus,
+}
+
+pub async fn handle(
+    Extension(status): Extension<Arc<ArcSwap<IndexerStatus>>>,
+    Extension(locale): Extension<LocaleRef>,
+    headers: HeaderMap,
+) -> Response {
+    let wants_json = headers
+        .get(http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+
+    if wants_json {
+        return json_response(&status.load());
+    }
+
+    let status = status.load_full();
+    let uptime = OffsetDateTime::now_utc() - status.started_at;
+
+    into_response(View {
+        status,
+        uptime,
+        latency: LATENCY.summarise(),
+        locale,
+    })
+    .into_response()
+}
+
+pub async fn handle_json(Extension(status): Extension<Arc<ArcSwap<IndexerStatus>>>) -> Response {
+    json_response(&status.load())
+}
+
+/// Queues an index run straight away rather than waiting for the next refresh interval, then
+/// sends the user back to the index page.
+pub async fn handle_refresh(
+    Extension(wakeup): Extension<Option<mpsc::Sender<IndexTrigger>>>,
+) -> Response {
+    let Some(wakeup) = wakeup else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // a full queue already has runs waiting that'll pick up the same changes
+    if let Err(TrySendError::Closed(_)) = wakeup.try_send(IndexTrigger::Admin) {
+        error!("Indexing thread has died and is no longer accepting wakeup messages");
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    Redirect::to("/").into_response()
+}
+
+/// Whether the instance is ready to take traffic, as reported by `/-/ready`. Held back until
+/// the caches have been warmed if `--ready-after-warm` is set, otherwise ready from the start.
+#[derive(Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn new(ready: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(ready)))
+    }
+
+    pub fn mark_ready(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// Responds with a 503 until the instance is ready, or whilst the scan path can't be read, for
+/// load balancers and orchestrators to hold traffic back on.
+pub async fn handle_ready(
+    Extension(readiness): Extension<Readiness>,
+    Extension(storage): Extension<Storage>,
+) -> Response {
+    if matches!(storage.check_async().await, Ok(false)) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "repository storage unavailable: {}\n",
+                storage.path().display()
+            ),
+        )
+            .into_response();
+    }
+
+    if readiness.0.load(Ordering::Acquire) {
+        (StatusCode::OK, "ready\n").into_response()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "warming caches\n").into_response()
+    }
+}
+
+fn json_response(status: &IndexerStatus) -> Response {
+    let view = JsonView {
+        version: BUILD_INFO.version,
+        build: BUILD_INFO,
+        uptime_seconds: (OffsetDateTime::now_utc() - status.started_at).whole_seconds(),
+        running: status.is_running(),
+        latency: LATENCY.summarise(),
+        status,
+    };
+
+    match serde_json::to_string(&view) {
+        Ok(body) => (
+            [(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )],
+            body,
+        )
+            .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use askama::Template;
+    use tempfile::TempDir;
+
+    use super::{json_response, View};
+    use crate::{
+        database::indexer::IndexerStatus, layers::logger::REQ_TIMESTAMP, locale::LocaleRef, testing,
+    };
+
+    #[tokio::test]
+    async fn database_size() {
+        let dir = TempDir::new().unwrap();
+        let db = testing::database(dir.path());
+        let status = IndexerStatus::new(&db, Some(123_456));
+
+        let body = axum::body::to_bytes(json_response(&status).into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["database_size"], 123_456);
+
+        let view = View {
+            status: status.into(),
+            uptime: time::Duration::ZERO,
+            latency: Vec::new(),
+            locale: LocaleRef::default(),
+        };
+        let html = REQ_TIMESTAMP
+            .sync_scope(Instant::now(), || view.render())
+            .unwrap();
+        assert!(html.contains("<th>database size</th>"));
+    }
+}
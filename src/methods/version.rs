@@ -0,0 +1,20 @@
+use axum::{
+    http::{self, HeaderValue},
+    response::{IntoResponse, Response},
+};
+
+use crate::build_info::BUILD_INFO;
+
+pub async fn handle() -> Response {
+    match serde_json::to_string(&BUILD_INFO) {
+        Ok(body) => (
+            [(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )],
+            body,
+        )
+            .into_response(),
+        Err(_) => http::StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
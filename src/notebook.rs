@@ -0,0 +1,188 @@
+//! Renders Jupyter notebooks to HTML, markdown cells go through the same pipeline as readmes
+//! and code cells are highlighted in the notebook's language.
+
+use std::fmt::Write;
+
+use serde::Deserialize;
+
+use crate::{
+    git::parse_and_transform_markdown,
+    syntax_highlight::{format_file_inner, FileIdentifier},
+};
+
+/// Largest base64 encoded image that'll be inlined into the page, anything bigger is replaced
+/// with a placeholder.
+const MAX_INLINE_IMAGE_LENGTH: usize = 256 * 1024;
+
+#[derive(Deserialize)]
+struct Notebook {
+    cells: Vec<Cell>,
+    #[serde(default)]
+    metadata: Metadata,
+}
+
+#[derive(Deserialize, Default)]
+struct Metadata {
+    language_info: Option<LanguageInfo>,
+}
+
+#[derive(Deserialize)]
+struct LanguageInfo {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "cell_type", rename_all = "lowercase")]
+enum Cell {
+    Markdown {
+        source: Source,
+    },
+    Code {
+        source: Source,
+        #[serde(default)]
+        outputs: Vec<Output>,
+    },
+    Raw {
+        source: Source,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "output_type", rename_all = "snake_case")]
+enum Output {
+    Stream {
+        text: Source,
+    },
+    ExecuteResult {
+        data: OutputData,
+    },
+    DisplayData {
+        data: OutputData,
+    },
+    Error {
+        ename: String,
+        evalue: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize)]
+struct OutputData {
+    #[serde(rename = "text/plain")]
+    text: Option<Source>,
+    #[serde(rename = "image/png")]
+    png: Option<Source>,
+}
+
+/// Multiline strings within notebooks are either a single string or a list of lines.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Source {
+    Text(String),
+    Lines(Vec<String>),
+}
+
+impl Source {
+    fn concat(&self) -> String {
+        match self {
+            Self::Text(v) => v.clone(),
+            Self::Lines(v) => v.concat(),
+        }
+    }
+}
+
+/// Renders the notebook in `content`, failing if it isn't a notebook we understand.
+pub fn render(content: &str) -> anyhow::Result<String> {
+    let notebook: Notebook = serde_json::from_str(content)?;
+    let language = notebook
+        .metadata
+        .language_info
+        .as_ref()
+        .map_or("python", |v| v.name.as_str());
+
+    let mut out = String::from("<div class=\"notebook\">");
+
+    for cell in &notebook.cells {
+        match cell {
+            Cell::Markdown { source } => {
+                out.push_str("<div class=\"cell markdown\">");
+                out.push_str(&parse_and_transform_markdown(&source.concat()));
+                out.push_str("</div>");
+            }
+            Cell::Code { source, outputs } => {
+                out.push_str("<div class=\"cell code\"><pre class=\"input\">");
+                format_file_inner(
+                    &mut out,
+                    &source.concat(),
+                    FileIdentifier::Token(language),
+                    false,
+                )?;
+                out.push_str("</pre>");
+
+                for output in outputs {
+                    render_output(&mut out, output)?;
+                }
+
+                out.push_str("</div>");
+            }
+            Cell::Raw { source } => {
+                out.push_str("<div class=\"cell raw\"><pre>");
+                push_escaped(&mut out, &source.concat());
+                out.push_str("</pre></div>");
+            }
+        }
+    }
+
+    out.push_str("</div>");
+
+    Ok(out)
+}
+
+fn render_output(out: &mut String, output: &Output) -> anyhow::Result<()> {
+    match output {
+        Output::Stream { text } => {
+            out.push_str("<pre class=\"output\">");
+            push_escaped(out, &text.concat());
+            out.push_str("</pre>");
+        }
+        Output::ExecuteResult { data } | Output::DisplayData { data } => {
+            if let Some(png) = &data.png {
+                let png = png.concat();
+                let png = png.trim();
+
+                // only ever inline something that's definitely base64, so nothing can escape
+                // the attribute
+                if png.len() <= MAX_INLINE_IMAGE_LENGTH
+                    && png.bytes().all(|b| {
+                        b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=' | b'\n')
+                    })
+                {
+                    write!(
+                        out,
+                        "<img class=\"output\" src=\"data:image/png;base64,{}\">",
+                        png.replace('\n', "")
+                    )?;
+                } else {
+                    out.push_str("<pre class=\"output\">&lt;image not displayed&gt;</pre>");
+                }
+            } else if let Some(text) = &data.text {
+                out.push_str("<pre class=\"output\">");
+                push_escaped(out, &text.concat());
+                out.push_str("</pre>");
+            }
+        }
+        Output::Error { ename, evalue } => {
+            out.push_str("<pre class=\"output error\">");
+            push_escaped(out, &format!("{ename}: {evalue}"));
+            out.push_str("</pre>");
+        }
+        Output::Unknown => {}
+    }
+
+    Ok(())
+}
+
+fn push_escaped(out: &mut String, content: &str) {
+    v_htmlescape::b_escape(content.as_bytes(), out);
+}
@@ -0,0 +1,316 @@
+//! Keeps generated snapshots and bundles on disk, so archives that get downloaded over and
+//! over again, such as release tarballs fetched by CI, only have to be built once.
+//!
+//! Snapshots only depend on the tree they're built from, the directory their files are placed
+//! within and their format, so they're keyed by the tree's oid and a hash of the directory's
+//! name, with the format's extension. Bundles are keyed by a digest of the refs they contain
+//! and the commit they're built on top of, see [`bundle_key`]. Both are prefixed with a hash
+//! of the repository's path, so archives are never shared between repositories, even forks
+//! holding the same objects.
+//! Each file ends with the length of the archive before it, which is checked whenever the
+//! file is opened to catch archives that were cut short.
+
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use gix::ObjectId;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::{debug, warn};
+use xxhash_rust::const_xxh3;
+
+use crate::git::ArchiveFormat;
+
+/// Length of the trailer holding the length of the archive.
+const TRAILER_LEN: u64 = 8;
+
+/// Extension given to archives that are still being written.
+const TEMP_EXTENSION: &str = "tmp";
+
+pub struct SnapshotCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// A complete archive read from the cache.
+pub struct CachedSnapshot {
+    pub file: File,
+    pub len: u64,
+}
+
+impl SnapshotCache {
+    /// Opens the cache in `dir`, creating it if it doesn't exist yet and clearing out any
+    /// archives that were still being written when rgit last stopped.
+    pub fn new(dir: PathBuf, max_size: u64) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir).with_context(|| {
+            format!(
+                "Failed to create snapshot cache directory {}",
+                dir.display()
+            )
+        })?;
+
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+
+            if path.extension().is_some_and(|v| v == TEMP_EXTENSION) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(Self { dir, max_size })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    /// Opens the cached archive with the given key, discarding it if it turns out to be
+    /// incomplete.
+    pub async fn open(&self, key: &str) -> Option<CachedSnapshot> {
+        let path = self.path(key);
+        let mut file = File::open(&path).await.ok()?;
+
+        match read_trailer(&mut file).await {
+            Ok(len) => {
+                // the modification time doubles as the last access time when evicting
+                if let Err(error) = touch(&file).await {
+                    debug!(%error, "Failed to update access time of {}", path.display());
+                }
+
+                Some(CachedSnapshot { file, len })
+            }
+            Err(error) => {
+                warn!(%error, "Discarding corrupt cached snapshot {}", path.display());
+
+                if let Err(error) = tokio::fs::remove_file(&path).await {
+                    warn!(%error, "Failed to remove corrupt cached snapshot");
+                }
+
+                None
+            }
+        }
+    }
+
+    /// Starts writing the archive with the given key to the cache, which only becomes visible
+    /// to [`SnapshotCache::open`] once [`SnapshotWriter::finish`] is called.
+    pub async fn writer(self: &Arc<Self>, key: String) -> anyhow::Result<SnapshotWriter> {
+        let temp = self
+            .dir
+            .join(format!("{key}.{}.{TEMP_EXTENSION}", uuid::Uuid::new_v4()));
+        let file = File::create(&temp)
+            .await
+            .with_context(|| format!("Failed to create {}", temp.display()))?;
+
+        Ok(SnapshotWriter {
+            cache: self.clone(),
+            key,
+            temp,
+            file,
+            len: 0,
+        })
+    }
+
+    /// Removes the least recently downloaded archives until the cache fits within its size
+    /// limit.
+    async fn evict(&self) -> anyhow::Result<()> {
+        let dir = self.dir.clone();
+        let max_size = self.max_size;
+
+        tokio::task::spawn_blocking(move || {
+            let mut archives = Vec::new();
+            let mut total = 0;
+
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+
+                if path.extension().is_some_and(|v| v == TEMP_EXTENSION) {
+                    continue;
+                }
+
+                let metadata = entry.metadata()?;
+                total += metadata.len();
+                archives.push((metadata.modified()?, metadata.len(), path));
+            }
+
+            archives.sort_unstable();
+
+            for (_, len, path) in archives {
+                if total <= max_size {
+                    break;
+                }
+
+                debug!("Evicting {} from snapshot cache", path.display());
+                std::fs::remove_file(&path)?;
+                total -= len;
+            }
+
+            Ok(())
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+}
+
+/// An archive being written to the cache.
+pub struct SnapshotWriter {
+    cache: Arc<SnapshotCache>,
+    key: String,
+    temp: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl SnapshotWriter {
+    pub async fn write(&mut self, chunk: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(chunk).await?;
+        self.len += chunk.len() as u64;
+
+        Ok(())
+    }
+
+    /// Moves the complete archive into place, evicting older archives if the cache has grown
+    /// too large.
+    pub async fn finish(mut self) -> anyhow::Result<()> {
+        self.file.write_u64(self.len).await?;
+        self.file.sync_all().await?;
+
+        tokio::fs::rename(&self.temp, self.cache.path(&self.key))
+            .await
+            .context("Failed to move snapshot into cache")?;
+
+        self.cache.evict().await
+    }
+
+    /// Throws away the archive, used if building it failed part of the way through.
+    pub async fn abandon(self) {
+        if let Err(error) = tokio::fs::remove_file(&self.temp).await {
+            warn!(%error, "Failed to remove {}", self.temp.display());
+        }
+    }
+}
+
+/// Key of the `format` archive of `tree` within the repository at `repository`, with its
+/// files placed within `prefix`.
+pub fn snapshot_key(
+    repository: &Path,
+    tree: ObjectId,
+    prefix: &str,
+    format: ArchiveFormat,
+) -> String {
+    format!(
+        "{:016x}-{tree}-{:016x}{}",
+        repository_hash(repository),
+        const_xxh3::xxh3_64(prefix.as_bytes()),
+        format.extension()
+    )
+}
+
+/// Key of a bundle of the refs hashing to `refs_digest` within the repository at
+/// `repository`, holding only the objects that aren't reachable from `since` if given.
+pub fn bundle_key(repository: &Path, refs_digest: u64, since: Option<ObjectId>) -> String {
+    let repository = repository_hash(repository);
+
+    match since {
+        Some(since) => format!("{repository:016x}-bundle-{refs_digest:016x}-{since}.bundle"),
+        None => format!("{repository:016x}-bundle-{refs_digest:016x}.bundle"),
+    }
+}
+
+fn repository_hash(repository: &Path) -> u64 {
+    const_xxh3::xxh3_64(repository.as_os_str().as_encoded_bytes())
+}
+
+/// Reads the length of the archive from the end of `file`, checking the file actually holds
+/// that much, and rewinds it back to the start of the archive.
+async fn read_trailer(file: &mut File) -> anyhow::Result<u64> {
+    let file_len = file.metadata().await?.len();
+    anyhow::ensure!(
+        file_len >= TRAILER_LEN,
+        "file is too short to hold an archive"
+    );
+
+    file.seek(SeekFrom::Start(file_len - TRAILER_LEN)).await?;
+    let len = file.read_u64().await?;
+    anyhow::ensure!(
+        len == file_len - TRAILER_LEN,
+        "archive is {} bytes but should be {len}",
+        file_len - TRAILER_LEN
+    );
+
+    file.rewind().await?;
+
+    Ok(len)
+}
+
+async fn touch(file: &File) -> std::io::Result<()> {
+    let file = file.try_clone().await?.into_std().await;
+    tokio::task::spawn_blocking(move || file.set_modified(SystemTime::now())).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use gix::ObjectId;
+    use tempfile::TempDir;
+    use tokio::io::AsyncReadExt;
+
+    use super::{bundle_key, snapshot_key, SnapshotCache};
+    use crate::git::ArchiveFormat;
+
+    #[test]
+    fn keys_scoped_to_repository() {
+        let (a, b) = (Path::new("/srv/git/a.git"), Path::new("/srv/git/b.git"));
+        let tree = ObjectId::empty_tree(gix::hash::Kind::Sha1);
+        let since = ObjectId::null(gix::hash::Kind::Sha1);
+
+        assert_eq!(
+            snapshot_key(a, tree, "a-main/", ArchiveFormat::TarGz),
+            snapshot_key(a, tree, "a-main/", ArchiveFormat::TarGz)
+        );
+        assert_ne!(
+            snapshot_key(a, tree, "a-main/", ArchiveFormat::TarGz),
+            snapshot_key(b, tree, "a-main/", ArchiveFormat::TarGz)
+        );
+        assert_ne!(
+            snapshot_key(a, tree, "a-main/", ArchiveFormat::TarGz),
+            snapshot_key(a, tree, "a-v1/", ArchiveFormat::TarGz)
+        );
+        assert_ne!(
+            snapshot_key(a, tree, "a-main/", ArchiveFormat::TarGz),
+            snapshot_key(a, tree, "a-main/", ArchiveFormat::Zip)
+        );
+
+        assert_eq!(bundle_key(a, 1, None), bundle_key(a, 1, None));
+        assert_ne!(bundle_key(a, 1, None), bundle_key(b, 1, None));
+        assert_ne!(bundle_key(a, 1, None), bundle_key(a, 2, None));
+        assert_ne!(bundle_key(a, 1, None), bundle_key(a, 1, Some(since)));
+        assert_ne!(bundle_key(a, 1, Some(since)), bundle_key(b, 1, Some(since)));
+    }
+
+    #[tokio::test]
+    async fn cached_per_repository() {
+        let dir = TempDir::new().unwrap();
+        let cache = Arc::new(SnapshotCache::new(dir.path().to_path_buf(), u64::MAX).unwrap());
+        let (a, b) = (Path::new("/srv/git/a.git"), Path::new("/srv/git/b.git"));
+
+        let mut writer = cache.writer(bundle_key(a, 1, None)).await.unwrap();
+        writer.write(b"bundle").await.unwrap();
+        writer.finish().await.unwrap();
+
+        let mut cached = cache.open(&bundle_key(a, 1, None)).await.unwrap();
+        let mut contents = vec![0; usize::try_from(cached.len).unwrap()];
+        cached.file.read_exact(&mut contents).await.unwrap();
+        assert_eq!(contents, b"bundle");
+
+        assert!(cache.open(&bundle_key(b, 1, None)).await.is_none());
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+/// Whether the scan path can currently be read. Repositories kept on a network share go
+/// missing all at once when the share is unmounted or unreachable, which would otherwise look
+/// like every repository having been deleted.
+#[derive(Clone)]
+pub struct Storage {
+    path: Arc<Path>,
+    available: Arc<AtomicBool>,
+}
+
+impl Storage {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: Arc::from(path),
+            available: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// The scan path being watched.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Checks whether the scan path can be read, logging only when that changes so an outage
+    /// doesn't flood the logs.
+    pub fn check(&self) -> bool {
+        let available = match std::fs::read_dir(&self.path) {
+            Ok(_) => true,
+            Err(error) => {
+                if self.available.load(Ordering::Acquire) {
+                    warn!(%error, "Scan path {} has become unavailable", self.path.display());
+                }
+
+                false
+            }
+        };
+
+        if available && !self.available.load(Ordering::Acquire) {
+            info!("Scan path {} is available again", self.path.display());
+        }
+
+        self.available.store(available, Ordering::Release);
+        available
+    }
+
+    /// [`Self::check`] without blocking the runtime, since reads from a hung network share
+    /// can take a long time to fail.
+    pub async fn check_async(&self) -> anyhow::Result<bool> {
+        let storage = self.clone();
+
+        tokio::task::spawn_blocking(move || storage.check())
+            .await
+            .context("Failed to join Tokio task")
+    }
+}
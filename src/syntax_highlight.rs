@@ -119,6 +119,37 @@ pub fn fetch_highlighter_config(file: &Path) -> Option<&'static HighlightConfigu
         .map(|idx| &HIGHLIGHTER_CONFIGS[idx])
 }
 
+/// Finds the highlighter for a script from the interpreter named in its shebang, for files
+/// such as extensionless scripts which can't be identified by their name.
+pub fn fetch_highlighter_config_by_shebang(
+    content: &str,
+) -> Option<&'static HighlightConfiguration> {
+    shebang_interpreter(content)
+        .and_then(Language::from_shebang)
+        .map(Language::grammar)
+        .map(Grammar::idx)
+        .map(|idx| &HIGHLIGHTER_CONFIGS[idx])
+}
+
+/// Pulls the name of the interpreter out of the shebang `content` starts with, without the
+/// directory it's in or its version, looking through `env` to the program it runs. Both
+/// `#!/usr/bin/python3` and `#!/usr/bin/env -S python3 -u` give `python`.
+fn shebang_interpreter(content: &str) -> Option<&str> {
+    let line = content.strip_prefix("#!")?.lines().next()?;
+    let mut words = line.split_whitespace();
+
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|v| !v.starts_with('-'))?;
+    }
+
+    let end = program
+        .find(|c: char| c.is_ascii_digit() || c == '.')
+        .unwrap_or(program.len());
+
+    Some(&program[..end]).filter(|v| !v.is_empty())
+}
+
 pub fn fetch_highlighter_config_by_token(token: &str) -> Option<&'static HighlightConfiguration> {
     Language::from_injection(token)
         .map(Language::grammar)
@@ -145,7 +176,7 @@ impl SyntaxHighlighterAdapter for ComrakHighlightAdapter {
         output: &mut dyn IoWrite,
         _attributes: HashMap<String, String>,
     ) -> std::io::Result<()> {
-        write!(output, r#"<pre>"#)
+        write!(output, r#"<pre class="code">"#)
     }
 
     fn write_code_tag(
@@ -169,28 +200,60 @@ pub fn format_file(content: &str, identifier: FileIdentifier<'_>) -> anyhow::Res
     Ok(out)
 }
 
+/// Highlights `content` for the file view, anchoring each line as `L<n>` so they can be linked
+/// to, as the line numbers of diffs do.
+pub fn format_file_with_anchors(
+    content: &str,
+    identifier: FileIdentifier<'_>,
+) -> anyhow::Result<String> {
+    let mut out = String::new();
+
+    for (i, line) in highlight_lines(content, identifier)?
+        .into_iter()
+        .enumerate()
+    {
+        let _res = writeln!(out, r#"<code id="L{}">{line}</code>"#, i + 1);
+    }
+
+    Ok(out)
+}
+
 pub fn format_file_inner(
     out: &mut String,
     content: &str,
     identifier: FileIdentifier<'_>,
     code_tag: bool,
 ) -> anyhow::Result<()> {
-    let config = match identifier {
-        FileIdentifier::Path(v) => fetch_highlighter_config(v),
-        FileIdentifier::Token(v) => fetch_highlighter_config_by_token(v),
-    };
-
     let line_prefix = if code_tag { "<code>" } else { "" };
     let line_suffix = if code_tag { "</code>\n" } else { "\n" };
 
-    let Some(config) = config else {
-        for line in content.lines() {
-            out.push_str(line_prefix);
-            v_htmlescape::b_escape(line.as_bytes(), out);
-            out.push_str(line_suffix);
+    for line in highlight_lines(content, identifier)? {
+        out.push_str(line_prefix);
+        out.push_str(&line);
+        out.push_str(line_suffix);
+    }
+
+    Ok(())
+}
+
+/// Highlights `content` in a single pass, returning the markup for each of its lines without
+/// their line terminators.
+///
+/// Highlights spanning multiple lines, such as block comments, are closed at the end of each
+/// line and reopened at the start of the next, so every line can be rendered on its own.
+pub fn highlight_lines(
+    content: &str,
+    identifier: FileIdentifier<'_>,
+) -> anyhow::Result<Vec<String>> {
+    let config = match identifier {
+        FileIdentifier::Path(v) => {
+            fetch_highlighter_config(v).or_else(|| fetch_highlighter_config_by_shebang(content))
         }
+        FileIdentifier::Token(v) => fetch_highlighter_config_by_token(v),
+    };
 
-        return Ok(());
+    let Some(config) = config else {
+        return Ok(plaintext_lines(content));
     };
 
     HIGHLIGHTER.with_borrow_mut(|highlighter| {
@@ -209,62 +272,82 @@ pub fn format_file_inner(
                     "Failed to run highlighter, falling back to plaintext"
                 );
 
-                for line in content.lines() {
-                    out.push_str(line_prefix);
-                    v_htmlescape::b_escape(line.as_bytes(), out);
-                    out.push_str(line_suffix);
-                }
-
-                return Ok(());
+                return Ok(plaintext_lines(content));
             }
         };
 
-        let mut tag_open = true;
-        out.push_str(line_prefix);
+        let mut lines = LineWriter::default();
 
         while let Some(span) = spans.next().transpose()? {
-            if !tag_open {
-                out.push_str(line_prefix);
-                tag_open = true;
-            }
-
             match span {
-                HighlightEvent::Source { start, end } => {
-                    let content = &content[start..end];
-
-                    for (i, line) in content.lines().enumerate() {
-                        if i != 0 {
-                            out.push_str(line_suffix);
-                            out.push_str(line_prefix);
-                        }
-
-                        v_htmlescape::b_escape(line.as_bytes(), out);
-                    }
-
-                    if content.ends_with('\n') {
-                        out.push_str(line_suffix);
-                        tag_open = false;
-                    }
-                }
+                HighlightEvent::Source { start, end } => lines.source(&content[start..end]),
                 HighlightEvent::HighlightStart(highlight) => {
-                    write!(
-                        out,
-                        r#"<span class="highlight {}">"#,
-                        HIGHLIGHT_CLASSES[highlight.0]
-                    )?;
-                }
-                HighlightEvent::HighlightEnd => {
-                    out.push_str("</span>");
+                    lines.start(HIGHLIGHT_CLASSES[highlight.0]);
                 }
+                HighlightEvent::HighlightEnd => lines.end(),
             }
         }
 
-        if tag_open {
-            out.push_str(line_suffix);
+        Ok(lines.finish(content))
+    })
+}
+
+/// Splits `content` into lines the same way highlighted content is, just without any
+/// highlights, so files without a highlighter get the same line structure.
+fn plaintext_lines(content: &str) -> Vec<String> {
+    let mut lines = LineWriter::default();
+    lines.source(content);
+    lines.finish(content)
+}
+
+/// Builds the markup for each line of a file from its source and the highlights over it.
+#[derive(Default)]
+struct LineWriter {
+    lines: Vec<String>,
+    line: String,
+    /// Classes of every highlight that's open at the current position.
+    open: Vec<&'static str>,
+}
+
+impl LineWriter {
+    fn source(&mut self, source: &str) {
+        for part in source.split_inclusive('\n') {
+            let Some(part) = part.strip_suffix('\n') else {
+                v_htmlescape::b_escape(part.as_bytes(), &mut self.line);
+                continue;
+            };
+
+            let part = part.strip_suffix('\r').unwrap_or(part);
+            v_htmlescape::b_escape(part.as_bytes(), &mut self.line);
+
+            for _ in &self.open {
+                self.line.push_str("</span>");
+            }
+
+            self.lines.push(std::mem::take(&mut self.line));
+
+            for class in &self.open {
+                let _res = write!(self.line, r#"<span class="highlight {class}">"#);
+            }
         }
+    }
 
-        Ok::<_, anyhow::Error>(())
-    })?;
+    fn start(&mut self, class: &'static str) {
+        let _res = write!(self.line, r#"<span class="highlight {class}">"#);
+        self.open.push(class);
+    }
 
-    Ok(())
+    fn end(&mut self) {
+        self.line.push_str("</span>");
+        self.open.pop();
+    }
+
+    fn finish(mut self, content: &str) -> Vec<String> {
+        // a trailing newline ends the last line rather than starting another
+        if !content.is_empty() && !content.ends_with('\n') {
+            self.lines.push(self.line);
+        }
+
+        self.lines
+    }
 }
@@ -0,0 +1,307 @@
+//! Throwaway git repositories for tests, built with the `git` binary so fixtures look the same
+//! as the repositories rgit serves.
+
+use std::{
+    fmt::Write as _,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::Arc,
+    time::Instant,
+};
+
+use arc_swap::ArcSwap;
+use axum::{
+    body::Body,
+    http::{Request, Response},
+    middleware::{self, Next},
+    routing, Extension, Router,
+};
+use tempfile::TempDir;
+use tokio::{net::TcpListener, sync::mpsc};
+use tower_service::Service;
+
+use crate::{
+    database::indexer::{self, IndexerStatus},
+    git::{ArchiveLimits, CacheConfig, Git},
+    layers::logger::REQ_TIMESTAMP,
+    locale::LocaleRef,
+    methods::{
+        self,
+        repo::{self, CloneUrls, EntryLimits, ExportAll, PushAccess, ScanRoot, StrictReachability},
+        status::Readiness,
+    },
+    snapshot_cache::SnapshotCache,
+    storage::Storage,
+    IndexTrigger,
+};
+
+/// Contents of two blobs whose object ids share their first 7 characters, `2acdf8c`.
+pub const COLLIDING_BLOBS: [&str; 2] = ["collision 26993\n", "collision 30025\n"];
+
+/// Opens a database in `dir` with every column family the server uses.
+pub fn database(dir: &Path) -> Arc<rocksdb::DB> {
+    crate::open_db(dir, None, false, false).unwrap()
+}
+
+/// Indexes every repository within `scan_path` into `db`, exported or not.
+pub fn index(scan_path: &Path, db: &Arc<rocksdb::DB>) {
+    indexer::run(
+        scan_path,
+        db,
+        u64::MAX,
+        true,
+        &ScanRoot::new(scan_path, false).unwrap(),
+        indexer::DEFAULT_TOMBSTONE_RETENTION,
+    );
+}
+
+/// Serves repositories within `scan_path` from what's been indexed into `db`, with the same
+/// extensions the server gives requests when run without any options.
+pub fn app(scan_path: &Path, db: Arc<rocksdb::DB>) -> Router {
+    app_with_git(
+        scan_path,
+        db,
+        Arc::new(Git::new(CacheConfig::default(), u64::MAX)),
+    )
+}
+
+/// Serves repositories like [`app`], through `git` so the caller can see or invalidate what it
+/// caches.
+pub fn app_with_git(scan_path: &Path, db: Arc<rocksdb::DB>, git: Arc<Git>) -> Router {
+    router(
+        scan_path,
+        db,
+        git,
+        StrictReachability(false),
+        ExportAll(true),
+    )
+}
+
+/// Serves repositories like [`app`], as though run with `--strict-reachability`.
+pub fn strict_app(scan_path: &Path, db: Arc<rocksdb::DB>) -> Router {
+    let git = Arc::new(Git::new(CacheConfig::default(), u64::MAX));
+    router(
+        scan_path,
+        db,
+        git,
+        StrictReachability(true),
+        ExportAll(true),
+    )
+}
+
+/// Serves repositories like [`app`], as though run with `--export-all false` so only those
+/// with a `git-daemon-export-ok` file are served.
+pub fn export_ok_app(scan_path: &Path, db: Arc<rocksdb::DB>) -> Router {
+    let git = Arc::new(Git::new(CacheConfig::default(), u64::MAX));
+    router(
+        scan_path,
+        db,
+        git,
+        StrictReachability(false),
+        ExportAll(false),
+    )
+}
+
+fn router(
+    scan_path: &Path,
+    db: Arc<rocksdb::DB>,
+    git: Arc<Git>,
+    strict: StrictReachability,
+    export_all: ExportAll,
+) -> Router {
+    // set by the server once it's built the stylesheets, which pages link to
+    crate::HIGHLIGHT_CSS_HASH.get_or_init(|| "test".into());
+    crate::DARK_HIGHLIGHT_CSS_HASH.get_or_init(|| "test".into());
+
+    Router::new()
+        .route("/", routing::get(methods::index::handle))
+        .route("/-/ready", routing::get(methods::status::handle_ready))
+        .fallback(repo::service)
+        .layer(Extension(git))
+        .layer(Extension(None::<Arc<SnapshotCache>>))
+        .layer(Extension(Arc::new(ArcSwap::from_pointee(
+            IndexerStatus::new(&db, None),
+        ))))
+        .layer(Extension(db))
+        .layer(Extension(Readiness::new(true)))
+        .layer(Extension(Storage::new(scan_path)))
+        .layer(Extension(None::<mpsc::Sender<IndexTrigger>>))
+        .layer(Extension(ScanRoot::new(scan_path, false).unwrap()))
+        .layer(Extension(None::<PushAccess>))
+        .layer(Extension(Arc::new(scan_path.to_path_buf())))
+        .layer(Extension(export_all))
+        .layer(Extension(strict))
+        .layer(Extension(ArchiveLimits::default()))
+        .layer(Extension(EntryLimits::default()))
+        .layer(Extension(CloneUrls(Arc::from([]))))
+        .layer(Extension(LocaleRef::default()))
+}
+
+/// Sends a GET request for `uri` with `headers` through `app`.
+pub async fn get(app: &mut Router, uri: &str, headers: &[(&str, &str)]) -> Response<Body> {
+    let mut request = Request::get(uri);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+
+    let response = app.call(request.body(Body::empty()).unwrap());
+    REQ_TIMESTAMP.scope(Instant::now(), response).await.unwrap()
+}
+
+/// Serves `app` over HTTP on a free port of localhost, for clients such as `git` that can only
+/// be pointed at a URL, returning the address it's listening on.
+pub async fn serve(app: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // set by the logger in front of every request the server handles
+    let app = app.layer(middleware::from_fn(|request, next: Next| {
+        REQ_TIMESTAMP.scope(Instant::now(), next.run(request))
+    }));
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    address
+}
+
+/// A bare repository named `repo.git` alone in a temporary scan path, which is removed once
+/// it's dropped.
+pub struct TestRepo {
+    dir: TempDir,
+    path: PathBuf,
+}
+
+impl TestRepo {
+    /// Creates an empty repository with its HEAD pointing at `main`.
+    pub fn new() -> Self {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("repo.git");
+        std::fs::create_dir(&path).unwrap();
+
+        let repo = Self { dir, path };
+        repo.git(&["init", "--quiet", "--bare", "--initial-branch=main"]);
+        repo
+    }
+
+    /// The directory the repository was created in, to be used as the scan path.
+    pub fn scan_path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn open(&self) -> gix::Repository {
+        gix::open(self.path()).unwrap()
+    }
+
+    /// Runs `git` in the repository, isolated from the user's configuration, returning its
+    /// trimmed stdout.
+    pub fn git(&self, args: &[&str]) -> String {
+        self.git_with_input(args, &[])
+    }
+
+    /// Runs `git` in the repository like [`Self::git`], with `input` as its stdin.
+    pub fn git_with_input(&self, args: &[&str], input: &[u8]) -> String {
+        let output = self.run(args, input);
+        assert!(
+            output.status.success(),
+            "git {args:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Writes `contents` to `file`, which may be nested within directories, and commits it
+    /// on top of HEAD, returning the oid of the new commit.
+    pub fn commit(&self, file: &str, contents: impl AsRef<[u8]>) -> String {
+        // staged through an index kept outside of the repository, since it's bare
+        let blob = self.blob(contents);
+        self.git(&[
+            "update-index",
+            "--add",
+            "--cacheinfo",
+            &format!("100644,{blob},{file}"),
+        ]);
+        let tree = self.git(&["write-tree"]);
+
+        let parent = self.run(&["rev-parse", "--verify", "--quiet", "HEAD^{commit}"], &[]);
+        let parent = String::from_utf8(parent.stdout).unwrap();
+        let message = format!("Update {file}");
+        let mut args = vec!["commit-tree", &tree, "-m", &message];
+        if !parent.trim().is_empty() {
+            args.extend(["-p", parent.trim()]);
+        }
+
+        let commit = self.git(&args);
+        self.git(&["update-ref", "HEAD", &commit]);
+        commit
+    }
+
+    /// Removes `file` from the index, so it's left out of the next commit.
+    pub fn remove(&self, file: &str) {
+        // --force-remove needs a work tree, which bare repositories don't have
+        let input = format!("0 {}\t{file}\n", "0".repeat(40));
+        self.git_with_input(&["update-index", "--index-info"], input.as_bytes());
+    }
+
+    /// Clones the repository into `name` alongside it in the scan path, passing `args` to
+    /// `git clone` such as to make a shallow or partial mirror, returning the clone's path.
+    pub fn clone(&self, name: &str, args: &[&str]) -> PathBuf {
+        let path = self.dir.path().join(name);
+        // local clones ignore --depth and --filter unless they go through a transport
+        let url = format!("file://{}", self.path().display());
+
+        let mut clone = vec!["clone", "--quiet", "--bare"];
+        clone.extend(args);
+        clone.extend([url.as_str(), path.to_str().unwrap()]);
+        self.git(&clone);
+
+        path
+    }
+
+    /// Writes a blob holding `contents`, returning its oid.
+    pub fn blob(&self, contents: impl AsRef<[u8]>) -> String {
+        self.git_with_input(&["hash-object", "-w", "--stdin"], contents.as_ref())
+    }
+
+    /// Writes a tree of the blobs in `entries`, given as their name and oid, returning its
+    /// oid. Unlike commits made through the index, the tree isn't checked for names git
+    /// itself would refuse to write.
+    pub fn tree(&self, entries: &[(&str, &str)]) -> String {
+        let mut input = String::new();
+        for (name, oid) in entries {
+            writeln!(input, "100644 blob {oid}\t{name}").unwrap();
+        }
+
+        self.git_with_input(&["mktree"], input.as_bytes())
+    }
+
+    fn run(&self, args: &[&str], input: &[u8]) -> Output {
+        let mut child = Command::new("git")
+            .args(args)
+            .current_dir(self.path())
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_INDEX_FILE", self.dir.path().join("index"))
+            .env("GIT_AUTHOR_NAME", "rgit")
+            .env("GIT_AUTHOR_EMAIL", "rgit@example.com")
+            .env("GIT_AUTHOR_DATE", "2024-01-01T00:00:00Z")
+            .env("GIT_COMMITTER_NAME", "rgit")
+            .env("GIT_COMMITTER_EMAIL", "rgit@example.com")
+            .env("GIT_COMMITTER_DATE", "2024-01-01T00:00:00Z")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // the commands used read all of their input before writing any output, so this can't
+        // deadlock on a full pipe
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        child.wait_with_output().unwrap()
+    }
+}
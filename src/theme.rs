@@ -85,7 +85,9 @@ impl Theme {
         let mut out = String::new();
 
         for (kind, palette_ref) in &self.definitions {
-            write!(out, ".highlight.{kind} {{").unwrap();
+            // scoped to the containers of highlighted code, so they can't clash with anything
+            // else that happens to share the class names
+            write!(out, ".code .highlight.{kind} {{").unwrap();
 
             match palette_ref {
                 PaletteReference::Foreground(color) => {
@@ -125,3 +127,24 @@ impl Theme {
         out
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Theme;
+
+    #[test]
+    fn rules_scoped_to_code() {
+        for theme in [
+            include_str!("../themes/github_light.toml"),
+            include_str!("../themes/onedark.toml"),
+        ] {
+            let css = basic_toml::from_str::<Theme>(theme).unwrap().build_css();
+
+            let rules: Vec<_> = css.split_terminator('}').collect();
+            assert!(!rules.is_empty());
+            for rule in rules {
+                assert!(rule.starts_with(".code .highlight."), "{rule}");
+            }
+        }
+    }
+}
@@ -8,23 +8,26 @@ use gix::diff::blob::{
     Sink,
 };
 
+/// Formats each line of the diff. Along with its content, each line is given its index
+/// within the side of the diff it came from, the new side for additions and the old side for
+/// removals. Context is on both sides, so is given its index within each.
 pub(crate) trait Callback {
-    fn addition(&mut self, data: &str, dst: &mut String);
-    fn remove(&mut self, data: &str, dst: &mut String);
-    fn context(&mut self, data: &str, dst: &mut String);
+    fn addition(&mut self, line: u32, data: &str, dst: &mut String);
+    fn remove(&mut self, line: u32, data: &str, dst: &mut String);
+    fn context(&mut self, old_line: u32, new_line: u32, data: &str, dst: &mut String);
 }
 
 impl<C: Callback> Callback for &mut C {
-    fn addition(&mut self, data: &str, dst: &mut String) {
-        (*self).addition(data, dst);
+    fn addition(&mut self, line: u32, data: &str, dst: &mut String) {
+        (*self).addition(line, data, dst);
     }
 
-    fn remove(&mut self, data: &str, dst: &mut String) {
-        (*self).remove(data, dst);
+    fn remove(&mut self, line: u32, data: &str, dst: &mut String) {
+        (*self).remove(line, data, dst);
     }
 
-    fn context(&mut self, data: &str, dst: &mut String) {
-        (*self).context(data, dst);
+    fn context(&mut self, old_line: u32, new_line: u32, data: &str, dst: &mut String) {
+        (*self).context(old_line, new_line, data, dst);
     }
 }
 
@@ -40,6 +43,9 @@ where
     interner: &'a Interner<&'a str>,
 
     pos: u32,
+    /// How far lines on the new side have moved from where they were on the old side, as of
+    /// the last change.
+    offset: i64,
     before_hunk_start: u32,
     after_hunk_start: u32,
     before_hunk_len: u32,
@@ -70,6 +76,7 @@ where
             after: &input.after,
             callback,
             pos: 0,
+            offset: 0,
         }
     }
 
@@ -97,9 +104,11 @@ where
     }
 
     fn update_pos(&mut self, print_to: u32, move_to: u32) {
-        for token in &self.before[self.pos as usize..print_to as usize] {
+        for line in self.pos..print_to {
+            let token = self.before[line as usize];
+            let new_line = u32::try_from(i64::from(line) + self.offset).unwrap_or(line);
             self.callback
-                .context(self.interner[*token], &mut self.buffer);
+                .context(line, new_line, self.interner[token], &mut self.buffer);
         }
         let len = print_to - self.pos;
         self.pos = move_to;
@@ -125,15 +134,18 @@ where
         self.update_pos(before.start, before.end);
         self.before_hunk_len += before.end - before.start;
         self.after_hunk_len += after.end - after.start;
+        self.offset = i64::from(after.end) - i64::from(before.end);
 
-        for token in &self.before[before.start as usize..before.end as usize] {
+        for line in before {
+            let token = self.before[line as usize];
             self.callback
-                .remove(self.interner[*token], &mut self.buffer);
+                .remove(line, self.interner[token], &mut self.buffer);
         }
 
-        for token in &self.after[after.start as usize..after.end as usize] {
+        for line in after {
+            let token = self.after[line as usize];
             self.callback
-                .addition(self.interner[*token], &mut self.buffer);
+                .addition(line, self.interner[token], &mut self.buffer);
         }
     }
 
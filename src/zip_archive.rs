@@ -0,0 +1,161 @@
+//! A minimal zip writer for snapshots, built on top of flate2 so it can stream archives out
+//! as they're built.
+//!
+//! Every file is held in memory when it's added, so the sizes and checksum can be written
+//! ahead of its data without needing data descriptors. Zip64 isn't supported, archives that
+//! would need it fail to build.
+
+use std::io::Write;
+
+use anyhow::Context;
+use bytes::{BufMut, Bytes, BytesMut};
+use flate2::{write::DeflateEncoder, Compression, Crc};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+
+/// Version 2.0 of the format, the first to support deflate and directories.
+const VERSION: u16 = 20;
+/// Marks the archive as made on unix, so extractors apply the file modes we give.
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION;
+/// Marks file names as UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// 1980-01-01 00:00:00, the earliest time the format can represent. Snapshots only depend on
+/// their tree, so there's no time to give files that wouldn't change the archive's content.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+struct CentralDirectoryEntry {
+    name: Vec<u8>,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    method: u16,
+    mode: u32,
+    offset: u32,
+}
+
+/// Writes a zip archive into an in-memory buffer, which can be drained with
+/// [`ZipBuilder::buffer`] as it fills.
+pub struct ZipBuilder {
+    buffer: BytesMut,
+    /// Number of bytes written to the archive so far, including those drained.
+    offset: u64,
+    entries: Vec<CentralDirectoryEntry>,
+}
+
+impl ZipBuilder {
+    pub fn new(buffer: BytesMut) -> Self {
+        Self {
+            buffer,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    /// The archive written so far that hasn't been drained yet.
+    pub fn buffer(&mut self) -> &mut BytesMut {
+        &mut self.buffer
+    }
+
+    /// Adds a file to the archive. `mode` is the unix mode of the file, including its type,
+    /// so symlinks are stored as files holding the path they point to but are recreated as
+    /// links by extractors that understand unix modes, as `git archive` does.
+    pub fn append(&mut self, name: &[u8], mode: u32, data: &[u8]) -> anyhow::Result<()> {
+        let mut crc = Crc::new();
+        crc.update(data);
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+
+        // incompressible files are bigger once deflated
+        let (method, content) = if compressed.len() < data.len() {
+            (METHOD_DEFLATED, compressed.as_slice())
+        } else {
+            (METHOD_STORED, data)
+        };
+
+        let entry = CentralDirectoryEntry {
+            name: name.to_vec(),
+            crc: crc.sum(),
+            compressed_size: u32::try_from(content.len()).context(TOO_LARGE)?,
+            size: u32::try_from(data.len()).context(TOO_LARGE)?,
+            method,
+            mode,
+            offset: u32::try_from(self.offset).context(TOO_LARGE)?,
+        };
+
+        let header_start = self.buffer.len();
+        self.buffer.put_u32_le(LOCAL_FILE_HEADER_SIGNATURE);
+        self.buffer.put_u16_le(VERSION);
+        self.buffer.put_u16_le(FLAG_UTF8);
+        self.buffer.put_u16_le(entry.method);
+        self.buffer.put_u16_le(DOS_TIME);
+        self.buffer.put_u16_le(DOS_DATE);
+        self.buffer.put_u32_le(entry.crc);
+        self.buffer.put_u32_le(entry.compressed_size);
+        self.buffer.put_u32_le(entry.size);
+        self.buffer
+            .put_u16_le(u16::try_from(name.len()).context("file name too long")?);
+        self.buffer.put_u16_le(0);
+        self.buffer.put_slice(name);
+        self.buffer.put_slice(content);
+
+        self.offset += (self.buffer.len() - header_start) as u64;
+        self.entries.push(entry);
+
+        Ok(())
+    }
+
+    /// Writes the central directory, returning the remainder of the archive.
+    pub fn finish(mut self) -> anyhow::Result<Bytes> {
+        let central_directory_start = self.buffer.len();
+
+        for entry in &self.entries {
+            self.buffer.put_u32_le(CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+            self.buffer.put_u16_le(VERSION_MADE_BY);
+            self.buffer.put_u16_le(VERSION);
+            self.buffer.put_u16_le(FLAG_UTF8);
+            self.buffer.put_u16_le(entry.method);
+            self.buffer.put_u16_le(DOS_TIME);
+            self.buffer.put_u16_le(DOS_DATE);
+            self.buffer.put_u32_le(entry.crc);
+            self.buffer.put_u32_le(entry.compressed_size);
+            self.buffer.put_u32_le(entry.size);
+            // already checked when the file was added
+            #[allow(clippy::cast_possible_truncation)]
+            self.buffer.put_u16_le(entry.name.len() as u16);
+            self.buffer.put_u16_le(0); // extra field length
+            self.buffer.put_u16_le(0); // comment length
+            self.buffer.put_u16_le(0); // disk number
+            self.buffer.put_u16_le(0); // internal attributes
+            self.buffer.put_u32_le(entry.mode << 16);
+            self.buffer.put_u32_le(entry.offset);
+            self.buffer.put_slice(&entry.name);
+        }
+
+        let central_directory_size = self.buffer.len() - central_directory_start;
+        let entries = u16::try_from(self.entries.len()).context(TOO_LARGE)?;
+
+        self.buffer.put_u32_le(END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        self.buffer.put_u16_le(0); // disk number
+        self.buffer.put_u16_le(0); // disk with the central directory
+        self.buffer.put_u16_le(entries);
+        self.buffer.put_u16_le(entries);
+        self.buffer
+            .put_u32_le(u32::try_from(central_directory_size).context(TOO_LARGE)?);
+        self.buffer
+            .put_u32_le(u32::try_from(self.offset).context(TOO_LARGE)?);
+        self.buffer.put_u16_le(0); // comment length
+
+        Ok(self.buffer.freeze())
+    }
+}
+
+const TOO_LARGE: &str = "snapshot is too large to be built as a zip archive";
@@ -157,6 +157,9 @@ fn build_language_registry(
     let mut injection_regex_str_len = Vec::new();
     let mut regex_to_camel = Vec::new();
 
+    let mut shebangs = Vec::new();
+    let mut shebang_to_camel = Vec::new();
+
     for language in &language_definition {
         if BLACKLISTED_MODULES.contains(&language.name.as_str())
             || (!dylib && BLACKLISTED_FOR_STATIC_LINKING.contains(&language.name.as_str()))
@@ -187,6 +190,14 @@ fn build_language_registry(
             injection_regex_str_len.push(regex.len());
             regex_to_camel.push(camel_cased_name.clone());
         }
+
+        for shebang in &language.shebangs {
+            // the first language to claim an interpreter keeps it
+            if !shebangs.contains(shebang) {
+                shebangs.push(shebang.clone());
+                shebang_to_camel.push(camel_cased_name.clone());
+            }
+        }
     }
 
     let injection_regex_len = injection_regex.len();
@@ -269,6 +280,15 @@ fn build_language_registry(
 
                 curr
             }
+
+            /// Finds the language of scripts run by `interpreter`, the name of the program in
+            /// their shebang without any version (ie. `python` for `#!/usr/bin/python3`).
+            pub fn from_shebang(interpreter: &str) -> Option<Self> {
+                match interpreter {
+                    #(#shebangs => Some(Self::#shebang_to_camel),)*
+                    _ => None,
+                }
+            }
         }
     })
 }
@@ -525,6 +545,8 @@ struct LanguageDefinition {
     injection_regex: Option<String>,
     file_types: Vec<FileType>,
     grammar: Option<String>,
+    #[serde(default)]
+    shebangs: Vec<String>,
 }
 
 #[derive(Deserialize)]